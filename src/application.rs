@@ -2,15 +2,22 @@
 const TEST_MEMORY_CAPACITY: u64 = 1024 * 512;
 const PROGRAM_MEMORY_CAPACITY: u64 = 1024 * 1024 * 128; // big enough to run xv6
 
-use cpu::{Cpu, Xlen};
+use clint::TimerMode;
+use entropy_source::EntropySource;
+use cpu::{AccessedDirtyPolicy, Cpu, MisalignedAccessPolicy, TraceEvent, UnimplementedPolicy, WatchpointHit, Xlen};
+use gdbstub::GdbStub;
+use mmu::{MemoryMap, WatchKind};
+use mmio_device::MmioDevice;
+use stats::ExecStats;
 use terminal::Terminal;
 
+use std::fs::File;
+
 pub struct Application {
 	cpu: Cpu,
 
-	// riscv-tests specific properties
-	is_test: bool,
-	tohost_addr: u64
+	// riscv-tests specific property
+	is_test: bool
 }
 
 struct SectionHeader {
@@ -27,13 +34,12 @@ struct SectionHeader {
 }
 
 impl Application {
-	pub fn new(terminal: Box<dyn Terminal>) -> Self {
+	pub fn new(terminal: Box<dyn Terminal>, timer_mode: TimerMode) -> Self {
 		Application {
-			cpu: Cpu::new(terminal),
+			cpu: Cpu::new(terminal, timer_mode),
 
-			// These can be updated in setup_from_elf
-			is_test: false,
-			tohost_addr: 0
+			// This can be updated in setup_from_elf
+			is_test: false
 		}
 	}
 
@@ -44,8 +50,9 @@ impl Application {
 		};
 	}
 
+	// Runs until the program halts the machine
 	pub fn run_program(&mut self) {
-		loop {
+		while !self.cpu.is_halted() {
 			self.tick();
 		}
 	}
@@ -53,34 +60,75 @@ impl Application {
 	// Method for running riscv-tests
 	pub fn run_test(&mut self) {
 		println!("This elf file seems riscv-tests elf file. Running in test mode.");
-		loop {
+		// riscv-tests end by writing the result to tohost, which
+		// halts the machine. Exit code 0 means pass and the others
+		// are the failed test number.
+		while !self.cpu.is_halted() {
 			self.cpu.dump_current_instruction_to_terminal();
 
 			self.tick();
-
-			// It seems in riscv-tests ends with end code
-			// written to a certain physical memory address
-			// (0x80001000 in mose test cases) so checking
-			// the data in the address and terminating the test
-			// if non-zero data is written.
-			// End code 1 seems to mean pass.
-			let endcode = self.cpu.load_word_raw(self.tohost_addr);
-			if endcode != 0 {
-				match endcode {
-					1 => {
-						self.cpu.put_bytes_to_terminal(format!("Test Passed with {:X}\n", endcode).as_bytes())
-					},
-					_ => {
-						self.cpu.put_bytes_to_terminal(format!("Test Failed with {:X}\n", endcode).as_bytes())
-					}
-				};
-				break;
-			}
 		}
+		match self.cpu.get_exit_code() {
+			Some(0) => {
+				self.cpu.put_bytes_to_terminal("Test Passed\n".as_bytes())
+			},
+			Some(exit_code) => {
+				self.cpu.put_bytes_to_terminal(format!("Test Failed with {:X}\n", exit_code).as_bytes())
+			},
+			None => {}
+		};
+	}
+
+	// Waits for a debugger to connect to the address and runs under
+	// its control until it detaches
+	pub fn run_with_gdb(&mut self, address: &str) -> std::io::Result<()> {
+		let mut stub = match GdbStub::accept(address) {
+			Ok(stub) => stub,
+			Err(e) => return Err(e)
+		};
+		stub.run(&mut self.cpu)
+	}
+
+	// Returns true if pc hits a breakpoint
+	pub fn tick(&mut self) -> bool {
+		self.cpu.tick()
+	}
+
+	pub fn is_halted(&self) -> bool {
+		self.cpu.is_halted()
+	}
+
+	pub fn has_pending_interrupt(&self) -> bool {
+		self.cpu.has_pending_interrupt()
+	}
+
+	pub fn get_next_timer_deadline(&self) -> Option<u64> {
+		self.cpu.get_next_timer_deadline()
+	}
+
+	pub fn preload_input(&mut self, data: &[u8]) {
+		self.cpu.preload_input(data);
+	}
+
+	pub fn get_uart_divisor(&self) -> u16 {
+		self.cpu.get_uart_divisor()
 	}
 
-	pub fn tick(&mut self) {
-		self.cpu.tick();
+	// Exit code given by the program when halting the machine
+	pub fn get_exit_code(&self) -> Option<u64> {
+		self.cpu.get_exit_code()
+	}
+
+	pub fn update_stats_enabled(&mut self, enabled: bool) {
+		self.cpu.update_stats_enabled(enabled);
+	}
+
+	pub fn get_stats(&self) -> &ExecStats {
+		self.cpu.get_stats()
+	}
+
+	pub fn reset_stats(&mut self) {
+		self.cpu.reset_stats();
 	}
 
 	// Expecting this method is called only once
@@ -423,11 +471,12 @@ impl Application {
 
 		if tohost_addr != 0 {
 			self.is_test = true;
-			self.tohost_addr = tohost_addr;
+			self.cpu.update_tohost_address(tohost_addr);
+			// fromhost follows tohost in .tohost section, aligned to 64 bytes
+			self.cpu.update_fromhost_address(tohost_addr + 0x40);
 			self.cpu.setup_memory(TEST_MEMORY_CAPACITY);
 		} else {
 			self.is_test = false;
-			self.tohost_addr = 0;
 			self.cpu.setup_memory(PROGRAM_MEMORY_CAPACITY);
 		}
 
@@ -435,10 +484,11 @@ impl Application {
 			let sh_addr = program_data_section_headers[i].sh_addr;
 			let sh_offset = program_data_section_headers[i].sh_offset;
 			let sh_size = program_data_section_headers[i].sh_size;
-			if sh_addr >= 0x80000000 && sh_offset > 0 && sh_size > 0 {
-				for j in 0..sh_size as usize {
-					self.cpu.store_raw(sh_addr + j as u64, data[sh_offset as usize + j]);
-				}
+			if sh_addr >= self.cpu.get_memory_map().dram_base && sh_offset > 0 && sh_size > 0 {
+				match self.cpu.write_memory(sh_addr, &data[sh_offset as usize..(sh_offset + sh_size) as usize]) {
+					Ok(()) => {},
+					Err(()) => panic!("No memory to load elf section AD:{:X}", sh_addr)
+				};
 			}
 		}
 
@@ -449,10 +499,125 @@ impl Application {
 		self.cpu.setup_filesystem(data);
 	}
 
+	pub fn setup_filesystem_with_file(&mut self, file: File) -> std::io::Result<()> {
+		self.cpu.setup_filesystem_with_file(file)
+	}
+
+	pub fn update_disk_legacy(&mut self, legacy: bool) {
+		self.cpu.update_disk_legacy(legacy);
+	}
+
+	pub fn update_entropy_source(&mut self, entropy_source: Box<dyn EntropySource>) {
+		self.cpu.update_entropy_source(entropy_source);
+	}
+
+	pub fn flush_filesystem(&mut self) -> std::io::Result<()> {
+		self.cpu.flush_filesystem()
+	}
+
 	pub fn update_xlen(&mut self, xlen: Xlen) {
 		self.cpu.update_xlen(xlen);
 	}
 
+	pub fn setup_boot(&mut self, hart_id: u64, dtb_address: u64) {
+		self.cpu.setup_boot(hart_id, dtb_address);
+	}
+
+	// Expected to be called after setup_from_elf()
+	pub fn setup_dtb(&mut self, address: u64, data: &[u8]) -> Result<(), ()> {
+		self.cpu.setup_dtb(address, data)
+	}
+
+	// Expected to be called after setup_from_elf() and setup_harts()
+	pub fn generate_dtb(&self) -> Vec<u8> {
+		self.cpu.generate_dtb()
+	}
+
+	// Services SBI calls from supervisor mode in the emulator and starts
+	// the program in supervisor mode, to run a kernel without firmware.
+	// Expected to be called after setup_from_elf() and before setup_harts()
+	pub fn setup_sbi(&mut self) {
+		self.cpu.update_sbi_enabled(true);
+		self.cpu.enter_supervisor_mode();
+	}
+
+	// Expected to be called after setup_from_elf()
+	pub fn setup_harts(&mut self, num: usize) {
+		self.cpu.setup_harts(num);
+	}
+
+	// Expected to be called before setup_from_elf()
+	pub fn update_memory_map(&mut self, memory_map: MemoryMap) {
+		self.cpu.update_memory_map(memory_map);
+	}
+
+	pub fn register_device(&mut self, device: Box<dyn MmioDevice>) {
+		self.cpu.register_device(device);
+	}
+
+	pub fn get_memory_size(&self) -> u64 {
+		self.cpu.get_memory_size()
+	}
+
+	pub fn raise_irq(&mut self, irq: u32) {
+		self.cpu.raise_irq(irq);
+	}
+
+	pub fn lower_irq(&mut self, irq: u32) {
+		self.cpu.lower_irq(irq);
+	}
+
+	// (address, name, value) of the CSRs of the running hart, for debugging
+	pub fn dump_csrs(&self) -> Vec<(u16, &'static str, u64)> {
+		self.cpu.dump_csrs()
+	}
+
+	pub fn set_unimplemented_policy(&mut self, policy: UnimplementedPolicy) {
+		self.cpu.set_unimplemented_policy(policy);
+	}
+
+	pub fn set_misaligned_access_policy(&mut self, policy: MisalignedAccessPolicy) {
+		self.cpu.set_misaligned_access_policy(policy);
+	}
+
+	pub fn set_accessed_dirty_policy(&mut self, policy: AccessedDirtyPolicy) {
+		self.cpu.set_accessed_dirty_policy(policy);
+	}
+
+	pub fn set_trace_hook(&mut self, hook: Box<dyn FnMut(TraceEvent)>) {
+		self.cpu.set_trace_hook(hook);
+	}
+
+	#[cfg(feature = "serde")]
+	pub fn save_state(&self) -> Result<Vec<u8>, String> {
+		self.cpu.save_state()
+	}
+
+	#[cfg(feature = "serde")]
+	pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+		self.cpu.load_state(data)
+	}
+
+	pub fn add_breakpoint(&mut self, address: u64) {
+		self.cpu.add_breakpoint(address);
+	}
+
+	pub fn remove_breakpoint(&mut self, address: u64) {
+		self.cpu.remove_breakpoint(address);
+	}
+
+	pub fn add_watchpoint(&mut self, address: u64, length: u64, kind: WatchKind) {
+		self.cpu.add_watchpoint(address, length, kind);
+	}
+
+	pub fn remove_watchpoint(&mut self, address: u64, length: u64, kind: WatchKind) -> bool {
+		self.cpu.remove_watchpoint(address, length, kind)
+	}
+
+	pub fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+		self.cpu.take_watchpoint_hit()
+	}
+
 	// Wasm speicific methods
 
 	pub fn get_output(&mut self) -> u8 {
@@ -462,4 +627,12 @@ impl Application {
 	pub fn put_input(&mut self, data: u8) {
 		self.cpu.put_input(data);
 	}
+
+	pub fn get_output_bytes(&mut self) -> Vec<u8> {
+		self.cpu.get_output_bytes()
+	}
+
+	pub fn put_input_bytes(&mut self, data: &[u8]) {
+		self.cpu.put_input_bytes(data);
+	}
 }
\ No newline at end of file
@@ -1,53 +1,187 @@
+use clock_source::ClockSource;
+#[cfg(feature = "serde")]
+use clock_source::MonotonicClock;
+
+// Core Local Interruptor. msip and mtimecmp registers are per hart,
+// msip at 0x0000 + 4 * hart and mtimecmp at 0x4000 + 8 * hart.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Clint {
 	clock: u64,
-	period_clock: u64,
-	interrupting: bool
+	timer_mode: TimerMode,
+	mtime: u64,
+	// Host time in nanoseconds and mtime when mtime started following the clock source.
+	// Reset after restoring state so that mtime continues from the saved value.
+	#[cfg_attr(feature = "serde", serde(skip))]
+	base_time: Option<(u64, u64)>,
+	msip: Vec<u32>,
+	mtimecmp: Vec<u64>,
+	// Clock source can't be serialized. Restored Clint follows host monotonic
+	// clock until the clock source is moved in with swap_clock_source()
+	#[cfg_attr(feature = "serde", serde(skip, default = "create_monotonic_clock"))]
+	clock_source: Box<dyn ClockSource>
+}
+
+// How mtime advances
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TimerMode {
+	// Follows the clock source at the timebase frequency in Hz. The
+	// default host monotonic clock isn't available on wasm32.
+	RealTime(u64),
+	// Incremented by one every tick, for reproducible runs. UART takes
+	// input only from preloaded one in this mode, see Cpu.preload_input().
+	Deterministic
+}
+
+// The same as common device trees, including QEMU virt machine
+pub const DEFAULT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+#[cfg(feature = "serde")]
+fn create_monotonic_clock() -> Box<dyn ClockSource> {
+	Box::new(MonotonicClock::new())
 }
 
 impl Clint {
-	pub fn new() -> Self {
-		Clint {
+	// clock_source is used only in real time mode
+	pub fn new(timer_mode: TimerMode, clock_source: Box<dyn ClockSource>) -> Self {
+		let mut clint = Clint {
 			clock: 0,
-			period_clock: 0,
-			interrupting: false
-		}
+			timer_mode: timer_mode,
+			mtime: 0,
+			base_time: None,
+			msip: vec![],
+			mtimecmp: vec![],
+			clock_source: clock_source
+		};
+		clint.init_harts(1);
+		clint
+	}
+
+	pub fn init_harts(&mut self, num: usize) {
+		self.msip.resize(num, 0);
+		// Timer doesn't fire until mtimecmp is written
+		self.mtimecmp.resize(num, 0xffffffffffffffff);
 	}
 
 	pub fn tick(&mut self) {
-		// @TODO: Implement more properly
-		if self.period_clock > 0 && (self.clock % self.period_clock) == 0 {
-			self.interrupting = true;
-		}
+		self.update_mtime();
 		self.clock = self.clock.wrapping_add(1);
 	}
 
-	pub fn load(&self, _address: u64) -> u8 {
-		0 // @TODO: Implement properly
+	fn update_mtime(&mut self) {
+		match self.timer_mode {
+			TimerMode::RealTime(frequency) => {
+				// Reading host clock every tick is expensive so
+				// it's read once per 256 ticks
+				if (self.clock & 0xff) != 0 && self.base_time.is_some() {
+					return;
+				}
+				let now = self.clock_source.now_ns();
+				let (base_ns, base_mtime) = match self.base_time {
+					Some(base_time) => base_time,
+					None => {
+						let base_time = (now, self.mtime);
+						self.base_time = Some(base_time);
+						base_time
+					}
+				};
+				let elapsed = now.wrapping_sub(base_ns);
+				let ticks = (elapsed / 1_000_000_000) * frequency +
+					(elapsed % 1_000_000_000) * frequency / 1_000_000_000;
+				self.mtime = base_mtime.wrapping_add(ticks);
+			},
+			TimerMode::Deterministic => {
+				self.mtime = self.mtime.wrapping_add(1);
+			}
+		};
+	}
+
+	#[cfg(feature = "serde")]
+	pub fn swap_clock_source(&mut self, other: &mut Clint) {
+		std::mem::swap(&mut self.clock_source, &mut other.clock_source);
 	}
 
-	pub fn store(&mut self, address: u64, value: u8) {
-		match address {
-			0x02004000 => {
-				self.period_clock = (self.period_clock & !0xff) | (value as u64);
+	pub fn read_mtime(&self) -> u64 {
+		self.mtime
+	}
+
+	pub fn load(&self, offset: u64) -> u8 {
+		match offset {
+			0x0000..=0x3fff => {
+				let hart = (offset / 4) as usize;
+				match hart < self.msip.len() {
+					true => (self.msip[hart] >> ((offset % 4) * 8)) as u8,
+					false => 0
+				}
 			},
-			0x02004001 => {
-				self.period_clock = (self.period_clock & !0xff00) | ((value as u64) << 8);
+			0x4000..=0xbff7 => {
+				let hart = ((offset - 0x4000) / 8) as usize;
+				match hart < self.mtimecmp.len() {
+					true => (self.mtimecmp[hart] >> ((offset % 8) * 8)) as u8,
+					false => 0
+				}
+			},
+			0xbff8..=0xbfff => (self.mtime >> ((offset % 8) * 8)) as u8,
+			_ => 0
+		}
+	}
+
+	pub fn store(&mut self, offset: u64, value: u8) {
+		match offset {
+			0x0000..=0x3fff => {
+				let hart = (offset / 4) as usize;
+				// Only the lowest bit of msip is writable
+				if hart < self.msip.len() && (offset & 0x3) == 0 {
+					self.msip[hart] = (value & 1) as u32;
+				}
 			},
-			0x02004002 => {
-				self.period_clock = (self.period_clock & !0xff0000) | ((value as u64) << 16);
+			0x4000..=0xbff7 => {
+				let hart = ((offset - 0x4000) / 8) as usize;
+				if hart < self.mtimecmp.len() {
+					let shift = (offset % 8) * 8;
+					self.mtimecmp[hart] = (self.mtimecmp[hart] & !(0xff << shift)) | ((value as u64) << shift);
+				}
 			},
-			0x02004003 => {
-				self.period_clock = (self.period_clock & !0xff000000) | ((value as u64) << 24);
+			0xbff8..=0xbfff => {
+				let shift = (offset % 8) * 8;
+				self.mtime = (self.mtime & !(0xff << shift)) | ((value as u64) << shift);
+				// Following host clock restarts from the written value
+				self.base_time = None;
 			},
 			_ => {}
 		};
 	}
 
-	pub fn is_interrupting(&self) -> bool {
-		self.interrupting
+	// For firmware emulated by host, the same as writing mtimecmp
+	pub fn update_mtimecmp(&mut self, hart: usize, value: u64) {
+		self.mtimecmp[hart] = value;
+	}
+
+	pub fn is_software_interrupting(&self, hart: usize) -> bool {
+		self.msip[hart] != 0
+	}
+
+	// Level-sensitive. Keeps pending until mtimecmp is updated.
+	pub fn is_interrupting(&self, hart: usize) -> bool {
+		self.mtime >= self.mtimecmp[hart]
+	}
+
+	// Whether any hart's software or timer interrupt is raised
+	pub fn is_any_hart_interrupting(&self) -> bool {
+		(0..self.mtimecmp.len()).any(|hart| self.is_software_interrupting(hart) || self.is_interrupting(hart))
+	}
+
+	// Frequency mtime advances at. Deterministic mode advances it by tick,
+	// so the default is given for the guest to convert time.
+	pub fn get_timebase_frequency(&self) -> u64 {
+		match self.timer_mode {
+			TimerMode::RealTime(frequency) => frequency,
+			TimerMode::Deterministic => DEFAULT_TIMEBASE_FREQUENCY
+		}
 	}
 
-	pub fn reset_interrupting(&mut self) {
-		self.interrupting = false;
+	// The earliest mtimecmp of the harts. None if no timer is set.
+	pub fn get_next_deadline(&self) -> Option<u64> {
+		self.mtimecmp.iter().cloned().filter(|mtimecmp| *mtimecmp != 0xffffffffffffffff).min()
 	}
 }
@@ -0,0 +1,71 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Instant;
+
+// Host time which mtime of CLINT follows in real time mode, see TimerMode.
+// Given to Cpu at construction so that host can control time.
+pub trait ClockSource {
+	// Nanoseconds since an arbitrary point. Must not go backward.
+	fn now_ns(&self) -> u64;
+}
+
+// Host monotonic clock, the default. Not available on wasm32 because it
+// doesn't have a monotonic clock, so the clock is read first on now_ns().
+pub struct MonotonicClock {
+	base: Cell<Option<Instant>>
+}
+
+impl MonotonicClock {
+	pub fn new() -> Self {
+		MonotonicClock {
+			base: Cell::new(None)
+		}
+	}
+}
+
+impl ClockSource for MonotonicClock {
+	fn now_ns(&self) -> u64 {
+		let base = match self.base.get() {
+			Some(base) => base,
+			None => {
+				let base = Instant::now();
+				self.base.set(Some(base));
+				base
+			}
+		};
+		let elapsed = base.elapsed();
+		elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64
+	}
+}
+
+// Time advanced only by host, for tests to fire timers at the exact
+// point. Clones share the time so host keeps one to advance the clock
+// moved into Cpu. CLINT reads the clock every 256 ticks, so mtime catches
+// up within 256 ticks after advance().
+//
+// let clock = ManualClock::new();
+// let cpu = Cpu::new_with_clock_source(terminal, TimerMode::RealTime(frequency),
+// 	Box::new(clock.clone()));
+// clock.advance(1_000_000);
+#[derive(Clone)]
+pub struct ManualClock {
+	now: Rc<Cell<u64>>
+}
+
+impl ManualClock {
+	pub fn new() -> Self {
+		ManualClock {
+			now: Rc::new(Cell::new(0))
+		}
+	}
+
+	pub fn advance(&self, ns: u64) {
+		self.now.set(self.now.get().wrapping_add(ns));
+	}
+}
+
+impl ClockSource for ManualClock {
+	fn now_ns(&self) -> u64 {
+		self.now.get()
+	}
+}
@@ -1,59 +1,233 @@
-use mmu::{AddressingMode, Mmu};
-use plic::InterruptType;
+use std::fs::File;
+use std::num::FpCategory;
+
+use clint::TimerMode;
+use clock_source::{ClockSource, MonotonicClock};
+use entropy_source::EntropySource;
+use decode_cache::DecodeCache;
+use fdt::{get_reg_cells, FdtBuilder};
+use mmu::{AddressingMode, MemoryMap, Mmu, MmuContext, WatchKind, UART_IRQ, VIRTIO_IRQ, VIRTIO_RNG_IRQ};
+use mmio_device::MmioDevice;
+use plic::NUM_SOURCES;
+use stats::ExecStats;
 use terminal::Terminal;
 
 const CSR_CAPACITY: usize = 4096;
 
 const CSR_USTATUS_ADDRESS: u16 = 0x000;
-const _CSR_UIR_ADDRESS: u16 = 0x004;
+const CSR_FFLAGS_ADDRESS: u16 = 0x001;
+const CSR_FRM_ADDRESS: u16 = 0x002;
+const CSR_FCSR_ADDRESS: u16 = 0x003;
+const CSR_UIE_ADDRESS: u16 = 0x004;
 const CSR_UTVEC_ADDRESS: u16 = 0x005;
-const _CSR_USCRATCH_ADDRESS: u16 = 0x040;
+const CSR_USCRATCH_ADDRESS: u16 = 0x040;
 const CSR_UEPC_ADDRESS: u16 = 0x041;
 const CSR_UCAUSE_ADDRESS: u16 = 0x042;
 const CSR_UTVAL_ADDRESS: u16 = 0x043;
-const _CSR_UIP_ADDRESS: u16 = 0x044;
+const CSR_UIP_ADDRESS: u16 = 0x044;
 const CSR_SSTATUS_ADDRESS: u16 = 0x100;
+const CSR_SCOUNTEREN_ADDRESS: u16 = 0x106;
 const CSR_SEDELEG_ADDRESS: u16 = 0x102;
 const CSR_SIDELEG_ADDRESS: u16 = 0x103;
+const CSR_SIE_ADDRESS: u16 = 0x104;
 const CSR_STVEC_ADDRESS: u16 = 0x105;
-const _CSR_SSCRATCH_ADDRESS: u16 = 0x140;
+const CSR_SSCRATCH_ADDRESS: u16 = 0x140;
 const CSR_SEPC_ADDRESS: u16 = 0x141;
 const CSR_SCAUSE_ADDRESS: u16 = 0x142;
 const CSR_STVAL_ADDRESS: u16 = 0x143;
+const CSR_SIP_ADDRESS: u16 = 0x144;
+const CSR_STIMECMP_ADDRESS: u16 = 0x14d;
+const CSR_STIMECMPH_ADDRESS: u16 = 0x15d;
 const CSR_SATP_ADDRESS: u16 = 0x180;
 const CSR_MSTATUS_ADDRESS: u16 = 0x300;
+const CSR_MISA_ADDRESS: u16 = 0x301;
 const CSR_MEDELEG_ADDRESS: u16 = 0x302;
 const CSR_MIDELEG_ADDRESS: u16 = 0x303;
-const _CSR_MIE_ADDRESS: u16 = 0x304;
+const CSR_MIE_ADDRESS: u16 = 0x304;
 const CSR_MTVEC_ADDRESS: u16 = 0x305;
-const _CSR_MSCRATCH_ADDRESS: u16 = 0x340;
+const CSR_MCOUNTEREN_ADDRESS: u16 = 0x306;
+const CSR_MENVCFG_ADDRESS: u16 = 0x30a;
+const CSR_MENVCFGH_ADDRESS: u16 = 0x31a;
+const CSR_MSCRATCH_ADDRESS: u16 = 0x340;
 const CSR_MEPC_ADDRESS: u16 = 0x341;
 const CSR_MCAUSE_ADDRESS: u16 = 0x342;
 const CSR_MTVAL_ADDRESS: u16 = 0x343;
-const _CSR_PMPCFG0_ADDRESS: u16 = 0x3a0;
-const _CSR_PMPADDR0_ADDRESS: u16 = 0x3b0;
-const _CSR_MHARTID_ADDRESS: u16 = 0xf14;
+const CSR_MIP_ADDRESS: u16 = 0x344;
+const CSR_PMPCFG0_ADDRESS: u16 = 0x3a0;
+const CSR_PMPCFG3_ADDRESS: u16 = 0x3a3;
+const CSR_PMPADDR0_ADDRESS: u16 = 0x3b0;
+const CSR_PMPADDR15_ADDRESS: u16 = 0x3bf;
+const CSR_MCYCLE_ADDRESS: u16 = 0xb00;
+const CSR_MINSTRET_ADDRESS: u16 = 0xb02;
+const CSR_MCYCLEH_ADDRESS: u16 = 0xb80;
+const CSR_MINSTRETH_ADDRESS: u16 = 0xb82;
+const CSR_CYCLE_ADDRESS: u16 = 0xc00;
+const CSR_TIME_ADDRESS: u16 = 0xc01;
+const CSR_INSTRET_ADDRESS: u16 = 0xc02;
+const CSR_CYCLEH_ADDRESS: u16 = 0xc80;
+const CSR_TIMEH_ADDRESS: u16 = 0xc81;
+const CSR_INSTRETH_ADDRESS: u16 = 0xc82;
+const CSR_MHARTID_ADDRESS: u16 = 0xf14;
+
+// sstatus is a view of mstatus. UIE, SIE, UPIE, SPIE, UBE, SPP, VS, FS,
+// XS, SUM, MXR, UXL, and SD are visible, and UIE, SIE, UPIE, SPIE, SPP,
+// FS, SUM, and MXR are writable through it.
+const SSTATUS_MASK_32: u64 = 0x800de773;
+const SSTATUS_MASK_64: u64 = 0x80000003000de773;
+const SSTATUS_WRITABLE_MASK: u64 = 0xc6133;
+
+// Fields of mstatus writable by the guest, UIE, SIE, MIE, UPIE, SPIE, MPIE,
+// SPP, MPP, FS, MPRV, SUM, MXR, TVM, TW, and TSR. SD, XS, and UXL are
+// read-only, and VS, SXL, and the others aren't implemented.
+const MSTATUS_WRITABLE_MASK: u64 = 0x7e79bb;
+
+// ustatus is a view of UIE and UPIE of mstatus
+const USTATUS_MASK: u64 = 0x11;
+
+// UXL of mstatus in 64-bit mode. It's read-only and follows XLEN.
+const MSTATUS_UXL_64: u64 = 0x200000000;
+
+// FS, VS, and XS of mstatus. SD is read-only and set if any of them is Dirty.
+// Floating point instructions and CSRs are illegal while FS is Off.
+const MSTATUS_FS: u64 = 0x6000;
+const MSTATUS_VS: u64 = 0x600;
+const MSTATUS_XS: u64 = 0x18000;
+
+// Extensions in misa at reset, A, C, D, F, I, M, N, S, and U.
+// Zba, Zbb, and Zicond have no misa bit. A, C, and M can be disabled
+// by writing misa.
+const MISA_EXTENSIONS: u64 = 0x14312d;
+const MISA_WRITABLE_MASK: u64 = 0x1005;
+const MISA_A: u64 = 0x1;
+const MISA_C: u64 = 0x4;
+const MISA_M: u64 = 0x1000;
+
+// STCE of menvcfg enables stimecmp of Sstc. CBIE[5:4], CBCFE[6], and
+// CBZE[7] enable cache-block management instructions below M-mode.
+// The other fields aren't implemented and are read-only zero.
+const MENVCFG_STCE: u64 = 0x8000000000000000;
+const MENVCFG_CBIE: u64 = 0x30;
+const MENVCFG_CBCFE: u64 = 0x40;
+const MENVCFG_CBZE: u64 = 0x80;
+const MENVCFG_WRITABLE_MASK: u64 = MENVCFG_STCE | MENVCFG_CBZE | MENVCFG_CBCFE | MENVCFG_CBIE;
 
+// Default size in bytes of cache blocks CBO.ZERO clears
+const DEFAULT_CACHE_BLOCK_SIZE: u64 = 64;
+
+// Accrued exception flags in fcsr
+const FFLAGS_NV: u64 = 0x10; // Invalid operation
+const FFLAGS_DZ: u64 = 0x8; // Divide by zero
+const FFLAGS_OF: u64 = 0x4; // Overflow
+const FFLAGS_UF: u64 = 0x2; // Underflow
+const FFLAGS_NX: u64 = 0x1; // Inexact
+
+// SBI extension IDs emulated for supervisor mode. Extension IDs below
+// 0x10 are legacy ones which take a function by themselves.
+const SBI_EXT_LEGACY_SET_TIMER: u64 = 0x00;
+const SBI_EXT_LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const SBI_EXT_LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const SBI_EXT_LEGACY_SHUTDOWN: u64 = 0x08;
+const SBI_EXT_BASE: u64 = 0x10;
+const SBI_EXT_TIME: u64 = 0x54494d45; // "TIME"
+const SBI_EXT_DBCN: u64 = 0x4442434e; // "DBCN", debug console
+const SBI_EXT_SRST: u64 = 0x53525354; // "SRST", system reset
+
+const SBI_SPEC_VERSION: i64 = 2 << 24; // 2.0
+// Not registered in SBI specification
+const SBI_IMPL_ID: i64 = 0x7276;
+
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+
+// Ticks without a retired instruction after which Cpu.run() gives up
+const DEFAULT_STALL_TICKS: u64 = 10000000;
+
+const CANONICAL_NAN_F32: u32 = 0x7fc00000;
+const CANONICAL_NAN_F64: u64 = 0x7ff8000000000000;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Cpu {
 	clock: u64,
+	instret: u64, // the number of retired instructions
 	xlen: Xlen,
 	privilege_mode: PrivilegeMode,
 	// using only lower 32bits of x, pc, and csr registers
 	// for 32-bit mode
 	x: [i64; 32],
+	// raw bits of floating point registers. Single-precision
+	// values are NaN-boxed in the upper 32 bits
+	f: [u64; 32],
+	pc: u64,
+	// pc of all harts after reset. Shared by harts.
+	reset_vector: u64,
+	// Address of the instruction being executed. pc has already moved
+	// past it, by 2 or 4 bytes, when an exception is raised.
+	instruction_address: u64,
+	// Bits of the instruction being executed as fetched, 16 bits for
+	// compressed instructions. tval of illegal instruction exceptions.
+	instruction_word: u32,
+	// Vec rather than array so that switching harts doesn't copy it
+	csr: Vec<u64>,
+	wfi: bool, // Waiting for interrupt after WFI instruction
+	mmu: Mmu,
+	// The running hart's state is in the fields above and the other harts'
+	// are parked in harts. harts is empty while only one hart exists.
+	hart_id: usize,
+	harts: Vec<Hart>,
+	zba_enabled: bool,
+	zbb_enabled: bool,
+	// Bytes CBO.ZERO clears at once
+	cache_block_size: u64,
+	// ECALLs from supervisor mode are serviced by the emulator as SBI firmware
+	sbi_enabled: bool,
+	unimplemented_policy: UnimplementedPolicy,
+	// Shared by harts. Just a cache so it starts empty after restoring state.
+	#[cfg_attr(feature = "serde", serde(skip, default = "DecodeCache::new"))]
+	decode_cache: DecodeCache,
+	// Debugging facilities below are set up by host, not a part of machine state
+	#[cfg_attr(feature = "serde", serde(skip))]
+	trace_hook: Option<Box<dyn FnMut(TraceEvent)>>,
+	#[cfg_attr(feature = "serde", serde(skip))]
+	written_csr: Option<(u16, u64)>, // the last CSR written by an instruction, for trace
+	#[cfg_attr(feature = "serde", serde(skip))]
+	breakpoints: Vec<u64>,
+	#[cfg_attr(feature = "serde", serde(skip))]
+	watchpoint_hit: Option<WatchpointHit>,
+	#[cfg_attr(feature = "serde", serde(skip, default = "get_default_stall_ticks"))]
+	stall_ticks: u64
+}
+
+#[cfg(feature = "serde")]
+fn get_default_stall_ticks() -> u64 {
+	DEFAULT_STALL_TICKS
+}
+
+// Register state of a hart which is not running. Cpu swaps it
+// with its own fields when switching harts.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct Hart {
+	clock: u64,
+	instret: u64,
+	privilege_mode: PrivilegeMode,
+	x: [i64; 32],
+	f: [u64; 32],
 	pc: u64,
-	csr: [u64; CSR_CAPACITY],
-	mmu: Mmu
+	csr: Vec<u64>,
+	wfi: bool,
+	mmu_context: MmuContext
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Xlen {
 	Bit32,
 	Bit64
 	// @TODO: Support Bit128
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[allow(dead_code)]
 pub enum PrivilegeMode {
 	User,
@@ -62,11 +236,79 @@ pub enum PrivilegeMode {
 	Machine
 }
 
+// What to do when a decoded instruction reaches a case
+// this emulator doesn't implement yet
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UnimplementedPolicy {
+	Trap, // Raise IllegalInstruction to the guest
+	NopWarn, // Print a warning to the terminal and skip the instruction
+	Panic
+}
+
+// What to do on a load or store to an address not aligned to its width.
+// Atomic memory operations trap regardless of the policy.
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MisalignedAccessPolicy {
+	Emulate, // Split into byte accesses transparently
+	Trap // Raise LoadAddressMisaligned or StoreAddressMisaligned to the guest
+}
+
+// What to do when a load, store, or fetch finds A of the leaf page table
+// entry clear, or a store finds D clear
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AccessedDirtyPolicy {
+	Update, // Set them by writing back the entry, as Svadu
+	Fault // Raise the page fault of the access for software to set them, as Svade
+}
+
 pub struct Trap {
 	pub trap_type: TrapType,
 	pub value: u64 // Trap type specific value
 }
 
+// What Cpu.step() executed
+pub struct StepResult {
+	pub pc: u64,
+	pub word: u32, // Lower 16 bits only for compressed instruction
+	pub name: Option<&'static str>, // None if fetch or decode fails
+	pub instruction: Option<Instruction>, // None if fetch or decode fails
+	pub trapped: bool,
+	pub breakpoint: bool, // The next pc hits a registered breakpoint
+	pub watchpoint: bool // A load or store hit a registered watchpoint
+}
+
+// Why Cpu.run() returned
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunOutcome {
+	Halted(u64), // The machine halted with the exit code
+	Breakpoint, // pc hit a breakpoint
+	Watchpoint, // A load or store hit a watchpoint, taken by take_watchpoint_hit()
+	BudgetExhausted, // As many instructions as the budget retired
+	// No instruction retired for the stall ticks, e.g. the harts keep
+	// trapping or wait for interrupt that never comes
+	Stalled
+}
+
+// The access which hit a watchpoint, reported after the instruction is done
+pub struct WatchpointHit {
+	pub pc: u64, // Address of the instruction which accessed
+	pub address: u64, // Accessed address in the watched range
+	pub kind: WatchKind // Kind of the watchpoint hit
+}
+
+// Passed to the trace hook for each retired instruction
+pub struct TraceEvent {
+	pub pc: u64,
+	pub word: u32, // Lower 16 bits only for compressed instruction
+	pub instruction: Instruction,
+	pub privilege_mode: PrivilegeMode, // Privilege mode the instruction ran in
+	pub register: Option<(usize, i64)>, // Changed integer register and its new value
+	pub f_register: Option<(usize, u64)>, // Changed floating point register and its new raw bits
+	pub csr: Option<(u16, u64)> // Written CSR and the written value
+}
+
 #[allow(dead_code)]
 pub enum TrapType {
 	InstructionAddressMisaligned,
@@ -94,15 +336,34 @@ pub enum TrapType {
 	MachineExternalInterrupt
 }
 
-enum Instruction {
+#[derive(Clone, Copy)]
+pub enum Instruction {
 	ADD,
 	ADDI,
 	ADDIW,
+	ADDUW,
 	ADDW,
+	AMOADDD,
 	AMOADDW,
+	AMOANDD,
+	AMOANDW,
+	AMOMAXD,
+	AMOMAXUD,
+	AMOMAXUW,
+	AMOMAXW,
+	AMOMIND,
+	AMOMINUD,
+	AMOMINUW,
+	AMOMINW,
+	AMOORD,
+	AMOORW,
+	AMOSWAPD,
 	AMOSWAPW,
+	AMOXORD,
+	AMOXORW,
 	AND,
 	ANDI,
+	ANDN,
 	AUIPC,
 	BEQ,
 	BGE,
@@ -110,18 +371,95 @@ enum Instruction {
 	BLT,
 	BLTU,
 	BNE,
+	CBOCLEAN,
+	CBOFLUSH,
+	CBOINVAL,
+	CBOZERO,
+	CLZ,
+	CLZW,
+	CPOP,
+	CPOPW,
 	CSRRC,
 	CSRRCI,
 	CSRRS,
 	CSRRSI,
 	CSRRW,
 	CSRRWI,
+	CTZ,
+	CTZW,
+	CZEROEQZ,
+	CZERONEZ,
 	DIV,
 	DIVU,
 	DIVUW,
 	DIVW,
+	EBREAK,
 	ECALL,
+	FADDD,
+	FADDS,
+	FCLASSD,
+	FCLASSS,
+	FCVTDL,
+	FCVTDLU,
+	FCVTDS,
+	FCVTDW,
+	FCVTDWU,
+	FCVTLD,
+	FCVTLS,
+	FCVTLUD,
+	FCVTLUS,
+	FCVTSD,
+	FCVTSL,
+	FCVTSLU,
+	FCVTSW,
+	FCVTSWU,
+	FCVTWD,
+	FCVTWS,
+	FCVTWUD,
+	FCVTWUS,
+	FDIVD,
+	FDIVS,
 	FENCE,
+	FENCEI,
+	FENCETSO,
+	FEQD,
+	FEQS,
+	FLD,
+	FLED,
+	FLES,
+	FLTD,
+	FLTS,
+	FLW,
+	FMADDD,
+	FMADDS,
+	FMAXD,
+	FMAXS,
+	FMIND,
+	FMINS,
+	FMSUBD,
+	FMSUBS,
+	FMULD,
+	FMULS,
+	FMVDX,
+	FMVWX,
+	FMVXD,
+	FMVXW,
+	FNMADDD,
+	FNMADDS,
+	FNMSUBD,
+	FNMSUBS,
+	FSD,
+	FSGNJD,
+	FSGNJND,
+	FSGNJNS,
+	FSGNJS,
+	FSGNJXD,
+	FSGNJXS,
+	FSQRTD,
+	FSQRTS,
+	FSUBD,
+	FSUBS,
+	FSW,
 	JAL,
 	JALR,
 	LB,
@@ -129,9 +467,15 @@ enum Instruction {
 	LD,
 	LH,
 	LHU,
+	LRD,
+	LRW,
 	LUI,
 	LW,
 	LWU,
+	MAX,
+	MAXU,
+	MIN,
+	MINU,
 	MUL,
 	MULH,
 	MULHU,
@@ -139,17 +483,37 @@ enum Instruction {
 	MULW,
 	MRET,
 	OR,
+	ORCB,
 	ORI,
+	ORN,
 	REM,
 	REMU,
 	REMUW,
 	REMW,
+	REV8,
+	ROL,
+	ROLW,
+	ROR,
+	RORI,
+	RORIW,
+	RORW,
 	SB,
+	SCD,
+	SCW,
 	SD,
+	SEXTB,
+	SEXTH,
 	SFENCEVMA,
 	SH,
+	SH1ADD,
+	SH1ADDUW,
+	SH2ADD,
+	SH2ADDUW,
+	SH3ADD,
+	SH3ADDUW,
 	SLL,
 	SLLI,
+	SLLIUW,
 	SLLIW,
 	SLLW,
 	SLT,
@@ -169,11 +533,14 @@ enum Instruction {
 	SUBW,
 	SW,
 	URET,
+	WFI,
+	XNOR,
 	XOR,
-	XORI
+	XORI,
+	ZEXTH
 }
 
-enum InstructionFormat {
+pub enum InstructionFormat {
 	B,
 	C, // CSR
 	I,
@@ -184,6 +551,128 @@ enum InstructionFormat {
 	U
 }
 
+// Fields of a 32-bit instruction word, see get_instruction_fields().
+// Which of them are meaningful depends on the instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstructionFields {
+	pub opcode: u32, // [6:0]
+	pub rd: u32, // [11:7]
+	pub funct3: u32, // [14:12]
+	pub rs1: u32, // [19:15], uimm of CSR instructions with immediate
+	pub rs2: u32, // [24:20]
+	pub rs3: u32, // [31:27], of fused multiply-add instructions
+	pub funct7: u32, // [31:25]
+	// Sign-extended immediate of the format. C has csr zero-extended,
+	// O has the same as I, and R doesn't have any and is 0.
+	pub imm: i64
+}
+
+// Memory ordering of atomic instructions, given by aq[26] and rl[25].
+// Harts run one instruction at a time in turn and every memory access
+// completes before the next one starts, so atomic instructions are already
+// sequentially consistent whatever the bits are. The emulator doesn't need
+// to act on them but they're decoded for disassembly and a future memory model.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AtomicOrdering {
+	Relaxed,
+	Acquire,
+	Release,
+	AcquireRelease
+}
+
+pub fn get_atomic_ordering(word: u32) -> AtomicOrdering {
+	match (word >> 25) & 0x3 { // aq[26], rl[25]
+		0 => AtomicOrdering::Relaxed,
+		1 => AtomicOrdering::Release,
+		2 => AtomicOrdering::Acquire,
+		_ => AtomicOrdering::AcquireRelease
+	}
+}
+
+// Ordering sets of FENCE, pred[27:24] and succ[23:20]. Bits 3 to 0 of each
+// are device input (I), device output (O), memory reads (R), and memory
+// writes (W). fm[31:28] is the fence mode, 0 for a normal fence and 8 for
+// FENCE.TSO. Other modes are reserved and behave as a normal fence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FenceOrdering {
+	pub mode: u8,
+	pub predecessor: u8,
+	pub successor: u8
+}
+
+pub fn get_fence_ordering(word: u32) -> FenceOrdering {
+	FenceOrdering {
+		mode: (word >> 28) as u8,
+		predecessor: ((word >> 24) & 0xf) as u8,
+		successor: ((word >> 20) & 0xf) as u8
+	}
+}
+
+// Length in bytes of the instruction starting with the 16 bits, 4 if they
+// end with 0b11 and otherwise 2 for compressed instruction. Longer
+// instructions aren't supported.
+pub fn get_instruction_length(halfword: u16) -> u8 {
+	match halfword & 0x3 {
+		0x3 => 4,
+		_ => 2
+	}
+}
+
+// Extracts the fields of a 32-bit instruction word, uncompressed if it's
+// compressed one, without executing it. format is of the decoded
+// instruction, from get_instruction_format().
+pub fn get_instruction_fields(word: u32, format: &InstructionFormat) -> InstructionFields {
+	let imm = match format {
+		InstructionFormat::B => (
+			match word & 0x80000000 { // imm[31:12] = [31]
+				0x80000000 => 0xfffff000,
+				_ => 0
+			} |
+			((word & 0x00000080) << 4) | // imm[11] = [7]
+			((word & 0x7e000000) >> 20) | // imm[10:5] = [30:25]
+			((word & 0x00000f00) >> 7) // imm[4:1] = [11:8]
+		) as i32 as i64,
+		InstructionFormat::C => ((word >> 20) & 0xfff) as i64, // csr[11:0] = [31:20]
+		InstructionFormat::I |
+		InstructionFormat::O => (
+			match word & 0x80000000 { // imm[31:11] = [31]
+				0x80000000 => 0xfffff800,
+				_ => 0
+			} |
+			((word >> 20) & 0x000007ff) // imm[10:0] = [30:20]
+		) as i32 as i64,
+		InstructionFormat::J => (
+			match word & 0x80000000 { // imm[31:20] = [31]
+				0x80000000 => 0xfff00000,
+				_ => 0
+			} |
+			(word & 0x000ff000) | // imm[19:12] = [19:12]
+			((word & 0x00100000) >> 9) | // imm[11] = [20]
+			((word & 0x7fe00000) >> 20) // imm[10:1] = [30:21]
+		) as i32 as i64,
+		InstructionFormat::R => 0,
+		InstructionFormat::S => (
+			match word & 0x80000000 { // imm[31:12] = [31]
+				0x80000000 => 0xfffff000,
+				_ => 0
+			} |
+			((word & 0xfe000000) >> 20) | // imm[11:5] = [31:25]
+			((word & 0x00000f80) >> 7) // imm[4:0] = [11:7]
+		) as i32 as i64,
+		InstructionFormat::U => (word & 0xfffff000) as i32 as i64 // imm[31:12] = [31:12]
+	};
+	InstructionFields {
+		opcode: word & 0x7f,
+		rd: (word >> 7) & 0x1f,
+		funct3: (word >> 12) & 0x7,
+		rs1: (word >> 15) & 0x1f,
+		rs2: (word >> 20) & 0x1f,
+		rs3: (word >> 27) & 0x1f,
+		funct7: (word >> 25) & 0x7f,
+		imm: imm
+	}
+}
+
 fn _get_privilege_mode_name(mode: &PrivilegeMode) -> &'static str {
 	match mode {
 		PrivilegeMode::User => "User",
@@ -231,12 +720,32 @@ fn get_trap_type_name(trap_type: &TrapType) -> &'static str {
 	}
 }
 
+// mcause value, the exception code with the interrupt bit for interrupts
 fn get_trap_cause(trap: &Trap, xlen: &Xlen) -> u64 {
 	let interrupt_bit = match xlen {
 		Xlen::Bit32 => 0x80000000 as u64,
 		Xlen::Bit64 => 0x8000000000000000 as u64,
 	};
+	let code = get_trap_code(&trap.trap_type);
 	match trap.trap_type {
+		TrapType::UserSoftwareInterrupt |
+		TrapType::SupervisorSoftwareInterrupt |
+		TrapType::MachineSoftwareInterrupt |
+		TrapType::UserTimerInterrupt |
+		TrapType::SupervisorTimerInterrupt |
+		TrapType::MachineTimerInterrupt |
+		TrapType::UserExternalInterrupt |
+		TrapType::SupervisorExternalInterrupt |
+		TrapType::MachineExternalInterrupt => interrupt_bit | code,
+		_ => code
+	}
+}
+
+// Exception code of the trap without the interrupt bit. For interrupts
+// it's the interrupt number, the bit position in mie, mip, and mideleg.
+// For exceptions it's the bit position in medeleg.
+fn get_trap_code(trap_type: &TrapType) -> u64 {
+	match trap_type {
 		TrapType::InstructionAddressMisaligned => 0,
 		TrapType::InstructionAccessFault => 1,
 		TrapType::IllegalInstruction => 2,
@@ -251,43 +760,116 @@ fn get_trap_cause(trap: &Trap, xlen: &Xlen) -> u64 {
 		TrapType::InstructionPageFault => 12,
 		TrapType::LoadPageFault => 13,
 		TrapType::StorePageFault => 15,
-		TrapType::UserSoftwareInterrupt => interrupt_bit,
-		TrapType::SupervisorSoftwareInterrupt => interrupt_bit + 1,
-		TrapType::MachineSoftwareInterrupt => interrupt_bit + 3,
-		TrapType::UserTimerInterrupt => interrupt_bit + 4,
-		TrapType::SupervisorTimerInterrupt => interrupt_bit + 5,
-		TrapType::MachineTimerInterrupt => interrupt_bit + 7,
-		TrapType::UserExternalInterrupt => interrupt_bit + 8,
-		TrapType::SupervisorExternalInterrupt => interrupt_bit + 9,
-		TrapType::MachineExternalInterrupt => interrupt_bit + 11
+		TrapType::UserSoftwareInterrupt => 0,
+		TrapType::SupervisorSoftwareInterrupt => 1,
+		TrapType::MachineSoftwareInterrupt => 3,
+		TrapType::UserTimerInterrupt => 4,
+		TrapType::SupervisorTimerInterrupt => 5,
+		TrapType::MachineTimerInterrupt => 7,
+		TrapType::UserExternalInterrupt => 8,
+		TrapType::SupervisorExternalInterrupt => 9,
+		TrapType::MachineExternalInterrupt => 11
 	}
 }
 
-fn get_interrupt_privilege_mode(trap: &Trap) -> PrivilegeMode {
-	match trap.trap_type {
-		TrapType::MachineSoftwareInterrupt |
-		TrapType::MachineTimerInterrupt |
-		TrapType::MachineExternalInterrupt => PrivilegeMode::Machine,
-		TrapType::SupervisorSoftwareInterrupt |
-		TrapType::SupervisorTimerInterrupt |
-		TrapType::SupervisorExternalInterrupt => PrivilegeMode::Supervisor,
-		TrapType::UserSoftwareInterrupt |
-		TrapType::UserTimerInterrupt |
-		TrapType::UserExternalInterrupt => PrivilegeMode::User,
-		_ => panic!("{} is not an interrupt", get_trap_type_name(&trap.trap_type))
-	}
+const PMPCFG_NAMES: [&str; 4] = ["pmpcfg0", "pmpcfg1", "pmpcfg2", "pmpcfg3"];
+
+const PMPADDR_NAMES: [&str; 16] = [
+	"pmpaddr0", "pmpaddr1", "pmpaddr2", "pmpaddr3",
+	"pmpaddr4", "pmpaddr5", "pmpaddr6", "pmpaddr7",
+	"pmpaddr8", "pmpaddr9", "pmpaddr10", "pmpaddr11",
+	"pmpaddr12", "pmpaddr13", "pmpaddr14", "pmpaddr15"
+];
+
+// Name of the CSR at the address. None if the emulator doesn't implement
+// it. Some CSRs, like the upper halves of counters, exist only in
+// 32-bit mode. See Cpu::dump_csrs().
+pub fn get_csr_name(address: u16) -> Option<&'static str> {
+	Some(match address {
+		CSR_USTATUS_ADDRESS => "ustatus",
+		CSR_FFLAGS_ADDRESS => "fflags",
+		CSR_FRM_ADDRESS => "frm",
+		CSR_FCSR_ADDRESS => "fcsr",
+		CSR_UIE_ADDRESS => "uie",
+		CSR_UTVEC_ADDRESS => "utvec",
+		CSR_USCRATCH_ADDRESS => "uscratch",
+		CSR_UEPC_ADDRESS => "uepc",
+		CSR_UCAUSE_ADDRESS => "ucause",
+		CSR_UTVAL_ADDRESS => "utval",
+		CSR_UIP_ADDRESS => "uip",
+		CSR_SSTATUS_ADDRESS => "sstatus",
+		CSR_SEDELEG_ADDRESS => "sedeleg",
+		CSR_SIDELEG_ADDRESS => "sideleg",
+		CSR_SIE_ADDRESS => "sie",
+		CSR_STVEC_ADDRESS => "stvec",
+		CSR_SCOUNTEREN_ADDRESS => "scounteren",
+		CSR_SSCRATCH_ADDRESS => "sscratch",
+		CSR_SEPC_ADDRESS => "sepc",
+		CSR_SCAUSE_ADDRESS => "scause",
+		CSR_STVAL_ADDRESS => "stval",
+		CSR_SIP_ADDRESS => "sip",
+		CSR_STIMECMP_ADDRESS => "stimecmp",
+		CSR_STIMECMPH_ADDRESS => "stimecmph",
+		CSR_SATP_ADDRESS => "satp",
+		CSR_MSTATUS_ADDRESS => "mstatus",
+		CSR_MISA_ADDRESS => "misa",
+		CSR_MEDELEG_ADDRESS => "medeleg",
+		CSR_MIDELEG_ADDRESS => "mideleg",
+		CSR_MIE_ADDRESS => "mie",
+		CSR_MTVEC_ADDRESS => "mtvec",
+		CSR_MCOUNTEREN_ADDRESS => "mcounteren",
+		CSR_MENVCFG_ADDRESS => "menvcfg",
+		CSR_MENVCFGH_ADDRESS => "menvcfgh",
+		CSR_MSCRATCH_ADDRESS => "mscratch",
+		CSR_MEPC_ADDRESS => "mepc",
+		CSR_MCAUSE_ADDRESS => "mcause",
+		CSR_MTVAL_ADDRESS => "mtval",
+		CSR_MIP_ADDRESS => "mip",
+		CSR_MCYCLE_ADDRESS => "mcycle",
+		CSR_MINSTRET_ADDRESS => "minstret",
+		CSR_MCYCLEH_ADDRESS => "mcycleh",
+		CSR_MINSTRETH_ADDRESS => "minstreth",
+		CSR_CYCLE_ADDRESS => "cycle",
+		CSR_TIME_ADDRESS => "time",
+		CSR_INSTRET_ADDRESS => "instret",
+		CSR_CYCLEH_ADDRESS => "cycleh",
+		CSR_TIMEH_ADDRESS => "timeh",
+		CSR_INSTRETH_ADDRESS => "instreth",
+		CSR_MHARTID_ADDRESS => "mhartid",
+		CSR_PMPCFG0_ADDRESS..=CSR_PMPCFG3_ADDRESS => PMPCFG_NAMES[(address - CSR_PMPCFG0_ADDRESS) as usize],
+		CSR_PMPADDR0_ADDRESS..=CSR_PMPADDR15_ADDRESS => PMPADDR_NAMES[(address - CSR_PMPADDR0_ADDRESS) as usize],
+		_ => return None
+	})
 }
 
-fn get_instruction_name(instruction: &Instruction) -> &'static str {
+pub fn get_instruction_name(instruction: &Instruction) -> &'static str {
 	match instruction {
 		Instruction::ADD => "ADD",
 		Instruction::ADDI => "ADDI",
 		Instruction::ADDIW => "ADDIW",
+		Instruction::ADDUW => "ADD.UW",
 		Instruction::ADDW => "ADDW",
+		Instruction::AMOADDD => "AMOADD.D",
 		Instruction::AMOADDW => "AMOADD.W",
+		Instruction::AMOANDD => "AMOAND.D",
+		Instruction::AMOANDW => "AMOAND.W",
+		Instruction::AMOMAXD => "AMOMAX.D",
+		Instruction::AMOMAXUD => "AMOMAXU.D",
+		Instruction::AMOMAXUW => "AMOMAXU.W",
+		Instruction::AMOMAXW => "AMOMAX.W",
+		Instruction::AMOMIND => "AMOMIN.D",
+		Instruction::AMOMINUD => "AMOMINU.D",
+		Instruction::AMOMINUW => "AMOMINU.W",
+		Instruction::AMOMINW => "AMOMIN.W",
+		Instruction::AMOORD => "AMOOR.D",
+		Instruction::AMOORW => "AMOOR.W",
+		Instruction::AMOSWAPD => "AMOSWAP.D",
 		Instruction::AMOSWAPW => "AMOSWAP.W",
+		Instruction::AMOXORD => "AMOXOR.D",
+		Instruction::AMOXORW => "AMOXOR.W",
 		Instruction::AND => "AND",
 		Instruction::ANDI => "ANDI",
+		Instruction::ANDN => "ANDN",
 		Instruction::AUIPC => "AUIPC",
 		Instruction::BEQ => "BEQ",
 		Instruction::BGE => "BGE",
@@ -295,18 +877,95 @@ fn get_instruction_name(instruction: &Instruction) -> &'static str {
 		Instruction::BLT => "BLT",
 		Instruction::BLTU => "BLTU",
 		Instruction::BNE => "BNE",
+		Instruction::CBOCLEAN => "CBO.CLEAN",
+		Instruction::CBOFLUSH => "CBO.FLUSH",
+		Instruction::CBOINVAL => "CBO.INVAL",
+		Instruction::CBOZERO => "CBO.ZERO",
+		Instruction::CLZ => "CLZ",
+		Instruction::CLZW => "CLZW",
+		Instruction::CPOP => "CPOP",
+		Instruction::CPOPW => "CPOPW",
 		Instruction::CSRRC => "CSRRC",
 		Instruction::CSRRCI => "CSRRCI",
 		Instruction::CSRRS => "CSRRS",
 		Instruction::CSRRSI => "CSRRSI",
 		Instruction::CSRRW => "CSRRW",
 		Instruction::CSRRWI => "CSRRWI",
+		Instruction::CTZ => "CTZ",
+		Instruction::CTZW => "CTZW",
+		Instruction::CZEROEQZ => "CZERO.EQZ",
+		Instruction::CZERONEZ => "CZERO.NEZ",
 		Instruction::DIV => "DIV",
 		Instruction::DIVU => "DIVU",
 		Instruction::DIVUW => "DIVUW",
 		Instruction::DIVW => "DIVW",
+		Instruction::EBREAK => "EBREAK",
 		Instruction::ECALL => "ECALL",
+		Instruction::FADDD => "FADD.D",
+		Instruction::FADDS => "FADD.S",
+		Instruction::FCLASSD => "FCLASS.D",
+		Instruction::FCLASSS => "FCLASS.S",
+		Instruction::FCVTDL => "FCVT.D.L",
+		Instruction::FCVTDLU => "FCVT.D.LU",
+		Instruction::FCVTDS => "FCVT.D.S",
+		Instruction::FCVTDW => "FCVT.D.W",
+		Instruction::FCVTDWU => "FCVT.D.WU",
+		Instruction::FCVTLD => "FCVT.L.D",
+		Instruction::FCVTLS => "FCVT.L.S",
+		Instruction::FCVTLUD => "FCVT.LU.D",
+		Instruction::FCVTLUS => "FCVT.LU.S",
+		Instruction::FCVTSD => "FCVT.S.D",
+		Instruction::FCVTSL => "FCVT.S.L",
+		Instruction::FCVTSLU => "FCVT.S.LU",
+		Instruction::FCVTSW => "FCVT.S.W",
+		Instruction::FCVTSWU => "FCVT.S.WU",
+		Instruction::FCVTWD => "FCVT.W.D",
+		Instruction::FCVTWS => "FCVT.W.S",
+		Instruction::FCVTWUD => "FCVT.WU.D",
+		Instruction::FCVTWUS => "FCVT.WU.S",
+		Instruction::FDIVD => "FDIV.D",
+		Instruction::FDIVS => "FDIV.S",
 		Instruction::FENCE => "FENCE",
+		Instruction::FENCEI => "FENCE.I",
+		Instruction::FENCETSO => "FENCE.TSO",
+		Instruction::FEQD => "FEQ.D",
+		Instruction::FEQS => "FEQ.S",
+		Instruction::FLD => "FLD",
+		Instruction::FLED => "FLE.D",
+		Instruction::FLES => "FLE.S",
+		Instruction::FLTD => "FLT.D",
+		Instruction::FLTS => "FLT.S",
+		Instruction::FLW => "FLW",
+		Instruction::FMADDD => "FMADD.D",
+		Instruction::FMADDS => "FMADD.S",
+		Instruction::FMAXD => "FMAX.D",
+		Instruction::FMAXS => "FMAX.S",
+		Instruction::FMIND => "FMIN.D",
+		Instruction::FMINS => "FMIN.S",
+		Instruction::FMSUBD => "FMSUB.D",
+		Instruction::FMSUBS => "FMSUB.S",
+		Instruction::FMULD => "FMUL.D",
+		Instruction::FMULS => "FMUL.S",
+		Instruction::FMVDX => "FMV.D.X",
+		Instruction::FMVWX => "FMV.W.X",
+		Instruction::FMVXD => "FMV.X.D",
+		Instruction::FMVXW => "FMV.X.W",
+		Instruction::FNMADDD => "FNMADD.D",
+		Instruction::FNMADDS => "FNMADD.S",
+		Instruction::FNMSUBD => "FNMSUB.D",
+		Instruction::FNMSUBS => "FNMSUB.S",
+		Instruction::FSD => "FSD",
+		Instruction::FSGNJD => "FSGNJ.D",
+		Instruction::FSGNJND => "FSGNJN.D",
+		Instruction::FSGNJNS => "FSGNJN.S",
+		Instruction::FSGNJS => "FSGNJ.S",
+		Instruction::FSGNJXD => "FSGNJX.D",
+		Instruction::FSGNJXS => "FSGNJX.S",
+		Instruction::FSQRTD => "FSQRT.D",
+		Instruction::FSQRTS => "FSQRT.S",
+		Instruction::FSUBD => "FSUB.D",
+		Instruction::FSUBS => "FSUB.S",
+		Instruction::FSW => "FSW",
 		Instruction::JAL => "JAL",
 		Instruction::JALR => "JALR",
 		Instruction::LB => "LB",
@@ -314,9 +973,15 @@ fn get_instruction_name(instruction: &Instruction) -> &'static str {
 		Instruction::LD => "LD",
 		Instruction::LH => "LH",
 		Instruction::LHU => "LHU",
+		Instruction::LRD => "LR.D",
+		Instruction::LRW => "LR.W",
 		Instruction::LUI => "LUI",
 		Instruction::LW => "LW",
 		Instruction::LWU => "LWU",
+		Instruction::MAX => "MAX",
+		Instruction::MAXU => "MAXU",
+		Instruction::MIN => "MIN",
+		Instruction::MINU => "MINU",
 		Instruction::MRET => "MRET",
 		Instruction::MUL => "MUL",
 		Instruction::MULH => "MULH",
@@ -324,17 +989,37 @@ fn get_instruction_name(instruction: &Instruction) -> &'static str {
 		Instruction::MULHSU => "MULHSU",
 		Instruction::MULW => "MULW",
 		Instruction::OR => "OR",
+		Instruction::ORCB => "ORC.B",
 		Instruction::ORI => "ORI",
+		Instruction::ORN => "ORN",
 		Instruction::REM => "REM",
 		Instruction::REMU => "REMU",
 		Instruction::REMUW => "REMUW",
 		Instruction::REMW => "REMW",
+		Instruction::REV8 => "REV8",
+		Instruction::ROL => "ROL",
+		Instruction::ROLW => "ROLW",
+		Instruction::ROR => "ROR",
+		Instruction::RORI => "RORI",
+		Instruction::RORIW => "RORIW",
+		Instruction::RORW => "RORW",
 		Instruction::SB => "SB",
+		Instruction::SCD => "SC.D",
+		Instruction::SCW => "SC.W",
 		Instruction::SD => "SD",
+		Instruction::SEXTB => "SEXT.B",
+		Instruction::SEXTH => "SEXT.H",
 		Instruction::SFENCEVMA => "SFENCE_VMA",
 		Instruction::SH => "SH",
+		Instruction::SH1ADD => "SH1ADD",
+		Instruction::SH1ADDUW => "SH1ADD.UW",
+		Instruction::SH2ADD => "SH2ADD",
+		Instruction::SH2ADDUW => "SH2ADD.UW",
+		Instruction::SH3ADD => "SH3ADD",
+		Instruction::SH3ADDUW => "SH3ADD.UW",
 		Instruction::SLL => "SLL",
 		Instruction::SLLI => "SLLI",
+		Instruction::SLLIUW => "SLLI.UW",
 		Instruction::SLLIW => "SLLIW",
 		Instruction::SLLW => "SLLW",
 		Instruction::SLT => "SLT",
@@ -354,12 +1039,15 @@ fn get_instruction_name(instruction: &Instruction) -> &'static str {
 		Instruction::SUBW => "SUBW",
 		Instruction::SW => "SW",
 		Instruction::URET => "URET",
+		Instruction::WFI => "WFI",
+		Instruction::XNOR => "XNOR",
 		Instruction::XOR => "XOR",
-		Instruction::XORI => "XORI"
+		Instruction::XORI => "XORI",
+		Instruction::ZEXTH => "ZEXT.H"
 	}
 }
 
-fn get_instruction_format(instruction: &Instruction) -> InstructionFormat {
+pub fn get_instruction_format(instruction: &Instruction) -> InstructionFormat {
 	match instruction {
 		Instruction::BEQ |
 		Instruction::BGE |
@@ -376,6 +1064,8 @@ fn get_instruction_format(instruction: &Instruction) -> InstructionFormat {
 		Instruction::ADDI |
 		Instruction::ADDIW |
 		Instruction::ANDI |
+		Instruction::FLD |
+		Instruction::FLW |
 		Instruction::JALR |
 		Instruction::LB |
 		Instruction::LBU |
@@ -385,7 +1075,10 @@ fn get_instruction_format(instruction: &Instruction) -> InstructionFormat {
 		Instruction::LW |
 		Instruction::LWU |
 		Instruction::ORI |
+		Instruction::RORI |
+		Instruction::RORIW |
 		Instruction::SLLI |
+		Instruction::SLLIUW |
 		Instruction::SLLIW |
 		Instruction::SLTI |
 		Instruction::SLTIU |
@@ -395,17 +1088,114 @@ fn get_instruction_format(instruction: &Instruction) -> InstructionFormat {
 		Instruction::SRAIW |
 		Instruction::XORI => InstructionFormat::I,
 		Instruction::JAL => InstructionFormat::J,
-		Instruction::FENCE => InstructionFormat::O,
+		Instruction::FENCE |
+		Instruction::FENCEI |
+		Instruction::FENCETSO => InstructionFormat::O,
 		Instruction::ADD |
+		Instruction::ADDUW |
 		Instruction::ADDW |
+		Instruction::AMOADDD |
 		Instruction::AMOADDW |
+		Instruction::AMOANDD |
+		Instruction::AMOANDW |
+		Instruction::AMOMAXD |
+		Instruction::AMOMAXUD |
+		Instruction::AMOMAXUW |
+		Instruction::AMOMAXW |
+		Instruction::AMOMIND |
+		Instruction::AMOMINUD |
+		Instruction::AMOMINUW |
+		Instruction::AMOMINW |
+		Instruction::AMOORD |
+		Instruction::AMOORW |
+		Instruction::AMOSWAPD |
 		Instruction::AMOSWAPW |
+		Instruction::AMOXORD |
+		Instruction::AMOXORW |
 		Instruction::AND |
+		Instruction::ANDN |
+		Instruction::CBOCLEAN |
+		Instruction::CBOFLUSH |
+		Instruction::CBOINVAL |
+		Instruction::CBOZERO |
+		Instruction::CLZ |
+		Instruction::CLZW |
+		Instruction::CPOP |
+		Instruction::CPOPW |
+		Instruction::CTZ |
+		Instruction::CTZW |
+		Instruction::CZEROEQZ |
+		Instruction::CZERONEZ |
 		Instruction::DIV |
 		Instruction::DIVU |
 		Instruction::DIVUW |
 		Instruction::DIVW |
+		Instruction::EBREAK |
 		Instruction::ECALL |
+		Instruction::FADDD |
+		Instruction::FADDS |
+		Instruction::FCLASSD |
+		Instruction::FCLASSS |
+		Instruction::FCVTDL |
+		Instruction::FCVTDLU |
+		Instruction::FCVTDS |
+		Instruction::FCVTDW |
+		Instruction::FCVTDWU |
+		Instruction::FCVTLD |
+		Instruction::FCVTLS |
+		Instruction::FCVTLUD |
+		Instruction::FCVTLUS |
+		Instruction::FCVTSD |
+		Instruction::FCVTSL |
+		Instruction::FCVTSLU |
+		Instruction::FCVTSW |
+		Instruction::FCVTSWU |
+		Instruction::FCVTWD |
+		Instruction::FCVTWS |
+		Instruction::FCVTWUD |
+		Instruction::FCVTWUS |
+		Instruction::FDIVD |
+		Instruction::FDIVS |
+		Instruction::FEQD |
+		Instruction::FEQS |
+		Instruction::FLED |
+		Instruction::FLES |
+		Instruction::FLTD |
+		Instruction::FLTS |
+		Instruction::FMADDD |
+		Instruction::FMADDS |
+		Instruction::FMAXD |
+		Instruction::FMAXS |
+		Instruction::FMIND |
+		Instruction::FMINS |
+		Instruction::FMSUBD |
+		Instruction::FMSUBS |
+		Instruction::FMULD |
+		Instruction::FMULS |
+		Instruction::FMVDX |
+		Instruction::FMVWX |
+		Instruction::FMVXD |
+		Instruction::FMVXW |
+		Instruction::FNMADDD |
+		Instruction::FNMADDS |
+		Instruction::FNMSUBD |
+		Instruction::FNMSUBS |
+		Instruction::FSGNJD |
+		Instruction::FSGNJND |
+		Instruction::FSGNJNS |
+		Instruction::FSGNJS |
+		Instruction::FSGNJXD |
+		Instruction::FSGNJXS |
+		Instruction::FSQRTD |
+		Instruction::FSQRTS |
+		Instruction::FSUBD |
+		Instruction::FSUBS |
+		Instruction::LRD |
+		Instruction::LRW |
+		Instruction::MAX |
+		Instruction::MAXU |
+		Instruction::MIN |
+		Instruction::MINU |
 		Instruction::MRET |
 		Instruction::MUL |
 		Instruction::MULH |
@@ -413,13 +1203,30 @@ fn get_instruction_format(instruction: &Instruction) -> InstructionFormat {
 		Instruction::MULHSU |
 		Instruction::MULW |
 		Instruction::OR |
+		Instruction::ORCB |
+		Instruction::ORN |
 		Instruction::REM |
 		Instruction::REMU |
 		Instruction::REMUW |
 		Instruction::REMW |
+		Instruction::REV8 |
+		Instruction::ROL |
+		Instruction::ROLW |
+		Instruction::ROR |
+		Instruction::RORW |
+		Instruction::SCD |
+		Instruction::SCW |
+		Instruction::SEXTB |
+		Instruction::SEXTH |
 		Instruction::SUB |
 		Instruction::SUBW |
 		Instruction::SFENCEVMA |
+		Instruction::SH1ADD |
+		Instruction::SH1ADDUW |
+		Instruction::SH2ADD |
+		Instruction::SH2ADDUW |
+		Instruction::SH3ADD |
+		Instruction::SH3ADDUW |
 		Instruction::SLL |
 		Instruction::SLLW |
 		Instruction::SLT |
@@ -430,7 +1237,12 @@ fn get_instruction_format(instruction: &Instruction) -> InstructionFormat {
 		Instruction::SRL |
 		Instruction::SRLW |
 		Instruction::URET |
-		Instruction::XOR => InstructionFormat::R,
+		Instruction::WFI |
+		Instruction::XNOR |
+		Instruction::XOR |
+		Instruction::ZEXTH => InstructionFormat::R,
+		Instruction::FSD |
+		Instruction::FSW |
 		Instruction::SB |
 		Instruction::SD |
 		Instruction::SH |
@@ -440,390 +1252,356 @@ fn get_instruction_format(instruction: &Instruction) -> InstructionFormat {
 	}
 }
 
-impl Cpu {
-	pub fn new(terminal: Box<dyn Terminal>) -> Self {
-		let mut cpu = Cpu {
-			clock: 0,
-			xlen: Xlen::Bit64,
-			privilege_mode: PrivilegeMode::Machine,
-			x: [0; 32],
-			pc: 0,
-			csr: [0; CSR_CAPACITY],
-			mmu: Mmu::new(Xlen::Bit64, terminal)
-		};
-		cpu.csr[CSR_SSTATUS_ADDRESS as usize] = 0x200000000;
-		cpu
-	}
+fn is_signaling_nan_f32(value: f32) -> bool {
+	value.is_nan() && (value.to_bits() & 0x400000) == 0
+}
 
-	// Five public methods for setting up from outside
+fn is_signaling_nan_f64(value: f64) -> bool {
+	value.is_nan() && (value.to_bits() & 0x8000000000000) == 0
+}
 
-	pub fn store_raw(&mut self, address: u64, value: u8) {
-		self.mmu.store_raw(address, value);
+// Whether the instruction has rm field in funct3
+fn uses_rounding_mode(instruction: &Instruction) -> bool {
+	match instruction {
+		Instruction::FADDD |
+		Instruction::FADDS |
+		Instruction::FCVTDL |
+		Instruction::FCVTDLU |
+		Instruction::FCVTDS |
+		Instruction::FCVTDW |
+		Instruction::FCVTDWU |
+		Instruction::FCVTLD |
+		Instruction::FCVTLS |
+		Instruction::FCVTLUD |
+		Instruction::FCVTLUS |
+		Instruction::FCVTSD |
+		Instruction::FCVTSL |
+		Instruction::FCVTSLU |
+		Instruction::FCVTSW |
+		Instruction::FCVTSWU |
+		Instruction::FCVTWD |
+		Instruction::FCVTWS |
+		Instruction::FCVTWUD |
+		Instruction::FCVTWUS |
+		Instruction::FDIVD |
+		Instruction::FDIVS |
+		Instruction::FMADDD |
+		Instruction::FMADDS |
+		Instruction::FMSUBD |
+		Instruction::FMSUBS |
+		Instruction::FMULD |
+		Instruction::FMULS |
+		Instruction::FNMADDD |
+		Instruction::FNMADDS |
+		Instruction::FNMSUBD |
+		Instruction::FNMSUBS |
+		Instruction::FSQRTD |
+		Instruction::FSQRTS |
+		Instruction::FSUBD |
+		Instruction::FSUBS => true,
+		_ => false
 	}
+}
 
-	pub fn update_pc(&mut self, value: u64) {
-		self.pc = value;
+// Whether the rounded results of the arithmetic differ from the exact ones.
+// Single precision operands are exactly computed in double precision.
+// Overflow to infinity is inexact.
+
+fn is_inexact_sum_f32(a: f32, b: f32, sum: f32) -> bool {
+	if sum.is_infinite() {
+		return a.is_finite() && b.is_finite();
 	}
+	let (sum64, error) = get_two_sum(a as f64, b as f64);
+	sum.is_finite() && (sum as f64 != sum64 || error != 0.0)
+}
 
-	pub fn update_xlen(&mut self, xlen: Xlen) {
-		self.xlen = xlen.clone();
-		self.mmu.update_xlen(xlen.clone());
+fn is_inexact_product_f32(a: f32, b: f32, product: f32) -> bool {
+	if product.is_infinite() {
+		return a.is_finite() && b.is_finite();
 	}
+	product.is_finite() && product as f64 != a as f64 * b as f64
+}
 
-	pub fn setup_memory(&mut self, capacity: u64) {
-		self.mmu.init_memory(capacity);
+fn is_inexact_quotient_f32(a: f32, b: f32, quotient: f32) -> bool {
+	if quotient.is_infinite() {
+		return a.is_finite() && b.is_finite() && b != 0.0;
 	}
+	quotient.is_finite() && (quotient as f64).mul_add(-(b as f64), a as f64) != 0.0
+}
 
-	pub fn setup_filesystem(&mut self, data: Vec<u8>) {
-		self.mmu.init_disk(data);
+fn is_inexact_square_root_f32(a: f32, root: f32) -> bool {
+	root.is_finite() && (root as f64).mul_add(root as f64, -(a as f64)) != 0.0
+}
+
+// a * b + c
+fn is_inexact_fused_f32(a: f32, b: f32, c: f32, result: f32) -> bool {
+	if result.is_infinite() {
+		return a.is_finite() && b.is_finite() && c.is_finite();
 	}
+	let (sum64, error) = get_two_sum(a as f64 * b as f64, c as f64);
+	result.is_finite() && (result as f64 != sum64 || error != 0.0)
+}
 
-	// One public methods for running riscv-tests
+// Error terms of tiny double precision values can underflow. The values
+// are scaled up by 2^128 beforehand, which doesn't change whether the
+// result is exact.
+const TINY_F64: f64 = 1e-270;
+const SCALE_F64: f64 = 340282366920938463463374607431768211456.0; // 2^128
 
-	pub fn load_word_raw(&mut self, address: u64) -> u32 {
-		self.mmu.load_word_raw(address)
+// Scales a and b if value is tiny
+fn scale_tiny_f64(value: f64, a: f64, b: f64) -> (f64, f64) {
+	match value.abs() < TINY_F64 {
+		true => (a * SCALE_F64, b * SCALE_F64),
+		false => (a, b)
 	}
+}
 
-	//
+fn is_inexact_sum_f64(a: f64, b: f64, sum: f64) -> bool {
+	if sum.is_infinite() {
+		return a.is_finite() && b.is_finite();
+	}
+	sum.is_finite() && get_two_sum(a, b).1 != 0.0
+}
 
-	pub fn tick(&mut self) {
-		match self.tick_operate() {
-			Ok(()) => {},
-			Err(e) => self.handle_exception(e)
-		}
-		self.mmu.tick();
-		self.handle_interrupt();
-		self.clock = self.clock.wrapping_add(1);
+fn is_inexact_product_f64(a: f64, b: f64, product: f64) -> bool {
+	if product.is_infinite() {
+		return a.is_finite() && b.is_finite();
 	}
+	if !product.is_finite() || product == 0.0 {
+		return product == 0.0 && a != 0.0 && b != 0.0;
+	}
+	let (a, product) = scale_tiny_f64(a, a, product);
+	let (b, product) = scale_tiny_f64(b, b, product);
+	let (a, product) = scale_tiny_f64(product, a, product);
+	a.mul_add(b, -product) != 0.0
+}
 
-	// @TODO: Rename
-	fn tick_operate(&mut self) -> Result<(), Trap> {
-		let word = match self.fetch() {
-			Ok(word) => word,
-			Err(e) => return Err(e)
-		};
-		let instruction_address = self.pc;
-		// First try to decode as non-compressed instruction
-		match self.decode(word) {
-			Ok(instruction) => {
-				self.pc = self.pc.wrapping_add(4); // 32-bit length instruction
-				self.operate(word, instruction, instruction_address)
-			},
-			Err(()) => {
-				// If fails to decode as non-compressed instruction,
-				// try to decode as compressed instruction
-				// @TODO: Optimize
-				let uncompressed_word = self.uncompress(word & 0xffff);
-				match self.decode(uncompressed_word) {
-					Ok(instruction) => {
-						self.pc = self.pc.wrapping_add(2); // 16-bit length instruction
-						self.operate(uncompressed_word, instruction, instruction_address)
-					},
-					Err(()) => panic!("Unknown instruction PC:{:X} WORD:{:X}", instruction_address, word)
-				}
-			}
-		}
+// a * b + c
+fn is_inexact_fused_f64(a: f64, b: f64, c: f64, result: f64) -> bool {
+	if result.is_infinite() {
+		return a.is_finite() && b.is_finite() && c.is_finite();
+	}
+	if !result.is_finite() || a == 0.0 || b == 0.0 {
+		return false;
+	}
+	// Scales tiny product up, and c and result as well. Nonzero product
+	// still tiny after scaling is below the last bit of c.
+	let (a, b, c, result) = match (a * b).abs() < TINY_F64 {
+		true => match c.abs() < 1.0 {
+			true => (a * SCALE_F64, b * SCALE_F64, c * SCALE_F64 * SCALE_F64, result * SCALE_F64 * SCALE_F64),
+			false => return true
+		},
+		false => (a, b, c, result)
+	};
+	let product = a * b;
+	if product.abs() < TINY_F64 {
+		return true;
 	}
+	// a * b + c - result = product + error + c - result exactly
+	!is_zero_sum(&[product, a.mul_add(b, -product), c, -result])
+}
 
-	fn handle_interrupt(&mut self) {
-		match self.mmu.detect_interrupt() {
-			InterruptType::None => {},
-			InterruptType::KeyInput => {
-				match self.handle_trap(Trap {
-					trap_type: TrapType::SupervisorExternalInterrupt,
-					value: self.pc // dummy
-				}, true) {
-					true => {
-						self.mmu.reset_uart_interrupting();
-						self.mmu.reset_interrupt();
-					},
-					false => {}
-				};
-			},
-			InterruptType::Timer => {
-				match self.handle_trap(Trap {
-					trap_type: TrapType::SupervisorSoftwareInterrupt,
-					value: self.pc // dummy
-				}, true) {
-					true => {
-						self.mmu.reset_clint_interrupting();
-						self.mmu.reset_interrupt();
-					},
-					false => {}
-				};
-			},
-			InterruptType::Virtio => {
-				match self.handle_trap(Trap {
-					trap_type: TrapType::SupervisorExternalInterrupt,
-					value: self.pc // dummy
-				}, true) {
-					true => {
-						self.mmu.handle_disk_access();
-						self.mmu.reset_disk_interrupting();
-						self.mmu.reset_interrupt();
-					},
-					false => {}
-				};
-			}
-		};
+fn is_inexact_quotient_f64(a: f64, b: f64, quotient: f64) -> bool {
+	if quotient.is_infinite() {
+		return a.is_finite() && b.is_finite() && b != 0.0;
 	}
+	if !quotient.is_finite() {
+		return false;
+	}
+	// a = quotient * b + remainder
+	let (a, b) = scale_tiny_f64(b, a, b);
+	let (a, quotient) = scale_tiny_f64(quotient, a, quotient);
+	let (a, quotient) = scale_tiny_f64(a, a, quotient);
+	quotient.mul_add(-b, a) != 0.0
+}
 
-	fn handle_exception(&mut self, exception: Trap) {
-		self.handle_trap(exception, false);
+fn is_inexact_square_root_f64(a: f64, root: f64) -> bool {
+	if !root.is_finite() {
+		return false;
 	}
+	let (a, root) = match a < TINY_F64 {
+		true => (a * SCALE_F64 * SCALE_F64, root * SCALE_F64),
+		false => (a, root)
+	};
+	root.mul_add(root, -a) != 0.0
+}
 
-	fn handle_trap(&mut self, trap: Trap, is_interrupt: bool) -> bool{
-		let current_privilege_encoding = get_privilege_encoding(&self.privilege_mode) as u64;
-		let cause = get_trap_cause(&trap, &self.xlen);
+// Returns the rounded sum and its error, a + b = sum + error exactly
+fn get_two_sum(a: f64, b: f64) -> (f64, f64) {
+	let sum = a + b;
+	let b_virtual = sum - a;
+	let a_virtual = sum - b_virtual;
+	(sum, (a - a_virtual) + (b - b_virtual))
+}
 
-		// @TODO: Check if this logic is correct
-		let mdeleg = match is_interrupt {
-			true => self.csr[CSR_MIDELEG_ADDRESS as usize],
-			false => self.csr[CSR_MEDELEG_ADDRESS as usize]
-		};
-		let sdeleg = match is_interrupt {
-			true => self.csr[CSR_SIDELEG_ADDRESS as usize],
-			false => self.csr[CSR_SEDELEG_ADDRESS as usize]
-		};
-		let pos = cause & 0xffff;
-		let new_privilege_mode = match ((mdeleg >> pos) & 1) == 0 {
-			true => PrivilegeMode::Machine,
-			false => match ((sdeleg >> pos) & 1) == 0 {
-				true => PrivilegeMode::Supervisor,
-				false => PrivilegeMode::User
-			}
-		};
+// Whether the exact sum of the values is zero. The values are added to an
+// expansion whose components don't overlap, so the sum is zero only if all
+// the components are zero.
+fn is_zero_sum(values: &[f64]) -> bool {
+	let mut expansion: Vec<f64> = vec![];
+	for value in values {
+		let mut sum = *value;
+		for component in expansion.iter_mut() {
+			let (new_sum, error) = get_two_sum(sum, *component);
+			*component = error;
+			sum = new_sum;
+		}
+		expansion.push(sum);
+	}
+	expansion.iter().all(|component| *component == 0.0)
+}
 
-		// @TODO: Which we should do, dispose or pend, if trap is disabled?
-		// Disposing so far.
+// Converts the integer with the rounding mode rm. Returns the result and
+// whether it's inexact.
+fn convert_integer_f32(value: i128, rm: u32) -> (f32, bool) {
+	let nearest = value as f32; // Ties to even
+	if nearest as i128 == value {
+		return (nearest, false);
+	}
+	// The adjacent value on the other side. nearest isn't zero here.
+	let other = match (nearest as i128 > value) == (nearest > 0.0) {
+		true => f32::from_bits(nearest.to_bits() - 1),
+		false => f32::from_bits(nearest.to_bits() + 1)
+	};
+	(select_rounded(value, nearest, other, rm, |v| v as i128), true)
+}
 
-		let status = match new_privilege_mode {
-			PrivilegeMode::Machine => self.csr[CSR_MSTATUS_ADDRESS as usize],
-			PrivilegeMode::Supervisor => self.csr[CSR_SSTATUS_ADDRESS as usize],
-			PrivilegeMode::User => self.csr[CSR_USTATUS_ADDRESS as usize],
-			PrivilegeMode::Reserved => panic!(),
-		};
+fn convert_integer_f64(value: i128, rm: u32) -> (f64, bool) {
+	let nearest = value as f64; // Ties to even
+	if nearest as i128 == value {
+		return (nearest, false);
+	}
+	let other = match (nearest as i128 > value) == (nearest > 0.0) {
+		true => f64::from_bits(nearest.to_bits() - 1),
+		false => f64::from_bits(nearest.to_bits() + 1)
+	};
+	(select_rounded(value, nearest, other, rm, |v| v as i128), true)
+}
 
-		let mie = (status >> 3) & 1;
-		let sie = (status >> 1) & 1;
-		let uie = status & 1;
+// Chooses the result from the two values adjacent to the integer.
+// nearest is the one the integer is rounded to with ties to even.
+fn select_rounded<T: Copy>(value: i128, nearest: T, other: T, rm: u32, to_integer: fn(T) -> i128) -> T {
+	let (lower, upper) = match to_integer(nearest) < value {
+		true => (nearest, other),
+		false => (other, nearest)
+	};
+	match rm {
+		1 => match value < 0 { // RTZ
+			true => upper,
+			false => lower
+		},
+		2 => lower, // RDN
+		3 => upper, // RUP
+		4 => match value - to_integer(lower) == to_integer(upper) - value { // RMM
+			true => match value < 0 {
+				true => lower,
+				false => upper
+			},
+			false => nearest
+		},
+		_ => nearest // RNE
+	}
+}
 
-		if is_interrupt {
-			let interrupt_privilege_mode = get_interrupt_privilege_mode(&trap);
-			let interrupt_privilege_encoding = get_privilege_encoding(&interrupt_privilege_mode) as u64;
-			match new_privilege_mode {
-				PrivilegeMode::Machine => {
-					if mie == 0 {
-						return false;
-					}
-				},
-				PrivilegeMode::Supervisor => {
-					if sie == 0 {
-						return false;
-					}
-				},
-				PrivilegeMode::User => {
-					if uie == 0 {
-						return false;
-					}
-				},
-				PrivilegeMode::Reserved => panic!()
-			};
-			if current_privilege_encoding > interrupt_privilege_encoding {
-				return false;
-			}
+// Rounds to an integral value with the rounding mode rm
+fn round_to_integral(value: f64, rm: u32) -> f64 {
+	match rm {
+		0 => match (value - value.trunc()).abs() == 0.5 { // RNE
+			true => 2.0 * (value / 2.0).round(),
+			false => value.round()
+		},
+		1 => value.trunc(), // RTZ
+		2 => value.floor(), // RDN
+		3 => value.ceil(), // RUP
+		4 => value.round(), // RMM
+		_ => panic!() // Rejected when decoding rounding mode
+	}
+}
+
+fn get_fclass(category: FpCategory, negative: bool, signaling: bool) -> i64 {
+	match category {
+		FpCategory::Infinite => match negative {
+			true => 1 << 0,
+			false => 1 << 7
+		},
+		FpCategory::Normal => match negative {
+			true => 1 << 1,
+			false => 1 << 6
+		},
+		FpCategory::Subnormal => match negative {
+			true => 1 << 2,
+			false => 1 << 5
+		},
+		FpCategory::Zero => match negative {
+			true => 1 << 3,
+			false => 1 << 4
+		},
+		FpCategory::Nan => match signaling {
+			true => 1 << 8,
+			false => 1 << 9
 		}
+	}
+}
 
-		self.privilege_mode = new_privilege_mode;
-		self.mmu.update_privilege_mode(self.privilege_mode.clone());
-		let csr_epc_address = match self.privilege_mode {
-			PrivilegeMode::Machine => CSR_MEPC_ADDRESS,
-			PrivilegeMode::Supervisor => CSR_SEPC_ADDRESS,
-			PrivilegeMode::User => CSR_UEPC_ADDRESS,
-			PrivilegeMode::Reserved => panic!()
-		};
-		let csr_cause_address = match self.privilege_mode {
-			PrivilegeMode::Machine => CSR_MCAUSE_ADDRESS,
-			PrivilegeMode::Supervisor => CSR_SCAUSE_ADDRESS,
-			PrivilegeMode::User => CSR_UCAUSE_ADDRESS,
-			PrivilegeMode::Reserved => panic!()
-		};
-		let csr_tval_address = match self.privilege_mode {
-			PrivilegeMode::Machine => CSR_MTVAL_ADDRESS,
-			PrivilegeMode::Supervisor => CSR_STVAL_ADDRESS,
-			PrivilegeMode::User => CSR_UTVAL_ADDRESS,
-			PrivilegeMode::Reserved => panic!()
-		};
-		let csr_tvec_address = match self.privilege_mode {
-			PrivilegeMode::Machine => CSR_MTVEC_ADDRESS,
-			PrivilegeMode::Supervisor => CSR_STVEC_ADDRESS,
-			PrivilegeMode::User => CSR_UTVEC_ADDRESS,
-			PrivilegeMode::Reserved => panic!()
-		};
+// Decoding doesn't depend on Cpu state other than xlen and enabled
+// extensions so that it can be used without Cpu, for example by disassembler
 
-		self.csr[csr_epc_address as usize] = match is_interrupt {
-			true => self.pc, // @TODO: remove this hack
-			false => self.pc.wrapping_sub(4)
-		};
-		self.csr[csr_cause_address as usize] = cause;
-		self.csr[csr_tval_address as usize] = trap.value;
-		self.pc = self.csr[csr_tvec_address as usize];
+// @TODO: Optimize
+pub fn uncompress(halfword: u32, xlen: &Xlen) -> u32 {
+	let op = halfword & 0x3; // [1:0]
+	let funct3 = (halfword >> 13) & 0x7; // [15:13]
 
-		match self.privilege_mode {
-			PrivilegeMode::Machine => {
-				let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
-				let mie = (status >> 3) & 1;
-				// clear MIE[3], override MPIE[7] with MIE[3], override MPP[12:11] with current privilege encoding
-				let new_status = (status & !0x1888) | (mie << 7) | (current_privilege_encoding << 11);
-				self.csr[CSR_MSTATUS_ADDRESS as usize] = new_status;
+	match op {
+		0 => match funct3 {
+			0 => {
+				// C.ADDI4SPN
+				// addi rd+8, x2, nzuimm
+				let rd = (halfword >> 2) & 0x7; // [4:2]
+				let nzuimm =
+					((halfword >> 7) & 0x30) | // nzuimm[5:4] <= [12:11]
+					((halfword >> 1) & 0x3c0) | // nzuimm[9:6] <= [10:7]
+					((halfword >> 4) & 0x4) | // nzuimm[2] <= [6]
+					((halfword >> 2) & 0x8); // nzuimm[3] <= [5]
+				// nzuimm == 0 is reserved instruction
+				if nzuimm != 0 {
+					return (nzuimm << 20) | (2 << 15) | ((rd + 8) << 7) | 0x13;
+				}
 			},
-			PrivilegeMode::Supervisor => {
-				let status = self.csr[CSR_SSTATUS_ADDRESS as usize];
-				let sie = (status >> 1) & 1;
-				// clear SIE[1], override SPIE[5] with SIE[1], override SPP[8] with current privilege encoding
-				let new_status = (status & !0x122) | (sie << 5) | ((current_privilege_encoding & 1) << 8);
-				self.csr[CSR_SSTATUS_ADDRESS as usize] = new_status;
+			1 => {
+				// @TODO: Support C.LQ in 128-bit mode
+				// C.FLD in 32, 64-bit mode
+				// fld rd+8, offset(rs1+8)
+				let rs1 = (halfword >> 7) & 0x7; // [9:7]
+				let rd = (halfword >> 2) & 0x7; // [4:2]
+				let offset =
+					((halfword >> 7) & 0x38) | // offset[5:3] <= [12:10]
+					((halfword << 1) & 0xc0); // offset[7:6] <= [6:5]
+				return (offset << 20) | ((rs1 + 8) << 15) | (3 << 12) | ((rd + 8) << 7) | 0x7;
 			},
-			PrivilegeMode::User => {
-				panic!("Not implemenete yet");
+			2 => {
+				// C.LW
+				// lw rd+8, offset(rs1+8)
+				let rs1 = (halfword >> 7) & 0x7; // [9:7]
+				let rd = (halfword >> 2) & 0x7; // [4:2]
+				let offset =
+					((halfword >> 7) & 0x38) | // offset[5:3] <= [12:10]
+					((halfword >> 4) & 0x4) | // offset[2] <= [6]
+					((halfword << 1) & 0x40); // offset[6] <= [5]
+				return (offset << 20) | ((rs1 + 8) << 15) | (2 << 12) | ((rd + 8) << 7) | 0x3;
 			},
-			PrivilegeMode::Reserved => panic!() // shouldn't happen
-		};
-		true
-	}
-
-	fn fetch(&mut self) -> Result<u32, Trap> {
-		let word = match self.mmu.fetch_word(self.pc) {
-			Ok(word) => word,
-			Err(e) => {
-				self.pc = self.pc.wrapping_add(4); // @TODO: What if instruction is compressed?
-				return Err(e);
-			}
-		};
-		Ok(word)
-	}
-
-	fn has_csr_access_privilege(&self, address: u16) -> bool {
-		let privilege = (address >> 8) & 0x3; // the lowest privilege level that can access the CSR
-		privilege as u8 <= get_privilege_encoding(&self.privilege_mode)
-	}
-
-	fn read_csr(&mut self, address: u16) -> Result<u64, Trap> {
-		match self.has_csr_access_privilege(address) {
-			true => Ok(self.csr[address as usize]),
-			false => Err(Trap {
-				trap_type: TrapType::IllegalInstruction,
-				value: self.pc.wrapping_sub(4) // @TODO: Is this always correct?
-			})
-		}
-	}
-
-	fn write_csr(&mut self, address: u16, value: u64) -> Result<(), Trap> {
-		// println!("PC:{:X} Privilege mode:{}", self.pc.wrapping_sub(4), _get_privilege_mode_name(&self.privilege_mode));
-		// println!("CSR:{:X} Value:{:X}", address, value);
-		match self.has_csr_access_privilege(address) {
-			true => {
-				/*
-				// Checking writability fails some tests so disabling so far
-				let read_only = ((address >> 10) & 0x3) == 0x3;
-				if read_only {
-					return Err(Exception::IllegalInstruction);
-				}
-				*/
-				self.csr[address as usize] = value;
-				if address == CSR_SATP_ADDRESS {
-					self.update_addressing_mode(value);
-				}
-				Ok(())
-			},
-			false => Err(Trap {
-				trap_type: TrapType::IllegalInstruction,
-				value: self.pc.wrapping_sub(4) // @TODO: Is this always correct?
-			})
-		}
-	}
-
-	fn update_addressing_mode(&mut self, value: u64) {
-		let addressing_mode = match self.xlen {
-			Xlen::Bit32 => match value & 0x80000000 {
-				0 => AddressingMode::None,
-				_ => AddressingMode::SV32
-			},
-			Xlen::Bit64 => match value >> 60 {
-				0 => AddressingMode::None,
-				8 => AddressingMode::SV39,
-				9 => AddressingMode::SV48,
-				_ => {
-					println!("Unknown addressing_mode {:X}", value >> 60);
-					panic!();
-				}
-			}
-		};
-		let ppn = match self.xlen {
-			Xlen::Bit32 => value & 0x3fffff,
-			Xlen::Bit64 => value & 0xfffffffffff
-		};
-		self.mmu.update_addressing_mode(addressing_mode);
-		self.mmu.update_ppn(ppn);
-	}
-
-	// @TODO: Rename to better name?
-	fn sign_extend(&self, value: i64) -> i64 {
-		match self.xlen {
-			Xlen::Bit32 => (match value & 0x80000000 {
-				0x80000000 => (value as u64) | 0xffffffff00000000,
-				_ => (value as u64) & 0xffffffff
-			}) as i64,
-			Xlen::Bit64 => value
-		}
-	}
-
-	// @TODO: Rename to better name?
-	fn unsigned_data(&self, value: i64) -> u64 {
-		match self.xlen {
-			Xlen::Bit32 => (value as u64) & 0xffffffff,
-			Xlen::Bit64 => value as u64
-		}
-	}
-
-	// @TODO: Optimize
-	fn uncompress(&self, halfword: u32) -> u32 {
-		let op = halfword & 0x3; // [1:0]
-		let funct3 = (halfword >> 13) & 0x7; // [15:13]
-
-		match op {
-			0 => match funct3 {
-				0 => {
-					// C.ADDI4SPN
-					// addi rd+8, x2, nzuimm
-					let rd = (halfword >> 2) & 0x7; // [4:2]
-					let nzuimm =
-						((halfword >> 7) & 0x30) | // nzuimm[5:4] <= [12:11]
-						((halfword >> 1) & 0x3e0) | // nzuimm{9:6] <= [10:7]
-						((halfword >> 4) & 0x4) | // nzuimm[2] <= [6]
-						((halfword >> 2) & 0x8); // nzuimm[3] <= [5]
-					// nzuimm == 0 is reserved instruction
-					if nzuimm != 0 {
-						return (nzuimm << 20) | (2 << 15) | ((rd + 8) << 7) | 0x13;
-					}
-				},
-				1 => {
-					// C.FLD(32, 64-bit) or C.LQ(128-bit)
-					panic!("C.FLD is not implemented yet.");
-				},
-				2 => {
-					// C.LW
-					// lw rd+8, offset(rs1+8)
+			3 => match xlen {
+				Xlen::Bit32 => {
+					// C.FLW in 32-bit mode
+					// flw rd+8, offset(rs1+8)
 					let rs1 = (halfword >> 7) & 0x7; // [9:7]
 					let rd = (halfword >> 2) & 0x7; // [4:2]
 					let offset =
 						((halfword >> 7) & 0x38) | // offset[5:3] <= [12:10]
 						((halfword >> 4) & 0x4) | // offset[2] <= [6]
 						((halfword << 1) & 0x40); // offset[6] <= [5]
-					return (offset << 20) | ((rs1 + 8) << 15) | (2 << 12) | ((rd + 8) << 7) | 0x3;
+					return (offset << 20) | ((rs1 + 8) << 15) | (2 << 12) | ((rd + 8) << 7) | 0x7;
 				},
-				3 => {
-					// @TODO: Support C.FLW in 32-bit mode
+				Xlen::Bit64 => {
 					// C.LD in 64-bit mode
 					// ld rd+8, offset(rs1+8)
 					let rs1 = (halfword >> 7) & 0x7; // [9:7]
@@ -832,30 +1610,53 @@ impl Cpu {
 						((halfword >> 7) & 0x38) | // offset[5:3] <= [12:10]
 						((halfword << 1) & 0xc0); // offset[7:6] <= [6:5]
 					return (offset << 20) | ((rs1 + 8) << 15) | (3 << 12) | ((rd + 8) << 7) | 0x3;
-				},
-				4 => {
-					// Reserved
-				},
-				5 => {
-					// C.FSD
-					panic!("C.FSD is not supported yet.");
-				},
-				6 => {
-					// C.SW
-					// sw rs2+8, offset(rs1+8)
+				}
+			},
+			4 => {
+				// Reserved
+			},
+			5 => {
+				// @TODO: Support C.SQ in 128-bit mode
+				// C.FSD in 32, 64-bit mode
+				// fsd rs2+8, offset(rs1+8)
+				let rs1 = (halfword >> 7) & 0x7; // [9:7]
+				let rs2 = (halfword >> 2) & 0x7; // [4:2]
+				let offset =
+					((halfword >> 7) & 0x38) | // uimm[5:3] <= [12:10]
+					((halfword << 1) & 0xc0); // uimm[7:6] <= [6:5]
+				let imm11_5 = (offset >> 5) & 0x7f;
+				let imm4_0 = offset & 0x1f;
+				return (imm11_5 << 25) | ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (3 << 12) | (imm4_0 << 7) | 0x27;
+			},
+			6 => {
+				// C.SW
+				// sw rs2+8, offset(rs1+8)
+				let rs1 = (halfword >> 7) & 0x7; // [9:7]
+				let rs2 = (halfword >> 2) & 0x7; // [4:2]
+				let offset = 
+					((halfword >> 7) & 0x38) | // offset[5:3] <= [12:10]
+					((halfword << 1) & 0x40) | // offset[6] <= [5]
+					((halfword >> 4) & 0x4); // offset[2] <= [6]
+				let imm11_5 = (offset >> 5) & 0x7f;
+				let imm4_0 = offset & 0x1f;
+				return (imm11_5 << 25) | ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (2 << 12) | (imm4_0 << 7) | 0x23;
+			},
+			7 => match xlen {
+				Xlen::Bit32 => {
+					// C.FSW in 32-bit mode
+					// fsw rs2+8, offset(rs1+8)
 					let rs1 = (halfword >> 7) & 0x7; // [9:7]
 					let rs2 = (halfword >> 2) & 0x7; // [4:2]
-					let offset = 
+					let offset =
 						((halfword >> 7) & 0x38) | // offset[5:3] <= [12:10]
 						((halfword << 1) & 0x40) | // offset[6] <= [5]
 						((halfword >> 4) & 0x4); // offset[2] <= [6]
 					let imm11_5 = (offset >> 5) & 0x7f;
 					let imm4_0 = offset & 0x1f;
-					return (imm11_5 << 25) | ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (2 << 12) | (imm4_0 << 7) | 0x23;
+					return (imm11_5 << 25) | ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (2 << 12) | (imm4_0 << 7) | 0x27;
 				},
-				7 => {
-					// @TODO: Support C.FSW in 32-bit mode
-					// C.SD
+				Xlen::Bit64 => {
+					// C.SD in 64-bit mode
 					// sd rs2+8, offset(rs1+8)
 					let rs1 = (halfword >> 7) & 0x7; // [9:7]
 					let rs2 = (halfword >> 2) & 0x7; // [4:2]
@@ -865,187 +1666,36 @@ impl Cpu {
 					let imm11_5 = (offset >> 5) & 0x7f;
 					let imm4_0 = offset & 0x1f;
 					return (imm11_5 << 25) | ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (3 << 12) | (imm4_0 << 7) | 0x23;
-				},
-				_ => {} // Not happens
+				}
 			},
-			1 => {
-				match funct3 {
-					0 => {
-						let r = (halfword >> 7) & 0x1f; // [11:7]
-						let imm = match halfword & 0x1000 {
-							0x1000 => 0xffffffc0,
-							_ => 0
-						} | // imm[31:6] <= [12]
-						((halfword >> 7) & 0x20) | // imm[5] <= [12]
-						((halfword >> 2) & 0x1f); // imm[4:0] <= [6:2]
-						if r == 0 && imm == 0 {
-							// C.NOP
-							// addi x0, x0, 0
-							return 0x13;
-						} else if r != 0 {
-							// C.ADDI
-							// addi r, r, imm
-							return (imm << 20) | (r << 15) | (r << 7) | 0x13;
-						}
-						// @TODO: Support HINTs
-						// r == 0 and imm != 0 is HINTs
-					},
-					1 => {
-						// @TODO: Support C.JAL in 32-bit mode
-						// C.ADDIW
-						// addiw r, r, imm
-						let r = (halfword >> 7) & 0x1f;
-						let imm = match halfword & 0x1000 {
-							0x1000 => 0xffffffc0,
-							_ => 0
-						} | // imm[31:6] <= [12]
-						((halfword >> 7) & 0x20) | // imm[5] <= [12]
-						((halfword >> 2) & 0x1f); // imm[4:0] <= [6:2]
-						if r != 0 {
-							return (imm << 20) | (r << 15) | (r << 7) | 0x1b;
-						}
-						// r == 0 is reserved instruction
-					},
-					2 => {
-						// C.LI
-						// addi rd, x0, imm
-						let r = (halfword >> 7) & 0x1f;
-						let imm = match halfword & 0x1000 {
-							0x1000 => 0xffffffc0,
-							_ => 0
-						} | // imm[31:6] <= [12]
-						((halfword >> 7) & 0x20) | // imm[5] <= [12]
-						((halfword >> 2) & 0x1f); // imm[4:0] <= [6:2]
-						if r != 0 {
-							return (imm << 20) | (r << 7) | 0x13;
-						}
-						// @TODO: Support HINTs
-						// r == 0 is for HINTs
-					},
-					3 => {
-						let r = (halfword >> 7) & 0x1f; // [11:7]
-						if r == 2 {
-							// C.ADDI16SP
-							// addi r, r, nzimm
-							let imm = match halfword & 0x1000 {
-								0x1000 => 0xfffffc00,
-								_ => 0
-							} | // imm[31:10] <= [12]
-							((halfword >> 3) & 0x200) | // imm[9] <= [12]
-							((halfword >> 2) & 0x10) | // imm[4] <= [6]
-							((halfword << 1) & 0x40) | // imm[6] <= [5]
-							((halfword << 4) & 0x180) | // imm[8:7] <= [4:3]
-							((halfword << 3) & 0x20); // imm[5] <= [2]
-							if imm != 0 {
-								return (imm << 20) | (r << 15) | (r << 7) | 0x13;
-							}
-							// imm == 0 is for reserved instruction
-						}
-						if r != 0 && r != 2 {
-							// C.LUI
-							// lui r, nzimm
-							let nzimm = match halfword & 0x1000 {
-								0x1000 => 0xfffc0000,
-								_ => 0
-							} | // nzimm[31:18] <= [12]
-							((halfword << 5) & 0x20000) | // nzimm[17] <= [12]
-							((halfword << 10) & 0x1f000); // nzimm[16:12] <= [6:2]
-							if nzimm != 0 {
-								return nzimm | (r << 7) | 0x37;
-							}
-							// nzimm == 0 is for reserved instruction
-						}
-					},
-					4 => {
-						let funct2 = (halfword >> 10) & 0x3; // [11:10]
-						match funct2 {
-							0 => {
-								// C.SRLI
-								// c.srli rs1+8, rs1+8, shamt
-								let shamt = 
-									((halfword >> 7) & 0x20) | // shamt[5] <= [12]
-									((halfword >> 2) & 0x1f); // shamt[4:0] <= [6:2]
-								let rs1 = (halfword >> 7) & 0x7; // [9:7]
-								return (shamt << 20) | ((rs1 + 8) << 15) | (5 << 12) | ((rs1 + 8) << 7) | 0x13;
-							},
-							1 => {
-								// C.SRAI
-								// srai rs1+8, rs1+8, shamt
-								let shamt = 
-									((halfword >> 7) & 0x20) | // shamt[5] <= [12]
-									((halfword >> 2) & 0x1f); // shamt[4:0] <= [6:2]
-								let rs1 = (halfword >> 7) & 0x7; // [9:7]
-								return (0x20 << 25) | (shamt << 20) | ((rs1 + 8) << 15) | (5 << 12) | ((rs1 + 8) << 7) | 0x13;
-							},
-							2 => {
-								// C.ANDI
-								// andi, r+8, r+8, imm
-								let r = (halfword >> 7) & 0x7; // [9:7]
-								let imm = match halfword & 0x1000 {
-									0x1000 => 0xffffffc0,
-									_ => 0
-								} | // imm[31:6] <= [12]
-								((halfword >> 7) & 0x20) | // imm[5] <= [12]
-								((halfword >> 2) & 0x1f); // imm[4:0] <= [6:2]
-								return (imm << 20) | ((r + 8) << 15) | (7 << 12) | ((r + 8) << 7) | 0x13;
-							},
-							3 => {
-								let funct1 = (halfword >> 12) & 1; // [12]
-								let funct2_2 = (halfword >> 5) & 0x3; // [6:5]
-								let rs1 = (halfword >> 7) & 0x7;
-								let rs2 = (halfword >> 2) & 0x7;
-								match funct1 {
-									0 => match funct2_2 {
-										0 => {
-											// C.SUB
-											// sub rs1+8, rs1+8, rs2+8
-											return (0x20 << 25) | ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | ((rs1 + 8) << 7) | 0x33;
-										},
-										1 => {
-											// C.XOR
-											// xor rs1+8, rs1+8, rs2+8
-											return ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (4 << 12) | ((rs1 + 8) << 7) | 0x33;
-										},
-										2 => {
-											// C.OR
-											// or rs1+8, rs1+8, rs2+8
-											return ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (6 << 12) | ((rs1 + 8) << 7) | 0x33;
-										},
-										3 => {
-											// C.AND
-											// and rs1+8, rs1+8, rs2+8
-											return ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (7 << 12) | ((rs1 + 8) << 7) | 0x33;
-										},
-										_ => {} // Not happens
-									},
-									1 => match funct2_2 {
-										0 => {
-											// C.SUBW
-											// subw r1+8, r1+8, r2+8
-											return (0x20 << 25) | ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | ((rs1 + 8) << 7) | 0x3b;
-										},
-										1 => {
-											// C.ADDW
-											// addw r1+8, r1+8, r2+8
-											return ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | ((rs1 + 8) << 7) | 0x3b;
-										},
-										2 => {
-											// Reserved
-										},
-										3 => {
-											// Reserved
-										},
-										_ => {} // Not happens
-									},
-									_ => {} // No happens
-								};
-							},
-							_ => {} // not happens
-						};
-					},
-					5 => {
-						// C.J
-						// jal x0, imm
+			_ => {} // Not happens
+		},
+		1 => {
+			match funct3 {
+				0 => {
+					let r = (halfword >> 7) & 0x1f; // [11:7]
+					let imm = match halfword & 0x1000 {
+						0x1000 => 0xffffffc0,
+						_ => 0
+					} | // imm[31:6] <= [12]
+					((halfword >> 7) & 0x20) | // imm[5] <= [12]
+					((halfword >> 2) & 0x1f); // imm[4:0] <= [6:2]
+					if r == 0 && imm == 0 {
+						// C.NOP
+						// addi x0, x0, 0
+						return 0x13;
+					} else if r != 0 {
+						// C.ADDI
+						// addi r, r, imm
+						return (imm << 20) | (r << 15) | (r << 7) | 0x13;
+					}
+					// @TODO: Support HINTs
+					// r == 0 and imm != 0 is HINTs
+				},
+				1 => match xlen {
+					Xlen::Bit32 => {
+						// C.JAL in 32-bit mode
+						// jal x1, imm
 						let offset =
 							match halfword & 0x1000 {
 								0x1000 => 0xfffff000,
@@ -1064,89 +1714,282 @@ impl Cpu {
 							((offset << 8) & 0x7fe00) | // imm[18:9] <= offset[10:1]
 							((offset >> 3) & 0x100) | // imm[8] <= offset[11]
 							((offset >> 12) & 0xff); // imm[7:0] <= offset[19:12]
-						return (imm << 12) | 0x6f;
+						return (imm << 12) | (1 << 7) | 0x6f;
 					},
-					6 => {
-						// C.BEQZ
-						// beq r+8, x0, offset
-						let r = (halfword >> 7) & 0x7;
-						let offset =
-							match halfword & 0x1000 {
-								0x1000 => 0xfffffe00,
-								_ => 0
-							} | // offset[31:9] <= [12]
-							((halfword >> 4) & 0x100) | // offset[8] <= [12]
-							((halfword >> 7) & 0x18) | // offset[4:3] <= [11:10]
-							((halfword << 1) & 0xc0) | // offset[7:6] <= [6:5]
-							((halfword >> 2) & 0x6) | // offset[2:1] <= [4:3]
-							((halfword << 3) & 0x20); // offset[5] <= [2]
-						let imm2 =
-							((offset >> 6) & 0x40) | // imm2[6] <= [12]
-							((offset >> 5) & 0x3f); // imm2[5:0] <= [10:5]
-						let imm1 =
-							(offset & 0x1e) | // imm1[4:1] <= [4:1]
-							((offset >> 11) & 0x1); // imm1[0] <= [11]
-						return (imm2 << 25) | ((r + 8) << 20) | (imm1 << 7) | 0x63;
-					},
-					7 => {
-						// C.BNEZ
-						// bne r+8, x0, offset
-						let r = (halfword >> 7) & 0x7;
-						let offset =
-							match halfword & 0x1000 {
-								0x1000 => 0xfffffe00,
-								_ => 0
-							} | // offset[31:9] <= [12]
-							((halfword >> 4) & 0x100) | // offset[8] <= [12]
-							((halfword >> 7) & 0x18) | // offset[4:3] <= [11:10]
-							((halfword << 1) & 0xc0) | // offset[7:6] <= [6:5]
-							((halfword >> 2) & 0x6) | // offset[2:1] <= [4:3]
-							((halfword << 3) & 0x20); // offset[5] <= [2]
-						let imm2 =
-							((offset >> 6) & 0x40) | // imm2[6] <= [12]
-							((offset >> 5) & 0x3f); // imm2[5:0] <= [10:5]
-						let imm1 =
-							(offset & 0x1e) | // imm1[4:1] <= [4:1]
-							((offset >> 11) & 0x1); // imm1[0] <= [11]
-						return (imm2 << 25) | ((r + 8) << 20) | (1 << 12) | (imm1 << 7) | 0x63;
-					},
-					_ => {} // No happens
-				};
-			},
-			2 => {
-				match funct3 {
-					0 => {
-						// C.SLLI
-						// slli r, r, shamt
+					Xlen::Bit64 => {
+						// C.ADDIW in 64-bit mode
+						// addiw r, r, imm
 						let r = (halfword >> 7) & 0x1f;
-						let shamt =
-							((halfword >> 7) & 0x20) | // imm[5] <= [12]
-							((halfword >> 2) & 0x1f); // imm[4:0] <= [6:2]
+						let imm = match halfword & 0x1000 {
+							0x1000 => 0xffffffc0,
+							_ => 0
+						} | // imm[31:6] <= [12]
+						((halfword >> 7) & 0x20) | // imm[5] <= [12]
+						((halfword >> 2) & 0x1f); // imm[4:0] <= [6:2]
 						if r != 0 {
-							return (shamt << 20) | (r << 15) | (1 << 12) | (r << 7) | 0x13;
+							return (imm << 20) | (r << 15) | (r << 7) | 0x1b;
 						}
-						// r == 0 is reserved instruction?
-					},
-					1 => {
-						// C.FLDSP
-						panic!("C.FLDSP is not implemented yet.");
-					},
-					2 => {
-						// C.LWSP
-						// lw r, offset(x2)
-						let r = (halfword >> 7) & 0x1f;
+						// r == 0 is reserved instruction
+					}
+				},
+				2 => {
+					// C.LI
+					// addi rd, x0, imm
+					let r = (halfword >> 7) & 0x1f;
+					let imm = match halfword & 0x1000 {
+						0x1000 => 0xffffffc0,
+						_ => 0
+					} | // imm[31:6] <= [12]
+					((halfword >> 7) & 0x20) | // imm[5] <= [12]
+					((halfword >> 2) & 0x1f); // imm[4:0] <= [6:2]
+					if r != 0 {
+						return (imm << 20) | (r << 7) | 0x13;
+					}
+					// @TODO: Support HINTs
+					// r == 0 is for HINTs
+				},
+				3 => {
+					let r = (halfword >> 7) & 0x1f; // [11:7]
+					if r == 2 {
+						// C.ADDI16SP
+						// addi r, r, nzimm
+						let imm = match halfword & 0x1000 {
+							0x1000 => 0xfffffc00,
+							_ => 0
+						} | // imm[31:10] <= [12]
+						((halfword >> 3) & 0x200) | // imm[9] <= [12]
+						((halfword >> 2) & 0x10) | // imm[4] <= [6]
+						((halfword << 1) & 0x40) | // imm[6] <= [5]
+						((halfword << 4) & 0x180) | // imm[8:7] <= [4:3]
+						((halfword << 3) & 0x20); // imm[5] <= [2]
+						if imm != 0 {
+							return (imm << 20) | (r << 15) | (r << 7) | 0x13;
+						}
+						// imm == 0 is for reserved instruction
+					}
+					if r != 0 && r != 2 {
+						// C.LUI
+						// lui r, nzimm
+						let nzimm = match halfword & 0x1000 {
+							0x1000 => 0xfffc0000,
+							_ => 0
+						} | // nzimm[31:18] <= [12]
+						((halfword << 5) & 0x20000) | // nzimm[17] <= [12]
+						((halfword << 10) & 0x1f000); // nzimm[16:12] <= [6:2]
+						if nzimm != 0 {
+							return nzimm | (r << 7) | 0x37;
+						}
+						// nzimm == 0 is for reserved instruction
+					}
+				},
+				4 => {
+					let funct2 = (halfword >> 10) & 0x3; // [11:10]
+					match funct2 {
+						0 => {
+							// C.SRLI
+							// c.srli rs1+8, rs1+8, shamt
+							let shamt = 
+								((halfword >> 7) & 0x20) | // shamt[5] <= [12]
+								((halfword >> 2) & 0x1f); // shamt[4:0] <= [6:2]
+							let rs1 = (halfword >> 7) & 0x7; // [9:7]
+							return (shamt << 20) | ((rs1 + 8) << 15) | (5 << 12) | ((rs1 + 8) << 7) | 0x13;
+						},
+						1 => {
+							// C.SRAI
+							// srai rs1+8, rs1+8, shamt
+							let shamt = 
+								((halfword >> 7) & 0x20) | // shamt[5] <= [12]
+								((halfword >> 2) & 0x1f); // shamt[4:0] <= [6:2]
+							let rs1 = (halfword >> 7) & 0x7; // [9:7]
+							return (0x20 << 25) | (shamt << 20) | ((rs1 + 8) << 15) | (5 << 12) | ((rs1 + 8) << 7) | 0x13;
+						},
+						2 => {
+							// C.ANDI
+							// andi, r+8, r+8, imm
+							let r = (halfword >> 7) & 0x7; // [9:7]
+							let imm = match halfword & 0x1000 {
+								0x1000 => 0xffffffc0,
+								_ => 0
+							} | // imm[31:6] <= [12]
+							((halfword >> 7) & 0x20) | // imm[5] <= [12]
+							((halfword >> 2) & 0x1f); // imm[4:0] <= [6:2]
+							return (imm << 20) | ((r + 8) << 15) | (7 << 12) | ((r + 8) << 7) | 0x13;
+						},
+						3 => {
+							let funct1 = (halfword >> 12) & 1; // [12]
+							let funct2_2 = (halfword >> 5) & 0x3; // [6:5]
+							let rs1 = (halfword >> 7) & 0x7;
+							let rs2 = (halfword >> 2) & 0x7;
+							match funct1 {
+								0 => match funct2_2 {
+									0 => {
+										// C.SUB
+										// sub rs1+8, rs1+8, rs2+8
+										return (0x20 << 25) | ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | ((rs1 + 8) << 7) | 0x33;
+									},
+									1 => {
+										// C.XOR
+										// xor rs1+8, rs1+8, rs2+8
+										return ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (4 << 12) | ((rs1 + 8) << 7) | 0x33;
+									},
+									2 => {
+										// C.OR
+										// or rs1+8, rs1+8, rs2+8
+										return ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (6 << 12) | ((rs1 + 8) << 7) | 0x33;
+									},
+									3 => {
+										// C.AND
+										// and rs1+8, rs1+8, rs2+8
+										return ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | (7 << 12) | ((rs1 + 8) << 7) | 0x33;
+									},
+									_ => {} // Not happens
+								},
+								1 => match funct2_2 {
+									0 => {
+										// C.SUBW
+										// subw r1+8, r1+8, r2+8
+										return (0x20 << 25) | ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | ((rs1 + 8) << 7) | 0x3b;
+									},
+									1 => {
+										// C.ADDW
+										// addw r1+8, r1+8, r2+8
+										return ((rs2 + 8) << 20) | ((rs1 + 8) << 15) | ((rs1 + 8) << 7) | 0x3b;
+									},
+									2 => {
+										// Reserved
+									},
+									3 => {
+										// Reserved
+									},
+									_ => {} // Not happens
+								},
+								_ => {} // No happens
+							};
+						},
+						_ => {} // not happens
+					};
+				},
+				5 => {
+					// C.J
+					// jal x0, imm
+					let offset =
+						match halfword & 0x1000 {
+							0x1000 => 0xfffff000,
+							_ => 0
+						} | // offset[31:12] <= [12]
+						((halfword >> 1) & 0x800) | // offset[11] <= [12]
+						((halfword >> 7) & 0x10) | // offset[4] <= [11]
+						((halfword >> 1) & 0x300) | // offset[9:8] <= [10:9]
+						((halfword << 2) & 0x400) | // offset[10] <= [8]
+						((halfword >> 1) & 0x40) | // offset[6] <= [7]
+						((halfword << 1) & 0x80) | // offset[7] <= [6]
+						((halfword >> 2) & 0xe) | // offset[3:1] <= [5:3]
+						((halfword << 3) & 0x20); // offset[5] <= [2]
+					let imm =
+						((offset >> 1) & 0x80000) | // imm[19] <= offset[20]
+						((offset << 8) & 0x7fe00) | // imm[18:9] <= offset[10:1]
+						((offset >> 3) & 0x100) | // imm[8] <= offset[11]
+						((offset >> 12) & 0xff); // imm[7:0] <= offset[19:12]
+					return (imm << 12) | 0x6f;
+				},
+				6 => {
+					// C.BEQZ
+					// beq r+8, x0, offset
+					let r = (halfword >> 7) & 0x7;
+					let offset =
+						match halfword & 0x1000 {
+							0x1000 => 0xfffffe00,
+							_ => 0
+						} | // offset[31:9] <= [12]
+						((halfword >> 4) & 0x100) | // offset[8] <= [12]
+						((halfword >> 7) & 0x18) | // offset[4:3] <= [11:10]
+						((halfword << 1) & 0xc0) | // offset[7:6] <= [6:5]
+						((halfword >> 2) & 0x6) | // offset[2:1] <= [4:3]
+						((halfword << 3) & 0x20); // offset[5] <= [2]
+					let imm2 =
+						((offset >> 6) & 0x40) | // imm2[6] <= [12]
+						((offset >> 5) & 0x3f); // imm2[5:0] <= [10:5]
+					let imm1 =
+						(offset & 0x1e) | // imm1[4:1] <= [4:1]
+						((offset >> 11) & 0x1); // imm1[0] <= [11]
+					return (imm2 << 25) | ((r + 8) << 20) | (imm1 << 7) | 0x63;
+				},
+				7 => {
+					// C.BNEZ
+					// bne r+8, x0, offset
+					let r = (halfword >> 7) & 0x7;
+					let offset =
+						match halfword & 0x1000 {
+							0x1000 => 0xfffffe00,
+							_ => 0
+						} | // offset[31:9] <= [12]
+						((halfword >> 4) & 0x100) | // offset[8] <= [12]
+						((halfword >> 7) & 0x18) | // offset[4:3] <= [11:10]
+						((halfword << 1) & 0xc0) | // offset[7:6] <= [6:5]
+						((halfword >> 2) & 0x6) | // offset[2:1] <= [4:3]
+						((halfword << 3) & 0x20); // offset[5] <= [2]
+					let imm2 =
+						((offset >> 6) & 0x40) | // imm2[6] <= [12]
+						((offset >> 5) & 0x3f); // imm2[5:0] <= [10:5]
+					let imm1 =
+						(offset & 0x1e) | // imm1[4:1] <= [4:1]
+						((offset >> 11) & 0x1); // imm1[0] <= [11]
+					return (imm2 << 25) | ((r + 8) << 20) | (1 << 12) | (imm1 << 7) | 0x63;
+				},
+				_ => {} // No happens
+			};
+		},
+		2 => {
+			match funct3 {
+				0 => {
+					// C.SLLI
+					// slli r, r, shamt
+					let r = (halfword >> 7) & 0x1f;
+					let shamt =
+						((halfword >> 7) & 0x20) | // imm[5] <= [12]
+						((halfword >> 2) & 0x1f); // imm[4:0] <= [6:2]
+					if r != 0 {
+						return (shamt << 20) | (r << 15) | (1 << 12) | (r << 7) | 0x13;
+					}
+					// r == 0 is reserved instruction?
+				},
+				1 => {
+					// @TODO: Support C.LQSP in 128-bit mode
+					// C.FLDSP in 32, 64-bit mode
+					// fld rd, offset(x2)
+					let rd = (halfword >> 7) & 0x1f;
+					let offset =
+						((halfword >> 7) & 0x20) | // offset[5] <= [12]
+						((halfword >> 2) & 0x18) | // offset[4:3] <= [6:5]
+						((halfword << 4) & 0x1c0); // offset[8:6] <= [4:2]
+					return (offset << 20) | (2 << 15) | (3 << 12) | (rd << 7) | 0x7;
+				},
+				2 => {
+					// C.LWSP
+					// lw r, offset(x2)
+					let r = (halfword >> 7) & 0x1f;
+					let offset =
+						((halfword >> 7) & 0x20) | // offset[5] <= [12]
+						((halfword >> 2) & 0x1c) | // offset[4:2] <= [6:4]
+						((halfword << 4) & 0xc0); // offset[7:6] <= [3:2]
+					if r != 0 {
+						return (offset << 20) | (2 << 15) | (2 << 12) | (r << 7) | 0x3;
+					}
+					// r == 0 is reseved instruction
+				},
+				3 => match xlen {
+					Xlen::Bit32 => {
+						// C.FLWSP in 32-bit mode
+						// flw rd, offset(x2)
+						let rd = (halfword >> 7) & 0x1f;
 						let offset =
 							((halfword >> 7) & 0x20) | // offset[5] <= [12]
 							((halfword >> 2) & 0x1c) | // offset[4:2] <= [6:4]
 							((halfword << 4) & 0xc0); // offset[7:6] <= [3:2]
-						if r != 0 {
-							return (offset << 20) | (2 << 15) | (2 << 12) | (r << 7) | 0x3;
-						}
-						// r == 0 is reseved instruction
+						return (offset << 20) | (2 << 15) | (2 << 12) | (rd << 7) | 0x7;
 					},
-					3 => {
-						// @TODO: Support C.FLWSP in 32-bit mode
-						// C.LDSP
+					Xlen::Bit64 => {
+						// C.LDSP in 64-bit mode
 						// ld rd, offset(x2)
 						let rd = (halfword >> 7) & 0x1f;
 						let offset =
@@ -1157,67 +2000,87 @@ impl Cpu {
 							return (offset << 20) | (2 << 15) | (3 << 12) | (rd << 7) | 0x3;
 						}
 						// rd == 0 is reseved instruction
-					},
-					4 => {
-						let funct1 = (halfword >> 12) & 1; // [12]
-						let rs1 = (halfword >> 7) & 0x1f; // [11:7]
-						let rs2 = (halfword >> 2) & 0x1f; // [6:2]
-						match funct1 {
-							0 => {
-								if rs1 != 0 && rs2 == 0 {
-									// C.JR
-									// jalr x0, 0(rs1)
-									return (rs1 << 15) | 0x67;
-								}
-								// rs1 == 0 is reserved instruction
-								if rs1 != 0 && rs2 != 0 {
-									// C.MV
-									// add rs1, x0, rs2
-									return (rs2 << 20) | (rs1 << 7) | 0x33;
-								}
-								// rs1 == 0 && rs2 != 0 is Hints
-								// @TODO: Support Hints
-							},
-							1 => {
-								if rs1 == 0 && rs2 == 0 {
-									// C.EBREAK
-									panic!("C.EBREAK is not supported yet.");
-								}
-								if rs1 != 0 && rs2 == 0 {
-									// C.JALR
-									// jalr x1, 0(rs1)
-									return (rs1 << 15) | (1 << 7) | 0x67;
-								}
-								if rs1 != 0 && rs2 != 0 {
-									// C.ADD
-									// add rs1, rs1, rs2
-									return (rs2 << 20) | (rs1 << 15) | (rs1 << 7) | 0x33;
-								}
-								// rs1 == 0 && rs2 != 0 is Hists
-								// @TODO: Supports Hinsts
-							},
-							_ => {} // Not happens
-						};
-					},
-					5 => {
-						// @TODO: Implement
-						// C.FSDSP
-						panic!("C.FSDSP is not implemented yet.");
-					},
-					6 => {
-						// C.SWSP
-						// sw rs2, offset(x2)
-						let rs2 = (halfword >> 2) & 0x1f; // [6:2]
+					}
+				},
+				4 => {
+					let funct1 = (halfword >> 12) & 1; // [12]
+					let rs1 = (halfword >> 7) & 0x1f; // [11:7]
+					let rs2 = (halfword >> 2) & 0x1f; // [6:2]
+					match funct1 {
+						0 => {
+							if rs1 != 0 && rs2 == 0 {
+								// C.JR
+								// jalr x0, 0(rs1)
+								return (rs1 << 15) | 0x67;
+							}
+							// rs1 == 0 is reserved instruction
+							if rs1 != 0 && rs2 != 0 {
+								// C.MV
+								// add rs1, x0, rs2
+								return (rs2 << 20) | (rs1 << 7) | 0x33;
+							}
+							// rs1 == 0 && rs2 != 0 is Hints
+							// @TODO: Support Hints
+						},
+						1 => {
+							if rs1 == 0 && rs2 == 0 {
+								// C.EBREAK
+								// ebreak
+								return 0x00100073;
+							}
+							if rs1 != 0 && rs2 == 0 {
+								// C.JALR
+								// jalr x1, 0(rs1)
+								return (rs1 << 15) | (1 << 7) | 0x67;
+							}
+							if rs1 != 0 && rs2 != 0 {
+								// C.ADD
+								// add rs1, rs1, rs2
+								return (rs2 << 20) | (rs1 << 15) | (rs1 << 7) | 0x33;
+							}
+							// rs1 == 0 && rs2 != 0 is Hists
+							// @TODO: Supports Hinsts
+						},
+						_ => {} // Not happens
+					};
+				},
+				5 => {
+					// @TODO: Support C.SQSP in 128-bit mode
+					// C.FSDSP in 32, 64-bit mode
+					// fsd rs2, offset(x2)
+					let rs2 = (halfword >> 2) & 0x1f; // [6:2]
+					let offset =
+						((halfword >> 7) & 0x38) | // offset[5:3] <= [12:10]
+						((halfword >> 1) & 0x1c0); // offset[8:6] <= [9:7]
+					let imm11_5 = (offset >> 5) & 0x3f;
+					let imm4_0 = offset & 0x1f;
+					return (imm11_5 << 25) | (rs2 << 20) | (2 << 15) | (3 << 12) | (imm4_0 << 7) | 0x27;
+				},
+				6 => {
+					// C.SWSP
+					// sw rs2, offset(x2)
+					let rs2 = (halfword >> 2) & 0x1f; // [6:2]
+					let offset =
+						((halfword >> 7) & 0x3c) | // offset[5:2] <= [12:9]
+						((halfword >> 1) & 0xc0); // offset[7:6] <= [8:7]
+					let imm11_5 = (offset >> 5) & 0x3f;
+					let imm4_0 = offset & 0x1f;
+					return (imm11_5 << 25) | (rs2 << 20) | (2 << 15) | (2 << 12) | (imm4_0 << 7) | 0x23;
+				},
+				7 => match xlen {
+					Xlen::Bit32 => {
+						// C.FSWSP in 32-bit mode
+						// fsw rs2, offset(x2)
+						let rs2 = (halfword >> 2) & 0x1f; // [6:2]
 						let offset =
 							((halfword >> 7) & 0x3c) | // offset[5:2] <= [12:9]
 							((halfword >> 1) & 0xc0); // offset[7:6] <= [8:7]
 						let imm11_5 = (offset >> 5) & 0x3f;
 						let imm4_0 = offset & 0x1f;
-						return (imm11_5 << 25) | (rs2 << 20) | (2 << 15) | (2 << 12) | (imm4_0 << 7) | 0x23;
+						return (imm11_5 << 25) | (rs2 << 20) | (2 << 15) | (2 << 12) | (imm4_0 << 7) | 0x27;
 					},
-					7 => {
-						// @TODO: Support C.FSWSP in 32-bit mode
-						// C.SDSP
+					Xlen::Bit64 => {
+						// C.SDSP in 64-bit mode
 						// sd rs, offset(x2)
 						let rs2 = (halfword >> 2) & 0x1f; // [6:2]
 						let offset =
@@ -1226,344 +2089,2489 @@ impl Cpu {
 						let imm11_5 = (offset >> 5) & 0x3f;
 						let imm4_0 = offset & 0x1f;
 						return (imm11_5 << 25) | (rs2 << 20) | (2 << 15) | (3 << 12) | (imm4_0 << 7) | 0x23;
-					},
-					_ => {} // Not happens
-				};
-			},
-			_ => {} // No happnes
-		};
-		0xffffffff // Return invalid value
-	}
+					}
+				},
+				_ => {} // Not happens
+			};
+		},
+		_ => {} // No happnes
+	};
+	0xffffffff // Return invalid value
+}
 
-	// @TODO: Optimize
-	fn decode(&mut self, word: u32) -> Result<Instruction, ()> {
-		let opcode = word & 0x7f; // [6:0]
-		let funct3 = (word >> 12) & 0x7; // [14:12]
-		let funct7 = (word >> 25) & 0x7f; // [31:25]
-
-		let instruction = match opcode {
-			0x03 => match funct3 {
-				0 => Instruction::LB,
-				1 => Instruction::LH,
-				2 => Instruction::LW,
-				3 => Instruction::LD,
-				4 => Instruction::LBU,
-				5 => Instruction::LHU,
-				6 => Instruction::LWU,
+// @TODO: Optimize
+pub fn decode(word: u32, xlen: &Xlen, zba_enabled: bool, zbb_enabled: bool) -> Result<Instruction, ()> {
+	let opcode = word & 0x7f; // [6:0]
+	let funct3 = (word >> 12) & 0x7; // [14:12]
+	let funct7 = (word >> 25) & 0x7f; // [31:25]
+	let rs2 = (word >> 20) & 0x1f; // [24:20], function selector of Zbb unary operations
+	// shamt[5] of RORI and funct12 of REV8 depend on xlen
+	let (rori_funct7_max, rev8_funct12) = match xlen {
+		Xlen::Bit32 => (0x30, 0x698),
+		Xlen::Bit64 => (0x31, 0x6b8)
+	};
+
+	let instruction = match opcode {
+		0x03 => match funct3 {
+			0 => Instruction::LB,
+			1 => Instruction::LH,
+			2 => Instruction::LW,
+			3 => Instruction::LD,
+			4 => Instruction::LBU,
+			5 => Instruction::LHU,
+			6 => Instruction::LWU,
+			_ => return Err(())
+		},
+		0x07 => match funct3 {
+			2 => Instruction::FLW,
+			3 => Instruction::FLD,
+			_ => return Err(())
+		},
+		0x0f => match funct3 {
+			// rd and rs1 of FENCE are reserved and ignored. FENCE.TSO is
+			// fm=8 with pred=RW and succ=RW, and other fm=8 encodings are a
+			// normal FENCE.
+			0 => match (word >> 20) & 0xfff {
+				0x833 => Instruction::FENCETSO,
+				_ => Instruction::FENCE
+			},
+			1 => Instruction::FENCEI,
+			// Zicbom and Zicboz. funct12[31:20] selects the operation and rd must be zero.
+			2 => match ((word >> 20), (word >> 7) & 0x1f) {
+				(0, 0) => Instruction::CBOINVAL,
+				(1, 0) => Instruction::CBOCLEAN,
+				(2, 0) => Instruction::CBOFLUSH,
+				(4, 0) => Instruction::CBOZERO,
 				_ => return Err(())
 			},
-			0x0f => Instruction::FENCE,
-			0x13 => match funct3 {
-				0 => Instruction::ADDI,
-				1 => Instruction::SLLI,
-				2 => Instruction::SLTI,
-				3 => Instruction::SLTIU,
-				4 => Instruction::XORI,
-				5 => match funct7 {
-					0 => Instruction::SRLI,
-					1 => Instruction::SRLI, // temporal workaround for xv6
-					0x20 => Instruction::SRAI,
+			_ => return Err(())
+		},
+		0x13 => match funct3 {
+			0 => Instruction::ADDI,
+			1 => match funct7 {
+				0x30 if zbb_enabled => match rs2 {
+					0 => Instruction::CLZ,
+					1 => Instruction::CTZ,
+					2 => Instruction::CPOP,
+					4 => Instruction::SEXTB,
+					5 => Instruction::SEXTH,
 					_ => return Err(())
-				}
-				6 => Instruction::ORI,
-				7 => Instruction::ANDI,
-				_ => return Err(())
+				},
+				_ => Instruction::SLLI
 			},
-			0x17 => Instruction::AUIPC,
-			0x1b => match funct3 {
-				0 => Instruction::ADDIW,
-				1 => Instruction::SLLIW,
-				5 => match funct7 {
-					0 => Instruction::SRLIW,
-					0x20 => Instruction::SRAIW,
+			2 => Instruction::SLTI,
+			3 => Instruction::SLTIU,
+			4 => Instruction::XORI,
+			5 => match funct7 {
+				0 => Instruction::SRLI,
+				1 => Instruction::SRLI, // temporal workaround for xv6
+				0x20 => Instruction::SRAI,
+				0x14 if zbb_enabled && (word >> 20) == 0x287 => Instruction::ORCB,
+				// funct6 = 0x18, funct7[0] = shamt[5] which must be zero in 32-bit mode
+				0x30 | 0x31 if zbb_enabled && funct7 <= rori_funct7_max => Instruction::RORI,
+				0x34 | 0x35 if zbb_enabled && (word >> 20) == rev8_funct12 => Instruction::REV8,
+				_ => return Err(())
+			}
+			6 => Instruction::ORI,
+			7 => Instruction::ANDI,
+			_ => return Err(())
+		},
+		0x17 => Instruction::AUIPC,
+		0x1b => match funct3 {
+			0 => Instruction::ADDIW,
+			1 => match funct7 {
+				0 => Instruction::SLLIW,
+				4 | 5 if zba_enabled => Instruction::SLLIUW, // funct6 = 2, funct7[0] = shamt[5]
+				0x30 if zbb_enabled => match rs2 {
+					0 => Instruction::CLZW,
+					1 => Instruction::CTZW,
+					2 => Instruction::CPOPW,
 					_ => return Err(())
 				},
 				_ => return Err(())
 			},
-			0x23 => match funct3 {
-				0 => Instruction::SB,
-				1 => Instruction::SH,
-				2 => Instruction::SW,
-				3 => Instruction::SD,
+			5 => match funct7 {
+				0 => Instruction::SRLIW,
+				0x20 => Instruction::SRAIW,
+				0x30 if zbb_enabled => Instruction::RORIW,
 				_ => return Err(())
 			},
-			0x2f => match funct3 {
-				2 => {
-					match funct7 >> 2 {
-						0 => Instruction::AMOADDW,
-						1 => Instruction::AMOSWAPW,
+			_ => return Err(())
+		},
+		0x23 => match funct3 {
+			0 => Instruction::SB,
+			1 => Instruction::SH,
+			2 => Instruction::SW,
+			3 => Instruction::SD,
+			_ => return Err(())
+		},
+		0x27 => match funct3 {
+			2 => Instruction::FSW,
+			3 => Instruction::FSD,
+			_ => return Err(())
+		},
+		0x2f => match funct3 {
+			2 => {
+				match funct7 >> 2 {
+					0 => Instruction::AMOADDW,
+					1 => Instruction::AMOSWAPW,
+					// LR has no rs2. Nonzero rs2 is reserved.
+					2 => match rs2 {
+						0 => Instruction::LRW,
 						_ => return Err(())
-					}
-				},
+					},
+					3 => Instruction::SCW,
+					4 => Instruction::AMOXORW,
+					8 => Instruction::AMOORW,
+					0xc => Instruction::AMOANDW,
+					0x10 => Instruction::AMOMINW,
+					0x14 => Instruction::AMOMAXW,
+					0x18 => Instruction::AMOMINUW,
+					0x1c => Instruction::AMOMAXUW,
+					_ => return Err(())
+				}
+			},
+			3 => {
+				match funct7 >> 2 {
+					0 => Instruction::AMOADDD,
+					1 => Instruction::AMOSWAPD,
+					// LR has no rs2. Nonzero rs2 is reserved.
+					2 => match rs2 {
+						0 => Instruction::LRD,
+						_ => return Err(())
+					},
+					3 => Instruction::SCD,
+					4 => Instruction::AMOXORD,
+					8 => Instruction::AMOORD,
+					0xc => Instruction::AMOANDD,
+					0x10 => Instruction::AMOMIND,
+					0x14 => Instruction::AMOMAXD,
+					0x18 => Instruction::AMOMINUD,
+					0x1c => Instruction::AMOMAXUD,
+					_ => return Err(())
+				}
+			},
+			_ => return Err(())
+		}
+		0x33 => match funct3 {
+			0 => match funct7 {
+				0 => Instruction::ADD,
+				1 => Instruction::MUL,
+				0x20 => Instruction::SUB,
 				_ => return Err(())
-			}
-			0x33 => match funct3 {
-				0 => match funct7 {
-					0 => Instruction::ADD,
-					1 => Instruction::MUL,
-					0x20 => Instruction::SUB,
+			},
+			1 => match funct7 {
+				0 => Instruction::SLL,
+				1 => Instruction::MULH,
+				0x30 if zbb_enabled => Instruction::ROL,
+				_ => return Err(())
+			},
+			2 => match funct7 {
+				0 => Instruction::SLT,
+				1 => Instruction::MULHSU,
+				0x10 if zba_enabled => Instruction::SH1ADD,
+				_ => return Err(())
+			},
+			3 => match funct7 {
+				0 => Instruction::SLTU,
+				1 => Instruction::MULHU,
+				_ => return Err(())
+			},
+			4 => match funct7 {
+				0 => Instruction::XOR,
+				1 => Instruction::DIV,
+				4 if zbb_enabled && rs2 == 0 => Instruction::ZEXTH, // 32-bit mode encoding
+				5 if zbb_enabled => Instruction::MIN,
+				0x10 if zba_enabled => Instruction::SH2ADD,
+				0x20 if zbb_enabled => Instruction::XNOR,
+				_ => return Err(())
+			},
+			5 => match funct7 {
+				0 => Instruction::SRL,
+				1 => Instruction::DIVU,
+				5 if zbb_enabled => Instruction::MINU,
+				7 => Instruction::CZEROEQZ,
+				0x20 => Instruction::SRA,
+				0x30 if zbb_enabled => Instruction::ROR,
+				_ => return Err(())
+			},
+			6 => match funct7 {
+				0 => Instruction::OR,
+				1 => Instruction::REM,
+				5 if zbb_enabled => Instruction::MAX,
+				0x10 if zba_enabled => Instruction::SH3ADD,
+				0x20 if zbb_enabled => Instruction::ORN,
+				_ => return Err(())
+			},
+			7 => match funct7 {
+				0 => Instruction::AND,
+				1 => Instruction::REMU,
+				5 if zbb_enabled => Instruction::MAXU,
+				7 => Instruction::CZERONEZ,
+				0x20 if zbb_enabled => Instruction::ANDN,
+				_ => return Err(())
+			},
+			_ => return Err(())
+		},
+		0x37 => Instruction::LUI,
+		0x3b => match funct3 {
+			0 => match funct7 {
+				0 => Instruction::ADDW,
+				1 => Instruction::MULW,
+				4 if zba_enabled => Instruction::ADDUW,
+				0x20 => Instruction::SUBW,
+				_ => return Err(())
+			},
+			1 => match funct7 {
+				0x30 if zbb_enabled => Instruction::ROLW,
+				_ => Instruction::SLLW
+			},
+			2 => match funct7 {
+				0x10 if zba_enabled => Instruction::SH1ADDUW,
+				_ => return Err(())
+			},
+			4 => match funct7 {
+				1 => Instruction::DIVW,
+				4 if zbb_enabled && rs2 == 0 => Instruction::ZEXTH, // 64-bit mode encoding
+				0x10 if zba_enabled => Instruction::SH2ADDUW,
+				_ => return Err(())
+			},
+			5 => match funct7 {
+				0 => Instruction::SRLW,
+				1 => Instruction::DIVUW,
+				0x20 => Instruction::SRAW,
+				0x30 if zbb_enabled => Instruction::RORW,
+				_ => return Err(())
+			},
+			6 => match funct7 {
+				1 => Instruction::REMW,
+				0x10 if zba_enabled => Instruction::SH3ADDUW,
+				_ => return Err(())
+			},
+			7 => Instruction::REMUW,
+			_ => return Err(())
+		},
+		// fmt[26:25] is 0 for single-precision and 1 for double-precision
+		0x43 => match funct7 & 0x3 {
+			0 => Instruction::FMADDS,
+			1 => Instruction::FMADDD,
+			_ => return Err(())
+		},
+		0x47 => match funct7 & 0x3 {
+			0 => Instruction::FMSUBS,
+			1 => Instruction::FMSUBD,
+			_ => return Err(())
+		},
+		0x4b => match funct7 & 0x3 {
+			0 => Instruction::FNMSUBS,
+			1 => Instruction::FNMSUBD,
+			_ => return Err(())
+		},
+		0x4f => match funct7 & 0x3 {
+			0 => Instruction::FNMADDS,
+			1 => Instruction::FNMADDD,
+			_ => return Err(())
+		},
+		0x53 => {
+			let rs2 = (word >> 20) & 0x1f; // [24:20]
+			match funct7 {
+				0x00 => Instruction::FADDS,
+				0x01 => Instruction::FADDD,
+				0x04 => Instruction::FSUBS,
+				0x05 => Instruction::FSUBD,
+				0x08 => Instruction::FMULS,
+				0x09 => Instruction::FMULD,
+				0x0c => Instruction::FDIVS,
+				0x0d => Instruction::FDIVD,
+				0x10 => match funct3 {
+					0 => Instruction::FSGNJS,
+					1 => Instruction::FSGNJNS,
+					2 => Instruction::FSGNJXS,
 					_ => return Err(())
 				},
-				1 => match funct7 {
-					0 => Instruction::SLL,
-					1 => Instruction::MULH,
+				0x11 => match funct3 {
+					0 => Instruction::FSGNJD,
+					1 => Instruction::FSGNJND,
+					2 => Instruction::FSGNJXD,
 					_ => return Err(())
 				},
-				2 => match funct7 {
-					0 => Instruction::SLT,
-					1 => Instruction::MULHSU,
+				0x14 => match funct3 {
+					0 => Instruction::FMINS,
+					1 => Instruction::FMAXS,
 					_ => return Err(())
 				},
-				3 => match funct7 {
-					0 => Instruction::SLTU,
-					1 => Instruction::MULHU,
+				0x15 => match funct3 {
+					0 => Instruction::FMIND,
+					1 => Instruction::FMAXD,
 					_ => return Err(())
 				},
-				4 => match funct7 {
-					0 => Instruction::XOR,
-					1 => Instruction::DIV,
+				0x20 => match rs2 {
+					1 => Instruction::FCVTSD,
 					_ => return Err(())
 				},
-				5 => match funct7 {
-					0 => Instruction::SRL,
-					1 => Instruction::DIVU,
-					0x20 => Instruction::SRA,
+				0x21 => match rs2 {
+					0 => Instruction::FCVTDS,
 					_ => return Err(())
 				},
-				6 => match funct7 {
-					0 => Instruction::OR,
-					1 => Instruction::REM,
+				0x2c => match rs2 {
+					0 => Instruction::FSQRTS,
 					_ => return Err(())
 				},
-				7 => match funct7 {
-					0 => Instruction::AND,
-					1 => Instruction::REMU,
+				0x2d => match rs2 {
+					0 => Instruction::FSQRTD,
 					_ => return Err(())
 				},
-				_ => return Err(())
-			},
-			0x37 => Instruction::LUI,
-			0x3b => match funct3 {
-				0 => match funct7 {
-					0 => Instruction::ADDW,
-					1 => Instruction::MULW,
-					0x20 => Instruction::SUBW,
+				0x50 => match funct3 {
+					0 => Instruction::FLES,
+					1 => Instruction::FLTS,
+					2 => Instruction::FEQS,
 					_ => return Err(())
 				},
-				1 => Instruction::SLLW,
-				4 => Instruction::DIVW,
-				5 => match funct7 {
-					0 => Instruction::SRLW,
-					1 => Instruction::DIVUW,
-					0x20 => Instruction::SRAW,
+				0x51 => match funct3 {
+					0 => Instruction::FLED,
+					1 => Instruction::FLTD,
+					2 => Instruction::FEQD,
 					_ => return Err(())
 				},
-				6 => Instruction::REMW,
-				7 => Instruction::REMUW,
-				_ => return Err(())
-			},
-			0x63 => match funct3 {
-				0 => Instruction::BEQ,
-				1 => Instruction::BNE,
-				4 => Instruction::BLT,
-				5 => Instruction::BGE,
-				6 => Instruction::BLTU,
-				7 => Instruction::BGEU,
+				0x60 => match rs2 {
+					0 => Instruction::FCVTWS,
+					1 => Instruction::FCVTWUS,
+					2 => Instruction::FCVTLS,
+					3 => Instruction::FCVTLUS,
+					_ => return Err(())
+				},
+				0x61 => match rs2 {
+					0 => Instruction::FCVTWD,
+					1 => Instruction::FCVTWUD,
+					2 => Instruction::FCVTLD,
+					3 => Instruction::FCVTLUD,
+					_ => return Err(())
+				},
+				0x68 => match rs2 {
+					0 => Instruction::FCVTSW,
+					1 => Instruction::FCVTSWU,
+					2 => Instruction::FCVTSL,
+					3 => Instruction::FCVTSLU,
+					_ => return Err(())
+				},
+				0x69 => match rs2 {
+					0 => Instruction::FCVTDW,
+					1 => Instruction::FCVTDWU,
+					2 => Instruction::FCVTDL,
+					3 => Instruction::FCVTDLU,
+					_ => return Err(())
+				},
+				0x70 => match funct3 {
+					0 => Instruction::FMVXW,
+					1 => Instruction::FCLASSS,
+					_ => return Err(())
+				},
+				0x71 => match funct3 {
+					0 => Instruction::FMVXD,
+					1 => Instruction::FCLASSD,
+					_ => return Err(())
+				},
+				0x78 => Instruction::FMVWX,
+				0x79 => Instruction::FMVDX,
 				_ => return Err(())
-			},
-			0x67 => Instruction::JALR,
-			0x6f => Instruction::JAL,
-			0x73 => match funct3 {
-				0 => {
-					match funct7 {
-						9 => Instruction::SFENCEVMA,
-						_ => match word {
-							0x00000073 => Instruction::ECALL,
-							0x00200073 => Instruction::URET,
-							0x10200073 => Instruction::SRET,
-							0x30200073 => Instruction::MRET,
-							_ => return Err(())
-						}
+			}
+		},
+		0x63 => match funct3 {
+			0 => Instruction::BEQ,
+			1 => Instruction::BNE,
+			4 => Instruction::BLT,
+			5 => Instruction::BGE,
+			6 => Instruction::BLTU,
+			7 => Instruction::BGEU,
+			_ => return Err(())
+		},
+		0x67 => Instruction::JALR,
+		0x6f => Instruction::JAL,
+		0x73 => match funct3 {
+			0 => {
+				match funct7 {
+					9 => Instruction::SFENCEVMA,
+					_ => match word {
+						0x00000073 => Instruction::ECALL,
+						0x00100073 => Instruction::EBREAK,
+						0x00200073 => Instruction::URET,
+						0x10200073 => Instruction::SRET,
+						0x30200073 => Instruction::MRET,
+						0x10500073 => Instruction::WFI,
+						_ => return Err(())
 					}
 				}
-				1 => Instruction::CSRRW,
-				2 => Instruction::CSRRS,
-				3 => Instruction::CSRRC,
-				5 => Instruction::CSRRWI,
-				6 => Instruction::CSRRSI,
-				7 => Instruction::CSRRCI,
-				_ => return Err(())
-			},
+			}
+			1 => Instruction::CSRRW,
+			2 => Instruction::CSRRS,
+			3 => Instruction::CSRRC,
+			5 => Instruction::CSRRWI,
+			6 => Instruction::CSRRSI,
+			7 => Instruction::CSRRCI,
 			_ => return Err(())
+		},
+		_ => return Err(())
+	};
+	Ok(instruction)
+}
+
+impl Cpu {
+	// mtime follows host monotonic clock in real time mode
+	pub fn new(terminal: Box<dyn Terminal>, timer_mode: TimerMode) -> Self {
+		Cpu::new_with_clock_source(terminal, timer_mode, Box::new(MonotonicClock::new()))
+	}
+
+	// mtime follows clock_source in real time mode, e.g. ManualClock
+	// for tests to control time
+	pub fn new_with_clock_source(terminal: Box<dyn Terminal>, timer_mode: TimerMode,
+		clock_source: Box<dyn ClockSource>) -> Self {
+		let mut cpu = Cpu {
+			clock: 0,
+			instret: 0,
+			xlen: Xlen::Bit64,
+			privilege_mode: PrivilegeMode::Machine,
+			x: [0; 32],
+			f: [0; 32],
+			pc: 0,
+			reset_vector: 0,
+			instruction_address: 0,
+			instruction_word: 0,
+			csr: vec![0; CSR_CAPACITY],
+			wfi: false,
+			mmu: Mmu::new(Xlen::Bit64, terminal, timer_mode, clock_source),
+			hart_id: 0,
+			harts: vec![],
+			zba_enabled: true,
+			zbb_enabled: true,
+			cache_block_size: DEFAULT_CACHE_BLOCK_SIZE,
+			sbi_enabled: false,
+			unimplemented_policy: UnimplementedPolicy::Trap,
+			decode_cache: DecodeCache::new(),
+			trace_hook: None,
+			written_csr: None,
+			breakpoints: vec![],
+			watchpoint_hit: None,
+			stall_ticks: DEFAULT_STALL_TICKS
 		};
-		Ok(instruction)
+		cpu.reset_csrs();
+		cpu
 	}
 
-	fn operate(&mut self, word: u32, instruction: Instruction, instruction_address: u64) -> Result<(), Trap> {
-		let instruction_format = get_instruction_format(&instruction);
-		match instruction_format {
-			InstructionFormat::B => {
-				let rs1 = (word & 0x000f8000) >> 15; // [19:15]
-				let rs2 = (word & 0x01f00000) >> 20; // [24:20]
-				let imm = (
-					match word & 0x80000000 { // imm[31:12] = [31]
-						0x80000000 => 0xfffff800,
-						_ => 0
-					} |
-					((word & 0x00000080) << 4) | // imm[11] = [7]
-					((word & 0x7e000000) >> 20) | // imm[10:5] = [30:25]
-					((word & 0x00000f00) >> 7) // imm[4:1] = [11:8]
-				) as i32 as i64 as u64;
-				// println!("Compare {:X} {:X}", self.x[rs1 as usize], self.x[rs2 as usize]);
-				match instruction {
-					Instruction::BEQ => {
-						if self.sign_extend(self.x[rs1 as usize]) == self.sign_extend(self.x[rs2 as usize]) {
-							self.pc = instruction_address.wrapping_add(imm);
-						}
-					},
-					Instruction::BGE => {
-						if self.sign_extend(self.x[rs1 as usize]) >= self.sign_extend(self.x[rs2 as usize]) {
-							self.pc = instruction_address.wrapping_add(imm);
-						}
-					},
-					Instruction::BGEU => {
-						if self.unsigned_data(self.x[rs1 as usize]) >= self.unsigned_data(self.x[rs2 as usize]) {
-							self.pc = instruction_address.wrapping_add(imm);
-						}
-					},
-					Instruction::BLT => {
-						if self.sign_extend(self.x[rs1 as usize]) < self.sign_extend(self.x[rs2 as usize]) {
-							self.pc = instruction_address.wrapping_add(imm);
-						}
-					},
-					Instruction::BLTU => {
-						if self.unsigned_data(self.x[rs1 as usize]) < self.unsigned_data(self.x[rs2 as usize]) {
-							self.pc = instruction_address.wrapping_add(imm);
-						}
-					},
-					Instruction::BNE => {
-						if self.sign_extend(self.x[rs1 as usize]) != self.sign_extend(self.x[rs2 as usize]) {
-							self.pc = instruction_address.wrapping_add(imm);
-						}
-					},
-					_ => {
-						println!("{}", get_instruction_name(&instruction).to_owned() + " instruction is not supported yet.");
-						self.dump_instruction(instruction_address);
-						panic!();
-					}
-				};
-			},
-			InstructionFormat::C => {
-				let csr = ((word >> 20) & 0xfff) as u16; // [31:20];
-				let rs = (word >> 15) & 0x1f; // [19:15];
-				let rd = (word >> 7) & 0x1f; // [11:7];
-				// @TODO: Don't write if csr bits aren't writable
-				match instruction {
-					Instruction::CSRRC => {
-						let data = match self.read_csr(csr) {
-							Ok(data) => data,
-							Err(e) => return Err(e)
-						};
-						let tmp = self.x[rs as usize];
-						self.x[rd as usize] = self.sign_extend(data as i64);
-						self.x[0] = 0; // hard-wired zero
-						match self.write_csr(csr, (self.x[rd as usize] & !tmp) as u64) {
-							Ok(()) => {},
-							Err(e) => return Err(e)
-						};
-					},
-					Instruction::CSRRCI => {
-						let data = match self.read_csr(csr) {
-							Ok(data) => data,
-							Err(e) => return Err(e)
-						};
-						self.x[rd as usize] = self.sign_extend(data as i64);
-						self.x[0] = 0; // hard-wired zero
-						match self.write_csr(csr, (self.x[rd as usize] as u64) & !(rs as u64)) {
-							Ok(()) => {},
-							Err(e) => return Err(e)
-						};
-					},
-					Instruction::CSRRS => {
-						let data = match self.read_csr(csr) {
-							Ok(data) => data,
-							Err(e) => return Err(e)
-						};
-						let tmp = self.x[rs as usize];
-						self.x[rd as usize] = self.sign_extend(data as i64);
-						self.x[0] = 0; // hard-wired zero
-						match self.write_csr(csr, self.unsigned_data(self.x[rd as usize] | tmp)) {
-							Ok(()) => {},
-							Err(e) => return Err(e)
-						};
-					},
-					Instruction::CSRRSI => {
-						let data = match self.read_csr(csr) {
-							Ok(data) => data,
-							Err(e) => return Err(e)
-						};
-						self.x[rd as usize] = self.sign_extend(data as i64);
-						self.x[0] = 0; // hard-wired zero
-						match self.write_csr(csr, self.unsigned_data((self.x[rd as usize] as u64 | rs as u64) as i64)) {
-							Ok(()) => {},
-							Err(e) => return Err(e)
-						};
-					},
-					Instruction::CSRRW => {
-						let data = match self.read_csr(csr) {
-							Ok(data) => data,
-							Err(e) => return Err(e)
-						};
-						let tmp = self.x[rs as usize];
-						self.x[rd as usize] = self.sign_extend(data as i64);
-						self.x[0] = 0; // hard-wired zero
-						match self.write_csr(csr, self.unsigned_data(tmp)) {
-							Ok(()) => {},
-							Err(e) => return Err(e)
-						};
-					},
-					Instruction::CSRRWI => {
-						let data = match self.read_csr(csr) {
-							Ok(data) => data,
-							Err(e) => return Err(e)
-						};
-						self.x[rd as usize] = self.sign_extend(data as i64);
-						self.x[0] = 0; // hard-wired zero
-						match self.write_csr(csr, rs as u64) {
-							Ok(()) => {},
-							Err(e) => return Err(e)
-						};
-					},
-					_ => {
-						println!("{}", get_instruction_name(&instruction).to_owned() + " instruction is not supported yet.");
-						self.dump_instruction(instruction_address);
-						panic!();
-					}
-				};
-			},
-			InstructionFormat::I => {
-				let rd = (word >> 7) & 0x1f; // [11:7]
-				let rs1 = (word >> 15) & 0x1f; // [19:15]
-				let imm = (
-					match word & 0x80000000 { // imm[31:11] = [31]
-						0x80000000 => 0xfffff800,
-						_ => 0
-					} |
-					((word >> 20) & 0x000007ff) // imm[10:0] = [30:20]
-				) as i32 as i64;
-				match instruction {
-					Instruction::ADDI => {
-						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize].wrapping_add(imm));
-					},
-					Instruction::ADDIW => {
-						self.x[rd as usize] = self.x[rs1 as usize].wrapping_add(imm) as i32 as i64;
-					},
-					Instruction::ANDI => {
-						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] & imm);
-					},
-					Instruction::JALR => {
-						let tmp = self.sign_extend(self.pc as i64);
-						self.pc = (self.x[rs1 as usize] as u64).wrapping_add(imm as u64);
+	// Public methods for setting up from outside. CpuBuilder calls them
+	// in the expected order.
+
+	// Returns Err if no memory or device is mapped to the address
+	pub fn store_raw(&mut self, address: u64, value: u8) -> Result<(), ()> {
+		self.mmu.store_raw(address, value)
+	}
+
+	// Writes data to the physical address range at once if it is in
+	// the main memory. Returns Err if any byte is unmapped.
+	pub fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<(), ()> {
+		self.mmu.write_memory(address, data)
+	}
+
+	// pc is always kept masked to XLEN width so that the stored pc
+	// and the fetched address agree in 32-bit mode
+	pub fn update_pc(&mut self, value: u64) {
+		self.pc = self.unsigned_data(value as i64);
+	}
+
+	// pc which reset() sets. 0 by default. Doesn't change the current pc.
+	pub fn update_reset_vector(&mut self, address: u64) {
+		self.reset_vector = address;
+	}
+
+	pub fn get_reset_vector(&self) -> u64 {
+		self.reset_vector
+	}
+
+	pub fn update_xlen(&mut self, xlen: Xlen) {
+		self.xlen = xlen.clone();
+		self.mmu.update_xlen(xlen.clone());
+		// Compressed instructions expand differently
+		self.decode_cache.clear();
+		let pc = self.pc;
+		self.update_pc(pc);
+	}
+
+	pub fn setup_memory(&mut self, capacity: u64) {
+		self.mmu.init_memory(capacity);
+	}
+
+	// Main memory size in bytes given by setup_memory(), for host to
+	// describe the memory to the guest, e.g. in a device tree. Physical
+	// addresses from dram_base to dram_base + size - 1 are the memory and
+	// accesses beyond it without any device raise access faults.
+	pub fn get_memory_size(&self) -> u64 {
+		self.mmu.get_memory_size()
+	}
+
+	// Expected to be called before setup_memory() and loading program
+	pub fn update_memory_map(&mut self, memory_map: MemoryMap) {
+		self.mmu.update_memory_map(memory_map);
+	}
+
+	pub fn get_memory_map(&self) -> &MemoryMap {
+		self.mmu.get_memory_map()
+	}
+
+	pub fn register_device(&mut self, device: Box<dyn MmioDevice>) {
+		self.mmu.register_device(device);
+	}
+
+	// Raises and lowers an external interrupt line of PLIC from host,
+	// for devices modeled outside of the emulator. The line stays raised
+	// until lowered, and the source is claimed through PLIC as usual.
+	pub fn raise_irq(&mut self, irq: u32) {
+		self.mmu.raise_irq(irq);
+	}
+
+	pub fn lower_irq(&mut self, irq: u32) {
+		self.mmu.lower_irq(irq);
+	}
+
+	pub fn setup_filesystem(&mut self, data: Vec<u8>) {
+		self.mmu.init_disk(data);
+	}
+
+	// Virtio block disk presents legacy (version 1) MMIO interface by
+	// default, which xv6 expects. Otherwise it presents modern (version 2)
+	// one, which Linux's virtio-blk driver prefers.
+	pub fn update_disk_legacy(&mut self, legacy: bool) {
+		self.mmu.update_disk_legacy(legacy);
+	}
+
+	// Randomness virtio entropy device gives the guest. SeededEntropy with
+	// seed 0 in deterministic timer mode, HostEntropy otherwise by default.
+	pub fn update_entropy_source(&mut self, entropy_source: Box<dyn EntropySource>) {
+		self.mmu.update_entropy_source(entropy_source);
+	}
+
+	// File system image in the host file, opened readable and writable.
+	// The guest's writes are written back to the file.
+	pub fn setup_filesystem_with_file(&mut self, file: File) -> std::io::Result<()> {
+		self.mmu.init_disk_with_file(file)
+	}
+
+	// Sets up a0 and a1 of the running hart as the boot protocol of
+	// Linux and OpenSBI, the hart ID and the device tree address. Cpu::new()
+	// leaves registers at the reset values so call this for booting such
+	// a program, for each hart after switch_hart().
+	pub fn setup_boot(&mut self, hart_id: u64, dtb_address: u64) {
+		self.x[10] = hart_id as i64;
+		self.x[11] = dtb_address as i64;
+	}
+
+	// Stores the device tree blob at the physical address and passes the
+	// address to the program in a1 of every hart. Expected to be called
+	// after loading program. Returns Err if the whole blob doesn't fit in
+	// mapped memory.
+	pub fn setup_dtb(&mut self, address: u64, data: &[u8]) -> Result<(), ()> {
+		match self.mmu.write_memory(address, data) {
+			Ok(()) => {},
+			Err(()) => return Err(())
+		};
+		self.x[11] = address as i64;
+		// The running hart's entry is overwritten when it's parked
+		for hart in self.harts.iter_mut() {
+			hart.x[11] = address as i64;
+		}
+		Ok(())
+	}
+
+	// Generates a device tree blob describing the harts, the memory, and
+	// the built-in devices at the addresses of the memory map, so that it
+	// always agrees with the emulator. Expected to be called after
+	// setup_memory() and setup_harts(), and to be given to setup_dtb().
+	//
+	// let dtb = cpu.generate_dtb();
+	// cpu.setup_dtb(address, &dtb).unwrap();
+	pub fn generate_dtb(&self) -> Vec<u8> {
+		let memory_map = self.mmu.get_memory_map();
+		let num_harts = match self.harts.len() {
+			0 => 1,
+			num => num
+		};
+		// Phandles of the harts' interrupt controllers are 1 to num_harts
+		// and PLIC's follows them
+		let plic_phandle = num_harts as u32 + 1;
+		let isa = self.get_isa_string();
+		let mmu_type = match self.xlen {
+			Xlen::Bit32 => "riscv,sv32",
+			Xlen::Bit64 => "riscv,sv48"
+		};
+		let mut fdt = FdtBuilder::new();
+		fdt.begin_node("");
+		fdt.property_u32("#address-cells", 2);
+		fdt.property_u32("#size-cells", 2);
+		fdt.property_string("compatible", "riscv-virtio");
+		fdt.property_string("model", "riscv-rust");
+
+		fdt.begin_node("chosen");
+		fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", memory_map.uart.base));
+		fdt.end_node();
+
+		fdt.begin_node(&format!("memory@{:x}", memory_map.dram_base));
+		fdt.property_string("device_type", "memory");
+		fdt.property_cells("reg", &get_reg_cells(memory_map.dram_base, self.mmu.get_memory_size()));
+		fdt.end_node();
+
+		fdt.begin_node("cpus");
+		fdt.property_u32("#address-cells", 1);
+		fdt.property_u32("#size-cells", 0);
+		fdt.property_u32("timebase-frequency", self.mmu.get_timebase_frequency() as u32);
+		for i in 0..num_harts {
+			fdt.begin_node(&format!("cpu@{:x}", i));
+			fdt.property_string("device_type", "cpu");
+			fdt.property_u32("reg", i as u32);
+			fdt.property_string("status", "okay");
+			fdt.property_string("compatible", "riscv");
+			fdt.property_string("riscv,isa", &isa);
+			fdt.property_string("mmu-type", mmu_type);
+			fdt.property_u32("riscv,cbom-block-size", self.cache_block_size as u32);
+			fdt.property_u32("riscv,cboz-block-size", self.cache_block_size as u32);
+			fdt.begin_node("interrupt-controller");
+			fdt.property_u32("#interrupt-cells", 1);
+			fdt.property_empty("interrupt-controller");
+			fdt.property_string("compatible", "riscv,cpu-intc");
+			fdt.property_u32("phandle", i as u32 + 1);
+			fdt.end_node();
+			fdt.end_node();
+		}
+		fdt.end_node();
+
+		// CLINT raises MSIP[3] and MTIP[7], and PLIC raises MEIP[11] and
+		// SEIP[9], of each hart in the order of PLIC contexts
+		let mut clint_interrupts = vec![];
+		let mut plic_interrupts = vec![];
+		for i in 0..num_harts {
+			let phandle = i as u32 + 1;
+			clint_interrupts.extend_from_slice(&[phandle, 3, phandle, 7]);
+			plic_interrupts.extend_from_slice(&[phandle, 11, phandle, 9]);
+		}
+
+		fdt.begin_node("soc");
+		fdt.property_u32("#address-cells", 2);
+		fdt.property_u32("#size-cells", 2);
+		fdt.property_string("compatible", "simple-bus");
+		fdt.property_empty("ranges");
+
+		fdt.begin_node(&format!("clint@{:x}", memory_map.clint.base));
+		fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+		fdt.property_cells("reg", &get_reg_cells(memory_map.clint.base, memory_map.clint.size));
+		fdt.property_cells("interrupts-extended", &clint_interrupts);
+		fdt.end_node();
+
+		fdt.begin_node(&format!("plic@{:x}", memory_map.plic.base));
+		fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+		fdt.property_cells("reg", &get_reg_cells(memory_map.plic.base, memory_map.plic.size));
+		fdt.property_u32("#address-cells", 0);
+		fdt.property_u32("#interrupt-cells", 1);
+		fdt.property_empty("interrupt-controller");
+		fdt.property_u32("riscv,ndev", NUM_SOURCES as u32 - 1);
+		fdt.property_cells("interrupts-extended", &plic_interrupts);
+		fdt.property_u32("phandle", plic_phandle);
+		fdt.end_node();
+
+		fdt.begin_node(&format!("serial@{:x}", memory_map.uart.base));
+		fdt.property_string("compatible", "ns16550a");
+		fdt.property_cells("reg", &get_reg_cells(memory_map.uart.base, memory_map.uart.size));
+		fdt.property_u32("clock-frequency", 0x384000);
+		fdt.property_u32("interrupt-parent", plic_phandle);
+		fdt.property_u32("interrupts", UART_IRQ);
+		fdt.end_node();
+
+		fdt.begin_node(&format!("virtio_mmio@{:x}", memory_map.virtio.base));
+		fdt.property_string("compatible", "virtio,mmio");
+		fdt.property_cells("reg", &get_reg_cells(memory_map.virtio.base, memory_map.virtio.size));
+		fdt.property_u32("interrupt-parent", plic_phandle);
+		fdt.property_u32("interrupts", VIRTIO_IRQ);
+		fdt.end_node();
+
+		fdt.begin_node(&format!("virtio_mmio@{:x}", memory_map.virtio_rng.base));
+		fdt.property_string("compatible", "virtio,mmio");
+		fdt.property_cells("reg", &get_reg_cells(memory_map.virtio_rng.base, memory_map.virtio_rng.size));
+		fdt.property_u32("interrupt-parent", plic_phandle);
+		fdt.property_u32("interrupts", VIRTIO_RNG_IRQ);
+		fdt.end_node();
+
+		fdt.begin_node(&format!("test@{:x}", memory_map.finisher.base));
+		fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+		fdt.property_cells("reg", &get_reg_cells(memory_map.finisher.base, memory_map.finisher.size));
+		fdt.end_node();
+
+		fdt.end_node(); // soc
+		fdt.end_node(); // root
+		fdt.finish(self.hart_id as u32)
+	}
+
+	// ISA string of device tree, the single-letter extensions enabled in
+	// misa followed by the multi-letter ones
+	fn get_isa_string(&self) -> String {
+		let misa = self.csr[CSR_MISA_ADDRESS as usize];
+		let mut isa = match self.xlen {
+			Xlen::Bit32 => "rv32".to_string(),
+			Xlen::Bit64 => "rv64".to_string()
+		};
+		for extension in "imafdc".chars() {
+			if (misa & (1 << (extension as u8 - b'a'))) != 0 {
+				isa.push(extension);
+			}
+		}
+		isa.push_str("_zicbom_zicboz_zicond_zicsr_zifencei");
+		if self.zba_enabled {
+			isa.push_str("_zba");
+		}
+		if self.zbb_enabled {
+			isa.push_str("_zbb");
+		}
+		isa.push_str("_sstc");
+		isa
+	}
+
+	// Forces writing back the file system to the host file
+	pub fn flush_filesystem(&mut self) -> std::io::Result<()> {
+		self.mmu.flush_disk()
+	}
+
+	// Expected to be called after loading program. All the harts
+	// start from the current state of hart 0 except for mhartid.
+	pub fn setup_harts(&mut self, num: usize) {
+		self.switch_hart(0);
+		self.harts = match num > 1 {
+			true => (0..num).map(|_| Hart {
+				clock: self.clock,
+				instret: self.instret,
+				privilege_mode: self.privilege_mode.clone(),
+				x: self.x,
+				f: self.f,
+				pc: self.pc,
+				csr: self.csr.clone(),
+				wfi: self.wfi,
+				mmu_context: MmuContext::new()
+			}).collect(),
+			false => vec![]
+		};
+		self.mmu.init_harts(num);
+	}
+
+	pub fn get_hart_id(&self) -> usize {
+		self.hart_id
+	}
+
+	// Makes the hart running. tick() switches harts round-robin but
+	// this can be used to inspect a hart's state from outside.
+	pub fn switch_hart(&mut self, hart_id: usize) {
+		if hart_id == self.hart_id {
+			return;
+		}
+		// Parks the running hart and then takes the new one
+		let current_hart_id = self.hart_id;
+		self.swap_hart(current_hart_id);
+		self.swap_hart(hart_id);
+		self.hart_id = hart_id;
+		self.mmu.update_hart_id(hart_id);
+	}
+
+	fn swap_hart(&mut self, hart_id: usize) {
+		let hart = &mut self.harts[hart_id];
+		std::mem::swap(&mut self.clock, &mut hart.clock);
+		std::mem::swap(&mut self.instret, &mut hart.instret);
+		std::mem::swap(&mut self.privilege_mode, &mut hart.privilege_mode);
+		std::mem::swap(&mut self.x, &mut hart.x);
+		std::mem::swap(&mut self.f, &mut hart.f);
+		std::mem::swap(&mut self.pc, &mut hart.pc);
+		std::mem::swap(&mut self.csr, &mut hart.csr);
+		std::mem::swap(&mut self.wfi, &mut hart.wfi);
+		self.mmu.swap_context(&mut hart.mmu_context);
+	}
+
+	// Puts all harts back to the reset state without reallocating memory,
+	// for warm reboot and for running another program in the same Cpu.
+	// Harts start from the reset vector in machine mode with registers and
+	// CSRs cleared, and the machine is no longer halted. xlen, memory,
+	// devices, and the setup by host like SBI and breakpoints are kept.
+	// Set up pc and registers for booting again after this, like after new().
+	pub fn reset(&mut self) {
+		for hart_id in 0..std::cmp::max(self.harts.len(), 1) {
+			self.switch_hart(hart_id);
+			self.clock = 0;
+			self.instret = 0;
+			self.privilege_mode = PrivilegeMode::Machine;
+			self.x = [0; 32];
+			self.f = [0; 32];
+			let reset_vector = self.reset_vector;
+			self.update_pc(reset_vector);
+			self.wfi = false;
+			self.reset_csrs();
+			self.mmu.swap_context(&mut MmuContext::new());
+		}
+		self.switch_hart(0);
+		self.instruction_address = 0;
+		self.instruction_word = 0;
+		self.written_csr = None;
+		self.watchpoint_hit = None;
+		// Memory may be loaded with another program
+		self.decode_cache.clear();
+		self.mmu.reset();
+	}
+
+	fn reset_csrs(&mut self) {
+		for value in self.csr.iter_mut() {
+			*value = 0;
+		}
+		self.csr[CSR_MISA_ADDRESS as usize] = MISA_EXTENSIONS;
+		// Supervisor timer doesn't fire until stimecmp is written
+		self.csr[CSR_STIMECMP_ADDRESS as usize] = 0xffffffffffffffff;
+	}
+
+	// Zba address generation instructions are decoded only while enabled
+	pub fn update_zba_enabled(&mut self, enabled: bool) {
+		self.zba_enabled = enabled;
+		self.decode_cache.clear();
+	}
+
+	// Zbb basic bit manipulation instructions are decoded only while enabled
+	pub fn update_zbb_enabled(&mut self, enabled: bool) {
+		self.zbb_enabled = enabled;
+		self.decode_cache.clear();
+	}
+
+	// Size of the cache blocks CBO.ZERO clears. It must be a power of two
+	// from 8 to 4096 so that a block never crosses a page.
+	pub fn update_cache_block_size(&mut self, size: u64) {
+		self.cache_block_size = size;
+	}
+
+	// While enabled, ECALLs from supervisor mode are serviced as SBI calls,
+	// legacy extensions and BASE, TIME, DBCN, and SRST, without trap. CLINT
+	// timer interrupt is raised as STIP as SBI firmware forwards it.
+	pub fn update_sbi_enabled(&mut self, enabled: bool) {
+		self.sbi_enabled = enabled;
+	}
+
+	// Switches the running hart to supervisor mode as SBI firmware hands
+	// over to a kernel. With no machine mode handler, all the exceptions
+	// but ECALLs from S/M-mode and supervisor interrupts are delegated,
+	// the counters are readable in supervisor mode, and stimecmp and
+	// cache-block management instructions are enabled.
+	pub fn enter_supervisor_mode(&mut self) {
+		self.csr[CSR_MEDELEG_ADDRESS as usize] = 0xb1ff;
+		self.csr[CSR_MIDELEG_ADDRESS as usize] = 0x222;
+		self.csr[CSR_MCOUNTEREN_ADDRESS as usize] = 0x7;
+		self.csr[CSR_MENVCFG_ADDRESS as usize] = MENVCFG_STCE | MENVCFG_CBZE | MENVCFG_CBCFE | MENVCFG_CBIE;
+		self.privilege_mode = PrivilegeMode::Supervisor;
+		self.mmu.update_privilege_mode(self.privilege_mode.clone());
+		self.update_data_privilege_mode();
+	}
+
+	// riscv-tests request the host through tohost and fromhost at the
+	// physical addresses, by HTIF. Setting them lets the machine halt with
+	// the test result and print the output.
+	pub fn update_tohost_address(&mut self, address: u64) {
+		self.mmu.update_tohost_address(address);
+	}
+
+	pub fn update_fromhost_address(&mut self, address: u64) {
+		self.mmu.update_fromhost_address(address);
+	}
+
+	// Returns true after the guest halts the machine by SBI shutdown,
+	// the test finisher device, or tohost. Harts don't run any more.
+	pub fn is_halted(&self) -> bool {
+		self.mmu.is_halted()
+	}
+
+	// Exit code given by the guest when halting, 0 means success
+	pub fn get_exit_code(&self) -> Option<u64> {
+		self.mmu.get_exit_code()
+	}
+
+	// Statistics are collected only while enabled. Disabled by default.
+	pub fn update_stats_enabled(&mut self, enabled: bool) {
+		self.mmu.get_mut_stats().update_enabled(enabled);
+	}
+
+	// Retired instructions and taken traps of all harts, and TLB of the MMU
+	pub fn get_stats(&self) -> &ExecStats {
+		self.mmu.get_stats()
+	}
+
+	pub fn reset_stats(&mut self) {
+		self.mmu.get_mut_stats().reset();
+	}
+
+	pub fn set_unimplemented_policy(&mut self, policy: UnimplementedPolicy) {
+		self.unimplemented_policy = policy;
+	}
+
+	pub fn set_misaligned_access_policy(&mut self, policy: MisalignedAccessPolicy) {
+		self.mmu.update_misaligned_access_policy(policy);
+	}
+
+	pub fn set_accessed_dirty_policy(&mut self, policy: AccessedDirtyPolicy) {
+		self.mmu.update_accessed_dirty_policy(policy);
+	}
+
+	// The hook is called with a TraceEvent for each retired instruction
+	pub fn set_trace_hook(&mut self, hook: Box<dyn FnMut(TraceEvent)>) {
+		self.trace_hook = Some(hook);
+	}
+
+	pub fn clear_trace_hook(&mut self) {
+		self.trace_hook = None;
+	}
+
+	// Snapshot of the entire machine state including memory and built-in devices.
+	// Terminal, clock source, registered devices, trace hook, and breakpoints are not included.
+	#[cfg(feature = "serde")]
+	pub fn save_state(&self) -> Result<Vec<u8>, String> {
+		match bincode::serialize(self) {
+			Ok(data) => Ok(data),
+			Err(e) => Err(format!("Failed to save state: {}", e))
+		}
+	}
+
+	// Restores the state saved by save_state(). The current terminal, clock source,
+	// entropy source, registered devices, trace hook, breakpoints, and watchpoints
+	// are kept attached. The state is unchanged if the data is broken.
+	#[cfg(feature = "serde")]
+	pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+		let mut cpu: Cpu = match bincode::deserialize(data) {
+			Ok(cpu) => cpu,
+			Err(e) => return Err(format!("Failed to load state: {}", e))
+		};
+		cpu.mmu.swap_terminal(&mut self.mmu);
+		cpu.mmu.swap_clock_source(&mut self.mmu);
+		cpu.mmu.swap_entropy_source(&mut self.mmu);
+		cpu.mmu.swap_devices(&mut self.mmu);
+		cpu.mmu.swap_watchpoints(&mut self.mmu);
+		cpu.trace_hook = self.trace_hook.take();
+		cpu.breakpoints = self.breakpoints.clone();
+		cpu.stall_ticks = self.stall_ticks;
+		*self = cpu;
+		Ok(())
+	}
+
+	// step(), step_with_devices(), and tick() report when pc reaches
+	// a breakpoint, before the instruction there is executed.
+	// Hitting a breakpoint doesn't change guest state, so just calling
+	// them again executes the instruction and continues.
+
+	pub fn add_breakpoint(&mut self, address: u64) {
+		if !self.breakpoints.contains(&address) {
+			self.breakpoints.push(address);
+		}
+	}
+
+	pub fn remove_breakpoint(&mut self, address: u64) {
+		self.breakpoints.retain(|&a| a != address);
+	}
+
+	// Watchpoints watch the virtual address range for loads and stores of
+	// instructions. An access hitting one is done as usual and then step(),
+	// step_with_devices(), and tick() report it, the same as breakpoints.
+	pub fn add_watchpoint(&mut self, address: u64, length: u64, kind: WatchKind) {
+		self.mmu.add_watchpoint(address, length, kind);
+	}
+
+	pub fn remove_watchpoint(&mut self, address: u64, length: u64, kind: WatchKind) -> bool {
+		self.mmu.remove_watchpoint(address, length, kind)
+	}
+
+	// The last watchpoint hit. step_with_devices() and tick() keep reporting
+	// it until taken.
+	pub fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+		self.watchpoint_hit.take()
+	}
+
+	// Public methods for inspecting and modifying the state from outside,
+	// for example from an embedding test harness
+
+	pub fn read_register(&self, index: usize) -> i64 {
+		self.x[index]
+	}
+
+	pub fn write_register(&mut self, index: usize, value: i64) {
+		if index != 0 { // x0 is hard-wired zero
+			self.x[index] = value;
+		}
+	}
+
+	pub fn read_pc(&self) -> u64 {
+		self.pc
+	}
+
+	pub fn get_xlen(&self) -> &Xlen {
+		&self.xlen
+	}
+
+	// Returns Err if no memory or device is mapped to the address
+	pub fn load_raw(&mut self, address: u64) -> Result<u8, ()> {
+		self.mmu.load_raw(address)
+	}
+
+	// Reads the physical address range into buf. Returns Err unless
+	// the whole range is in the main memory.
+	pub fn read_memory(&self, address: u64, buf: &mut [u8]) -> Result<(), ()> {
+		self.mmu.read_memory(address, buf)
+	}
+
+	// Unlike read_csr and write_csr, these don't check privilege
+	// and don't have any side effect
+
+	pub fn read_csr_raw(&self, address: u16) -> u64 {
+		self.csr[address as usize]
+	}
+
+	pub fn write_csr_raw(&mut self, address: u16, value: u64) {
+		self.csr[address as usize] = value;
+	}
+
+	// Addresses, names, and values of the CSRs implemented in the current
+	// xlen, as the running hart reads them, for debugging. Unlike
+	// read_csr_raw(), views like sstatus and counters are resolved.
+	pub fn dump_csrs(&self) -> Vec<(u16, &'static str, u64)> {
+		let mut csrs = vec![];
+		for address in 0..CSR_CAPACITY as u16 {
+			let name = match get_csr_name(address) {
+				Some(name) => name,
+				None => continue
+			};
+			// The upper halves of 64-bit CSRs and the odd pmpcfgs exist only in 32-bit mode
+			let exists = match address {
+				CSR_CYCLEH_ADDRESS |
+				CSR_TIMEH_ADDRESS |
+				CSR_INSTRETH_ADDRESS |
+				CSR_MCYCLEH_ADDRESS |
+				CSR_MINSTRETH_ADDRESS |
+				CSR_STIMECMPH_ADDRESS |
+				CSR_MENVCFGH_ADDRESS => match self.xlen {
+					Xlen::Bit32 => true,
+					Xlen::Bit64 => false
+				},
+				CSR_PMPCFG0_ADDRESS..=CSR_PMPCFG3_ADDRESS => self.get_pmp_config_entries(address).is_some(),
+				_ => true
+			};
+			if exists {
+				csrs.push((address, name, self.get_csr_value(address)));
+			}
+		}
+		csrs
+	}
+
+	// Public method for running riscv-tests
+
+	pub fn load_word_raw(&mut self, address: u64) -> Result<u32, ()> {
+		self.mmu.load_word_raw(address)
+	}
+
+	//
+
+	// Runs one instruction of the running hart and then switches to the next
+	// hart. Returns true if pc of the next hart hits a breakpoint or
+	// a watchpoint hit isn't taken yet
+	pub fn tick(&mut self) -> bool {
+		self.tick_hart();
+		self.hits_breakpoint() || self.watchpoint_hit.is_some()
+	}
+
+	// Runs one instruction of the running hart and then ticks devices and
+	// handles interrupts. The hart doesn't run instructions while waiting
+	// for interrupt but devices are still ticked.
+	// Returns true if pc hits a breakpoint or a watchpoint hit isn't taken yet
+	pub fn step_with_devices(&mut self) -> bool {
+		self.step_hart_with_devices();
+		self.hits_breakpoint() || self.watchpoint_hit.is_some()
+	}
+
+	// Runs until the machine halts, pc hits a breakpoint, a watchpoint is
+	// hit, or max_instructions instructions retire in total of the harts.
+	// Instructions which trap don't retire. Returns Stalled if none of the
+	// harts retires an instruction for the stall ticks.
+	pub fn run(&mut self, max_instructions: u64) -> RunOutcome {
+		let mut retired = 0;
+		let mut stalled_ticks = 0;
+		loop {
+			match self.get_exit_code() {
+				Some(exit_code) => return RunOutcome::Halted(exit_code),
+				None => {}
+			};
+			if retired >= max_instructions {
+				return RunOutcome::BudgetExhausted;
+			}
+			match self.tick_hart() {
+				true => {
+					retired += 1;
+					stalled_ticks = 0;
+				},
+				false => {
+					stalled_ticks += 1;
+					if stalled_ticks >= self.stall_ticks {
+						return RunOutcome::Stalled;
+					}
+				}
+			};
+			if self.watchpoint_hit.is_some() {
+				return RunOutcome::Watchpoint;
+			}
+			if self.hits_breakpoint() {
+				return RunOutcome::Breakpoint;
+			}
+		}
+	}
+
+	// Ticks without a retired instruction after which run() returns Stalled.
+	// 10000000 by default, long enough for usual timer interrupts to come.
+	pub fn update_stall_ticks(&mut self, ticks: u64) {
+		self.stall_ticks = ticks;
+	}
+
+	// tick() returning true if an instruction retires
+	fn tick_hart(&mut self) -> bool {
+		let retired = self.step_hart_with_devices();
+		if self.harts.len() > 1 {
+			let next_hart_id = (self.hart_id + 1) % self.harts.len();
+			self.switch_hart(next_hart_id);
+		}
+		retired
+	}
+
+	// step_with_devices() returning true if an instruction retires
+	fn step_hart_with_devices(&mut self) -> bool {
+		let retired = match !self.wfi && !self.mmu.is_halted() {
+			true => !self.step().trapped,
+			false => false
+		};
+		self.mmu.tick();
+		self.handle_interrupt();
+		retired
+	}
+
+	// Runs exactly one instruction without ticking devices
+	// or handling interrupts, so that the core can be driven deterministically
+	pub fn step(&mut self) -> StepResult {
+		let mut result = StepResult {
+			pc: self.pc,
+			word: 0,
+			name: None,
+			instruction: None,
+			trapped: false,
+			breakpoint: false,
+			watchpoint: false
+		};
+		// Snapshot for the trace hook, taken only while the hook is set
+		let snapshot = match self.trace_hook.is_some() {
+			true => {
+				self.written_csr = None;
+				Some((self.x, self.f, self.privilege_mode.clone()))
+			},
+			false => None
+		};
+		match self.tick_operate(&mut result) {
+			Ok(()) => {
+				self.instret = self.instret.wrapping_add(1);
+				match result.name {
+					Some(name) => self.mmu.get_mut_stats().record_instruction(name),
+					None => {}
+				};
+				match snapshot {
+					Some((x, f, privilege_mode)) => self.trace(&result, &x, &f, privilege_mode),
+					None => {}
+				};
+			},
+			Err(e) => {
+				self.handle_exception(e);
+				result.trapped = true;
+			}
+		};
+		match self.mmu.take_watchpoint_access() {
+			Some((address, kind)) => {
+				self.watchpoint_hit = Some(WatchpointHit {
+					pc: result.pc,
+					address: address,
+					kind: kind
+				});
+				result.watchpoint = true;
+			},
+			None => {}
+		};
+		self.clock = self.clock.wrapping_add(1);
+		result.breakpoint = self.hits_breakpoint();
+		result
+	}
+
+	fn hits_breakpoint(&self) -> bool {
+		self.breakpoints.contains(&self.pc)
+	}
+
+	fn trace(&mut self, result: &StepResult, x: &[i64; 32], f: &[u64; 32], privilege_mode: PrivilegeMode) {
+		let instruction = match result.instruction {
+			Some(instruction) => instruction,
+			None => return
+		};
+		let mut register = None;
+		let mut f_register = None;
+		for i in 0..32 {
+			if register.is_none() && self.x[i] != x[i] {
+				register = Some((i, self.x[i]));
+			}
+			if f_register.is_none() && self.f[i] != f[i] {
+				f_register = Some((i, self.f[i]));
+			}
+		}
+		let event = TraceEvent {
+			pc: result.pc,
+			word: result.word,
+			instruction: instruction,
+			privilege_mode: privilege_mode,
+			register: register,
+			f_register: f_register,
+			csr: self.written_csr
+		};
+		match self.trace_hook {
+			Some(ref mut hook) => hook(event),
+			None => {}
+		};
+	}
+
+	// @TODO: Rename
+	fn tick_operate(&mut self, result: &mut StepResult) -> Result<(), Trap> {
+		let instruction_address = self.pc;
+		self.instruction_address = instruction_address;
+		let word = match self.fetch() {
+			Ok(word) => word,
+			Err(e) => return Err(e)
+		};
+		self.instruction_word = word;
+		// Only 32-bit instructions end with 0b11. The others are compressed
+		// instructions, fetched as 16 bits, which are illegal if C is disabled.
+		let length = match get_instruction_length(word as u16) {
+			4 => 4,
+			_ => match (self.csr[CSR_MISA_ADDRESS as usize] & MISA_C) == 0 {
+				true => return Err(Trap {
+					trap_type: TrapType::IllegalInstruction,
+					value: word as u64
+				}),
+				false => 2
+			}
+		};
+		let (instruction, operand_word) = match self.decode_cache.get(word) {
+			Some(decoded) => decoded,
+			None => {
+				let operand_word = match length {
+					4 => word,
+					_ => self.uncompress(word)
+				};
+				match decode(operand_word, &self.xlen, self.zba_enabled, self.zbb_enabled) {
+					Ok(instruction) => {
+						self.decode_cache.insert(word, instruction, operand_word);
+						(instruction, operand_word)
+					},
+					Err(()) => return Err(Trap {
+						trap_type: TrapType::IllegalInstruction,
+						value: word as u64
+					})
+				}
+			}
+		};
+		// misa is checked every time since it can differ between harts
+		if !self.is_extension_enabled(operand_word) {
+			return Err(Trap {
+				trap_type: TrapType::IllegalInstruction,
+				value: word as u64
+			});
+		}
+		result.word = word;
+		result.name = Some(get_instruction_name(&instruction));
+		result.instruction = Some(instruction);
+		let pc = self.pc.wrapping_add(length);
+		self.update_pc(pc);
+		self.operate(operand_word, instruction, instruction_address)
+	}
+
+	// Returns true while the hart is halted by WFI instruction
+	pub fn is_waiting_for_interrupt(&self) -> bool {
+		self.wfi
+	}
+
+	// Returns true if any device or timer raises an interrupt to any hart,
+	// whether or not it's enabled. With get_next_timer_deadline(), host can
+	// sleep while the harts wait for interrupt and nothing is raised.
+	pub fn has_pending_interrupt(&self) -> bool {
+		let mtime = self.mmu.read_mtime();
+		self.mmu.has_pending_interrupt() ||
+			self.get_stimecmps().iter().any(|stimecmp| mtime >= *stimecmp)
+	}
+
+	// mtime when the next timer interrupt of any hart, CLINT or stimecmp,
+	// is raised. None if no timer is set. It can be in the past if the
+	// interrupt is already raised.
+	pub fn get_next_timer_deadline(&self) -> Option<u64> {
+		let stimecmp = self.get_stimecmps().into_iter()
+			.filter(|stimecmp| *stimecmp != 0xffffffffffffffff)
+			.min();
+		match (self.mmu.get_next_timer_deadline(), stimecmp) {
+			(Some(mtimecmp), Some(stimecmp)) => Some(std::cmp::min(mtimecmp, stimecmp)),
+			(mtimecmp, None) => mtimecmp,
+			(None, stimecmp) => stimecmp
+		}
+	}
+
+	// stimecmp of the harts where menvcfg.STCE is set. CSRs of the running
+	// hart are in Cpu and the ones in its Hart are stale.
+	fn get_stimecmps(&self) -> Vec<u64> {
+		let mut csrs = vec![&self.csr];
+		for (hart_id, hart) in self.harts.iter().enumerate() {
+			if hart_id != self.hart_id {
+				csrs.push(&hart.csr);
+			}
+		}
+		csrs.iter()
+			.filter(|csr| (csr[CSR_MENVCFG_ADDRESS as usize] & MENVCFG_STCE) != 0)
+			.map(|csr| csr[CSR_STIMECMP_ADDRESS as usize])
+			.collect()
+	}
+
+	fn handle_interrupt(&mut self) {
+		// MSIP[3], MTIP[7], SEIP[9], and MEIP[11] of mip reflect devices.
+		// They are level-sensitive and keep pending until the guest clears
+		// msip, updates mtimecmp, or claims the external interrupt.
+		// With SBI emulation, CLINT timer is reflected to STIP[5] instead.
+		// While menvcfg.STCE is set, STIP[5] is also pending while time >= stimecmp.
+		let timer_bit = match self.sbi_enabled {
+			true => 0x20,
+			false => 0x80
+		};
+		let mut mip = self.csr[CSR_MIP_ADDRESS as usize] & !(0xa08 | timer_bit);
+		if self.is_sstc_enabled() {
+			mip &= !0x20;
+			if self.mmu.read_mtime() >= self.csr[CSR_STIMECMP_ADDRESS as usize] {
+				mip |= 0x20;
+			}
+		}
+		if self.mmu.is_clint_software_interrupting() {
+			mip |= 0x8;
+		}
+		if self.mmu.is_clint_interrupting() {
+			mip |= timer_bit;
+		}
+		self.mmu.detect_interrupt();
+		if self.mmu.is_plic_interrupting(&PrivilegeMode::Supervisor) {
+			mip |= 0x200;
+		}
+		if self.mmu.is_plic_interrupting(&PrivilegeMode::Machine) {
+			mip |= 0x800;
+		}
+		self.csr[CSR_MIP_ADDRESS as usize] = mip;
+
+		let pending = mip & self.csr[CSR_MIE_ADDRESS as usize];
+		if pending == 0 {
+			return;
+		}
+
+		// WFI ends when any enabled interrupt becomes pending even if
+		// interrupts are globally disabled. If they're enabled, the hart
+		// resumes in the trap handler and returns to the instruction after WFI.
+		self.wfi = false;
+
+		// In the order of priority. SSIP[1] and STIP[5] are set by software,
+		// typically by machine mode to forward interrupts to supervisor mode.
+		if (pending & 0x800) != 0 && self.handle_pending_interrupt(TrapType::MachineExternalInterrupt) {
+			return;
+		}
+		if (pending & 0x8) != 0 && self.handle_pending_interrupt(TrapType::MachineSoftwareInterrupt) {
+			return;
+		}
+		if (pending & 0x80) != 0 && self.handle_pending_interrupt(TrapType::MachineTimerInterrupt) {
+			return;
+		}
+		if (pending & 0x200) != 0 && self.handle_pending_interrupt(TrapType::SupervisorExternalInterrupt) {
+			return;
+		}
+		if (pending & 0x2) != 0 && self.handle_pending_interrupt(TrapType::SupervisorSoftwareInterrupt) {
+			return;
+		}
+		if (pending & 0x20) != 0 && self.handle_pending_interrupt(TrapType::SupervisorTimerInterrupt) {
+			return;
+		}
+		if (pending & 0x100) != 0 && self.handle_pending_interrupt(TrapType::UserExternalInterrupt) {
+			return;
+		}
+		if (pending & 0x1) != 0 && self.handle_pending_interrupt(TrapType::UserSoftwareInterrupt) {
+			return;
+		}
+		if (pending & 0x10) != 0 {
+			self.handle_pending_interrupt(TrapType::UserTimerInterrupt);
+		}
+	}
+
+	// Returns true if the interrupt is taken
+	// tval is zero for interrupts
+	fn handle_pending_interrupt(&mut self, trap_type: TrapType) -> bool {
+		self.handle_trap(Trap {
+			trap_type: trap_type,
+			value: 0
+		}, true)
+	}
+
+	fn handle_exception(&mut self, exception: Trap) {
+		self.handle_trap(exception, false);
+	}
+
+	fn handle_trap(&mut self, trap: Trap, is_interrupt: bool) -> bool{
+		let current_privilege_encoding = get_privilege_encoding(&self.privilege_mode) as u64;
+		let cause = get_trap_cause(&trap, &self.xlen);
+		// Delegation registers, mie, and vectored tvec are indexed by the
+		// exception code, not by cause which has the interrupt bit
+		let code = get_trap_code(&trap.trap_type);
+
+		// Interrupts are delegated by mideleg and sideleg, and exceptions
+		// by medeleg and sedeleg
+		let mdeleg = match is_interrupt {
+			true => self.csr[CSR_MIDELEG_ADDRESS as usize],
+			false => self.csr[CSR_MEDELEG_ADDRESS as usize]
+		};
+		let sdeleg = match is_interrupt {
+			true => self.csr[CSR_SIDELEG_ADDRESS as usize],
+			false => self.csr[CSR_SEDELEG_ADDRESS as usize]
+		};
+
+		// Each interrupt is enabled by the corresponding bit of mie
+		if is_interrupt && ((self.csr[CSR_MIE_ADDRESS as usize] >> code) & 1) == 0 {
+			return false;
+		}
+
+		let new_privilege_mode = match ((mdeleg >> code) & 1) == 0 {
+			true => PrivilegeMode::Machine,
+			false => match ((sdeleg >> code) & 1) == 0 {
+				true => PrivilegeMode::Supervisor,
+				false => PrivilegeMode::User
+			}
+		};
+		// Traps never move to lower privilege mode than the current one
+		let new_privilege_mode = match (get_privilege_encoding(&new_privilege_mode) as u64) < current_privilege_encoding {
+			true => self.privilege_mode.clone(),
+			false => new_privilege_mode
+		};
+
+		// @TODO: Which we should do, dispose or pend, if trap is disabled?
+		// Disposing so far.
+
+		let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
+
+		let mie = (status >> 3) & 1;
+		let sie = (status >> 1) & 1;
+		let uie = status & 1;
+
+		// Interrupts handled in higher privilege mode than current are
+		// enabled regardless of the interrupt enable bit in status
+		let new_privilege_encoding = get_privilege_encoding(&new_privilege_mode) as u64;
+		if is_interrupt && current_privilege_encoding >= new_privilege_encoding {
+			match new_privilege_mode {
+				PrivilegeMode::Machine => {
+					if mie == 0 {
+						return false;
+					}
+				},
+				PrivilegeMode::Supervisor => {
+					if sie == 0 {
+						return false;
+					}
+				},
+				PrivilegeMode::User => {
+					if uie == 0 {
+						return false;
+					}
+				},
+				PrivilegeMode::Reserved => panic!()
+			};
+		}
+
+		// Taking a trap invalidates LR reservation
+		self.mmu.clear_reservation();
+		self.mmu.get_mut_stats().record_trap(get_trap_type_name(&trap.trap_type));
+
+		self.privilege_mode = new_privilege_mode;
+		self.mmu.update_privilege_mode(self.privilege_mode.clone());
+		let csr_epc_address = match self.privilege_mode {
+			PrivilegeMode::Machine => CSR_MEPC_ADDRESS,
+			PrivilegeMode::Supervisor => CSR_SEPC_ADDRESS,
+			PrivilegeMode::User => CSR_UEPC_ADDRESS,
+			PrivilegeMode::Reserved => panic!()
+		};
+		let csr_cause_address = match self.privilege_mode {
+			PrivilegeMode::Machine => CSR_MCAUSE_ADDRESS,
+			PrivilegeMode::Supervisor => CSR_SCAUSE_ADDRESS,
+			PrivilegeMode::User => CSR_UCAUSE_ADDRESS,
+			PrivilegeMode::Reserved => panic!()
+		};
+		let csr_tval_address = match self.privilege_mode {
+			PrivilegeMode::Machine => CSR_MTVAL_ADDRESS,
+			PrivilegeMode::Supervisor => CSR_STVAL_ADDRESS,
+			PrivilegeMode::User => CSR_UTVAL_ADDRESS,
+			PrivilegeMode::Reserved => panic!()
+		};
+		let csr_tvec_address = match self.privilege_mode {
+			PrivilegeMode::Machine => CSR_MTVEC_ADDRESS,
+			PrivilegeMode::Supervisor => CSR_STVEC_ADDRESS,
+			PrivilegeMode::User => CSR_UTVEC_ADDRESS,
+			PrivilegeMode::Reserved => panic!()
+		};
+
+		// Interrupts are taken between instructions, after pc has moved to
+		// the next instruction to run. Exceptions abort the instruction.
+		self.csr[csr_epc_address as usize] = match is_interrupt {
+			true => self.pc,
+			false => self.instruction_address
+		};
+		self.csr[csr_cause_address as usize] = cause;
+		self.csr[csr_tval_address as usize] = trap.value;
+		// MODE[1:0] of tvec selects direct mode (0) where every trap jumps to
+		// BASE, or vectored mode (1) where interrupts jump to BASE + 4 * cause
+		let tvec = self.csr[csr_tvec_address as usize];
+		let base = tvec & !0x3;
+		let trap_vector = match is_interrupt && (tvec & 0x3) == 1 {
+			true => base.wrapping_add(4 * code),
+			false => base
+		};
+		self.update_pc(trap_vector);
+
+		match self.privilege_mode {
+			PrivilegeMode::Machine => {
+				let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
+				let mie = (status >> 3) & 1;
+				// clear MIE[3], override MPIE[7] with MIE[3], override MPP[12:11] with current privilege encoding
+				let new_status = (status & !0x1888) | (mie << 7) | (current_privilege_encoding << 11);
+				self.csr[CSR_MSTATUS_ADDRESS as usize] = new_status;
+			},
+			PrivilegeMode::Supervisor => {
+				let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
+				let sie = (status >> 1) & 1;
+				// clear SIE[1], override SPIE[5] with SIE[1], override SPP[8] with current privilege encoding
+				let new_status = (status & !0x122) | (sie << 5) | ((current_privilege_encoding & 1) << 8);
+				self.csr[CSR_MSTATUS_ADDRESS as usize] = new_status;
+			},
+			PrivilegeMode::User => {
+				let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
+				let uie = status & 1;
+				// clear UIE[0], override UPIE[4] with UIE[0]
+				let new_status = (status & !0x11) | (uie << 4);
+				self.csr[CSR_MSTATUS_ADDRESS as usize] = new_status;
+			},
+			PrivilegeMode::Reserved => panic!() // shouldn't happen
+		};
+		self.update_data_privilege_mode();
+		true
+	}
+
+	fn fetch(&mut self) -> Result<u32, Trap> {
+		self.mmu.fetch_instruction(self.pc)
+	}
+
+	fn has_csr_access_privilege(&self, address: u16) -> bool {
+		// satp is inaccessible in S-mode if mstatus.TVM[20] is set
+		let tvm = (self.csr[CSR_MSTATUS_ADDRESS as usize] >> 20) & 1;
+		if address == CSR_SATP_ADDRESS && tvm == 1 && self.privilege_mode == PrivilegeMode::Supervisor {
+			return false;
+		}
+		// fflags, frm, and fcsr are inaccessible while mstatus.FS is Off
+		let fp_csr = address == CSR_FFLAGS_ADDRESS || address == CSR_FRM_ADDRESS || address == CSR_FCSR_ADDRESS;
+		if fp_csr && !self.is_fp_enabled() {
+			return false;
+		}
+		let privilege = (address >> 8) & 0x3; // the lowest privilege level that can access the CSR
+		privilege as u8 <= get_privilege_encoding(&self.privilege_mode)
+	}
+
+	// User and Supervisor mode can read a counter only
+	// if it's enabled in mcounteren, and also in scounteren for User mode
+	fn has_counter_access_privilege(&self, address: u16) -> bool {
+		let bit = 1 << (address & 0x1f);
+		let mcounteren = self.csr[CSR_MCOUNTEREN_ADDRESS as usize];
+		let scounteren = self.csr[CSR_SCOUNTEREN_ADDRESS as usize];
+		let enabled = match self.privilege_mode {
+			PrivilegeMode::Machine => true,
+			PrivilegeMode::Supervisor => (mcounteren & bit) != 0,
+			PrivilegeMode::User => (mcounteren & bit) != 0 && (scounteren & bit) != 0,
+			PrivilegeMode::Reserved => false
+		};
+		// Upper half counters exist only in 32-bit mode
+		let high_half = match address {
+			CSR_CYCLEH_ADDRESS |
+			CSR_TIMEH_ADDRESS |
+			CSR_INSTRETH_ADDRESS |
+			CSR_MCYCLEH_ADDRESS |
+			CSR_MINSTRETH_ADDRESS => true,
+			_ => false
+		};
+		match self.xlen {
+			Xlen::Bit32 => enabled,
+			Xlen::Bit64 => enabled && !high_half
+		}
+	}
+
+	fn is_sstc_enabled(&self) -> bool {
+		(self.csr[CSR_MENVCFG_ADDRESS as usize] & MENVCFG_STCE) != 0
+	}
+
+	// Below machine mode, stimecmp is accessible only if menvcfg.STCE and
+	// mcounteren.TM[1] are set. stimecmph and menvcfgh exist only in 32-bit mode.
+	fn has_envcfg_access_privilege(&self, address: u16) -> bool {
+		let exists = match address {
+			CSR_STIMECMPH_ADDRESS | CSR_MENVCFGH_ADDRESS => match self.xlen {
+				Xlen::Bit32 => true,
+				Xlen::Bit64 => false
+			},
+			_ => true
+		};
+		let enabled = match address {
+			CSR_STIMECMP_ADDRESS | CSR_STIMECMPH_ADDRESS => match self.privilege_mode {
+				PrivilegeMode::Machine => true,
+				_ => self.is_sstc_enabled() && (self.csr[CSR_MCOUNTEREN_ADDRESS as usize] & 0x2) != 0
+			},
+			_ => true
+		};
+		exists && enabled
+	}
+
+	// In 32-bit mode, a 64-bit CSR is read as the lower half and
+	// the upper half at another address
+	fn read_csr_half(&self, value: u64, upper: bool) -> u64 {
+		match (&self.xlen, upper) {
+			(_, true) => value >> 32,
+			(Xlen::Bit32, false) => value & 0xffffffff,
+			(Xlen::Bit64, false) => value
+		}
+	}
+
+	fn write_csr_half(&self, current: u64, value: u64, upper: bool) -> u64 {
+		match (&self.xlen, upper) {
+			(_, true) => (current & 0xffffffff) | (value << 32),
+			(Xlen::Bit32, false) => (current & 0xffffffff00000000) | (value & 0xffffffff),
+			(Xlen::Bit64, false) => value
+		}
+	}
+
+	fn read_csr(&mut self, address: u16) -> Result<u64, Trap> {
+		// Reads of counters not permitted by mcounteren and scounteren trap,
+		// so that a higher privilege handler can emulate them
+		let permitted = self.has_csr_access_privilege(address) && match address {
+			CSR_CYCLE_ADDRESS |
+			CSR_TIME_ADDRESS |
+			CSR_INSTRET_ADDRESS |
+			CSR_CYCLEH_ADDRESS |
+			CSR_TIMEH_ADDRESS |
+			CSR_INSTRETH_ADDRESS |
+			CSR_MCYCLE_ADDRESS |
+			CSR_MINSTRET_ADDRESS |
+			CSR_MCYCLEH_ADDRESS |
+			CSR_MINSTRETH_ADDRESS => self.has_counter_access_privilege(address),
+			CSR_PMPCFG0_ADDRESS..=CSR_PMPCFG3_ADDRESS => self.get_pmp_config_entries(address).is_some(),
+			CSR_STIMECMP_ADDRESS |
+			CSR_STIMECMPH_ADDRESS |
+			CSR_MENVCFG_ADDRESS |
+			CSR_MENVCFGH_ADDRESS => self.has_envcfg_access_privilege(address),
+			_ => true
+		};
+		match permitted {
+			true => Ok(self.get_csr_value(address)),
+			false => Err(Trap {
+				trap_type: TrapType::IllegalInstruction,
+				value: self.instruction_word as u64
+			})
+		}
+	}
+
+	// Value the guest reads from the CSR, without checking privilege
+	fn get_csr_value(&self, address: u16) -> u64 {
+		match address {
+			// fflags and frm are views of fcsr
+			CSR_FFLAGS_ADDRESS => self.csr[CSR_FCSR_ADDRESS as usize] & 0x1f,
+			CSR_MHARTID_ADDRESS => self.hart_id as u64,
+			CSR_MISA_ADDRESS => self.get_misa(),
+			CSR_MSTATUS_ADDRESS => self.get_mstatus(),
+			CSR_USTATUS_ADDRESS => self.csr[CSR_MSTATUS_ADDRESS as usize] & USTATUS_MASK,
+			CSR_SSTATUS_ADDRESS => self.get_mstatus() & match self.xlen {
+				Xlen::Bit32 => SSTATUS_MASK_32,
+				Xlen::Bit64 => SSTATUS_MASK_64
+			},
+			// sie and sip are views of the bits of mie and mip delegated to supervisor mode,
+			// and uie and uip are of the bits delegated further to user mode
+			CSR_SIE_ADDRESS => self.csr[CSR_MIE_ADDRESS as usize] & self.csr[CSR_MIDELEG_ADDRESS as usize],
+			CSR_SIP_ADDRESS => self.csr[CSR_MIP_ADDRESS as usize] & self.csr[CSR_MIDELEG_ADDRESS as usize],
+			CSR_UIE_ADDRESS => self.csr[CSR_MIE_ADDRESS as usize] & self.get_user_interrupt_mask(),
+			CSR_UIP_ADDRESS => self.csr[CSR_MIP_ADDRESS as usize] & self.get_user_interrupt_mask(),
+			CSR_FRM_ADDRESS => (self.csr[CSR_FCSR_ADDRESS as usize] >> 5) & 0x7,
+			// time reads mtime of CLINT
+			CSR_CYCLE_ADDRESS | CSR_MCYCLE_ADDRESS => self.clock,
+			CSR_TIME_ADDRESS => self.mmu.read_mtime(),
+			CSR_INSTRET_ADDRESS | CSR_MINSTRET_ADDRESS => self.instret,
+			CSR_CYCLEH_ADDRESS | CSR_MCYCLEH_ADDRESS => self.clock >> 32,
+			CSR_TIMEH_ADDRESS => self.mmu.read_mtime() >> 32,
+			CSR_INSTRETH_ADDRESS | CSR_MINSTRETH_ADDRESS => self.instret >> 32,
+			CSR_PMPCFG0_ADDRESS..=CSR_PMPCFG3_ADDRESS => match self.get_pmp_config_entries(address) {
+				Some((first, num)) => {
+					let mut value = 0;
+					for i in 0..num {
+						value |= (self.mmu.get_pmp_config(first + i) as u64) << (i * 8);
+					}
+					value
+				},
+				None => 0
+			},
+			CSR_PMPADDR0_ADDRESS..=CSR_PMPADDR15_ADDRESS => {
+				self.mmu.get_pmp_address((address - CSR_PMPADDR0_ADDRESS) as usize)
+			},
+			CSR_STIMECMP_ADDRESS | CSR_STIMECMPH_ADDRESS => {
+				self.read_csr_half(self.csr[CSR_STIMECMP_ADDRESS as usize], address == CSR_STIMECMPH_ADDRESS)
+			},
+			CSR_MENVCFG_ADDRESS | CSR_MENVCFGH_ADDRESS => {
+				self.read_csr_half(self.csr[CSR_MENVCFG_ADDRESS as usize], address == CSR_MENVCFGH_ADDRESS)
+			},
+			_ => self.csr[address as usize]
+		}
+	}
+
+	fn write_csr(&mut self, address: u16, value: u64) -> Result<(), Trap> {
+		// println!("PC:{:X} Privilege mode:{}", self.pc.wrapping_sub(4), _get_privilege_mode_name(&self.privilege_mode));
+		// println!("CSR:{:X} Value:{:X}", address, value);
+		match self.has_csr_access_privilege(address) {
+			true => {
+				// CSRs whose top two address bits are 0b11 are read-only
+				if ((address >> 10) & 0x3) == 0x3 {
+					return Err(Trap {
+						trap_type: TrapType::IllegalInstruction,
+						value: self.instruction_word as u64
+					});
+				}
+				match address {
+					CSR_FFLAGS_ADDRESS => {
+						let fcsr = self.csr[CSR_FCSR_ADDRESS as usize];
+						self.csr[CSR_FCSR_ADDRESS as usize] = (fcsr & !0x1f) | (value & 0x1f);
+						self.mark_fs_dirty();
+					},
+					CSR_FRM_ADDRESS => {
+						let fcsr = self.csr[CSR_FCSR_ADDRESS as usize];
+						self.csr[CSR_FCSR_ADDRESS as usize] = (fcsr & !0xe0) | ((value & 0x7) << 5);
+						self.mark_fs_dirty();
+					},
+					CSR_FCSR_ADDRESS => {
+						self.csr[CSR_FCSR_ADDRESS as usize] = value & 0xff;
+						self.mark_fs_dirty();
+					},
+					CSR_MCYCLE_ADDRESS => {
+						self.clock = value;
+					},
+					CSR_MINSTRET_ADDRESS => {
+						self.instret = value;
+					},
+					CSR_MSTATUS_ADDRESS => {
+						// MPP[12:11] is WARL. The reserved encoding 2 keeps the current mode.
+						let mstatus = self.csr[address as usize];
+						let value = (mstatus & !MSTATUS_WRITABLE_MASK) | (value & MSTATUS_WRITABLE_MASK);
+						self.csr[address as usize] = match (value >> 11) & 0x3 {
+							2 => (value & !0x1800) | (mstatus & 0x1800),
+							_ => value
+						};
+					},
+					CSR_MISA_ADDRESS => {
+						// WARL. Only A, C, and M can be switched. C can't be disabled
+						// if the next instruction isn't 32-bit aligned.
+						let misa = self.csr[address as usize];
+						let mut writable_mask = MISA_WRITABLE_MASK;
+						if (self.pc & 0x2) != 0 {
+							writable_mask &= !MISA_C;
+						}
+						self.csr[address as usize] = (misa & !writable_mask) | (value & writable_mask);
+					},
+					CSR_MTVEC_ADDRESS | CSR_STVEC_ADDRESS => {
+						// MODE[1:0] is WARL. The reserved modes 2 and 3 fall back to direct mode.
+						self.csr[address as usize] = match value & 0x3 {
+							0 | 1 => value,
+							_ => value & !0x3
+						};
+					},
+					CSR_SATP_ADDRESS => {
+						// MODE is WARL. Writing an unsupported mode has no effect at all.
+						let supported = match self.xlen {
+							Xlen::Bit32 => true,
+							Xlen::Bit64 => match value >> 60 {
+								0 | 8 | 9 => true,
+								_ => false
+							}
+						};
+						if supported {
+							self.csr[address as usize] = value;
+							self.update_addressing_mode(value);
+						}
+					},
+					CSR_MIDELEG_ADDRESS => {
+						// Machine level interrupts can't be delegated
+						self.csr[address as usize] = value & !0x888;
+					},
+					CSR_MIP_ADDRESS => {
+						// MSIP[3], MTIP[7], SEIP[9], and MEIP[11] are driven by devices and read-only.
+						// So is STIP[5] driven by stimecmp while menvcfg.STCE is set.
+						let read_only_mask = match self.is_sstc_enabled() {
+							true => 0xaa8,
+							false => 0xa88
+						};
+						let mip = self.csr[address as usize];
+						self.csr[address as usize] = (mip & read_only_mask) | (value & !read_only_mask);
+					},
+					CSR_USTATUS_ADDRESS => {
+						let mstatus = self.csr[CSR_MSTATUS_ADDRESS as usize];
+						self.csr[CSR_MSTATUS_ADDRESS as usize] = (mstatus & !USTATUS_MASK) | (value & USTATUS_MASK);
+					},
+					CSR_SSTATUS_ADDRESS => {
+						let mstatus = self.csr[CSR_MSTATUS_ADDRESS as usize];
+						self.csr[CSR_MSTATUS_ADDRESS as usize] = (mstatus & !SSTATUS_WRITABLE_MASK) | (value & SSTATUS_WRITABLE_MASK);
+					},
+					CSR_SIE_ADDRESS => {
+						let mask = self.csr[CSR_MIDELEG_ADDRESS as usize];
+						let mie = self.csr[CSR_MIE_ADDRESS as usize];
+						self.csr[CSR_MIE_ADDRESS as usize] = (mie & !mask) | (value & mask);
+					},
+					CSR_SIP_ADDRESS => {
+						// Only SSIP[1] is writable through sip
+						let mask = self.csr[CSR_MIDELEG_ADDRESS as usize] & 0x2;
+						let mip = self.csr[CSR_MIP_ADDRESS as usize];
+						self.csr[CSR_MIP_ADDRESS as usize] = (mip & !mask) | (value & mask);
+					},
+					CSR_UIE_ADDRESS => {
+						let mask = self.get_user_interrupt_mask();
+						let mie = self.csr[CSR_MIE_ADDRESS as usize];
+						self.csr[CSR_MIE_ADDRESS as usize] = (mie & !mask) | (value & mask);
+					},
+					CSR_UIP_ADDRESS => {
+						// Only USIP[0] is writable through uip
+						let mask = self.get_user_interrupt_mask() & 0x1;
+						let mip = self.csr[CSR_MIP_ADDRESS as usize];
+						self.csr[CSR_MIP_ADDRESS as usize] = (mip & !mask) | (value & mask);
+					},
+					CSR_PMPCFG0_ADDRESS..=CSR_PMPCFG3_ADDRESS => {
+						let (first, num) = match self.get_pmp_config_entries(address) {
+							Some(entries) => entries,
+							None => return Err(Trap {
+								trap_type: TrapType::IllegalInstruction,
+								value: self.instruction_word as u64
+							})
+						};
+						for i in 0..num {
+							self.mmu.update_pmp_config(first + i, (value >> (i * 8)) as u8);
+						}
+					},
+					CSR_PMPADDR0_ADDRESS..=CSR_PMPADDR15_ADDRESS => {
+						let value = match self.xlen {
+							Xlen::Bit32 => value & 0xffffffff,
+							Xlen::Bit64 => value
+						};
+						self.mmu.update_pmp_address((address - CSR_PMPADDR0_ADDRESS) as usize, value);
+					},
+					CSR_STIMECMP_ADDRESS |
+					CSR_STIMECMPH_ADDRESS |
+					CSR_MENVCFG_ADDRESS |
+					CSR_MENVCFGH_ADDRESS => {
+						if !self.has_envcfg_access_privilege(address) {
+							return Err(Trap {
+								trap_type: TrapType::IllegalInstruction,
+								value: self.instruction_word as u64
+							});
+						}
+						match address {
+							CSR_STIMECMP_ADDRESS | CSR_STIMECMPH_ADDRESS => {
+								let stimecmp = self.csr[CSR_STIMECMP_ADDRESS as usize];
+								self.csr[CSR_STIMECMP_ADDRESS as usize] = self.write_csr_half(stimecmp, value, address == CSR_STIMECMPH_ADDRESS);
+							},
+							_ => {
+								let menvcfg = self.csr[CSR_MENVCFG_ADDRESS as usize];
+								let value = self.write_csr_half(menvcfg, value, address == CSR_MENVCFGH_ADDRESS);
+								self.csr[CSR_MENVCFG_ADDRESS as usize] = value & MENVCFG_WRITABLE_MASK;
+							}
+						};
+					},
+					_ => {
+						self.csr[address as usize] = value;
+					}
+				};
+				if address == CSR_MSTATUS_ADDRESS || address == CSR_SSTATUS_ADDRESS {
+					self.update_data_privilege_mode();
+				}
+				self.written_csr = Some((address, value));
+				Ok(())
+			},
+			false => Err(Trap {
+				trap_type: TrapType::IllegalInstruction,
+				value: self.instruction_word as u64
+			})
+		}
+	}
+
+	fn get_mstatus(&self) -> u64 {
+		let mstatus = self.csr[CSR_MSTATUS_ADDRESS as usize];
+		let dirty = (mstatus & MSTATUS_FS) == MSTATUS_FS ||
+			(mstatus & MSTATUS_VS) == MSTATUS_VS ||
+			(mstatus & MSTATUS_XS) == MSTATUS_XS;
+		let sd_bit = match self.xlen {
+			Xlen::Bit32 => 31,
+			Xlen::Bit64 => 63
+		};
+		let mstatus = (mstatus & !(1 << sd_bit)) | ((dirty as u64) << sd_bit);
+		match self.xlen {
+			Xlen::Bit32 => mstatus,
+			Xlen::Bit64 => mstatus | MSTATUS_UXL_64
+		}
+	}
+
+	// MXL is 1 in 32-bit mode and 2 in 64-bit mode
+	fn get_misa(&self) -> u64 {
+		match self.xlen {
+			Xlen::Bit32 => (1 << 30) | self.csr[CSR_MISA_ADDRESS as usize],
+			Xlen::Bit64 => (2 << 62) | self.csr[CSR_MISA_ADDRESS as usize]
+		}
+	}
+
+	// Services an SBI call in place of firmware. Extension ID is in a7,
+	// function ID in a6, and arguments from a0. Legacy extensions return
+	// a value in a0 and the others return an error in a0 and a value in a1.
+	fn handle_sbi_call(&mut self) {
+		let extension_id = self.unsigned_data(self.x[17]);
+		match extension_id {
+			SBI_EXT_LEGACY_SET_TIMER => {
+				let value = self.get_sbi_argument_u64(10);
+				self.mmu.update_mtimecmp(value);
+				self.x[10] = SBI_SUCCESS;
+			},
+			SBI_EXT_LEGACY_CONSOLE_PUTCHAR => {
+				self.mmu.put_uart_output(self.x[10] as u8);
+				self.x[10] = SBI_SUCCESS;
+			},
+			SBI_EXT_LEGACY_CONSOLE_GETCHAR => {
+				self.x[10] = match self.mmu.receive_uart_input() {
+					Some(value) => value as i64,
+					None => -1
+				};
+			},
+			SBI_EXT_LEGACY_SHUTDOWN => {
+				self.mmu.halt(0);
+			},
+			0x03..=0x0f => {
+				self.x[10] = SBI_ERR_NOT_SUPPORTED;
+			},
+			_ => {
+				let function_id = self.unsigned_data(self.x[16]);
+				let (error, value) = self.call_sbi_function(extension_id, function_id);
+				self.x[10] = error;
+				self.x[11] = self.sign_extend(value);
+			}
+		};
+	}
+
+	fn call_sbi_function(&mut self, extension_id: u64, function_id: u64) -> (i64, i64) {
+		match (extension_id, function_id) {
+			(SBI_EXT_BASE, 0) => (SBI_SUCCESS, SBI_SPEC_VERSION),
+			(SBI_EXT_BASE, 1) => (SBI_SUCCESS, SBI_IMPL_ID),
+			(SBI_EXT_BASE, 3) => { // probe_extension
+				let probed = match self.unsigned_data(self.x[10]) {
+					SBI_EXT_LEGACY_SET_TIMER |
+					SBI_EXT_LEGACY_CONSOLE_PUTCHAR |
+					SBI_EXT_LEGACY_CONSOLE_GETCHAR |
+					SBI_EXT_LEGACY_SHUTDOWN |
+					SBI_EXT_BASE |
+					SBI_EXT_TIME |
+					SBI_EXT_DBCN |
+					SBI_EXT_SRST => 1,
+					_ => 0
+				};
+				(SBI_SUCCESS, probed)
+			},
+			// Implementation version, mvendorid, marchid, and mimpid
+			(SBI_EXT_BASE, 2) |
+			(SBI_EXT_BASE, 4) |
+			(SBI_EXT_BASE, 5) |
+			(SBI_EXT_BASE, 6) => (SBI_SUCCESS, 0),
+			(SBI_EXT_TIME, 0) => { // set_timer
+				let value = self.get_sbi_argument_u64(10);
+				self.mmu.update_mtimecmp(value);
+				(SBI_SUCCESS, 0)
+			},
+			(SBI_EXT_DBCN, 0) => { // console_write
+				// Copied page by page not to allocate as much as the guest asks
+				let length = self.unsigned_data(self.x[10]);
+				let address = self.get_sbi_argument_u64(11);
+				let mut written = 0;
+				while written < length {
+					let mut data = vec![0; std::cmp::min(length - written, 4096) as usize];
+					match self.mmu.read_memory(address.wrapping_add(written), &mut data) {
+						Ok(()) => {},
+						Err(()) => break
+					};
+					for value in data.iter() {
+						self.mmu.put_uart_output(*value);
+					}
+					written += data.len() as u64;
+				}
+				match written == 0 && length > 0 {
+					true => (SBI_ERR_INVALID_PARAM, 0),
+					false => (SBI_SUCCESS, written as i64)
+				}
+			},
+			(SBI_EXT_DBCN, 1) => { // console_read
+				let length = self.unsigned_data(self.x[10]);
+				let address = self.get_sbi_argument_u64(11);
+				let mut data = vec![];
+				while (data.len() as u64) < length {
+					match self.mmu.receive_uart_input() {
+						Some(value) => data.push(value),
+						None => break
+					};
+				}
+				match self.mmu.write_memory(address, &data) {
+					Ok(()) => (SBI_SUCCESS, data.len() as i64),
+					Err(()) => (SBI_ERR_INVALID_PARAM, 0)
+				}
+			},
+			(SBI_EXT_DBCN, 2) => { // console_write_byte
+				self.mmu.put_uart_output(self.x[10] as u8);
+				(SBI_SUCCESS, 0)
+			},
+			(SBI_EXT_SRST, 0) => { // system_reset
+				match self.unsigned_data(self.x[10]) {
+					0 => { // Shutdown. Reason 1 is system failure.
+						let exit_code = match self.unsigned_data(self.x[11]) {
+							0 => 0,
+							_ => 1
+						};
+						self.mmu.halt(exit_code);
+						(SBI_SUCCESS, 0)
+					},
+					1 | 2 => (SBI_ERR_NOT_SUPPORTED, 0), // Cold and warm reboot
+					_ => (SBI_ERR_INVALID_PARAM, 0)
+				}
+			},
+			_ => (SBI_ERR_NOT_SUPPORTED, 0)
+		}
+	}
+
+	// 64-bit argument is passed in a pair of registers from the index
+	// in 32-bit mode, lower 32 bits first
+	fn get_sbi_argument_u64(&self, index: usize) -> u64 {
+		match self.xlen {
+			Xlen::Bit32 => self.unsigned_data(self.x[index]) | (self.unsigned_data(self.x[index + 1]) << 32),
+			Xlen::Bit64 => self.x[index] as u64
+		}
+	}
+
+	// Interrupts delegated to user mode, by both mideleg and sideleg
+	fn get_user_interrupt_mask(&self) -> u64 {
+		self.csr[CSR_MIDELEG_ADDRESS as usize] & self.csr[CSR_SIDELEG_ADDRESS as usize]
+	}
+
+	// LR, SC, and AMOs need naturally aligned address
+	fn check_atomic_alignment(&self, address: u64, width: u64, trap_type: TrapType) -> Result<(), Trap> {
+		match (address % width) == 0 {
+			true => Ok(()),
+			false => Err(Trap {
+				trap_type: trap_type,
+				value: address
+			})
+		}
+	}
+
+	// Returns the first PMP entry and the number of entries packed in
+	// the pmpcfg CSR. pmpcfg1 and pmpcfg3 don't exist in 64-bit mode.
+	fn get_pmp_config_entries(&self, address: u16) -> Option<(usize, usize)> {
+		let index = (address - CSR_PMPCFG0_ADDRESS) as usize;
+		match self.xlen {
+			Xlen::Bit32 => Some((index * 4, 4)),
+			Xlen::Bit64 => match index % 2 {
+				0 => Some((index * 4, 8)),
+				_ => None
+			}
+		}
+	}
+
+	fn update_addressing_mode(&mut self, value: u64) {
+		let addressing_mode = match self.xlen {
+			Xlen::Bit32 => match value & 0x80000000 {
+				0 => AddressingMode::None,
+				_ => AddressingMode::SV32
+			},
+			Xlen::Bit64 => match value >> 60 {
+				0 => AddressingMode::None,
+				8 => AddressingMode::SV39,
+				9 => AddressingMode::SV48,
+				_ => {
+					println!("Unknown addressing_mode {:X}", value >> 60);
+					panic!();
+				}
+			}
+		};
+		let ppn = match self.xlen {
+			Xlen::Bit32 => value & 0x3fffff,
+			Xlen::Bit64 => value & 0xfffffffffff
+		};
+		self.mmu.update_addressing_mode(addressing_mode);
+		self.mmu.update_ppn(ppn);
+	}
+
+	// Loads and stores in M-mode are translated and checked in the
+	// privilege mode of mstatus.MPP while mstatus.MPRV is set.
+	// SUM[18] and MXR[19] of mstatus, which page permission checks
+	// depend on, are passed to Mmu as well.
+	fn update_data_privilege_mode(&mut self) {
+		let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
+		self.mmu.update_sum_and_mxr(((status >> 18) & 1) == 1, ((status >> 19) & 1) == 1);
+		let mode = match self.privilege_mode {
+			PrivilegeMode::Machine if ((status >> 17) & 1) == 1 => match (status >> 11) & 0x3 {
+				0 => PrivilegeMode::User,
+				1 => PrivilegeMode::Supervisor,
+				_ => PrivilegeMode::Machine
+			},
+			_ => self.privilege_mode.clone()
+		};
+		self.mmu.update_data_privilege_mode(mode);
+	}
+
+	// @TODO: Rename to better name?
+	fn sign_extend(&self, value: i64) -> i64 {
+		match self.xlen {
+			Xlen::Bit32 => (match value & 0x80000000 {
+				0x80000000 => (value as u64) | 0xffffffff00000000,
+				_ => (value as u64) & 0xffffffff
+			}) as i64,
+			Xlen::Bit64 => value
+		}
+	}
+
+	// @TODO: Rename to better name?
+	fn unsigned_data(&self, value: i64) -> u64 {
+		match self.xlen {
+			Xlen::Bit32 => (value as u64) & 0xffffffff,
+			Xlen::Bit64 => value as u64
+		}
+	}
+
+	// The most negative signed value of XLEN bits, sign extended
+	fn get_most_negative(&self) -> i64 {
+		match self.xlen {
+			Xlen::Bit32 => i32::MIN as i64,
+			Xlen::Bit64 => i64::MIN
+		}
+	}
+
+	// The guest OS checks FS[14:13] in status to decide
+	// whether it needs to save the floating point state.
+	// SD follows FS when mstatus is read.
+	fn mark_fs_dirty(&mut self) {
+		self.csr[CSR_MSTATUS_ADDRESS as usize] |= MSTATUS_FS;
+	}
+
+	fn is_fp_enabled(&self) -> bool {
+		(self.csr[CSR_MSTATUS_ADDRESS as usize] & MSTATUS_FS) != 0
+	}
+
+	// Not properly NaN-boxed value is read as canonical NaN
+	fn read_f32(&self, r: u32) -> f32 {
+		let bits = self.f[r as usize];
+		match bits >> 32 {
+			0xffffffff => f32::from_bits(bits as u32),
+			_ => f32::from_bits(CANONICAL_NAN_F32)
+		}
+	}
+
+	fn read_f64(&self, r: u32) -> f64 {
+		f64::from_bits(self.f[r as usize])
+	}
+
+	fn write_f32_bits(&mut self, r: u32, bits: u32) {
+		self.f[r as usize] = 0xffffffff00000000 | (bits as u64);
+		self.mark_fs_dirty();
+	}
+
+	fn write_f64_bits(&mut self, r: u32, bits: u64) {
+		self.f[r as usize] = bits;
+		self.mark_fs_dirty();
+	}
+
+	// Arithmetic results write canonical NaN instead of any NaN
+	fn write_f32(&mut self, r: u32, value: f32) {
+		let bits = match value.is_nan() {
+			true => CANONICAL_NAN_F32,
+			false => value.to_bits()
+		};
+		self.write_f32_bits(r, bits);
+	}
+
+	fn write_f64(&mut self, r: u32, value: f64) {
+		let bits = match value.is_nan() {
+			true => CANONICAL_NAN_F64,
+			false => value.to_bits()
+		};
+		self.write_f64_bits(r, bits);
+	}
+
+	fn update_fflags(&mut self, flags: u64) {
+		self.csr[CSR_FCSR_ADDRESS as usize] |= flags;
+		self.mark_fs_dirty();
+	}
+
+	// Invalid operation for signaling NaN operand or NaN result from non-NaN operands
+	fn update_fflags_f32(&mut self, operands: &[f32], result: f32) {
+		let signaling = operands.iter().any(|value| is_signaling_nan_f32(*value));
+		let invalid = result.is_nan() && !operands.iter().any(|value| value.is_nan());
+		if signaling || invalid {
+			self.update_fflags(FFLAGS_NV);
+		}
+	}
+
+	// Invalid operation for signaling NaN operand or NaN result from non-NaN operands
+	fn update_fflags_f64(&mut self, operands: &[f64], result: f64) {
+		let signaling = operands.iter().any(|value| is_signaling_nan_f64(*value));
+		let invalid = result.is_nan() && !operands.iter().any(|value| value.is_nan());
+		if signaling || invalid {
+			self.update_fflags(FFLAGS_NV);
+		}
+	}
+
+	// Overflow and underflow are raised with inexact. Tininess is detected
+	// after rounding.
+	fn update_inexact_fflags_f32(&mut self, result: f32, inexact: bool) {
+		if inexact {
+			self.update_fflags(FFLAGS_NX | match result.is_infinite() {
+				true => FFLAGS_OF,
+				false => match result.abs() < f32::MIN_POSITIVE {
+					true => FFLAGS_UF,
+					false => 0
+				}
+			});
+		}
+	}
+
+	fn update_inexact_fflags_f64(&mut self, result: f64, inexact: bool) {
+		if inexact {
+			self.update_fflags(FFLAGS_NX | match result.is_infinite() {
+				true => FFLAGS_OF,
+				false => match result.abs() < f64::MIN_POSITIVE {
+					true => FFLAGS_UF,
+					false => 0
+				}
+			});
+		}
+	}
+
+	// rm field 7 means dynamic rounding mode held in frm
+	fn get_rounding_mode(&self, word: u32) -> Result<u32, Trap> {
+		let rm = match (word >> 12) & 0x7 { // [14:12]
+			7 => ((self.csr[CSR_FCSR_ADDRESS as usize] >> 5) & 0x7) as u32,
+			rm => rm
+		};
+		match rm {
+			0..=4 => Ok(rm),
+			_ => Err(Trap {
+				trap_type: TrapType::IllegalInstruction,
+				value: self.instruction_word as u64
+			})
+		}
+	}
+
+	fn uncompress(&self, halfword: u32) -> u32 {
+		uncompress(halfword, &self.xlen)
+	}
+
+	fn decode(&mut self, word: u32) -> Result<Instruction, ()> {
+		if !self.is_extension_enabled(word) {
+			return Err(());
+		}
+		decode(word, &self.xlen, self.zba_enabled, self.zbb_enabled)
+	}
+
+	// Instructions of the extensions disabled in misa are illegal
+	fn is_extension_enabled(&self, word: u32) -> bool {
+		let misa = self.csr[CSR_MISA_ADDRESS as usize];
+		let opcode = word & 0x7f;
+		let funct7 = (word >> 25) & 0x7f;
+		match opcode {
+			0x2f => (misa & MISA_A) != 0,
+			// Floating point loads, stores, fused multiply-adds, and the others
+			0x07 | 0x27 | 0x43 | 0x47 | 0x4b | 0x4f | 0x53 => self.is_fp_enabled(),
+			0x33 | 0x3b if funct7 == 1 => (misa & MISA_M) != 0,
+			_ => true
+		}
+	}
+
+	fn operate(&mut self, word: u32, instruction: Instruction, instruction_address: u64) -> Result<(), Trap> {
+		let instruction_format = get_instruction_format(&instruction);
+		let InstructionFields { rd, rs1, rs2, rs3, imm, .. } = get_instruction_fields(word, &instruction_format);
+		// Reserved rounding mode is illegal even if the result is exact
+		if uses_rounding_mode(&instruction) {
+			match self.get_rounding_mode(word) {
+				Ok(_) => {},
+				Err(e) => return Err(e)
+			};
+		}
+		match instruction_format {
+			InstructionFormat::B => {
+				let imm = imm as u64;
+				// println!("Compare {:X} {:X}", self.x[rs1 as usize], self.x[rs2 as usize]);
+				match instruction {
+					Instruction::BEQ => {
+						if self.sign_extend(self.x[rs1 as usize]) == self.sign_extend(self.x[rs2 as usize]) {
+							self.update_pc(instruction_address.wrapping_add(imm));
+						}
+					},
+					Instruction::BGE => {
+						if self.sign_extend(self.x[rs1 as usize]) >= self.sign_extend(self.x[rs2 as usize]) {
+							self.update_pc(instruction_address.wrapping_add(imm));
+						}
+					},
+					Instruction::BGEU => {
+						if self.unsigned_data(self.x[rs1 as usize]) >= self.unsigned_data(self.x[rs2 as usize]) {
+							self.update_pc(instruction_address.wrapping_add(imm));
+						}
+					},
+					Instruction::BLT => {
+						if self.sign_extend(self.x[rs1 as usize]) < self.sign_extend(self.x[rs2 as usize]) {
+							self.update_pc(instruction_address.wrapping_add(imm));
+						}
+					},
+					Instruction::BLTU => {
+						if self.unsigned_data(self.x[rs1 as usize]) < self.unsigned_data(self.x[rs2 as usize]) {
+							self.update_pc(instruction_address.wrapping_add(imm));
+						}
+					},
+					Instruction::BNE => {
+						if self.sign_extend(self.x[rs1 as usize]) != self.sign_extend(self.x[rs2 as usize]) {
+							self.update_pc(instruction_address.wrapping_add(imm));
+						}
+					},
+					_ => return self.handle_unimplemented(&instruction, instruction_address)
+				};
+			},
+			InstructionFormat::C => {
+				let csr = imm as u16;
+				let rs = rs1;
+				// @TODO: Don't write if csr bits aren't writable
+				match instruction {
+					// CSRRC, CSRRCI, CSRRS, and CSRRSI don't write CSR if rs1 is x0
+					// or uimm is zero, so that read-only CSR can be read by them.
+					// rd is updated only after the write succeeds.
+					Instruction::CSRRC => {
+						let data = match self.read_csr(csr) {
+							Ok(data) => data,
+							Err(e) => return Err(e)
+						};
+						if rs != 0 {
+							let mask = self.x[rs as usize] as u64;
+							match self.write_csr(csr, self.unsigned_data((data & !mask) as i64)) {
+								Ok(()) => {},
+								Err(e) => return Err(e)
+							};
+						}
+						self.x[rd as usize] = self.sign_extend(data as i64);
+						self.x[0] = 0; // hard-wired zero
+					},
+					Instruction::CSRRCI => {
+						let data = match self.read_csr(csr) {
+							Ok(data) => data,
+							Err(e) => return Err(e)
+						};
+						if rs != 0 {
+							match self.write_csr(csr, self.unsigned_data((data & !(rs as u64)) as i64)) {
+								Ok(()) => {},
+								Err(e) => return Err(e)
+							};
+						}
+						self.x[rd as usize] = self.sign_extend(data as i64);
+						self.x[0] = 0; // hard-wired zero
+					},
+					Instruction::CSRRS => {
+						let data = match self.read_csr(csr) {
+							Ok(data) => data,
+							Err(e) => return Err(e)
+						};
+						if rs != 0 {
+							let mask = self.x[rs as usize] as u64;
+							match self.write_csr(csr, self.unsigned_data((data | mask) as i64)) {
+								Ok(()) => {},
+								Err(e) => return Err(e)
+							};
+						}
+						self.x[rd as usize] = self.sign_extend(data as i64);
+						self.x[0] = 0; // hard-wired zero
+					},
+					Instruction::CSRRSI => {
+						let data = match self.read_csr(csr) {
+							Ok(data) => data,
+							Err(e) => return Err(e)
+						};
+						if rs != 0 {
+							match self.write_csr(csr, self.unsigned_data((data | rs as u64) as i64)) {
+								Ok(()) => {},
+								Err(e) => return Err(e)
+							};
+						}
+						self.x[rd as usize] = self.sign_extend(data as i64);
+						self.x[0] = 0; // hard-wired zero
+					},
+					Instruction::CSRRW => {
+						let data = match self.read_csr(csr) {
+							Ok(data) => data,
+							Err(e) => return Err(e)
+						};
+						match self.write_csr(csr, self.unsigned_data(self.x[rs as usize])) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+						self.x[rd as usize] = self.sign_extend(data as i64);
+						self.x[0] = 0; // hard-wired zero
+					},
+					Instruction::CSRRWI => {
+						let data = match self.read_csr(csr) {
+							Ok(data) => data,
+							Err(e) => return Err(e)
+						};
+						match self.write_csr(csr, rs as u64) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+						self.x[rd as usize] = self.sign_extend(data as i64);
+						self.x[0] = 0; // hard-wired zero
+					},
+					_ => return self.handle_unimplemented(&instruction, instruction_address)
+				};
+			},
+			InstructionFormat::I => {
+				match instruction {
+					Instruction::ADDI => {
+						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize].wrapping_add(imm));
+					},
+					Instruction::ADDIW => {
+						self.x[rd as usize] = self.x[rs1 as usize].wrapping_add(imm) as i32 as i64;
+					},
+					Instruction::ANDI => {
+						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] & imm);
+					},
+					Instruction::FLD => {
+						let data = match self.mmu.load_doubleword(self.x[rs1 as usize].wrapping_add(imm) as u64) {
+							Ok(data) => data,
+							Err(e) => return Err(e)
+						};
+						self.write_f64_bits(rd, data);
+					},
+					Instruction::FLW => {
+						let data = match self.mmu.load_word(self.x[rs1 as usize].wrapping_add(imm) as u64) {
+							Ok(data) => data,
+							Err(e) => return Err(e)
+						};
+						self.write_f32_bits(rd, data);
+					},
+					Instruction::JALR => {
+						let tmp = self.sign_extend(self.pc as i64);
+						let target = (self.x[rs1 as usize] as u64).wrapping_add(imm as u64);
+						self.update_pc(target);
 						self.x[rd as usize] = tmp;
 					},
 					Instruction::LB => {
@@ -1611,6 +4619,15 @@ impl Cpu {
 					Instruction::ORI => {
 						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] | imm);
 					},
+					Instruction::RORI => {
+						self.x[rd as usize] = match self.xlen {
+							Xlen::Bit32 => (self.x[rs1 as usize] as u32).rotate_right((imm & 0x1f) as u32) as i32 as i64,
+							Xlen::Bit64 => (self.x[rs1 as usize] as u64).rotate_right((imm & 0x3f) as u32) as i64
+						};
+					},
+					Instruction::RORIW => {
+						self.x[rd as usize] = (self.x[rs1 as usize] as u32).rotate_right((imm & 0x1f) as u32) as i32 as i64;
+					},
 					Instruction::SLLI => {
 						let shamt = (imm & match self.xlen {
 							Xlen::Bit32 => 0x1f,
@@ -1618,6 +4635,10 @@ impl Cpu {
 						}) as u32;
 						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] << shamt);
 					},
+					Instruction::SLLIUW => {
+						let shamt = (imm & 0x3f) as u32;
+						self.x[rd as usize] = ((self.x[rs1 as usize] as u32 as u64) << shamt) as i64;
+					},
 					Instruction::SLLIW => {
 						let shamt = (imm as u32) & 0x1f;
 						self.x[rd as usize] = (self.x[rs1 as usize] << shamt) as i32 as i64;
@@ -1659,76 +4680,118 @@ impl Cpu {
 					Instruction::XORI => {
 						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] ^ imm);
 					},
-					_ => {
-						println!("{}", get_instruction_name(&instruction).to_owned() + " instruction is not supported yet.");
-						self.dump_instruction(instruction_address);
-						panic!();
-					}
+					_ => return self.handle_unimplemented(&instruction, instruction_address)
 				};
 			},
 			InstructionFormat::J => {
-				let rd = (word >> 7) & 0x1f; // [11:7]
-				let imm = (
-					match word & 0x80000000 { // imm[31:20] = [31]
-						0x80000000 => 0xfff00000,
-						_ => 0
-					} |
-					(word & 0x000ff000) | // imm[19:12] = [19:12]
-					((word & 0x00100000) >> 9) | // imm[11] = [20]
-					((word & 0x7fe00000) >> 20) // imm[10:1] = [30:21]
-				) as i32 as i64 as u64;
+				let imm = imm as u64;
 				match instruction {
 					Instruction::JAL => {
 						self.x[rd as usize] = self.sign_extend(self.pc as i64);
-						self.pc = instruction_address.wrapping_add(imm);
+						self.update_pc(instruction_address.wrapping_add(imm));
 					},
-					_ => {
-						println!("{}", get_instruction_name(&instruction).to_owned() + " instruction is not supported yet.");
-						self.dump_instruction(instruction_address);
-						panic!();
-					}
+					_ => return self.handle_unimplemented(&instruction, instruction_address)
 				};
 			},
 			InstructionFormat::O => {
 				match instruction {
-					Instruction::FENCE => {
-						// @TODO: Implement
+					Instruction::FENCE |
+					Instruction::FENCETSO => {
+						self.mmu.fence(&get_fence_ordering(word));
 					},
-					_ => {
-						println!("{}", get_instruction_name(&instruction).to_owned() + " instruction is not supported yet.");
-						self.dump_instruction(instruction_address);
-						panic!();
-					}
+					Instruction::FENCEI => {
+						// Stores so far must be visible to the following
+						// instruction fetches of this hart. Words are fetched
+						// from memory every time and the decode cache is keyed
+						// by the instruction bits (see decode_cache.rs), so only
+						// the translations cached for fetch need to be dropped.
+						self.mmu.flush_instruction_tlb();
+					},
+					_ => return self.handle_unimplemented(&instruction, instruction_address)
 				};
 			},
 			InstructionFormat::R => {
-				let rd = (word >> 7) & 0x1f; // [11:7]
-				let rs1 = (word >> 15) & 0x1f; // [19:15]
-				let rs2 = (word >> 20) & 0x1f; // [24:20]
 				match instruction {
 					Instruction::ADD => {
 						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize].wrapping_add(self.x[rs2 as usize]));
 					},
+					// Zba .uw forms zero-extend the lower 32 bits of rs1, so that
+					// an unsigned 32-bit index with bit 31 set doesn't become negative
+					Instruction::ADDUW => {
+						self.x[rd as usize] = (self.x[rs1 as usize] as u32 as i64).wrapping_add(self.x[rs2 as usize]);
+					},
 					Instruction::ADDW => {
 						self.x[rd as usize] = self.x[rs1 as usize].wrapping_add(self.x[rs2 as usize]) as i32 as i64;
 					},
-					Instruction::AMOADDW => {
-						let tmp = match self.mmu.load_word(self.unsigned_data(self.x[rs1 as usize])) {
+					Instruction::AMOADDD |
+					Instruction::AMOANDD |
+					Instruction::AMOMAXD |
+					Instruction::AMOMAXUD |
+					Instruction::AMOMIND |
+					Instruction::AMOMINUD |
+					Instruction::AMOORD |
+					Instruction::AMOSWAPD |
+					Instruction::AMOXORD => {
+						let address = self.unsigned_data(self.x[rs1 as usize]);
+						match self.check_atomic_alignment(address, 8, TrapType::StoreAddressMisaligned) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+						let tmp = match self.mmu.load_doubleword(address) {
 							Ok(data) => data,
 							Err(e) => return Err(e)
 						};
-						match self.mmu.store_word(self.unsigned_data(self.x[rs1 as usize]), self.x[rs2 as usize].wrapping_add(tmp as i64) as u32) {
+						let value = self.x[rs2 as usize] as u64;
+						let result = match instruction {
+							Instruction::AMOADDD => tmp.wrapping_add(value),
+							Instruction::AMOANDD => tmp & value,
+							Instruction::AMOMAXD => (tmp as i64).max(value as i64) as u64,
+							Instruction::AMOMAXUD => tmp.max(value),
+							Instruction::AMOMIND => (tmp as i64).min(value as i64) as u64,
+							Instruction::AMOMINUD => tmp.min(value),
+							Instruction::AMOORD => tmp | value,
+							Instruction::AMOSWAPD => value,
+							Instruction::AMOXORD => tmp ^ value,
+							_ => panic!() // shouldn't happen
+						};
+						match self.mmu.store_doubleword(address, result) {
 							Ok(()) => {},
 							Err(e) => return Err(e)
 						};
-						self.x[rd as usize] = tmp as i32 as i64;
+						self.x[rd as usize] = tmp as i64;
 					},
-					Instruction::AMOSWAPW => {
-						let tmp = match self.mmu.load_word(self.unsigned_data(self.x[rs1 as usize])) {
+					Instruction::AMOADDW |
+					Instruction::AMOANDW |
+					Instruction::AMOMAXW |
+					Instruction::AMOMAXUW |
+					Instruction::AMOMINW |
+					Instruction::AMOMINUW |
+					Instruction::AMOORW |
+					Instruction::AMOSWAPW |
+					Instruction::AMOXORW => {
+						let address = self.unsigned_data(self.x[rs1 as usize]);
+						match self.check_atomic_alignment(address, 4, TrapType::StoreAddressMisaligned) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+						let tmp = match self.mmu.load_word(address) {
 							Ok(data) => data,
 							Err(e) => return Err(e)
 						};
-						match self.mmu.store_word(self.unsigned_data(self.x[rs1 as usize]), self.x[rs2 as usize] as u32) {
+						let value = self.x[rs2 as usize] as u32;
+						let result = match instruction {
+							Instruction::AMOADDW => tmp.wrapping_add(value),
+							Instruction::AMOANDW => tmp & value,
+							Instruction::AMOMAXW => (tmp as i32).max(value as i32) as u32,
+							Instruction::AMOMAXUW => tmp.max(value),
+							Instruction::AMOMINW => (tmp as i32).min(value as i32) as u32,
+							Instruction::AMOMINUW => tmp.min(value),
+							Instruction::AMOORW => tmp | value,
+							Instruction::AMOSWAPW => value,
+							Instruction::AMOXORW => tmp ^ value,
+							_ => panic!() // shouldn't happen
+						};
+						match self.mmu.store_word(address, result) {
 							Ok(()) => {},
 							Err(e) => return Err(e)
 						};
@@ -1737,71 +4800,692 @@ impl Cpu {
 					Instruction::AND => {
 						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] & self.x[rs2 as usize]);
 					},
+					Instruction::ANDN => {
+						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] & !self.x[rs2 as usize]);
+					},
+					Instruction::CLZ => {
+						self.x[rd as usize] = match self.xlen {
+							Xlen::Bit32 => (self.x[rs1 as usize] as u32).leading_zeros(),
+							Xlen::Bit64 => (self.x[rs1 as usize] as u64).leading_zeros()
+						} as i64;
+					},
+					Instruction::CLZW => {
+						self.x[rd as usize] = (self.x[rs1 as usize] as u32).leading_zeros() as i64;
+					},
+					Instruction::CPOP => {
+						self.x[rd as usize] = self.unsigned_data(self.x[rs1 as usize]).count_ones() as i64;
+					},
+					Instruction::CPOPW => {
+						self.x[rd as usize] = (self.x[rs1 as usize] as u32).count_ones() as i64;
+					},
+					Instruction::CTZ => {
+						self.x[rd as usize] = match self.xlen {
+							Xlen::Bit32 => (self.x[rs1 as usize] as u32).trailing_zeros(),
+							Xlen::Bit64 => (self.x[rs1 as usize] as u64).trailing_zeros()
+						} as i64;
+					},
+					Instruction::CTZW => {
+						self.x[rd as usize] = (self.x[rs1 as usize] as u32).trailing_zeros() as i64;
+					},
+					Instruction::CZEROEQZ => {
+						self.x[rd as usize] = match self.x[rs2 as usize] {
+							0 => 0,
+							_ => self.x[rs1 as usize]
+						};
+					},
+					Instruction::CZERONEZ => {
+						self.x[rd as usize] = match self.x[rs2 as usize] {
+							0 => self.x[rs1 as usize],
+							_ => 0
+						};
+					},
 					Instruction::DIV => {
+						let dividend = self.x[rs1 as usize];
+						let divisor = self.x[rs2 as usize];
+						self.x[rd as usize] = match divisor {
+							0 => -1,
+							// Signed overflow, the most negative value divided by -1, results in the dividend
+							-1 if dividend == self.get_most_negative() => dividend,
+							_ => self.sign_extend(dividend.wrapping_div(divisor))
+						};
+					},
+					Instruction::DIVU => {
 						self.x[rd as usize] = match self.x[rs2 as usize] {
 							0 => -1,
-							_ => self.sign_extend(self.x[rs1 as usize].wrapping_div(self.x[rs2 as usize]))
+							_ => self.sign_extend(self.unsigned_data(self.x[rs1 as usize]).wrapping_div(self.unsigned_data(self.x[rs2 as usize])) as i64)
+						};
+					},
+					// Word operations see only the lower 32 bits of the divisor, too
+					Instruction::DIVUW => {
+						let dividend = self.x[rs1 as usize] as u32;
+						let divisor = self.x[rs2 as usize] as u32;
+						self.x[rd as usize] = match divisor {
+							0 => -1,
+							_ => dividend.wrapping_div(divisor) as i32 as i64
+						};
+					},
+					Instruction::DIVW => {
+						let dividend = self.x[rs1 as usize] as i32;
+						let divisor = self.x[rs2 as usize] as i32;
+						self.x[rd as usize] = match divisor {
+							0 => -1,
+							-1 if dividend == i32::MIN => dividend as i64,
+							_ => self.sign_extend(dividend.wrapping_div(divisor) as i64)
+						};
+					},
+					Instruction::EBREAK => {
+						return Err(Trap {
+							trap_type: TrapType::Breakpoint,
+							value: instruction_address
+						});
+					},
+					Instruction::ECALL => {
+						if self.sbi_enabled && self.privilege_mode == PrivilegeMode::Supervisor {
+							self.handle_sbi_call();
+							return Ok(());
+						}
+						let exception_type = match self.privilege_mode {
+							PrivilegeMode::User => TrapType::EnvironmentCallFromUMode,
+							PrivilegeMode::Supervisor => TrapType::EnvironmentCallFromSMode,
+							PrivilegeMode::Machine => TrapType::EnvironmentCallFromMMode,
+							PrivilegeMode::Reserved => panic!()
+						};
+						// tval is zero for environment calls
+						return Err(Trap {
+							trap_type: exception_type,
+							value: 0
+						});
+					},
+					Instruction::FADDD => {
+						let (a, b) = (self.read_f64(rs1), self.read_f64(rs2));
+						let result = a + b;
+						self.update_fflags_f64(&[a, b], result);
+						self.update_inexact_fflags_f64(result, is_inexact_sum_f64(a, b, result));
+						self.write_f64(rd, result);
+					},
+					Instruction::FADDS => {
+						let (a, b) = (self.read_f32(rs1), self.read_f32(rs2));
+						let result = a + b;
+						self.update_fflags_f32(&[a, b], result);
+						self.update_inexact_fflags_f32(result, is_inexact_sum_f32(a, b, result));
+						self.write_f32(rd, result);
+					},
+					Instruction::FCLASSD => {
+						let a = self.read_f64(rs1);
+						self.x[rd as usize] = get_fclass(a.classify(), a.is_sign_negative(), is_signaling_nan_f64(a));
+					},
+					Instruction::FCLASSS => {
+						let a = self.read_f32(rs1);
+						self.x[rd as usize] = get_fclass(a.classify(), a.is_sign_negative(), is_signaling_nan_f32(a));
+					},
+					Instruction::FCVTDL => {
+						let rm = match self.get_rounding_mode(word) {
+							Ok(rm) => rm,
+							Err(e) => return Err(e)
+						};
+						let (result, inexact) = convert_integer_f64(self.x[rs1 as usize] as i128, rm);
+						self.update_inexact_fflags_f64(result, inexact);
+						self.write_f64(rd, result);
+					},
+					Instruction::FCVTDLU => {
+						let rm = match self.get_rounding_mode(word) {
+							Ok(rm) => rm,
+							Err(e) => return Err(e)
+						};
+						let (result, inexact) = convert_integer_f64(self.x[rs1 as usize] as u64 as i128, rm);
+						self.update_inexact_fflags_f64(result, inexact);
+						self.write_f64(rd, result);
+					},
+					Instruction::FCVTDS => {
+						let a = self.read_f32(rs1);
+						let result = a as f64;
+						self.update_fflags_f64(&[], match is_signaling_nan_f32(a) {
+							true => f64::NAN,
+							false => 0.0
+						});
+						self.write_f64(rd, result);
+					},
+					Instruction::FCVTDW => {
+						self.write_f64(rd, self.x[rs1 as usize] as i32 as f64);
+					},
+					Instruction::FCVTDWU => {
+						self.write_f64(rd, self.x[rs1 as usize] as u32 as f64);
+					},
+					Instruction::FCVTLD |
+					Instruction::FCVTLS => {
+						let value = match instruction {
+							Instruction::FCVTLS => self.read_f32(rs1) as f64,
+							_ => self.read_f64(rs1)
+						};
+						let rm = match self.get_rounding_mode(word) {
+							Ok(rm) => rm,
+							Err(e) => return Err(e)
+						};
+						let rounded = round_to_integral(value, rm);
+						self.x[rd as usize] = match rounded.is_nan() || rounded < -9223372036854775808.0 || rounded >= 9223372036854775808.0 {
+							true => {
+								self.update_fflags(FFLAGS_NV);
+								match rounded < 0.0 {
+									true => i64::MIN,
+									false => i64::MAX
+								}
+							},
+							false => {
+								if rounded != value {
+									self.update_fflags(FFLAGS_NX);
+								}
+								rounded as i64
+							}
+						};
+					},
+					Instruction::FCVTLUD |
+					Instruction::FCVTLUS => {
+						let value = match instruction {
+							Instruction::FCVTLUS => self.read_f32(rs1) as f64,
+							_ => self.read_f64(rs1)
 						};
+						let rm = match self.get_rounding_mode(word) {
+							Ok(rm) => rm,
+							Err(e) => return Err(e)
+						};
+						let rounded = round_to_integral(value, rm);
+						self.x[rd as usize] = match rounded.is_nan() || rounded < 0.0 || rounded >= 18446744073709551616.0 {
+							true => {
+								self.update_fflags(FFLAGS_NV);
+								match rounded < 0.0 {
+									true => 0,
+									false => u64::MAX as i64
+								}
+							},
+							false => {
+								if rounded != value {
+									self.update_fflags(FFLAGS_NX);
+								}
+								rounded as u64 as i64
+							}
+						};
+					},
+					Instruction::FCVTSD => {
+						let a = self.read_f64(rs1);
+						let result = a as f32;
+						self.update_fflags_f64(&[a], result as f64);
+						self.update_inexact_fflags_f32(result, a.is_finite() && result as f64 != a);
+						self.write_f32(rd, result);
+					},
+					Instruction::FCVTSL => {
+						let rm = match self.get_rounding_mode(word) {
+							Ok(rm) => rm,
+							Err(e) => return Err(e)
+						};
+						let (result, inexact) = convert_integer_f32(self.x[rs1 as usize] as i128, rm);
+						self.update_inexact_fflags_f32(result, inexact);
+						self.write_f32(rd, result);
+					},
+					Instruction::FCVTSLU => {
+						let rm = match self.get_rounding_mode(word) {
+							Ok(rm) => rm,
+							Err(e) => return Err(e)
+						};
+						let (result, inexact) = convert_integer_f32(self.x[rs1 as usize] as u64 as i128, rm);
+						self.update_inexact_fflags_f32(result, inexact);
+						self.write_f32(rd, result);
+					},
+					Instruction::FCVTSW => {
+						let rm = match self.get_rounding_mode(word) {
+							Ok(rm) => rm,
+							Err(e) => return Err(e)
+						};
+						let (result, inexact) = convert_integer_f32(self.x[rs1 as usize] as i32 as i128, rm);
+						self.update_inexact_fflags_f32(result, inexact);
+						self.write_f32(rd, result);
+					},
+					Instruction::FCVTSWU => {
+						let rm = match self.get_rounding_mode(word) {
+							Ok(rm) => rm,
+							Err(e) => return Err(e)
+						};
+						let (result, inexact) = convert_integer_f32(self.x[rs1 as usize] as u32 as i128, rm);
+						self.update_inexact_fflags_f32(result, inexact);
+						self.write_f32(rd, result);
+					},
+					Instruction::FCVTWD |
+					Instruction::FCVTWS => {
+						let value = match instruction {
+							Instruction::FCVTWS => self.read_f32(rs1) as f64,
+							_ => self.read_f64(rs1)
+						};
+						let rm = match self.get_rounding_mode(word) {
+							Ok(rm) => rm,
+							Err(e) => return Err(e)
+						};
+						let rounded = round_to_integral(value, rm);
+						self.x[rd as usize] = match rounded.is_nan() || rounded < -2147483648.0 || rounded > 2147483647.0 {
+							true => {
+								self.update_fflags(FFLAGS_NV);
+								match rounded < 0.0 {
+									true => i32::MIN as i64,
+									false => i32::MAX as i64
+								}
+							},
+							false => {
+								if rounded != value {
+									self.update_fflags(FFLAGS_NX);
+								}
+								rounded as i32 as i64
+							}
+						};
+					},
+					Instruction::FCVTWUD |
+					Instruction::FCVTWUS => {
+						let value = match instruction {
+							Instruction::FCVTWUS => self.read_f32(rs1) as f64,
+							_ => self.read_f64(rs1)
+						};
+						let rm = match self.get_rounding_mode(word) {
+							Ok(rm) => rm,
+							Err(e) => return Err(e)
+						};
+						let rounded = round_to_integral(value, rm);
+						// 32-bit unsigned result is sign-extended
+						self.x[rd as usize] = match rounded.is_nan() || rounded < 0.0 || rounded > 4294967295.0 {
+							true => {
+								self.update_fflags(FFLAGS_NV);
+								match rounded < 0.0 {
+									true => 0,
+									false => u32::MAX as i32 as i64
+								}
+							},
+							false => {
+								if rounded != value {
+									self.update_fflags(FFLAGS_NX);
+								}
+								rounded as u32 as i32 as i64
+							}
+						};
+					},
+					Instruction::FDIVD => {
+						let (a, b) = (self.read_f64(rs1), self.read_f64(rs2));
+						if b == 0.0 && a.is_finite() && a != 0.0 {
+							self.update_fflags(FFLAGS_DZ);
+						}
+						let result = a / b;
+						self.update_fflags_f64(&[a, b], result);
+						self.update_inexact_fflags_f64(result, is_inexact_quotient_f64(a, b, result));
+						self.write_f64(rd, result);
+					},
+					Instruction::FDIVS => {
+						let (a, b) = (self.read_f32(rs1), self.read_f32(rs2));
+						if b == 0.0 && a.is_finite() && a != 0.0 {
+							self.update_fflags(FFLAGS_DZ);
+						}
+						let result = a / b;
+						self.update_fflags_f32(&[a, b], result);
+						self.update_inexact_fflags_f32(result, is_inexact_quotient_f32(a, b, result));
+						self.write_f32(rd, result);
+					},
+					Instruction::FEQD => {
+						let (a, b) = (self.read_f64(rs1), self.read_f64(rs2));
+						// Only signaling NaN is invalid for quiet comparison
+						if is_signaling_nan_f64(a) || is_signaling_nan_f64(b) {
+							self.update_fflags(FFLAGS_NV);
+						}
+						self.x[rd as usize] = match a == b {
+							true => 1,
+							false => 0
+						};
+					},
+					Instruction::FEQS => {
+						let (a, b) = (self.read_f32(rs1), self.read_f32(rs2));
+						// Only signaling NaN is invalid for quiet comparison
+						if is_signaling_nan_f32(a) || is_signaling_nan_f32(b) {
+							self.update_fflags(FFLAGS_NV);
+						}
+						self.x[rd as usize] = match a == b {
+							true => 1,
+							false => 0
+						};
+					},
+					Instruction::FLED => {
+						let (a, b) = (self.read_f64(rs1), self.read_f64(rs2));
+						if a.is_nan() || b.is_nan() {
+							self.update_fflags(FFLAGS_NV);
+						}
+						self.x[rd as usize] = match a <= b {
+							true => 1,
+							false => 0
+						};
+					},
+					Instruction::FLES => {
+						let (a, b) = (self.read_f32(rs1), self.read_f32(rs2));
+						if a.is_nan() || b.is_nan() {
+							self.update_fflags(FFLAGS_NV);
+						}
+						self.x[rd as usize] = match a <= b {
+							true => 1,
+							false => 0
+						};
+					},
+					Instruction::FLTD => {
+						let (a, b) = (self.read_f64(rs1), self.read_f64(rs2));
+						if a.is_nan() || b.is_nan() {
+							self.update_fflags(FFLAGS_NV);
+						}
+						self.x[rd as usize] = match a < b {
+							true => 1,
+							false => 0
+						};
+					},
+					Instruction::FLTS => {
+						let (a, b) = (self.read_f32(rs1), self.read_f32(rs2));
+						if a.is_nan() || b.is_nan() {
+							self.update_fflags(FFLAGS_NV);
+						}
+						self.x[rd as usize] = match a < b {
+							true => 1,
+							false => 0
+						};
+					},
+					Instruction::FMADDD |
+					Instruction::FMSUBD |
+					Instruction::FNMADDD |
+					Instruction::FNMSUBD => {
+						let (a, b, c) = (self.read_f64(rs1), self.read_f64(rs2), self.read_f64(rs3));
+						let result = match instruction {
+							Instruction::FMADDD => a.mul_add(b, c),
+							Instruction::FMSUBD => a.mul_add(b, -c),
+							Instruction::FNMSUBD => (-a).mul_add(b, c),
+							_ => (-a).mul_add(b, -c) // FNMADDD
+						};
+						self.update_fflags_f64(&[a, b, c], result);
+						let (a, c) = match instruction {
+							Instruction::FMADDD => (a, c),
+							Instruction::FMSUBD => (a, -c),
+							Instruction::FNMSUBD => (-a, c),
+							_ => (-a, -c) // FNMADDD
+						};
+						self.update_inexact_fflags_f64(result, is_inexact_fused_f64(a, b, c, result));
+						self.write_f64(rd, result);
+					},
+					Instruction::FMADDS |
+					Instruction::FMSUBS |
+					Instruction::FNMADDS |
+					Instruction::FNMSUBS => {
+						let (a, b, c) = (self.read_f32(rs1), self.read_f32(rs2), self.read_f32(rs3));
+						let result = match instruction {
+							Instruction::FMADDS => a.mul_add(b, c),
+							Instruction::FMSUBS => a.mul_add(b, -c),
+							Instruction::FNMSUBS => (-a).mul_add(b, c),
+							_ => (-a).mul_add(b, -c) // FNMADDS
+						};
+						self.update_fflags_f32(&[a, b, c], result);
+						let (a, c) = match instruction {
+							Instruction::FMADDS => (a, c),
+							Instruction::FMSUBS => (a, -c),
+							Instruction::FNMSUBS => (-a, c),
+							_ => (-a, -c) // FNMADDS
+						};
+						self.update_inexact_fflags_f32(result, is_inexact_fused_f32(a, b, c, result));
+						self.write_f32(rd, result);
+					},
+					Instruction::FMAXD |
+					Instruction::FMIND => {
+						let (a, b) = (self.read_f64(rs1), self.read_f64(rs2));
+						if is_signaling_nan_f64(a) || is_signaling_nan_f64(b) {
+							self.update_fflags(FFLAGS_NV);
+						}
+						// -0.0 is less than +0.0, and NaN is chosen only if both are NaN
+						let a_is_less = a < b || (a == b && a.is_sign_negative());
+						let result = match (a.is_nan(), b.is_nan()) {
+							(true, true) => f64::NAN,
+							(true, false) => b,
+							(false, true) => a,
+							(false, false) => match instruction {
+								Instruction::FMIND => match a_is_less {
+									true => a,
+									false => b
+								},
+								_ => match a_is_less {
+									true => b,
+									false => a
+								}
+							}
+						};
+						self.write_f64(rd, result);
+					},
+					Instruction::FMAXS |
+					Instruction::FMINS => {
+						let (a, b) = (self.read_f32(rs1), self.read_f32(rs2));
+						if is_signaling_nan_f32(a) || is_signaling_nan_f32(b) {
+							self.update_fflags(FFLAGS_NV);
+						}
+						// -0.0 is less than +0.0, and NaN is chosen only if both are NaN
+						let a_is_less = a < b || (a == b && a.is_sign_negative());
+						let result = match (a.is_nan(), b.is_nan()) {
+							(true, true) => f32::NAN,
+							(true, false) => b,
+							(false, true) => a,
+							(false, false) => match instruction {
+								Instruction::FMINS => match a_is_less {
+									true => a,
+									false => b
+								},
+								_ => match a_is_less {
+									true => b,
+									false => a
+								}
+							}
+						};
+						self.write_f32(rd, result);
+					},
+					Instruction::FMULD => {
+						let (a, b) = (self.read_f64(rs1), self.read_f64(rs2));
+						let result = a * b;
+						self.update_fflags_f64(&[a, b], result);
+						self.update_inexact_fflags_f64(result, is_inexact_product_f64(a, b, result));
+						self.write_f64(rd, result);
+					},
+					Instruction::FMULS => {
+						let (a, b) = (self.read_f32(rs1), self.read_f32(rs2));
+						let result = a * b;
+						self.update_fflags_f32(&[a, b], result);
+						self.update_inexact_fflags_f32(result, is_inexact_product_f32(a, b, result));
+						self.write_f32(rd, result);
+					},
+					Instruction::FMVDX => {
+						self.write_f64_bits(rd, self.x[rs1 as usize] as u64);
+					},
+					Instruction::FMVWX => {
+						self.write_f32_bits(rd, self.x[rs1 as usize] as u32);
+					},
+					Instruction::FMVXD => {
+						self.x[rd as usize] = self.f[rs1 as usize] as i64;
+					},
+					Instruction::FMVXW => {
+						// Moves the lower 32 bits as they are without NaN-boxing check
+						self.x[rd as usize] = self.f[rs1 as usize] as i32 as i64;
+					},
+					Instruction::FSGNJD |
+					Instruction::FSGNJND |
+					Instruction::FSGNJXD => {
+						let sign_bit = 0x8000000000000000;
+						let (a, b) = (self.f[rs1 as usize], self.f[rs2 as usize]);
+						let bits = match instruction {
+							Instruction::FSGNJD => (a & !sign_bit) | (b & sign_bit),
+							Instruction::FSGNJND => (a & !sign_bit) | (!b & sign_bit),
+							_ => a ^ (b & sign_bit) // FSGNJXD
+						};
+						self.write_f64_bits(rd, bits);
+					},
+					Instruction::FSGNJNS |
+					Instruction::FSGNJS |
+					Instruction::FSGNJXS => {
+						let sign_bit = 0x80000000;
+						let (a, b) = (self.read_f32(rs1).to_bits(), self.read_f32(rs2).to_bits());
+						let bits = match instruction {
+							Instruction::FSGNJS => (a & !sign_bit) | (b & sign_bit),
+							Instruction::FSGNJNS => (a & !sign_bit) | (!b & sign_bit),
+							_ => a ^ (b & sign_bit) // FSGNJXS
+						};
+						self.write_f32_bits(rd, bits);
+					},
+					Instruction::FSQRTD => {
+						let a = self.read_f64(rs1);
+						let result = a.sqrt();
+						self.update_fflags_f64(&[a], result);
+						self.update_inexact_fflags_f64(result, is_inexact_square_root_f64(a, result));
+						self.write_f64(rd, result);
+					},
+					Instruction::FSQRTS => {
+						let a = self.read_f32(rs1);
+						let result = a.sqrt();
+						self.update_fflags_f32(&[a], result);
+						self.update_inexact_fflags_f32(result, is_inexact_square_root_f32(a, result));
+						self.write_f32(rd, result);
+					},
+					Instruction::FSUBD => {
+						let (a, b) = (self.read_f64(rs1), self.read_f64(rs2));
+						let result = a - b;
+						self.update_fflags_f64(&[a, b], result);
+						self.update_inexact_fflags_f64(result, is_inexact_sum_f64(a, -b, result));
+						self.write_f64(rd, result);
+					},
+					Instruction::FSUBS => {
+						let (a, b) = (self.read_f32(rs1), self.read_f32(rs2));
+						let result = a - b;
+						self.update_fflags_f32(&[a, b], result);
+						self.update_inexact_fflags_f32(result, is_inexact_sum_f32(a, -b, result));
+						self.write_f32(rd, result);
+					},
+					Instruction::LRD => {
+						let address = self.unsigned_data(self.x[rs1 as usize]);
+						match self.check_atomic_alignment(address, 8, TrapType::LoadAddressMisaligned) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+						let data = match self.mmu.load_doubleword(address) {
+							Ok(data) => data,
+							Err(e) => return Err(e)
+						};
+						match self.mmu.reserve(address) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+						self.x[rd as usize] = data as i64;
+					},
+					Instruction::LRW => {
+						let address = self.unsigned_data(self.x[rs1 as usize]);
+						match self.check_atomic_alignment(address, 4, TrapType::LoadAddressMisaligned) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+						let data = match self.mmu.load_word(address) {
+							Ok(data) => data,
+							Err(e) => return Err(e)
+						};
+						match self.mmu.reserve(address) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+						self.x[rd as usize] = data as i32 as i64;
+					},
+					Instruction::MAX => {
+						self.x[rd as usize] = self.x[rs1 as usize].max(self.x[rs2 as usize]);
+					},
+					Instruction::MAXU => {
+						self.x[rd as usize] = self.sign_extend(self.unsigned_data(self.x[rs1 as usize]).max(self.unsigned_data(self.x[rs2 as usize])) as i64);
+					},
+					Instruction::MIN => {
+						self.x[rd as usize] = self.x[rs1 as usize].min(self.x[rs2 as usize]);
+					},
+					Instruction::MINU => {
+						self.x[rd as usize] = self.sign_extend(self.unsigned_data(self.x[rs1 as usize]).min(self.unsigned_data(self.x[rs2 as usize])) as i64);
+					},
+					Instruction::ORCB => {
+						// Each byte becomes 0xff if any bit of it is set, otherwise 0
+						let value = self.x[rs1 as usize] as u64;
+						let mut result: u64 = 0;
+						for i in 0..8 {
+							if ((value >> (i * 8)) & 0xff) != 0 {
+								result |= 0xff << (i * 8);
+							}
+						}
+						self.x[rd as usize] = self.sign_extend(result as i64);
 					},
-					Instruction::DIVU => {
-						self.x[rd as usize] = match self.x[rs2 as usize] {
-							0 => -1,
-							_ => self.sign_extend(self.unsigned_data(self.x[rs1 as usize]).wrapping_div(self.unsigned_data(self.x[rs2 as usize])) as i64)
-						};
+					Instruction::ORN => {
+						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] | !self.x[rs2 as usize]);
 					},
-					Instruction::DIVUW => {
-						self.x[rd as usize] = match self.x[rs2 as usize] {
-							0 => -1,
-							_ => (self.x[rs1 as usize] as u32).wrapping_div(self.x[rs2 as usize] as u32) as i32 as i64
+					Instruction::REV8 => {
+						self.x[rd as usize] = match self.xlen {
+							Xlen::Bit32 => (self.x[rs1 as usize] as u32).swap_bytes() as i32 as i64,
+							Xlen::Bit64 => (self.x[rs1 as usize] as u64).swap_bytes() as i64
 						};
 					},
-					Instruction::DIVW => {
-						self.x[rd as usize] = match self.x[rs2 as usize] {
-							0 => -1,
-							_ => self.sign_extend((self.x[rs1 as usize] as i32).wrapping_div(self.x[rs2 as usize] as i32) as i64)
+					Instruction::ROL => {
+						self.x[rd as usize] = match self.xlen {
+							Xlen::Bit32 => (self.x[rs1 as usize] as u32).rotate_left((self.x[rs2 as usize] & 0x1f) as u32) as i32 as i64,
+							Xlen::Bit64 => (self.x[rs1 as usize] as u64).rotate_left((self.x[rs2 as usize] & 0x3f) as u32) as i64
 						};
 					},
-					Instruction::ECALL => {
-						let csr_epc_address = match self.privilege_mode {
-							PrivilegeMode::User => CSR_UEPC_ADDRESS,
-							PrivilegeMode::Supervisor => CSR_SEPC_ADDRESS,
-							PrivilegeMode::Machine => CSR_MEPC_ADDRESS,
-							PrivilegeMode::Reserved => panic!()
-						};
-						self.csr[csr_epc_address as usize] = instruction_address;
-						let exception_type = match self.privilege_mode {
-							PrivilegeMode::User => TrapType::EnvironmentCallFromUMode,
-							PrivilegeMode::Supervisor => TrapType::EnvironmentCallFromSMode,
-							PrivilegeMode::Machine => TrapType::EnvironmentCallFromMMode,
-							PrivilegeMode::Reserved => panic!()
+					Instruction::ROLW => {
+						self.x[rd as usize] = (self.x[rs1 as usize] as u32).rotate_left((self.x[rs2 as usize] & 0x1f) as u32) as i32 as i64;
+					},
+					Instruction::ROR => {
+						self.x[rd as usize] = match self.xlen {
+							Xlen::Bit32 => (self.x[rs1 as usize] as u32).rotate_right((self.x[rs2 as usize] & 0x1f) as u32) as i32 as i64,
+							Xlen::Bit64 => (self.x[rs1 as usize] as u64).rotate_right((self.x[rs2 as usize] & 0x3f) as u32) as i64
 						};
-						return Err(Trap {
-							trap_type: exception_type,
-							value: instruction_address
-						});
+					},
+					Instruction::RORW => {
+						self.x[rd as usize] = (self.x[rs1 as usize] as u32).rotate_right((self.x[rs2 as usize] & 0x1f) as u32) as i32 as i64;
+					},
+					Instruction::SEXTB => {
+						self.x[rd as usize] = self.x[rs1 as usize] as i8 as i64;
+					},
+					Instruction::SEXTH => {
+						self.x[rd as usize] = self.x[rs1 as usize] as i16 as i64;
 					},
 					Instruction::MRET |
 					Instruction::SRET |
 					Instruction::URET => {
-						// @TODO: Throw error if higher privilege return instruction is executed
-						// @TODO: Implement propertly
+						// Return from higher privilege mode than current is illegal,
+						// and SRET is illegal in S-mode if mstatus.TSR[22] is set
+						let tsr = (self.csr[CSR_MSTATUS_ADDRESS as usize] >> 22) & 1;
+						let illegal = match (&instruction, &self.privilege_mode) {
+							(Instruction::MRET, PrivilegeMode::Machine) => false,
+							(Instruction::MRET, _) => true,
+							(Instruction::SRET, PrivilegeMode::User) => true,
+							(Instruction::SRET, PrivilegeMode::Supervisor) => tsr == 1,
+							_ => false
+						};
+						if illegal {
+							return Err(Trap {
+								trap_type: TrapType::IllegalInstruction,
+								value: self.instruction_word as u64
+							});
+						}
 						let csr_epc_address = match instruction {
 							Instruction::MRET => CSR_MEPC_ADDRESS,
 							Instruction::SRET => CSR_SEPC_ADDRESS,
 							Instruction::URET => CSR_UEPC_ADDRESS,
 							_ => panic!() // shouldn't happen
 						};
-						self.pc = match self.read_csr(csr_epc_address) {
+						let epc = match self.read_csr(csr_epc_address) {
 							Ok(data) => data,
 							Err(e) => return Err(e)
 						};
+						self.update_pc(epc);
 						match instruction {
 							Instruction::MRET => {
 								let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
 								let mpie = (status >> 7) & 1;
 								let mpp = (status >> 11) & 0x3;
-								// Override MIE[3] with MPIE[7], set MPIE[7] to 1, set MPP[12:11] to 0
-								let new_status = (status & !0x1888) | (mpie << 3) | (1 << 7);
+								// Override MIE[3] with MPIE[7], set MPIE[7] to 1, set MPP[12:11] to 0,
+								// and clear MPRV[17] if returning to lower privilege mode
+								let mprv_mask = match mpp {
+									3 => 0,
+									_ => 0x20000
+								};
+								let new_status = (status & !(0x1888 | mprv_mask)) | (mpie << 3) | (1 << 7);
 								self.csr[CSR_MSTATUS_ADDRESS as usize] = new_status;
 								self.privilege_mode = match mpp {
 									0 => PrivilegeMode::User,
@@ -1811,12 +5495,13 @@ impl Cpu {
 								};
 							},
 							Instruction::SRET => {
-								let status = self.csr[CSR_SSTATUS_ADDRESS as usize];
+								let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
 								let spie = (status >> 5) & 1;
 								let spp = (status >> 8) & 1;
-								// Override SIE[1] with SPIE[5], set SPIE[5] to 1, set SPP[8] to 0
-								let new_status = (status & !0x122) | (spie << 1) | (1 << 5);
-								self.csr[CSR_SSTATUS_ADDRESS as usize] = new_status;
+								// Override SIE[1] with SPIE[5], set SPIE[5] to 1, set SPP[8] to 0,
+								// and clear MPRV[17] because SRET returns to lower privilege mode than M
+								let new_status = (status & !0x20122) | (spie << 1) | (1 << 5);
+								self.csr[CSR_MSTATUS_ADDRESS as usize] = new_status;
 								self.privilege_mode = match spp {
 									0 => PrivilegeMode::User,
 									1 => PrivilegeMode::Supervisor,
@@ -1824,19 +5509,27 @@ impl Cpu {
 								};
 							},
 							Instruction::URET => {
-								panic!("Not implemented yet.");
+								let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
+								let upie = (status >> 4) & 1;
+								// Override UIE[0] with UPIE[4], set UPIE[4] to 1,
+								// and clear MPRV[17] because URET returns to lower privilege mode than M
+								let new_status = (status & !0x20011) | upie | (1 << 4);
+								self.csr[CSR_MSTATUS_ADDRESS as usize] = new_status;
+								self.privilege_mode = PrivilegeMode::User;
 							},
 							_ => panic!() // shouldn't happen
 						};
 						self.mmu.update_privilege_mode(self.privilege_mode.clone());
+						self.update_data_privilege_mode();
 					},
 					Instruction::MUL => {
 						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize].wrapping_mul(self.x[rs2 as usize]));
 					},
 					Instruction::MULH => {
 						self.x[rd as usize] = match self.xlen {
+							// Only the lower 32 bits of the registers are valid
 							Xlen::Bit32 => {
-								self.sign_extend((self.x[rs1 as usize] * self.x[rs2 as usize]) >> 32)
+								self.sign_extend((self.x[rs1 as usize] as i32 as i64).wrapping_mul(self.x[rs2 as usize] as i32 as i64) >> 32)
 							},
 							Xlen::Bit64 => {
 								((self.x[rs1 as usize] as i128) * (self.x[rs2 as usize] as i128) >> 64) as i64
@@ -1856,7 +5549,7 @@ impl Cpu {
 					Instruction::MULHSU => {
 						self.x[rd as usize] = match self.xlen {
 							Xlen::Bit32 => {
-								self.sign_extend(((self.x[rs1 as usize] as i64).wrapping_mul(self.x[rs2 as usize] as u32 as i64) >> 32) as i64)
+								self.sign_extend((self.x[rs1 as usize] as i32 as i64).wrapping_mul(self.x[rs2 as usize] as u32 as i64) >> 32)
 							},
 							Xlen::Bit64 => {
 								((self.x[rs1 as usize] as u128).wrapping_mul(self.x[rs2 as usize] as u64 as u128) >> 64) as i64
@@ -1870,9 +5563,13 @@ impl Cpu {
 						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] | self.x[rs2 as usize]);
 					},
 					Instruction::REM => {
-						self.x[rd as usize] = match self.x[rs2 as usize] {
-							0 => self.x[rs1 as usize],
-							_ => self.sign_extend(self.x[rs1 as usize].wrapping_rem(self.x[rs2 as usize]))
+						let dividend = self.x[rs1 as usize];
+						let divisor = self.x[rs2 as usize];
+						self.x[rd as usize] = match divisor {
+							0 => dividend,
+							// Signed overflow, the most negative value divided by -1, results in zero
+							-1 if dividend == self.get_most_negative() => 0,
+							_ => self.sign_extend(dividend.wrapping_rem(divisor))
 						};
 					},
 					Instruction::REMU => {
@@ -1882,19 +5579,154 @@ impl Cpu {
 						};
 					},
 					Instruction::REMUW => {
-						self.x[rd as usize] = match self.x[rs2 as usize] {
-							0 => self.x[rs1 as usize],
-							_ => self.sign_extend((self.x[rs1 as usize] as u32).wrapping_rem(self.x[rs2 as usize] as u32) as i32 as i64)
+						let dividend = self.x[rs1 as usize] as u32;
+						let divisor = self.x[rs2 as usize] as u32;
+						self.x[rd as usize] = match divisor {
+							0 => dividend as i32 as i64,
+							_ => self.sign_extend(dividend.wrapping_rem(divisor) as i32 as i64)
 						};
 					},
 					Instruction::REMW => {
-						self.x[rd as usize] = match self.x[rs2 as usize] {
-							0 => self.x[rs1 as usize],
-							_ => self.sign_extend((self.x[rs1 as usize] as i32).wrapping_rem((self.x[rs2 as usize]) as i32) as i64)
+						let dividend = self.x[rs1 as usize] as i32;
+						let divisor = self.x[rs2 as usize] as i32;
+						self.x[rd as usize] = match divisor {
+							0 => dividend as i64,
+							-1 if dividend == i32::MIN => 0,
+							_ => self.sign_extend(dividend.wrapping_rem(divisor) as i64)
+						};
+					},
+					Instruction::SCD => {
+						let address = self.unsigned_data(self.x[rs1 as usize]);
+						match self.check_atomic_alignment(address, 8, TrapType::StoreAddressMisaligned) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+						let reserved = match self.mmu.check_reservation(address) {
+							Ok(reserved) => reserved,
+							Err(e) => return Err(e)
+						};
+						self.x[rd as usize] = match reserved {
+							true => {
+								match self.mmu.store_doubleword(address, self.x[rs2 as usize] as u64) {
+									Ok(()) => {},
+									Err(e) => return Err(e)
+								};
+								0
+							},
+							false => 1
+						};
+					},
+					Instruction::SCW => {
+						let address = self.unsigned_data(self.x[rs1 as usize]);
+						match self.check_atomic_alignment(address, 4, TrapType::StoreAddressMisaligned) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+						let reserved = match self.mmu.check_reservation(address) {
+							Ok(reserved) => reserved,
+							Err(e) => return Err(e)
+						};
+						self.x[rd as usize] = match reserved {
+							true => {
+								match self.mmu.store_word(address, self.x[rs2 as usize] as u32) {
+									Ok(()) => {},
+									Err(e) => return Err(e)
+								};
+								0
+							},
+							false => 1
 						};
 					},
 					Instruction::SFENCEVMA => {
-						// @TODO: Implement
+						// SFENCE.VMA is illegal in U-mode, and in S-mode if mstatus.TVM[20] is set
+						let tvm = (self.csr[CSR_MSTATUS_ADDRESS as usize] >> 20) & 1;
+						match self.privilege_mode {
+							PrivilegeMode::User => return Err(Trap {
+								trap_type: TrapType::IllegalInstruction,
+								value: self.instruction_word as u64
+							}),
+							PrivilegeMode::Supervisor if tvm == 1 => return Err(Trap {
+								trap_type: TrapType::IllegalInstruction,
+								value: self.instruction_word as u64
+							}),
+							_ => {}
+						};
+						// @TODO: Flush only the entries for rs1 address and rs2 asid
+						self.mmu.flush_tlb();
+					},
+					Instruction::CBOCLEAN |
+					Instruction::CBOFLUSH |
+					Instruction::CBOINVAL => {
+						// No cache to write back or invalidate. Invalidation is
+						// allowed below M-mode if menvcfg.CBIE is 01 (as flush)
+						// or 11, and cleaning and flushing if menvcfg.CBCFE is set.
+						let menvcfg = self.csr[CSR_MENVCFG_ADDRESS as usize];
+						let enabled = match instruction {
+							Instruction::CBOINVAL => {
+								let cbie = (menvcfg & MENVCFG_CBIE) >> 4;
+								cbie == 1 || cbie == 3
+							},
+							_ => (menvcfg & MENVCFG_CBCFE) != 0
+						};
+						match self.privilege_mode {
+							PrivilegeMode::Machine => {},
+							_ => if !enabled {
+								return Err(Trap {
+									trap_type: TrapType::IllegalInstruction,
+									value: self.instruction_word as u64
+								});
+							}
+						};
+					},
+					Instruction::CBOZERO => {
+						// Allowed below M-mode if menvcfg.CBZE is set
+						let cbze = self.csr[CSR_MENVCFG_ADDRESS as usize] & MENVCFG_CBZE;
+						match self.privilege_mode {
+							PrivilegeMode::Machine => {},
+							_ => if cbze == 0 {
+								return Err(Trap {
+									trap_type: TrapType::IllegalInstruction,
+									value: self.instruction_word as u64
+								});
+							}
+						};
+						// Zeroes the block containing rs1 address. Stores are
+						// translated and checked as usual, and the block doesn't
+						// cross a page so a fault happens on the first store
+						// before anything is written. The fault reports rs1
+						// address as the effective address.
+						let size = self.cache_block_size;
+						let effective_address = self.unsigned_data(self.x[rs1 as usize]);
+						let address = effective_address & !(size - 1);
+						let mut offset = 0;
+						while offset < size {
+							match self.mmu.store_doubleword(address.wrapping_add(offset), 0) {
+								Ok(()) => {},
+								Err(e) => return Err(Trap {
+									trap_type: e.trap_type,
+									value: effective_address
+								})
+							};
+							offset += 8;
+						}
+					},
+					Instruction::SH1ADD => {
+						self.x[rd as usize] = self.sign_extend((self.x[rs1 as usize] << 1).wrapping_add(self.x[rs2 as usize]));
+					},
+					Instruction::SH1ADDUW => {
+						self.x[rd as usize] = ((self.x[rs1 as usize] as u32 as i64) << 1).wrapping_add(self.x[rs2 as usize]);
+					},
+					Instruction::SH2ADD => {
+						self.x[rd as usize] = self.sign_extend((self.x[rs1 as usize] << 2).wrapping_add(self.x[rs2 as usize]));
+					},
+					Instruction::SH2ADDUW => {
+						self.x[rd as usize] = ((self.x[rs1 as usize] as u32 as i64) << 2).wrapping_add(self.x[rs2 as usize]);
+					},
+					Instruction::SH3ADD => {
+						self.x[rd as usize] = self.sign_extend((self.x[rs1 as usize] << 3).wrapping_add(self.x[rs2 as usize]));
+					},
+					Instruction::SH3ADDUW => {
+						self.x[rd as usize] = ((self.x[rs1 as usize] as u32 as i64) << 3).wrapping_add(self.x[rs2 as usize]);
 					},
 					Instruction::SUB => {
 						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize].wrapping_sub(self.x[rs2 as usize]));
@@ -1902,8 +5734,13 @@ impl Cpu {
 					Instruction::SUBW => {
 						self.x[rd as usize] = self.x[rs1 as usize].wrapping_sub(self.x[rs2 as usize]) as i32 as i64;
 					},
+					// Shift amount is the lower 5 bits of rs2 in 32-bit mode, and 6 bits in 64-bit mode
 					Instruction::SLL => {
-						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize].wrapping_shl(self.x[rs2 as usize] as u32));
+						let shamt = (self.x[rs2 as usize] & match self.xlen {
+							Xlen::Bit32 => 0x1f,
+							Xlen::Bit64 => 0x3f
+						}) as u32;
+						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] << shamt);
 					},
 					Instruction::SLLW => {
 						self.x[rd as usize] = (self.x[rs1 as usize] as u32).wrapping_shl(self.x[rs2 as usize] as u32) as i32 as i64;
@@ -1921,39 +5758,69 @@ impl Cpu {
 						}
 					},
 					Instruction::SRA => {
-						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize].wrapping_shr(self.x[rs2 as usize] as u32));
+						let shamt = (self.x[rs2 as usize] & match self.xlen {
+							Xlen::Bit32 => 0x1f,
+							Xlen::Bit64 => 0x3f
+						}) as u32;
+						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] >> shamt);
 					},
 					Instruction::SRAW => {
 						self.x[rd as usize] = (self.x[rs1 as usize] as i32).wrapping_shr(self.x[rs2 as usize] as u32) as i32 as i64;
 					},
 					Instruction::SRL => {
-						self.x[rd as usize] = self.sign_extend(self.unsigned_data(self.x[rs1 as usize]).wrapping_shr(self.x[rs2 as usize] as u32) as i64);
+						let shamt = (self.x[rs2 as usize] & match self.xlen {
+							Xlen::Bit32 => 0x1f,
+							Xlen::Bit64 => 0x3f
+						}) as u32;
+						self.x[rd as usize] = self.sign_extend((self.unsigned_data(self.x[rs1 as usize]) >> shamt) as i64);
 					},
 					Instruction::SRLW => {
 						self.x[rd as usize] = (self.x[rs1 as usize] as u32).wrapping_shr(self.x[rs2 as usize] as u32) as i32 as i64;
 					},
+					Instruction::WFI => {
+						// WFI is illegal in U-mode, and in S-mode if mstatus.TW[21] is set
+						let tw = (self.csr[CSR_MSTATUS_ADDRESS as usize] >> 21) & 1;
+						match self.privilege_mode {
+							PrivilegeMode::User => return Err(Trap {
+								trap_type: TrapType::IllegalInstruction,
+								value: self.instruction_word as u64
+							}),
+							PrivilegeMode::Supervisor if tw == 1 => return Err(Trap {
+								trap_type: TrapType::IllegalInstruction,
+								value: self.instruction_word as u64
+							}),
+							_ => {}
+						};
+						// pc already points to the next instruction. tick() stops
+						// running instructions until an interrupt becomes pending
+						self.wfi = true;
+					},
+					Instruction::XNOR => {
+						self.x[rd as usize] = self.sign_extend(!(self.x[rs1 as usize] ^ self.x[rs2 as usize]));
+					},
 					Instruction::XOR => {
 						self.x[rd as usize] = self.sign_extend(self.x[rs1 as usize] ^ self.x[rs2 as usize]);
 					},
-					_ => {
-						println!("{}", get_instruction_name(&instruction).to_owned() + " instruction is not supported yet.");
-						self.dump_instruction(instruction_address);
-						panic!();
-					}
+					Instruction::ZEXTH => {
+						self.x[rd as usize] = self.x[rs1 as usize] as u16 as i64;
+					},
+					_ => return self.handle_unimplemented(&instruction, instruction_address)
 				};
 			},
 			InstructionFormat::S => {
-				let rs1 = (word >> 15) & 0x1f; // [19:15]
-				let rs2 = (word >> 20) & 0x1f; // [24:20]
-				let imm = (
-					match word & 0x80000000 {
-						0x80000000 => 0xfffff000,
-						_ => 0
-					} | // imm[31:12] = [31]
-					((word & 0xfe000000) >> 20) | // imm[11:5] = [31:25],
-					((word & 0x00000f80) >> 7) // imm[4:0] = [11:7]
-				) as i32 as i64;
 				match instruction {
+					Instruction::FSD => {
+						match self.mmu.store_doubleword(self.x[rs1 as usize].wrapping_add(imm) as u64, self.f[rs2 as usize]) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+					},
+					Instruction::FSW => {
+						match self.mmu.store_word(self.x[rs1 as usize].wrapping_add(imm) as u64, self.f[rs2 as usize] as u32) {
+							Ok(()) => {},
+							Err(e) => return Err(e)
+						};
+					},
 					Instruction::SB => {
 						match self.mmu.store(self.x[rs1 as usize].wrapping_add(imm) as u64, self.x[rs2 as usize] as u8) {
 							Ok(()) => {},
@@ -1978,22 +5845,11 @@ impl Cpu {
 							Err(e) => return Err(e)
 						};
 					},
-					_ => {
-						println!("{}", get_instruction_name(&instruction).to_owned() + " instruction is not supported yet.");
-						self.dump_instruction(instruction_address);
-						panic!();
-					}
+					_ => return self.handle_unimplemented(&instruction, instruction_address)
 				};
 			},
 			InstructionFormat::U => {
-				let rd = (word >> 7) & 0x1f; // [11:7]
-				let imm = (
-					match word & 0x80000000 {
-						0x80000000 => 0xffffffff00000000,
-						_ => 0
-					} | // imm[63:32] = [31]
-					((word as u64) & 0xfffff000) // imm[31:12] = [31:12]
-				) as u64;
+				let imm = imm as u64;
 				match instruction {
 					Instruction::AUIPC => {
 						self.x[rd as usize] = self.sign_extend(instruction_address.wrapping_add(imm) as i64);
@@ -2001,75 +5857,1797 @@ impl Cpu {
 					Instruction::LUI => {
 						self.x[rd as usize] = imm as i64;
 					}
-					_ => {
-						println!("{}", get_instruction_name(&instruction).to_owned() + " instruction is not supported yet.");
-						self.dump_instruction(instruction_address);
-						panic!();
-					}
+					_ => return self.handle_unimplemented(&instruction, instruction_address)
 				};
 			}
 		}
-		self.x[0] = 0; // hard-wired zero
-		Ok(())
+		self.x[0] = 0; // hard-wired zero
+		Ok(())
+	}
+
+	fn handle_unimplemented(&mut self, instruction: &Instruction, instruction_address: u64) -> Result<(), Trap> {
+		match self.unimplemented_policy {
+			UnimplementedPolicy::Trap => Err(Trap {
+				trap_type: TrapType::IllegalInstruction,
+				value: self.instruction_word as u64
+			}),
+			// Warnings go to the terminal, which the host controls, rather
+			// than stdout of the host process
+			UnimplementedPolicy::NopWarn => {
+				let s = format!("{} instruction is not supported yet. Skipping.\n", get_instruction_name(instruction));
+				self.put_bytes_to_terminal(s.as_bytes());
+				Ok(())
+			},
+			UnimplementedPolicy::Panic => {
+				self.dump_instruction(instruction_address);
+				panic!("{} instruction is not supported yet.", get_instruction_name(instruction));
+			}
+		}
+	}
+
+	fn dump_instruction(&mut self, address: u64) {
+		let word = match self.mmu.load_word(address) {
+			Ok(word) => word,
+			Err(_e) => return // @TODO: What should we do if trap happens?
+		};
+		let pc = self.unsigned_data(address as i64);
+		let opcode = word & 0x7f; // [6:0]
+		println!("Pc:{:016x}, Opcode:{:07b}, Word:{:016x}", pc, opcode, word);
+	}
+
+	// For riscv-tests
+
+	pub fn dump_current_instruction_to_terminal(&mut self) {
+		// @TODO: Fetching can make a side effect,
+		// for example updating page table entry or update peripheral hardware registers
+		// by accessing them. How can we avoid it?
+		let v_address = self.pc;
+		let mut word = match self.mmu.fetch_instruction(v_address) {
+			Ok(data) => data,
+			Err(_e) => {
+				let s = format!("PC:{:016x}, InstructionPageFault Trap!\n", v_address);
+				self.put_bytes_to_terminal(s.as_bytes());
+				return;
+			}
+		};
+		let instruction = match self.decode(word) {
+			Ok(instruction) => instruction,
+			Err(()) => match self.decode(self.uncompress(word & 0xffff)) {
+				Ok(instruction) => {
+					word = word & 0xffff;
+					instruction
+				},
+				Err(()) => {
+					println!("Unknown instruction PC:{:x} WORD:{:x}", self.pc, word);
+					self.dump_instruction(self.pc);
+					panic!();
+				}
+			}
+		};
+		let s = format!("PC:{:016x}, Word:{:08x}, Inst:{}\n",
+			self.unsigned_data(v_address as i64),
+			word, get_instruction_name(&instruction));
+		self.put_bytes_to_terminal(s.as_bytes());
+	}
+
+	// Feeds UART with input before any from the terminal. In deterministic
+	// timer mode, this is the only input so that runs are reproducible.
+	pub fn preload_input(&mut self, data: &[u8]) {
+		self.mmu.preload_uart_input(data);
+	}
+
+	// UART divisor latch the guest has configured, baud rate being the
+	// input clock / (16 * divisor)
+	pub fn get_uart_divisor(&self) -> u16 {
+		self.mmu.get_uart_divisor()
+	}
+
+	pub fn put_bytes_to_terminal(&mut self, bytes: &[u8]) {
+		for i in 0..bytes.len() {
+			self.mmu.put_uart_output(bytes[i]);
+		}
+	}
+	
+	// Wasm specific
+	pub fn get_output(&mut self) -> u8 {
+		self.mmu.get_uart_output()
+	}
+
+	pub fn put_input(&mut self, data: u8) {
+		self.mmu.put_uart_input(data);
+	}
+
+	// Takes all the output at once to save calls from JavaScript
+	pub fn get_output_bytes(&mut self) -> Vec<u8> {
+		self.mmu.get_uart_output_bytes()
+	}
+
+	pub fn put_input_bytes(&mut self, data: &[u8]) {
+		self.mmu.put_uart_input_bytes(data);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use test_helper::*;
+
+	// Runs an instruction reading x1 and x2 and returns x3
+	fn execute_x3(word: u32, rs1: i64, rs2: i64) -> i64 {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[word]);
+		cpu.write_register(1, rs1);
+		cpu.write_register(2, rs2);
+		assert!(!cpu.step().trapped);
+		cpu.read_register(3)
+	}
+
+	fn sh_add(funct3: u32, opcode: u32) -> u32 {
+		r_type(0x10, 2, 1, funct3, 3, opcode)
+	}
+
+	fn slli_uw(shamt: u32) -> u32 {
+		i_type((0x80 | shamt) as i32, 1, 1, 3, 0x1b)
+	}
+
+	#[test]
+	fn zba_shift_and_add() {
+		assert_eq!(execute_x3(sh_add(2, 0x33), 3, 100), 106);
+		assert_eq!(execute_x3(sh_add(4, 0x33), 3, 100), 112);
+		assert_eq!(execute_x3(sh_add(6, 0x33), 3, 100), 124);
+		// Not zero-extended
+		assert_eq!(execute_x3(sh_add(2, 0x33), -1, 0), -2);
+		assert_eq!(execute_x3(sh_add(6, 0x33), -1, 0), -8);
+		assert_eq!(execute_x3(sh_add(4, 0x33), 0xffffffff80000000u64 as i64, 0), 0xfffffffe00000000u64 as i64);
+	}
+
+	#[test]
+	fn zba_unsigned_word_forms() {
+		let add_uw = r_type(0x04, 2, 1, 0, 3, 0x3b);
+		assert_eq!(execute_x3(add_uw, 5, 7), 12);
+		assert_eq!(execute_x3(sh_add(2, 0x3b), 3, 100), 106);
+		assert_eq!(execute_x3(sh_add(4, 0x3b), 3, 100), 112);
+		assert_eq!(execute_x3(sh_add(6, 0x3b), 3, 100), 124);
+		assert_eq!(execute_x3(slli_uw(0), 5, 0), 5);
+		assert_eq!(execute_x3(slli_uw(4), 5, 0), 80);
+		// Only the lower 32 bits of rs1 are shifted
+		assert_eq!(execute_x3(slli_uw(32), 0x123456789, 0), 0x2345678900000000);
+	}
+
+	#[test]
+	fn zba_unsigned_word_forms_zero_extend_rs1() {
+		let rs1 = 0xffffffff80000000u64 as i64;
+		let add_uw = r_type(0x04, 2, 1, 0, 3, 0x3b);
+		assert_eq!(execute_x3(add_uw, rs1, 0), 0x80000000);
+		assert_eq!(execute_x3(add_uw, rs1, 1), 0x80000001);
+		assert_eq!(execute_x3(sh_add(2, 0x3b), rs1, 0), 0x100000000);
+		assert_eq!(execute_x3(sh_add(4, 0x3b), rs1, 0), 0x200000000);
+		assert_eq!(execute_x3(sh_add(6, 0x3b), rs1, 0), 0x400000000);
+		assert_eq!(execute_x3(slli_uw(1), rs1, 0), 0x100000000);
+
+		assert_eq!(execute_x3(add_uw, -1, 0), 0xffffffff);
+		assert_eq!(execute_x3(add_uw, -1, 1), 0x100000000);
+		assert_eq!(execute_x3(sh_add(2, 0x3b), -1, 0), 0x1fffffffe);
+		assert_eq!(execute_x3(sh_add(4, 0x3b), -1, 0), 0x3fffffffc);
+		assert_eq!(execute_x3(sh_add(6, 0x3b), -1, 0), 0x7fffffff8);
+		assert_eq!(execute_x3(slli_uw(3), -1, 0), 0x7fffffff8);
+		// rs2 is added as it is
+		assert_eq!(execute_x3(sh_add(2, 0x3b), -1, -0x1fffffffe), 0);
+	}
+
+	fn m_type(funct3: u32, opcode: u32) -> u32 {
+		r_type(1, 2, 1, funct3, 3, opcode)
+	}
+
+	#[test]
+	fn division_overflow() {
+		let (div, rem) = (m_type(4, 0x33), m_type(6, 0x33));
+		assert_eq!(execute_x3(div, i64::MIN, -1), i64::MIN);
+		assert_eq!(execute_x3(rem, i64::MIN, -1), 0);
+		assert_eq!(execute_x3(div, -7, 2), -3);
+		assert_eq!(execute_x3(rem, -7, 2), -1);
+
+		let (divw, remw) = (m_type(4, 0x3b), m_type(6, 0x3b));
+		assert_eq!(execute_x3(divw, i32::MIN as i64, -1), i32::MIN as i64);
+		assert_eq!(execute_x3(remw, i32::MIN as i64, -1), 0);
+		// Only the lower 32 bits of the operands are seen
+		assert_eq!(execute_x3(divw, 0x180000000, 0xffffffff), i32::MIN as i64);
+		assert_eq!(execute_x3(remw, 0x180000000, 0xffffffff), 0);
+	}
+
+	#[test]
+	fn division_overflow_32bit() {
+		let mut cpu = create_cpu();
+		cpu.update_xlen(Xlen::Bit32);
+		load_program(&mut cpu, DRAM_BASE, &[m_type(4, 0x33), r_type(1, 2, 1, 6, 4, 0x33)]);
+		cpu.write_register(1, i32::MIN as i64);
+		cpu.write_register(2, -1);
+		assert!(!cpu.step().trapped);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_register(3), i32::MIN as i64);
+		assert_eq!(cpu.read_register(4), 0);
+	}
+
+	#[test]
+	fn division_by_zero() {
+		let (div, divu, rem, remu) = (m_type(4, 0x33), m_type(5, 0x33), m_type(6, 0x33), m_type(7, 0x33));
+		assert_eq!(execute_x3(div, 5, 0), -1);
+		assert_eq!(execute_x3(divu, 5, 0), -1);
+		assert_eq!(execute_x3(rem, -5, 0), -5);
+		assert_eq!(execute_x3(remu, 5, 0), 5);
+	}
+
+	#[test]
+	fn word_division_by_divisor_whose_lower_bits_are_zero() {
+		// 1 << 32 is zero as a 32-bit divisor
+		let (divw, divuw, remw, remuw) = (m_type(4, 0x3b), m_type(5, 0x3b), m_type(6, 0x3b), m_type(7, 0x3b));
+		assert_eq!(execute_x3(divw, 5, 1 << 32), -1);
+		assert_eq!(execute_x3(divuw, 5, 1 << 32), -1);
+		assert_eq!(execute_x3(remw, 5, 1 << 32), 5);
+		assert_eq!(execute_x3(remuw, 5, 1 << 32), 5);
+		// Remainder is the sign-extended lower 32 bits of the dividend
+		assert_eq!(execute_x3(remw, 0x1fffffffb, 1 << 32), -5);
+		assert_eq!(execute_x3(remuw, 0x180000000, 1 << 32), i32::MIN as i64);
+	}
+
+	fn lr_d(rd: u32, rs1: u32) -> u32 {
+		r_type(0x08, 0, rs1, 3, rd, 0x2f)
+	}
+
+	fn sc_d(rd: u32, rs2: u32, rs1: u32) -> u32 {
+		r_type(0x0c, rs2, rs1, 3, rd, 0x2f)
+	}
+
+	fn read_doubleword(cpu: &Cpu, address: u64) -> u64 {
+		let mut bytes = [0; 8];
+		cpu.read_memory(address, &mut bytes).unwrap();
+		u64::from_le_bytes(bytes)
+	}
+
+	// x1 points to the reserved doubleword, x5 and x6 have values to store
+	fn create_atomic_cpu(words: &[u32]) -> Cpu {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, words);
+		cpu.write_register(1, (DRAM_BASE + 0x1000) as i64);
+		cpu.write_register(5, 5);
+		cpu.write_register(6, 6);
+		cpu
+	}
+
+	#[test]
+	fn store_conditional_succeeds_after_load_reserved() {
+		let mut cpu = create_atomic_cpu(&[lr_d(2, 1), sc_d(3, 6, 1), sc_d(4, 5, 1)]);
+		cpu.write_register(3, -1);
+		for _ in 0..3 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.read_register(3), 0);
+		// The reservation is used up
+		assert_eq!(cpu.read_register(4), 1);
+		assert_eq!(read_doubleword(&cpu, DRAM_BASE + 0x1000), 6);
+	}
+
+	#[test]
+	fn store_conditional_fails_after_store() {
+		let mut cpu = create_atomic_cpu(&[lr_d(2, 1), sd(5, 1, 0), sc_d(3, 6, 1)]);
+		for _ in 0..3 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.read_register(3), 1);
+		assert_eq!(read_doubleword(&cpu, DRAM_BASE + 0x1000), 5);
+	}
+
+	#[test]
+	fn store_conditional_fails_after_trap() {
+		// The illegal instruction traps to the next instruction
+		let mut cpu = create_atomic_cpu(&[lr_d(2, 1), 0, sc_d(3, 6, 1)]);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, DRAM_BASE + 8);
+		assert!(!cpu.step().trapped);
+		assert!(cpu.step().trapped);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_register(3), 1);
+		assert_eq!(read_doubleword(&cpu, DRAM_BASE + 0x1000), 0);
+	}
+
+	#[test]
+	fn store_conditional_fails_after_store_on_another_hart() {
+		let mut cpu = create_atomic_cpu(&[lr_d(2, 1), sc_d(3, 6, 1), sd(5, 1, 0)]);
+		cpu.setup_harts(2);
+		assert!(!cpu.step().trapped);
+		cpu.switch_hart(1);
+		cpu.update_pc(DRAM_BASE + 4);
+		cpu.write_register(3, -1);
+		// Hart 1 doesn't have the reservation, and then stores
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_register(3), 1);
+		assert!(!cpu.step().trapped);
+		cpu.switch_hart(0);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_register(3), 1);
+		assert_eq!(read_doubleword(&cpu, DRAM_BASE + 0x1000), 5);
+	}
+
+	#[test]
+	fn interleaved_store_conditionals_succeed_once() {
+		let mut cpu = create_atomic_cpu(&[lr_d(2, 1), sc_d(3, 6, 1)]);
+		cpu.setup_harts(2);
+		// Both harts reserve the same doubleword and then store conditionally
+		for hart_id in &[0, 1, 0, 1] {
+			cpu.switch_hart(*hart_id);
+			assert!(!cpu.step().trapped);
+		}
+		cpu.switch_hart(0);
+		assert_eq!(cpu.read_register(3), 0);
+		cpu.switch_hart(1);
+		assert_eq!(cpu.read_register(3), 1);
+		assert_eq!(read_doubleword(&cpu, DRAM_BASE + 0x1000), 6);
+	}
+
+	// Sv39 page tables, a page per level, from the root
+	const PAGE_TABLE_ADDRESS: u64 = DRAM_BASE + 0x10000;
+	const SATP_SV39: u64 = (8 << 60) | (PAGE_TABLE_ADDRESS >> 12);
+
+	// Maps the 4KiB page with the flags and A and D set. The mapped pages
+	// in a test share VPN[2] and VPN[1] because each level has a table.
+	fn map_page(cpu: &mut Cpu, v_address: u64, p_address: u64, flags: u64) {
+		for level in 0..3 {
+			let table = PAGE_TABLE_ADDRESS + level * 0x1000;
+			let pte_address = table + ((v_address >> (30 - level * 9)) & 0x1ff) * 8;
+			let pte = match level {
+				2 => ((p_address >> 12) << 10) | flags | 0xc1, // A, D, and V
+				_ => (((table + 0x1000) >> 12) << 10) | 1
+			};
+			cpu.write_memory(pte_address, &pte.to_le_bytes()).unwrap();
+		}
+	}
+
+	// Loads and stores in machine mode are translated in supervisor mode
+	fn enable_data_translation(cpu: &mut Cpu) {
+		let mstatus = cpu.read_csr_raw(CSR_MSTATUS_ADDRESS);
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, (mstatus & !0x1800) | 0x800 | (1 << 17));
+		cpu.update_data_privilege_mode();
+	}
+
+	#[test]
+	fn tlb_is_invalidated_by_satp_write_and_sfence_vma() {
+		let v_address = 0x40000000;
+		let (page_a, page_b) = (DRAM_BASE + 0x20000, DRAM_BASE + 0x21000);
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[
+			csrrw(0, CSR_SATP_ADDRESS, 10),
+			ld(2, 1, 0),
+			ld(3, 1, 0),
+			ld(4, 1, 0),
+			SFENCE_VMA,
+			ld(5, 1, 0),
+			csrrw(0, CSR_SATP_ADDRESS, 10),
+			ld(6, 1, 0)
+		]);
+		cpu.write_memory(page_a, &[0xa]).unwrap();
+		cpu.write_memory(page_b, &[0xb]).unwrap();
+		map_page(&mut cpu, v_address, page_a, 0x6);
+		enable_data_translation(&mut cpu);
+		cpu.write_register(1, v_address as i64);
+		cpu.write_register(10, SATP_SV39 as i64);
+		cpu.update_stats_enabled(true);
+		for _ in 0..3 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.get_stats().get_tlb_misses(), 1);
+		assert_eq!(cpu.get_stats().get_tlb_hits(), 1);
+		// The cached translation is used until the TLB is flushed
+		map_page(&mut cpu, v_address, page_b, 0x6);
+		for _ in 0..3 {
+			assert!(!cpu.step().trapped);
+		}
+		map_page(&mut cpu, v_address, page_a, 0x6);
+		for _ in 0..2 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.get_stats().get_tlb_misses(), 3);
+		assert_eq!(cpu.get_stats().get_tlb_hits(), 2);
+		assert_eq!([2, 3, 4, 5, 6].iter().map(|i| cpu.read_register(*i)).collect::<Vec<i64>>(),
+			vec![0xa, 0xa, 0xa, 0xb, 0xa]);
+	}
+
+	#[test]
+	fn tlb_hits_on_loop() {
+		let v_address = 0x40000000;
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[addi(1, 1, -1), bne(1, 0, -4), addi(0, 0, 0)]);
+		map_page(&mut cpu, v_address, DRAM_BASE, 0xa);
+		cpu.enter_supervisor_mode();
+		assert!(cpu.write_csr(CSR_SATP_ADDRESS, SATP_SV39).is_ok());
+		cpu.update_pc(v_address);
+		cpu.write_register(1, 100);
+		cpu.update_stats_enabled(true);
+		for _ in 0..200 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.read_pc(), v_address + 8);
+		// Each instruction is fetched as two halfwords
+		assert_eq!(cpu.get_stats().get_tlb_misses(), 1);
+		assert_eq!(cpu.get_stats().get_tlb_hits(), 399);
+	}
+
+	#[test]
+	fn sstatus_is_view_of_mstatus() {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[
+			csrrw(0, CSR_MSTATUS_ADDRESS, 1),
+			csrrs(2, CSR_SSTATUS_ADDRESS, 0),
+			csrrw(0, CSR_SSTATUS_ADDRESS, 0),
+			csrrs(3, CSR_MSTATUS_ADDRESS, 0)
+		]);
+		// MIE[3], MPIE[7], SIE[1], SPP[8], SUM[18], and TSR[22]
+		cpu.write_register(1, 0x44018a);
+		for _ in 0..4 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.read_register(2) as u64, MSTATUS_UXL_64 | 0x40102);
+		// Writing zero to sstatus clears only the supervisor bits
+		assert_eq!(cpu.read_register(3) as u64, MSTATUS_UXL_64 | 0x400088);
+	}
+
+	#[test]
+	fn sie_and_sip_are_views_of_delegated_bits() {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[
+			csrrw(0, CSR_MIDELEG_ADDRESS, 1),
+			csrrw(0, CSR_MIE_ADDRESS, 2),
+			csrrs(3, CSR_SIE_ADDRESS, 0),
+			csrrw(0, CSR_SIE_ADDRESS, 0),
+			csrrs(4, CSR_MIE_ADDRESS, 0),
+			csrrw(0, CSR_SIP_ADDRESS, 2),
+			csrrs(5, CSR_SIP_ADDRESS, 0),
+			csrrs(6, CSR_MIP_ADDRESS, 0)
+		]);
+		cpu.write_register(1, 0x222);
+		cpu.write_register(2, 0xaaa);
+		for _ in 0..8 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.read_register(3), 0x222);
+		assert_eq!(cpu.read_register(4), 0x888);
+		// Only SSIP is writable through sip
+		assert_eq!(cpu.read_register(5), 0x2);
+		assert_eq!(cpu.read_register(6), 0x2);
 	}
 
-	fn dump_instruction(&mut self, address: u64) {
-		let word = match self.mmu.load_word(address) {
-			Ok(word) => word,
-			Err(_e) => return // @TODO: What should we do if trap happens?
-		};
-		let pc = self.unsigned_data(address as i64);
-		let opcode = word & 0x7f; // [6:0]
-		println!("Pc:{:016x}, Opcode:{:07b}, Word:{:016x}", pc, opcode, word);
+	#[test]
+	fn write_to_read_only_csr_traps() {
+		const MVENDORID: u16 = 0xf11;
+		let mut cpu = create_cpu();
+		let word = csrrw(0, MVENDORID, 1);
+		load_program(&mut cpu, DRAM_BASE, &[csrrs(2, MVENDORID, 0), word]);
+		cpu.write_register(1, 0x1234);
+		// Reading is fine
+		assert!(!cpu.step().trapped);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 2);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), word as u64);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 4);
+		assert_eq!(cpu.read_csr_raw(MVENDORID), 0);
 	}
 
-	// For riscv-tests
+	#[test]
+	fn reserved_tvec_mode_is_masked() {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[
+			csrrw(0, CSR_MTVEC_ADDRESS, 1),
+			csrrs(3, CSR_MTVEC_ADDRESS, 0),
+			csrrw(0, CSR_MTVEC_ADDRESS, 2),
+			csrrs(4, CSR_MTVEC_ADDRESS, 0),
+			csrrw(0, CSR_STVEC_ADDRESS, 1),
+			csrrs(5, CSR_STVEC_ADDRESS, 0)
+		]);
+		cpu.write_register(1, 0x80001003);
+		cpu.write_register(2, 0x80001001);
+		for _ in 0..6 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.read_register(3), 0x80001000);
+		// Vectored mode is kept
+		assert_eq!(cpu.read_register(4), 0x80001001);
+		assert_eq!(cpu.read_register(5), 0x80001000);
+	}
 
-	pub fn dump_current_instruction_to_terminal(&mut self) {
-		// @TODO: Fetching can make a side effect,
-		// for example updating page table entry or update peripheral hardware registers
-		// by accessing them. How can we avoid it?
-		let v_address = self.pc;
-		let mut word = match self.mmu.fetch_word(v_address) {
-			Ok(data) => data,
-			Err(_e) => {
-				let s = format!("PC:{:016x}, InstructionPageFault Trap!\n", v_address);
-				self.put_bytes_to_terminal(s.as_bytes());
-				return;
+	fn update_privilege_mode(cpu: &mut Cpu, privilege_mode: PrivilegeMode) {
+		cpu.privilege_mode = privilege_mode;
+		cpu.mmu.update_privilege_mode(cpu.privilege_mode.clone());
+		cpu.update_data_privilege_mode();
+	}
+
+	#[test]
+	fn vectored_interrupt_jumps_to_base_plus_four_times_cause() {
+		let mut cpu = create_cpu();
+		let base = DRAM_BASE + 0x1000;
+		load_program(&mut cpu, DRAM_BASE, &[addi(0, 0, 0)]);
+		// Supervisor timer interrupt isn't delegated and traps to machine mode
+		update_privilege_mode(&mut cpu, PrivilegeMode::Supervisor);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, base | 1);
+		cpu.write_csr_raw(CSR_MIE_ADDRESS, 0x20); // STIE
+		cpu.write_csr_raw(CSR_MIP_ADDRESS, 0x20); // STIP
+		cpu.step_with_devices();
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 0x8000000000000005);
+		assert_eq!(cpu.read_pc(), base + 20);
+		assert!(cpu.privilege_mode == PrivilegeMode::Machine);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 4);
+	}
+
+	#[test]
+	fn vectored_mode_exception_jumps_to_base() {
+		let mut cpu = create_cpu();
+		let base = DRAM_BASE + 0x1000;
+		load_program(&mut cpu, DRAM_BASE, &[ECALL]);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, base | 1);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 11);
+		assert_eq!(cpu.read_pc(), base);
+	}
+
+	#[test]
+	fn compressed_instruction_trap_epc() {
+		let mut cpu = create_cpu();
+		// c.nop, c.lw x8, 0(x9), c.ebreak, and illegal zero
+		load_program(&mut cpu, DRAM_BASE, &[0x40800001, 0x00009002]);
+		assert!(!cpu.step().trapped);
+		// x9 is zero where nothing is mapped
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 5);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 2);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), 0);
+		cpu.update_pc(DRAM_BASE + 4);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 3);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 4);
+		cpu.update_pc(DRAM_BASE + 6);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 2);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 6);
+	}
+
+	// Runs the instruction in the privilege mode with mstatus and returns
+	// whether it raises illegal instruction exception with the word in mtval
+	fn is_illegal(word: u32, privilege_mode: PrivilegeMode, mstatus: u64) -> bool {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[word]);
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, mstatus);
+		update_privilege_mode(&mut cpu, privilege_mode);
+		cpu.step().trapped && cpu.read_csr_raw(CSR_MCAUSE_ADDRESS) == 2 &&
+			cpu.read_csr_raw(CSR_MTVAL_ADDRESS) == word as u64
+	}
+
+	#[test]
+	fn return_from_higher_privilege_mode_traps() {
+		assert!(is_illegal(SRET, PrivilegeMode::User, 0));
+		assert!(is_illegal(MRET, PrivilegeMode::User, 0));
+		assert!(is_illegal(MRET, PrivilegeMode::Supervisor, 0));
+		assert!(!is_illegal(SRET, PrivilegeMode::Supervisor, 0));
+		assert!(!is_illegal(SRET, PrivilegeMode::Machine, 0));
+		assert!(!is_illegal(MRET, PrivilegeMode::Machine, 0));
+	}
+
+	#[test]
+	fn trap_sret_traps_in_supervisor_mode() {
+		let tsr = 1 << 22;
+		assert!(is_illegal(SRET, PrivilegeMode::Supervisor, tsr));
+		assert!(!is_illegal(SRET, PrivilegeMode::Machine, tsr));
+	}
+
+	#[test]
+	fn trap_virtual_memory_traps_in_supervisor_mode() {
+		let tvm = 1 << 20;
+		let satp_words = [csrrw(0, CSR_SATP_ADDRESS, 0), csrrs(1, CSR_SATP_ADDRESS, 0)];
+		for word in satp_words.iter().chain(&[SFENCE_VMA]) {
+			assert!(is_illegal(*word, PrivilegeMode::Supervisor, tvm));
+			assert!(!is_illegal(*word, PrivilegeMode::Supervisor, 0));
+			assert!(!is_illegal(*word, PrivilegeMode::Machine, tvm));
+		}
+		assert!(is_illegal(SFENCE_VMA, PrivilegeMode::User, 0));
+	}
+
+	#[test]
+	fn page_fault_trap_value_is_faulting_address() {
+		let v_address = 0x40000000;
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[csrrw(0, CSR_SATP_ADDRESS, 10), ld(2, 1, 0x238), sd(2, 1, 0x240)]);
+		// The next page isn't mapped
+		map_page(&mut cpu, v_address, DRAM_BASE + 0x20000, 0x6);
+		enable_data_translation(&mut cpu);
+		cpu.write_register(1, (v_address + 0x1000) as i64);
+		cpu.write_register(10, SATP_SV39 as i64);
+		assert!(!cpu.step().trapped);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 13);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), v_address + 0x1238);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 4);
+		// The trap has set MPP to machine mode
+		enable_data_translation(&mut cpu);
+		cpu.update_pc(DRAM_BASE + 8);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 15);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), v_address + 0x1240);
+	}
+
+	#[test]
+	fn environment_call_and_breakpoint_trap_values() {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[ECALL, 0x00100073]);
+		cpu.write_csr_raw(CSR_MTVAL_ADDRESS, 0x1234);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 11);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), 0);
+		cpu.update_pc(DRAM_BASE + 4);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 3);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), DRAM_BASE + 4);
+	}
+
+	// Raises supervisor timer interrupt while running a user mode instruction
+	fn take_supervisor_timer_interrupt(mideleg: u64) -> Cpu {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[addi(0, 0, 0)]);
+		update_privilege_mode(&mut cpu, PrivilegeMode::User);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, DRAM_BASE + 0x1000);
+		cpu.write_csr_raw(CSR_STVEC_ADDRESS, DRAM_BASE + 0x2000);
+		cpu.write_csr_raw(CSR_MIDELEG_ADDRESS, mideleg);
+		cpu.write_csr_raw(CSR_MIE_ADDRESS, 0x20); // STIE
+		cpu.write_csr_raw(CSR_MIP_ADDRESS, 0x20); // STIP
+		cpu.step_with_devices();
+		cpu
+	}
+
+	#[test]
+	fn delegated_interrupt_traps_to_supervisor_mode() {
+		let cpu = take_supervisor_timer_interrupt(0x20);
+		assert!(cpu.privilege_mode == PrivilegeMode::Supervisor);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 0x2000);
+		assert_eq!(cpu.read_csr_raw(CSR_SCAUSE_ADDRESS), 0x8000000000000005);
+		assert_eq!(cpu.read_csr_raw(CSR_SEPC_ADDRESS), DRAM_BASE + 4);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 0);
+	}
+
+	#[test]
+	fn undelegated_interrupt_traps_to_machine_mode() {
+		let cpu = take_supervisor_timer_interrupt(0);
+		assert!(cpu.privilege_mode == PrivilegeMode::Machine);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 0x1000);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 0x8000000000000005);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 4);
+		assert_eq!(cpu.read_csr_raw(CSR_SCAUSE_ADDRESS), 0);
+	}
+
+	#[test]
+	fn exception_delegation_is_separate_from_interrupt() {
+		// medeleg bit 5, load access fault, doesn't delegate the timer interrupt
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[addi(0, 0, 0)]);
+		update_privilege_mode(&mut cpu, PrivilegeMode::User);
+		cpu.write_csr_raw(CSR_MEDELEG_ADDRESS, 0x20);
+		cpu.write_csr_raw(CSR_MIE_ADDRESS, 0x20);
+		cpu.write_csr_raw(CSR_MIP_ADDRESS, 0x20);
+		cpu.step_with_devices();
+		assert!(cpu.privilege_mode == PrivilegeMode::Machine);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 0x8000000000000005);
+	}
+
+	// Runs the instruction in supervisor mode with supervisor timer interrupt
+	// pending. It isn't delegated and is taken to machine mode after the instruction.
+	fn interrupt_after(word: u32, medeleg: u64) -> Cpu {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[word]);
+		update_privilege_mode(&mut cpu, PrivilegeMode::Supervisor);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, DRAM_BASE + 0x1000);
+		cpu.write_csr_raw(CSR_STVEC_ADDRESS, DRAM_BASE + 0x2000);
+		cpu.write_csr_raw(CSR_MEDELEG_ADDRESS, medeleg);
+		cpu.write_csr_raw(CSR_MIE_ADDRESS, 0x20); // STIE
+		cpu.write_csr_raw(CSR_MIP_ADDRESS, 0x20); // STIP
+		cpu.step_with_devices();
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 0x8000000000000005);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 0x1000);
+		cpu
+	}
+
+	#[test]
+	fn interrupt_epc_after_branch() {
+		let cpu = interrupt_after(beq(0, 0, 0x10), 0);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 0x10);
+		let cpu = interrupt_after(bne(0, 0, 0x10), 0);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 4);
+	}
+
+	#[test]
+	fn interrupt_epc_after_trap() {
+		// ECALL from S-mode traps to the supervisor handler, and then
+		// the interrupt is taken before the handler runs
+		let cpu = interrupt_after(ECALL, 1 << 9);
+		assert_eq!(cpu.read_csr_raw(CSR_SCAUSE_ADDRESS), 9);
+		assert_eq!(cpu.read_csr_raw(CSR_SEPC_ADDRESS), DRAM_BASE);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 0x2000);
+	}
+
+	#[test]
+	fn zba_decode() {
+		let words = [
+			(sh_add(2, 0x33), "SH1ADD"),
+			(sh_add(4, 0x33), "SH2ADD"),
+			(sh_add(6, 0x33), "SH3ADD"),
+			(r_type(0x04, 2, 1, 0, 3, 0x3b), "ADD.UW"),
+			(sh_add(2, 0x3b), "SH1ADD.UW"),
+			(sh_add(4, 0x3b), "SH2ADD.UW"),
+			(sh_add(6, 0x3b), "SH3ADD.UW"),
+			(slli_uw(0), "SLLI.UW"),
+			(slli_uw(63), "SLLI.UW")
+		];
+		for (word, name) in words.iter() {
+			assert_eq!(decode(*word, &Xlen::Bit64, true, false).map(|instruction| get_instruction_name(&instruction)), Ok(*name));
+			assert!(decode(*word, &Xlen::Bit64, false, true).is_err());
+		}
+		// Other funct7 in the encoding space aren't Zba
+		assert!(decode(r_type(0x04, 2, 1, 2, 3, 0x3b), &Xlen::Bit64, true, true).is_err());
+		assert!(decode(i_type(0x40, 1, 1, 3, 0x1b), &Xlen::Bit64, true, true).is_err());
+	}
+
+	#[test]
+	fn zba_disabled_traps() {
+		let mut cpu = create_cpu();
+		let word = sh_add(2, 0x33);
+		cpu.update_zba_enabled(false);
+		load_program(&mut cpu, DRAM_BASE, &[word]);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 2);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), word as u64);
+	}
+
+	#[test]
+	fn zba_unsigned_word_forms_add_full_rs2() {
+		// Only rs1 is zero-extended, and the sum isn't truncated
+		let add_uw = r_type(0x04, 2, 1, 0, 3, 0x3b);
+		assert_eq!(execute_x3(add_uw, 0xffffffff, 0xffffffff00000001u64 as i64), 0);
+		assert_eq!(execute_x3(sh_add(6, 0x3b), 0x100000001, 0x7ffffffffffffff0), 0x7ffffffffffffff8);
+		assert_eq!(execute_x3(slli_uw(31), 0x100000003, 0), 0x180000000);
+	}
+
+	#[test]
+	fn time_increases_across_ticks() {
+		let mut cpu = create_cpu();
+		let nop = addi(0, 0, 0);
+		load_program(&mut cpu, DRAM_BASE, &[csrrs(1, CSR_TIME_ADDRESS, 0), nop, nop, nop, csrrs(2, CSR_TIME_ADDRESS, 0)]);
+		for _ in 0..5 {
+			cpu.step_with_devices();
+		}
+		assert!(cpu.read_register(2) > cpu.read_register(1));
+		assert_eq!(cpu.read_register(2) as u64, cpu.mmu.read_mtime() - 1);
+	}
+
+	#[test]
+	fn time_access_follows_counter_enable() {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[csrrs(1, CSR_TIME_ADDRESS, 0)]);
+		update_privilege_mode(&mut cpu, PrivilegeMode::Supervisor);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 2);
+		// mcounteren.TM
+		cpu.write_csr_raw(CSR_MCOUNTEREN_ADDRESS, 0x2);
+		update_privilege_mode(&mut cpu, PrivilegeMode::Supervisor);
+		cpu.update_pc(DRAM_BASE);
+		assert!(!cpu.step().trapped);
+	}
+
+	#[test]
+	fn compressed_instruction_at_page_end() {
+		let v_address = 0x40000000;
+		let code_address = DRAM_BASE + 0x3000;
+		let mut cpu = create_cpu();
+		// c.addi x1, 1 at the end of the page. The next page isn't mapped.
+		cpu.write_memory(code_address + 0xffe, &[0x85, 0x00]).unwrap();
+		map_page(&mut cpu, v_address, code_address, 0x8);
+		update_privilege_mode(&mut cpu, PrivilegeMode::Supervisor);
+		assert!(cpu.write_csr(CSR_SATP_ADDRESS, SATP_SV39).is_ok());
+		cpu.update_pc(v_address + 0xffe);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_register(1), 1);
+		assert_eq!(cpu.read_pc(), v_address + 0x1000);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 12);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), v_address + 0x1000);
+	}
+
+	#[test]
+	fn instruction_crossing_page_end_faults() {
+		let v_address = 0x40000000;
+		let code_address = DRAM_BASE + 0x3000;
+		let mut cpu = create_cpu();
+		// The lower half of addi x1, x1, 1
+		cpu.write_memory(code_address + 0xffe, &addi(1, 1, 1).to_le_bytes()[..2]).unwrap();
+		map_page(&mut cpu, v_address, code_address, 0x8);
+		update_privilege_mode(&mut cpu, PrivilegeMode::Supervisor);
+		assert!(cpu.write_csr(CSR_SATP_ADDRESS, SATP_SV39).is_ok());
+		cpu.update_pc(v_address + 0xffe);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 12);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), v_address + 0xffe);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), v_address + 0x1000);
+		assert_eq!(cpu.read_register(1), 0);
+	}
+
+	// Whether rdcycle traps in the privilege mode with the counter enables
+	fn rdcycle_traps(privilege_mode: PrivilegeMode, mcounteren: u64, scounteren: u64) -> bool {
+		let mut cpu = create_cpu();
+		let word = csrrs(1, CSR_CYCLE_ADDRESS, 0);
+		load_program(&mut cpu, DRAM_BASE, &[word]);
+		cpu.write_csr_raw(CSR_MCOUNTEREN_ADDRESS, mcounteren);
+		cpu.write_csr_raw(CSR_SCOUNTEREN_ADDRESS, scounteren);
+		update_privilege_mode(&mut cpu, privilege_mode);
+		let trapped = cpu.step().trapped;
+		if trapped {
+			assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 2);
+			assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), word as u64);
+		}
+		trapped
+	}
+
+	#[test]
+	fn rdcycle_follows_counter_enables() {
+		// CY is bit 0
+		assert!(rdcycle_traps(PrivilegeMode::User, 0x1, 0x6));
+		assert!(rdcycle_traps(PrivilegeMode::User, 0x6, 0x1));
+		assert!(!rdcycle_traps(PrivilegeMode::User, 0x1, 0x1));
+		assert!(rdcycle_traps(PrivilegeMode::Supervisor, 0x6, 0x7));
+		assert!(!rdcycle_traps(PrivilegeMode::Supervisor, 0x1, 0x0));
+		assert!(!rdcycle_traps(PrivilegeMode::Machine, 0x0, 0x0));
+	}
+
+	#[test]
+	fn supervisor_software_interrupt_set_by_csrrs() {
+		let mut cpu = create_cpu();
+		let handler = DRAM_BASE + 0x1000;
+		load_program(&mut cpu, DRAM_BASE, &[csrrs(0, CSR_SIP_ADDRESS, 1)]);
+		load_program(&mut cpu, handler, &[addi(5, 0, 1)]);
+		cpu.update_pc(DRAM_BASE);
+		cpu.enter_supervisor_mode();
+		cpu.write_csr_raw(CSR_STVEC_ADDRESS, handler);
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 0x2); // SIE
+		cpu.write_csr_raw(CSR_MIE_ADDRESS, 0x2); // SSIE
+		cpu.write_register(1, 0x2); // SSIP
+		cpu.step_with_devices();
+		assert_eq!(cpu.read_pc(), handler);
+		assert_eq!(cpu.read_csr_raw(CSR_SCAUSE_ADDRESS), 0x8000000000000001);
+		assert_eq!(cpu.read_csr_raw(CSR_SEPC_ADDRESS), DRAM_BASE + 4);
+		cpu.step_with_devices();
+		assert_eq!(cpu.read_register(5), 1);
+		// SSIP keeps pending until software clears it but SIE is cleared in the handler
+		assert_eq!(cpu.read_csr_raw(CSR_MIP_ADDRESS) & 0x2, 0x2);
+		assert_eq!(cpu.read_pc(), handler + 4);
+	}
+
+	const FS_INITIAL: u64 = 0x2000;
+
+	// OP-FP instruction writing f3 (or x3) from f1 (or x1) and f2
+	fn fp_op(funct7: u32, rs2: u32, rm: u32) -> u32 {
+		r_type(funct7, rs2, 1, rm, 3, 0x53)
+	}
+
+	// f3 = f1 * f2 + f4, and the other fused multiply-adds by opcode
+	fn fp_fused(opcode: u32, fmt: u32, rm: u32) -> u32 {
+		(4 << 27) | (fmt << 25) | (2 << 20) | (1 << 15) | (rm << 12) | (3 << 7) | opcode
+	}
+
+	fn create_fp_cpu(words: &[u32]) -> Cpu {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, words);
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, FS_INITIAL);
+		cpu
+	}
+
+	// Whether the instruction with the rounding mode raises illegal
+	// instruction exception while frm holds the dynamic rounding mode
+	fn rounding_mode_traps(instruction: &dyn Fn(u32) -> u32, rm: u32, frm: u64) -> bool {
+		let mut cpu = create_fp_cpu(&[instruction(rm)]);
+		cpu.write_csr_raw(CSR_FCSR_ADDRESS, frm << 5);
+		cpu.step().trapped && cpu.read_csr_raw(CSR_MCAUSE_ADDRESS) == 2
+	}
+
+	#[test]
+	fn reserved_rounding_mode_traps() {
+		let instructions: Vec<Box<dyn Fn(u32) -> u32>> = vec![
+			Box::new(|rm| fp_op(0x00, 2, rm)), // FADD.S
+			Box::new(|rm| fp_op(0x05, 2, rm)), // FSUB.D
+			Box::new(|rm| fp_op(0x08, 2, rm)), // FMUL.S
+			Box::new(|rm| fp_op(0x0d, 2, rm)), // FDIV.D
+			Box::new(|rm| fp_op(0x2d, 0, rm)), // FSQRT.D
+			Box::new(|rm| fp_op(0x20, 1, rm)), // FCVT.S.D
+			Box::new(|rm| fp_op(0x21, 0, rm)), // FCVT.D.S
+			Box::new(|rm| fp_op(0x68, 2, rm)), // FCVT.S.L
+			Box::new(|rm| fp_op(0x69, 0, rm)), // FCVT.D.W
+			Box::new(|rm| fp_op(0x60, 0, rm)), // FCVT.W.S
+			Box::new(|rm| fp_fused(0x43, 0, rm)), // FMADD.S
+			Box::new(|rm| fp_fused(0x4f, 1, rm)) // FNMADD.D
+		];
+		for instruction in instructions.iter() {
+			assert!(rounding_mode_traps(instruction.as_ref(), 5, 0));
+			assert!(rounding_mode_traps(instruction.as_ref(), 6, 0));
+			for frm in 5..8 {
+				assert!(rounding_mode_traps(instruction.as_ref(), 7, frm));
 			}
-		};
-		let instruction = match self.decode(word) {
-			Ok(instruction) => instruction,
-			Err(()) => match self.decode(self.uncompress(word & 0xffff)) {
-				Ok(instruction) => {
-					word = word & 0xffff;
-					instruction
-				},
-				Err(()) => {
-					println!("Unknown instruction PC:{:x} WORD:{:x}", self.pc, word);
-					self.dump_instruction(self.pc);
-					panic!();
-				}
+			for rm in 0..5 {
+				assert!(!rounding_mode_traps(instruction.as_ref(), rm, 5));
+				assert!(!rounding_mode_traps(instruction.as_ref(), 7, rm as u64));
 			}
-		};
-		let s = format!("PC:{:016x}, Word:{:08x}, Inst:{}\n",
-			self.unsigned_data(v_address as i64),
-			word, get_instruction_name(&instruction));
-		self.put_bytes_to_terminal(s.as_bytes());
+		}
+		// FSGNJ.S and FMIN.S have no rounding mode
+		assert!(!rounding_mode_traps(&|_| fp_op(0x10, 2, 0), 7, 5));
+		assert!(!rounding_mode_traps(&|_| fp_op(0x14, 2, 0), 7, 5));
 	}
 
-	pub fn put_bytes_to_terminal(&mut self, bytes: &[u8]) {
-		for i in 0..bytes.len() {
-			self.mmu.put_uart_output(bytes[i]);
+	#[test]
+	fn fp_instructions_trap_while_fs_is_off() {
+		let words = [
+			fp_op(0x00, 2, 0), // FADD.S
+			fp_op(0x70, 0, 0), // FMV.X.W
+			fp_fused(0x47, 1, 0), // FMSUB.D
+			i_type(0, 0, 3, 3, 0x07), // FLD
+			s_type(0, 3, 0, 2, 0x27), // FSW
+			csrrs(3, CSR_FCSR_ADDRESS, 0),
+			csrrw(0, CSR_FRM_ADDRESS, 1),
+			csrrs(0, CSR_FFLAGS_ADDRESS, 1)
+		];
+		for word in words.iter() {
+			assert!(is_illegal(*word, PrivilegeMode::Machine, 0));
+		}
+		for word in [words[0], words[1], words[2], words[5], words[6]].iter() {
+			assert!(!is_illegal(*word, PrivilegeMode::Machine, FS_INITIAL));
 		}
+		// Compressed C.FLDSP is expanded to FLD
+		let mut cpu = create_cpu();
+		cpu.write_memory(DRAM_BASE, &[0x02, 0x20]).unwrap(); // c.fldsp f0, 0(sp)
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 2);
 	}
-	
-	// Wasm specific
-	pub fn get_output(&mut self) -> u8 {
-		self.mmu.get_uart_output()
+
+	#[test]
+	fn status_dirty_bit_follows_fs() {
+		let mut cpu = create_fp_cpu(&[fp_op(0x00, 2, 0)]);
+		assert_eq!(cpu.read_csr(CSR_MSTATUS_ADDRESS).ok().unwrap() >> 63, 0);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MSTATUS_ADDRESS) & 0x6000, 0x6000);
+		assert_eq!(cpu.read_csr(CSR_MSTATUS_ADDRESS).ok().unwrap() >> 63, 1);
+		assert_eq!(cpu.read_csr(CSR_SSTATUS_ADDRESS).ok().unwrap() >> 63, 1);
+		// Written SD is ignored. The guest saves the state and marks FS Clean.
+		assert!(cpu.write_csr(CSR_MSTATUS_ADDRESS, (1 << 63) | 0x4000).is_ok());
+		assert_eq!(cpu.read_csr(CSR_MSTATUS_ADDRESS).ok().unwrap() >> 63, 0);
+
+		let mut cpu = create_fp_cpu(&[fp_op(0x00, 2, 0)]);
+		cpu.update_xlen(Xlen::Bit32);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_csr(CSR_MSTATUS_ADDRESS).ok().unwrap(), 0x80006000);
 	}
 
-	pub fn put_input(&mut self, data: u8) {
-		self.mmu.put_uart_input(data);
+	#[test]
+	fn single_precision_values_are_nan_boxed() {
+		let mut cpu = create_fp_cpu(&[
+			fp_op(0x00, 2, 0), // FADD.S
+			fp_op(0x70, 0, 0), // FMV.X.W
+			fp_op(0x78, 0, 0) // FMV.W.X
+		]);
+		// f2 isn't properly NaN-boxed, read as canonical NaN
+		cpu.f[1] = 0xffffffff3f800000; // 1.0
+		cpu.f[2] = 0x000000003f800000;
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.f[3], 0xffffffff7fc00000);
+		// The lower 32 bits are moved and sign-extended
+		cpu.f[1] = 0xffffffffbf800000; // -1.0
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_register(3) as u64, 0xffffffffbf800000);
+		cpu.write_register(1, 0x1234567840000000);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.f[3], 0xffffffff40000000);
+	}
+
+	#[test]
+	fn compressed_double_load_and_store() {
+		let mut cpu = create_cpu();
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, FS_INITIAL);
+		// c.fld f8, 8(s1) and c.fsd f8, 16(s1)
+		cpu.write_memory(DRAM_BASE, &[0x80, 0x24, 0x80, 0xa8]).unwrap();
+		let data_address = DRAM_BASE + 0x100;
+		cpu.write_memory(data_address + 8, &0x400921fb54442d18u64.to_le_bytes()).unwrap();
+		cpu.write_register(9, data_address as i64);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.f[8], 0x400921fb54442d18);
+		assert!(!cpu.step().trapped);
+		let mut bytes = [0; 8];
+		cpu.read_memory(data_address + 16, &mut bytes).unwrap();
+		assert_eq!(u64::from_le_bytes(bytes), 0x400921fb54442d18);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 4);
+	}
+
+	fn box_f32(value: f32) -> u64 {
+		0xffffffff00000000 | value.to_bits() as u64
+	}
+
+	// Runs the instruction on f1, f2, and f4 and returns fflags
+	fn get_fflags(word: u32, a: u64, b: u64, c: u64) -> u64 {
+		let mut cpu = create_fp_cpu(&[word]);
+		cpu.f[1] = a;
+		cpu.f[2] = b;
+		cpu.f[4] = c;
+		assert!(!cpu.step().trapped);
+		cpu.read_csr_raw(CSR_FCSR_ADDRESS) & 0x1f
+	}
+
+	fn get_fflags_f32(word: u32, a: f32, b: f32) -> u64 {
+		get_fflags(word, box_f32(a), box_f32(b), box_f32(0.0))
+	}
+
+	fn get_fflags_f64(word: u32, a: f64, b: f64) -> u64 {
+		get_fflags(word, a.to_bits(), b.to_bits(), 0)
+	}
+
+	#[test]
+	fn inexact_overflow_and_underflow_flags() {
+		let (nx, uf, of, dz) = (0x1, 0x2, 0x4, 0x8);
+		let tiny = 1.0 / (1u64 << 30) as f32;
+		assert_eq!(get_fflags_f32(fp_op(0x00, 2, 0), 1.0, 1.0), 0); // FADD.S
+		assert_eq!(get_fflags_f32(fp_op(0x00, 2, 0), 1.0, tiny), nx);
+		assert_eq!(get_fflags_f32(fp_op(0x04, 2, 0), f32::MAX, f32::MAX), 0); // FSUB.S
+		assert_eq!(get_fflags_f32(fp_op(0x04, 2, 0), f32::MAX, -f32::MAX / 2.0), nx | of);
+		assert_eq!(get_fflags_f32(fp_op(0x08, 2, 0), f32::MAX, 2.0), nx | of); // FMUL.S
+		// Exact subnormal result isn't underflow
+		assert_eq!(get_fflags_f32(fp_op(0x08, 2, 0), f32::MIN_POSITIVE, 0.5), 0);
+		assert_eq!(get_fflags_f32(fp_op(0x08, 2, 0), f32::MIN_POSITIVE, 1.0 / 3.0), nx | uf);
+		assert_eq!(get_fflags_f32(fp_op(0x0c, 2, 0), 1.0, 3.0), nx); // FDIV.S
+		assert_eq!(get_fflags_f32(fp_op(0x0c, 2, 0), 1.0, 0.0), dz);
+		assert_eq!(get_fflags_f32(fp_op(0x2c, 0, 0), 4.0, 0.0), 0); // FSQRT.S
+		assert_eq!(get_fflags_f32(fp_op(0x2c, 0, 0), 2.0, 0.0), nx);
+		// FMADD.S with f4
+		assert_eq!(get_fflags(fp_fused(0x43, 0, 0), box_f32(3.0), box_f32(3.0), box_f32(tiny)), nx);
+		assert_eq!(get_fflags(fp_fused(0x43, 0, 0), box_f32(3.0), box_f32(3.0), box_f32(1.0)), 0);
+		assert_eq!(get_fflags_f32(fp_op(0x60, 0, 0), 1.5, 0.0), nx); // FCVT.W.S
+		assert_eq!(get_fflags_f32(fp_op(0x60, 0, 0), -2.0, 0.0), 0);
+
+		let tiny = 1.0 / (1u64 << 60) as f64;
+		assert_eq!(get_fflags_f64(fp_op(0x01, 2, 0), 1.0, tiny), nx); // FADD.D
+		assert_eq!(get_fflags_f64(fp_op(0x01, 2, 0), 1.0, 0.5), 0);
+		assert_eq!(get_fflags_f64(fp_op(0x09, 2, 0), f64::MAX, 1.5), nx | of); // FMUL.D
+		assert_eq!(get_fflags_f64(fp_op(0x09, 2, 0), f64::MIN_POSITIVE, 1.0 / 3.0), nx | uf);
+		assert_eq!(get_fflags_f64(fp_op(0x0d, 2, 0), 1.0, 10.0), nx); // FDIV.D
+		assert_eq!(get_fflags_f64(fp_op(0x09, 2, 0), f64::MIN_POSITIVE, 0.25), 0);
+		assert_eq!(get_fflags_f64(fp_op(0x09, 2, 0), 1e-200, 1e-200), nx | uf);
+		assert_eq!(get_fflags_f64(fp_op(0x0d, 2, 0), f64::MIN_POSITIVE, 3.0), nx | uf);
+		assert_eq!(get_fflags_f64(fp_op(0x0d, 2, 0), f64::MIN_POSITIVE, 1024.0), 0);
+		assert_eq!(get_fflags_f64(fp_op(0x2d, 0, 0), f64::from_bits(4), 0.0), 0);
+		assert_eq!(get_fflags_f64(fp_op(0x2d, 0, 0), f64::from_bits(3), 0.0), nx);
+		assert_eq!(get_fflags_f64(fp_op(0x0d, 2, 0), 1.0, 4.0), 0);
+		assert_eq!(get_fflags_f64(fp_op(0x2d, 0, 0), 3.0, 0.0), nx); // FSQRT.D
+		assert_eq!(get_fflags_f64(fp_op(0x20, 1, 0), 0.1, 0.0), nx); // FCVT.S.D
+		assert_eq!(get_fflags_f64(fp_op(0x20, 1, 0), 1e300, 0.0), nx | of);
+		assert_eq!(get_fflags_f64(fp_op(0x20, 1, 0), 0.5, 0.0), 0);
+		assert_eq!(get_fflags_f64(fp_op(0x20, 1, 0), f64::INFINITY, 0.0), 0);
+		assert_eq!(get_fflags_f64(fp_op(0x61, 2, 0), 2.5, 0.0), nx); // FCVT.L.D
+		// FMADD.D, FMSUB.D, FNMSUB.D, and FNMADD.D with f4
+		let fused_fflags = |opcode: u32, a: f64, b: f64, c: f64| get_fflags(fp_fused(opcode, 1, 0), a.to_bits(), b.to_bits(), c.to_bits());
+		let one_ulp = 1.0 + f64::EPSILON;
+		assert_eq!(fused_fflags(0x43, 3.0, 3.0, tiny), nx);
+		assert_eq!(fused_fflags(0x43, 3.0, 3.0, 1.0), 0);
+		assert_eq!(fused_fflags(0x43, f64::MAX, 2.0, 0.0), nx | of);
+		assert_eq!(fused_fflags(0x43, 1e-200, 1e-200, 0.0), nx | uf);
+		assert_eq!(fused_fflags(0x43, f64::MIN_POSITIVE, 0.25, 0.0), 0);
+		assert_eq!(fused_fflags(0x47, 3.0, 3.0, 9.0), 0);
+		// -(1 + 2^-52)^2 + (1 + 2^-51) = -2^-104 exactly, but not with the sign of c flipped
+		assert_eq!(fused_fflags(0x4b, one_ulp, one_ulp, 1.0 + 2.0 * f64::EPSILON), 0);
+		assert_eq!(fused_fflags(0x4f, one_ulp, one_ulp, 1.0 + 2.0 * f64::EPSILON), nx);
+		assert_eq!(fused_fflags(0x4f, one_ulp, one_ulp, -1.0), nx);
+	}
+
+	// Converts x1 with the rounding mode, returns f3 and fflags
+	fn convert_integer(funct7: u32, rs2: u32, rm: u32, value: i64) -> (u64, u64) {
+		let mut cpu = create_fp_cpu(&[fp_op(funct7, rs2, rm)]);
+		cpu.write_register(1, value);
+		assert!(!cpu.step().trapped);
+		(cpu.f[3], cpu.read_csr_raw(CSR_FCSR_ADDRESS) & 0x1f)
+	}
+
+	#[test]
+	fn integer_to_float_conversion_rounding_mode() {
+		// FCVT.S.W of 2^24 + 1, halfway between two single precision values
+		let value = (1 << 24) + 1;
+		let expected = [(1 << 24), (1 << 24), (1 << 24), (1 << 24) + 2, (1 << 24) + 2];
+		for rm in 0..5 {
+			assert_eq!(convert_integer(0x68, 0, rm, value), (box_f32(expected[rm as usize] as f32), 1));
+			assert_eq!(convert_integer(0x68, 0, rm, -value), (box_f32(match rm {
+				0 | 1 | 3 => -(1 << 24),
+				_ => -(1 << 24) - 2
+			} as f32), 1));
+		}
+		// Not halfway
+		let value = (1 << 24) + 3;
+		assert_eq!(convert_integer(0x68, 0, 4, value).0, box_f32(((1 << 24) + 4) as f32));
+		assert_eq!(convert_integer(0x68, 0, 1, value).0, box_f32(((1 << 24) + 2) as f32));
+		assert_eq!(convert_integer(0x68, 0, 0, 3), (box_f32(3.0), 0));
+
+		// FCVT.D.L of 2^53 + 1
+		let value = (1 << 53) + 1;
+		assert_eq!(convert_integer(0x69, 2, 0, value), (((1i64 << 53) as f64).to_bits(), 1));
+		assert_eq!(convert_integer(0x69, 2, 3, value), ((((1i64 << 53) + 2) as f64).to_bits(), 1));
+		assert_eq!(convert_integer(0x69, 2, 2, -value), ((-((1i64 << 53) + 2) as f64).to_bits(), 1));
+		assert_eq!(convert_integer(0x69, 2, 4, i64::MIN), ((i64::MIN as f64).to_bits(), 0));
+
+		// FCVT.S.LU of the largest value rounds up to 2^64 or toward zero
+		assert_eq!(convert_integer(0x68, 3, 0, -1), (box_f32(18446744073709551616.0), 1));
+		assert_eq!(convert_integer(0x68, 3, 1, -1), (box_f32(((1u64 << 24) - 1) as f32 * (1u64 << 40) as f32), 1));
+		// FCVT.D.LU and FCVT.S.WU
+		assert_eq!(convert_integer(0x69, 3, 2, -1), (((u64::MAX - 2047) as f64).to_bits(), 1));
+		assert_eq!(convert_integer(0x68, 1, 3, -1), (box_f32(4294967296.0), 1));
+
+		// Dynamic rounding mode in frm
+		let mut cpu = create_fp_cpu(&[fp_op(0x68, 0, 7)]);
+		cpu.write_csr_raw(CSR_FCSR_ADDRESS, 3 << 5); // RUP
+		cpu.write_register(1, (1 << 24) + 1);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.f[3], box_f32(((1 << 24) + 2) as f32));
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn snapshot_restore_continues_identically() {
+		let data_address = DRAM_BASE + 0x1000;
+		let program = [
+			addi(1, 1, 1),
+			sd(1, 2, 0),
+			ld(3, 2, 0),
+			r_type(0, 3, 4, 0, 4, 0x33), // add x4, x4, x3
+			beq(0, 0, -16)
+		];
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &program);
+		cpu.write_register(2, data_address as i64);
+		for _ in 0..1003 {
+			cpu.step_with_devices();
+		}
+		let state = cpu.save_state().ok().unwrap();
+
+		let mut restored = create_cpu();
+		assert!(restored.load_state(&state).is_ok());
+		for _ in 0..2000 {
+			cpu.step_with_devices();
+			restored.step_with_devices();
+		}
+		assert_eq!(restored.read_pc(), cpu.read_pc());
+		assert_eq!(restored.x, cpu.x);
+		assert!(restored.read_register(4) > 0);
+		for &address in [CSR_CYCLE_ADDRESS, CSR_INSTRET_ADDRESS, CSR_TIME_ADDRESS, CSR_MSTATUS_ADDRESS].iter() {
+			assert_eq!(restored.read_csr(address).ok(), cpu.read_csr(address).ok());
+		}
+		assert_eq!(restored.load_word_raw(data_address).ok(), cpu.load_word_raw(data_address).ok());
+
+		// Broken data fails without changing the state
+		let pc = restored.read_pc();
+		assert!(restored.load_state(&state[..state.len() / 2]).is_err());
+		assert_eq!(restored.read_pc(), pc);
+	}
+
+	// Writes the value to the test finisher, whose pass is 0x5555 and fail
+	// is 0x3333 with the exit code in the upper 16 bits
+	fn finisher_program(value: u32) -> Vec<u32> {
+		vec![
+			lui(1, 0x100), // Finisher base
+			lui(2, value >> 12),
+			addi(2, 2, (value & 0xfff) as i32),
+			s_type(0, 2, 1, 2, 0x23) // sw x2, 0(x1)
+		]
+	}
+
+	#[test]
+	fn run_until_halt() {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &finisher_program(0x5555));
+		assert_eq!(cpu.run(100), RunOutcome::Halted(0));
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &finisher_program(0x33333));
+		assert_eq!(cpu.run(100), RunOutcome::Halted(3));
+	}
+
+	#[test]
+	fn run_until_breakpoint_and_watchpoint() {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[addi(1, 0, 1), addi(2, 0, 2), addi(3, 0, 3)]);
+		cpu.add_breakpoint(DRAM_BASE + 8);
+		assert_eq!(cpu.run(100), RunOutcome::Breakpoint);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 8);
+		assert_eq!(cpu.read_register(3), 0);
+
+		let data_address = DRAM_BASE + 0x1000;
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[addi(1, 0, 1), sd(1, 2, 0), addi(3, 0, 3)]);
+		cpu.write_register(2, data_address as i64);
+		cpu.add_watchpoint(data_address, 8, WatchKind::Write);
+		assert_eq!(cpu.run(100), RunOutcome::Watchpoint);
+		let hit = cpu.take_watchpoint_hit().unwrap();
+		assert_eq!((hit.pc, hit.address), (DRAM_BASE + 4, data_address));
+	}
+
+	#[test]
+	fn run_until_budget_exhausted() {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[addi(1, 1, 1), beq(0, 0, -4)]);
+		assert_eq!(cpu.run(100), RunOutcome::BudgetExhausted);
+		assert_eq!(cpu.read_register(1), 50);
+	}
+
+	#[test]
+	fn run_until_stalled() {
+		// Waiting for interrupt that is disabled
+		let mut cpu = create_cpu();
+		cpu.update_stall_ticks(1000);
+		load_program(&mut cpu, DRAM_BASE, &[addi(1, 0, 1), WFI, addi(2, 0, 2)]);
+		assert_eq!(cpu.run(100), RunOutcome::Stalled);
+		assert_eq!(cpu.read_register(1), 1);
+		assert_eq!(cpu.read_register(2), 0);
+
+		// Trap loop, mtvec points to unmapped address
+		let mut cpu = create_cpu();
+		cpu.update_stall_ticks(1000);
+		load_program(&mut cpu, DRAM_BASE, &[0]);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, 0x1000);
+		assert_eq!(cpu.run(100), RunOutcome::Stalled);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 1); // Instruction access fault
+	}
+
+	#[test]
+	fn environment_call_keeps_epc_of_current_mode() {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[ECALL]);
+		update_privilege_mode(&mut cpu, PrivilegeMode::Supervisor);
+		cpu.write_csr_raw(CSR_SEPC_ADDRESS, 0x1234);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 9);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE);
+		assert_eq!(cpu.read_csr_raw(CSR_SEPC_ADDRESS), 0x1234);
+
+		// User mode environment call delegated to supervisor mode
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[ECALL]);
+		update_privilege_mode(&mut cpu, PrivilegeMode::User);
+		cpu.write_csr_raw(CSR_MEDELEG_ADDRESS, 1 << 8);
+		cpu.write_csr_raw(CSR_UEPC_ADDRESS, 0x1234);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_SCAUSE_ADDRESS), 8);
+		assert_eq!(cpu.read_csr_raw(CSR_SEPC_ADDRESS), DRAM_BASE);
+		assert_eq!(cpu.read_csr_raw(CSR_UEPC_ADDRESS), 0x1234);
+	}
+
+	#[test]
+	fn user_software_interrupt_returns_with_uret() {
+		let mut cpu = create_cpu();
+		let handler = DRAM_BASE + 0x1000;
+		load_program(&mut cpu, handler, &[addi(5, 0, 1), csrrw(0, CSR_UIP_ADDRESS, 0), 0x00200073]); // URET
+		load_program(&mut cpu, DRAM_BASE, &[addi(0, 0, 0), addi(6, 0, 1), ECALL]);
+		update_privilege_mode(&mut cpu, PrivilegeMode::User);
+		cpu.write_csr_raw(CSR_MIDELEG_ADDRESS, 0x1); // USI to supervisor mode
+		cpu.write_csr_raw(CSR_SIDELEG_ADDRESS, 0x1); // and further to user mode
+		cpu.write_csr_raw(CSR_MEDELEG_ADDRESS, 1 << 8); // User ECALL to supervisor mode
+		cpu.write_csr_raw(CSR_SEDELEG_ADDRESS, 1 << 8); // and further to user mode
+		cpu.write_csr_raw(CSR_UTVEC_ADDRESS, handler);
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 0x1); // UIE
+		cpu.write_csr_raw(CSR_MIE_ADDRESS, 0x1); // USIE
+		cpu.write_csr_raw(CSR_MIP_ADDRESS, 0x1); // USIP
+
+		cpu.step_with_devices();
+		assert_eq!(cpu.read_pc(), handler);
+		assert!(cpu.privilege_mode == PrivilegeMode::User);
+		assert_eq!(cpu.read_csr_raw(CSR_UCAUSE_ADDRESS), 0x8000000000000000);
+		assert_eq!(cpu.read_csr_raw(CSR_UEPC_ADDRESS), DRAM_BASE + 4);
+		// UIE is cleared and saved to UPIE
+		assert_eq!(cpu.read_csr_raw(CSR_MSTATUS_ADDRESS) & 0x11, 0x10);
+		for _ in 0..3 {
+			cpu.step_with_devices();
+		}
+		assert_eq!(cpu.read_register(5), 1);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 4);
+		assert_eq!(cpu.read_csr_raw(CSR_MSTATUS_ADDRESS) & 0x11, 0x11);
+		assert_eq!(cpu.read_csr_raw(CSR_MIP_ADDRESS) & 0x1, 0);
+		cpu.step_with_devices();
+		assert_eq!(cpu.read_register(6), 1);
+		assert!(cpu.privilege_mode == PrivilegeMode::User);
+
+		// Delegated exception is handled in user mode too
+		cpu.step_with_devices();
+		assert_eq!(cpu.read_pc(), handler);
+		assert_eq!(cpu.read_csr_raw(CSR_UCAUSE_ADDRESS), 8);
+		assert_eq!(cpu.read_csr_raw(CSR_UEPC_ADDRESS), DRAM_BASE + 8);
+	}
+
+	// Read only memory device for instructions placed outside of the main memory
+	struct Rom {
+		base: u64,
+		data: Vec<u8>
+	}
+
+	impl MmioDevice for Rom {
+		fn range(&self) -> (u64, u64) {
+			(self.base, self.data.len() as u64)
+		}
+
+		fn load(&mut self, address: u64) -> u8 {
+			self.data[address as usize]
+		}
+
+		fn store(&mut self, _address: u64, _value: u8) {}
+
+		fn tick(&mut self) {}
+	}
+
+	#[test]
+	fn program_counter_wraps_in_32_bit_mode() {
+		let mut cpu = create_cpu();
+		cpu.update_xlen(Xlen::Bit32);
+		// Main memory ends at the top of 32-bit address space
+		let mut memory_map = MemoryMap::new();
+		memory_map.dram_base = 0xfff00000;
+		cpu.update_memory_map(memory_map);
+		let mut data = vec![];
+		for word in [addi(2, 1, 2), addi(3, 2, 3)].iter() {
+			data.extend_from_slice(&word.to_le_bytes());
+		}
+		cpu.register_device(Box::new(Rom {
+			base: 0,
+			data: data
+		}));
+
+		load_program(&mut cpu, 0xfffffffc, &[addi(1, 0, 1)]);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_pc(), 0);
+		assert!(!cpu.step().trapped);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_pc(), 8);
+		assert_eq!(cpu.read_register(3), 6);
+
+		// pc reads back masked to 32 bits
+		cpu.update_pc(0x1_2345_6788);
+		assert_eq!(cpu.read_pc(), 0x23456788);
+	}
+
+	#[test]
+	fn compressed_decoding_depends_on_xlen() {
+		// C.ADDIW x1, 8 in RV64 and C.JAL 72 in RV32
+		let jal = (36 << 21) | (1 << 7) | 0x6f;
+		assert_eq!(uncompress(0x20a1, &Xlen::Bit64), i_type(8, 1, 0, 1, 0x1b));
+		assert_eq!(uncompress(0x20a1, &Xlen::Bit32), jal);
+		// C.LD x9, 8(x8) in RV64 and C.FLW f9, 8(x8) in RV32
+		assert_eq!(uncompress(0x6404, &Xlen::Bit64), i_type(8, 8, 3, 9, 0x03));
+		assert_eq!(uncompress(0x6404, &Xlen::Bit32), i_type(8, 8, 2, 9, 0x07));
+
+		// Changing xlen doesn't leave the RV64 decoding in the decode cache
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[0x000120a1]);
+		cpu.write_register(1, 5);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_register(1), 13);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 2);
+		cpu.update_xlen(Xlen::Bit32);
+		cpu.update_pc(DRAM_BASE);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_register(1) as u32 as u64, DRAM_BASE + 2);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 72);
+	}
+
+	#[test]
+	fn software_interrupt_wakes_another_hart() {
+		let mut cpu = create_cpu();
+		let sleeper = DRAM_BASE + 0x100;
+		let handler = DRAM_BASE + 0x200;
+		load_program(&mut cpu, handler, &[csrrs(9, CSR_MHARTID_ADDRESS, 0), beq(0, 0, 0)]);
+		load_program(&mut cpu, sleeper, &[WFI, beq(0, 0, 0)]);
+		// Hart 0 writes msip of hart 1 and then spins
+		load_program(&mut cpu, DRAM_BASE, &[lui(6, 0x2000), addi(7, 0, 1), s_type(4, 7, 6, 2, 0x23), beq(0, 0, 0)]);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, handler);
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 0x8); // MIE
+		cpu.write_csr_raw(CSR_MIE_ADDRESS, 0x8); // MSIE
+		cpu.setup_harts(2);
+		cpu.switch_hart(1);
+		cpu.update_pc(sleeper);
+		cpu.write_register(9, -1);
+		cpu.switch_hart(0);
+		cpu.write_register(9, -1);
+
+		// Hart 1 waits for interrupt while hart 0 sets up the store
+		assert_eq!(cpu.run(2), RunOutcome::BudgetExhausted);
+		cpu.switch_hart(1);
+		assert!(cpu.wfi);
+		assert_eq!(cpu.read_pc(), sleeper + 4);
+		cpu.switch_hart(0);
+
+		assert_eq!(cpu.run(10), RunOutcome::BudgetExhausted);
+		cpu.switch_hart(1);
+		assert!(!cpu.wfi);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 0x8000000000000003);
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), sleeper + 4);
+		assert_eq!(cpu.read_register(9), 1);
+		// Hart 0 isn't interrupted
+		cpu.switch_hart(0);
+		assert_eq!(cpu.read_register(9), -1);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 12);
+	}
+
+	// Runs an instruction reading x1 and x2 in 32-bit mode and returns
+	// x3, or None if the instruction is illegal
+	fn execute_x3_32(word: u32, rs1: i64, rs2: i64) -> Option<u32> {
+		let mut cpu = create_cpu();
+		cpu.update_xlen(Xlen::Bit32);
+		load_program(&mut cpu, DRAM_BASE, &[word]);
+		cpu.write_register(1, rs1);
+		cpu.write_register(2, rs2);
+		match cpu.step().trapped {
+			true => {
+				assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 2);
+				None
+			},
+			false => Some(cpu.read_register(3) as u32)
+		}
+	}
+
+	fn zbb_unary(funct12: u32, funct3: u32) -> u32 {
+		i_type(funct12 as i32, 1, funct3, 3, 0x13)
+	}
+
+	#[test]
+	fn zbb_bit_manipulation() {
+		assert_eq!(execute_x3(zbb_unary(0x602, 1), 0xff, 0), 8); // CPOP
+		assert_eq!(execute_x3(zbb_unary(0x602, 1), -1, 0), 64);
+		assert_eq!(execute_x3(zbb_unary(0x600, 1), 1, 0), 63); // CLZ
+		assert_eq!(execute_x3(zbb_unary(0x600, 1), 0, 0), 64);
+		assert_eq!(execute_x3(zbb_unary(0x601, 1), 0x100, 0), 8); // CTZ
+		assert_eq!(execute_x3(zbb_unary(0x601, 1), 0, 0), 64);
+		assert_eq!(execute_x3(zbb_unary(0x287, 5), 0x0001000080000100, 0), 0x00ff0000ff00ff00); // ORC.B
+		assert_eq!(execute_x3(zbb_unary(0x6b8, 5), 0x0102030405060708, 0), 0x0807060504030201); // REV8
+		assert_eq!(execute_x3(r_type(0x30, 2, 1, 1, 3, 0x33), 0x8000000000000001u64 as i64, 4), 0x18); // ROL
+		assert_eq!(execute_x3(r_type(0x30, 2, 1, 5, 3, 0x33), 1, 4), 0x1000000000000000); // ROR
+		assert_eq!(execute_x3(zbb_unary(0x600 | 36, 5), 1, 0), 1 << 28); // RORI
+
+		assert_eq!(execute_x3_32(zbb_unary(0x602, 1), 0xff, 0), Some(8));
+		assert_eq!(execute_x3_32(zbb_unary(0x600, 1), 1, 0), Some(31));
+		assert_eq!(execute_x3_32(zbb_unary(0x601, 1), 0, 0), Some(32));
+		assert_eq!(execute_x3_32(zbb_unary(0x698, 5), 0x01020304, 0), Some(0x04030201));
+		assert_eq!(execute_x3_32(r_type(0x30, 2, 1, 1, 3, 0x33), 0x80000001, 4), Some(0x18));
+		assert_eq!(execute_x3_32(zbb_unary(0x600 | 4, 5), 1, 0), Some(0x10000000));
+	}
+
+	#[test]
+	fn zbb_encodings_depend_on_xlen() {
+		// RORI with shamt[5] and REV8 of the other xlen are illegal
+		assert_eq!(execute_x3_32(zbb_unary(0x600 | 32, 5), 1, 0), None);
+		assert_eq!(execute_x3_32(zbb_unary(0x6b8, 5), 1, 0), None);
+		assert!(is_illegal(zbb_unary(0x698, 5), PrivilegeMode::Machine, 0));
+	}
+
+	#[test]
+	fn zicond_conditional_zero() {
+		let czero_eqz = r_type(0x07, 2, 1, 5, 3, 0x33);
+		let czero_nez = r_type(0x07, 2, 1, 7, 3, 0x33);
+		assert_eq!(execute_x3(czero_eqz, -5, 0), 0);
+		assert_eq!(execute_x3(czero_eqz, -5, 1), -5);
+		assert_eq!(execute_x3(czero_nez, -5, 0), -5);
+		assert_eq!(execute_x3(czero_nez, -5, 1), 0);
+		assert_eq!(execute_x3_32(czero_eqz, -0x80000000, 0), Some(0));
+		assert_eq!(execute_x3_32(czero_eqz, -0x80000000, -1), Some(0x80000000));
+		assert_eq!(execute_x3_32(czero_nez, -0x80000000, 0), Some(0x80000000));
+		assert_eq!(execute_x3_32(czero_nez, -0x80000000, -1), Some(0));
+	}
+
+	#[test]
+	fn fence_i_runs_stored_instruction() {
+		let mut cpu = create_cpu();
+		let target = DRAM_BASE + 0x100;
+		load_program(&mut cpu, target, &[addi(3, 0, 1)]);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_register(3), 1);
+
+		// Overwrites the instruction already run and jumps back to it
+		let jal_target = (0x7c << 21) | 0x6f;
+		load_program(&mut cpu, DRAM_BASE, &[s_type(0x100, 2, 1, 2, 0x23), 0x0000100f, jal_target]);
+		cpu.write_register(1, DRAM_BASE as i64);
+		cpu.write_register(2, addi(3, 0, 2) as i64);
+		for _ in 0..4 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.read_register(3), 2);
+		assert_eq!(cpu.read_pc(), target + 4);
+	}
+
+	#[test]
+	fn modify_privilege_translates_machine_mode_data_access() {
+		let v_address = 0x40000000;
+		let page = DRAM_BASE + 0x20000;
+		let mprv = 1 << 17;
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[
+			csrrw(0, CSR_SATP_ADDRESS, 10),
+			csrrs(0, CSR_MSTATUS_ADDRESS, 11),
+			ld(2, 1, 0),
+			sd(3, 1, 8),
+			i_type(CSR_MSTATUS_ADDRESS as i32, 11, 3, 0, 0x73), // csrrc
+			ld(4, 12, 0),
+			csrrw(0, CSR_MSTATUS_ADDRESS, 13),
+			ld(5, 1, 0),
+			csrrw(0, CSR_MSTATUS_ADDRESS, 14),
+			MRET
+		]);
+		cpu.write_memory(page, &0x1234u64.to_le_bytes()).unwrap();
+		map_page(&mut cpu, v_address, page, 0x6);
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 0x800); // MPP = S
+		cpu.write_register(1, v_address as i64);
+		cpu.write_register(3, 0x5678);
+		cpu.write_register(10, SATP_SV39 as i64);
+		cpu.write_register(11, mprv);
+		cpu.write_register(12, page as i64);
+		cpu.write_register(13, mprv); // MPP = U
+		cpu.write_register(14, mprv | 0x800);
+
+		// MPRV with MPP = S translates loads and stores but not fetches
+		for _ in 0..4 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.read_register(2), 0x1234);
+		assert_eq!(read_doubleword(&cpu, page + 8), 0x5678);
+		// Clearing MPRV goes back to physical access
+		for _ in 0..2 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.read_register(4), 0x1234);
+		// MPP = U can't access the supervisor page
+		assert!(!cpu.step().trapped);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 13);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), v_address);
+
+		// MRET to a mode other than machine mode clears MPRV
+		cpu.update_pc(DRAM_BASE + 32);
+		cpu.write_csr_raw(CSR_MEPC_ADDRESS, DRAM_BASE + 0x100);
+		for _ in 0..2 {
+			assert!(!cpu.step().trapped);
+		}
+		assert!(cpu.privilege_mode == PrivilegeMode::Supervisor);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 0x100);
+		assert_eq!(cpu.read_csr_raw(CSR_MSTATUS_ADDRESS) & mprv as u64, 0);
+	}
+
+	#[test]
+	fn multiply_high_in_32_bit_mode() {
+		let mulh = r_type(1, 2, 1, 1, 3, 0x33);
+		let mulhsu = r_type(1, 2, 1, 2, 3, 0x33);
+		let mulhu = r_type(1, 2, 1, 3, 3, 0x33);
+		assert_eq!(execute_x3_32(mulh, 0x7fffffff, 0x7fffffff), Some(0x3fffffff));
+		assert_eq!(execute_x3_32(mulh, -1, -1), Some(0));
+		assert_eq!(execute_x3_32(mulh, -0x80000000, -0x80000000), Some(0x40000000));
+		assert_eq!(execute_x3_32(mulh, -0x80000000, 0x7fffffff), Some(0xc0000000));
+		assert_eq!(execute_x3_32(mulhsu, -1, -1), Some(0xffffffff));
+		assert_eq!(execute_x3_32(mulhsu, 0x7fffffff, -1), Some(0x7ffffffe));
+		assert_eq!(execute_x3_32(mulhu, -1, -1), Some(0xfffffffe));
+		assert_eq!(execute_x3_32(mulhu, 0x7fffffff, 0x7fffffff), Some(0x3fffffff));
+	}
+
+	#[test]
+	fn uncompress_memory_access_immediates() {
+		// Encodings of each immediate bit alone and all together
+		let expansions = [
+			(0x5ce8, i_type(0x7c, 9, 2, 10, 0x03)), // C.LW
+			(0x43a0, i_type(0x40, 15, 2, 8, 0x03)),
+			(0x405c, i_type(0x4, 8, 2, 15, 0x03)),
+			(0xdce8, s_type(0x7c, 10, 9, 2, 0x23)), // C.SW
+			(0xc3a0, s_type(0x40, 8, 15, 2, 0x23)),
+			(0xc05c, s_type(0x4, 15, 8, 2, 0x23)),
+			(0x7ce8, i_type(0xf8, 9, 3, 10, 0x03)), // C.LD
+			(0x63a0, i_type(0x40, 15, 3, 8, 0x03)),
+			(0xfce8, s_type(0xf8, 10, 9, 3, 0x23)), // C.SD
+			(0xe780, s_type(0x8, 8, 15, 3, 0x23)),
+			(0x52fe, i_type(0xfc, 2, 2, 5, 0x03)), // C.LWSP
+			(0x4f92, i_type(0x4, 2, 2, 31, 0x03)),
+			(0xff96, s_type(0x1f8, 5, 2, 3, 0x23)), // C.SDSP
+			(0xe47e, s_type(0x8, 31, 2, 3, 0x23)),
+			(0x1fe4, i_type(0x3fc, 2, 0, 9, 0x13)), // C.ADDI4SPN
+			(0x005c, i_type(0x4, 2, 0, 15, 0x13)),
+			(0x0400, i_type(0x200, 2, 0, 8, 0x13))
+		];
+		for (halfword, word) in expansions.iter() {
+			assert_eq!(uncompress(*halfword, &Xlen::Bit64), *word, "halfword 0x{:04x}", halfword);
+		}
+	}
+
+	#[test]
+	fn register_shift_amount_is_masked_by_xlen() {
+		let sll = r_type(0, 2, 1, 1, 3, 0x33);
+		let srl = r_type(0, 2, 1, 5, 3, 0x33);
+		let sra = r_type(0x20, 2, 1, 5, 3, 0x33);
+		// 40 is masked to 8 in 32-bit mode
+		assert_eq!(execute_x3_32(sll, 1, 40), Some(0x100));
+		assert_eq!(execute_x3_32(srl, -0x80000000, 40), Some(0x00800000));
+		assert_eq!(execute_x3_32(sra, -0x80000000, 40), Some(0xff800000));
+		// and 72 to 8 in 64-bit mode
+		assert_eq!(execute_x3(sll, 1, 72), 0x100);
+		assert_eq!(execute_x3(srl, -0x8000000000000000, 72), 0x0080000000000000);
+		assert_eq!(execute_x3(sra, -0x8000000000000000, 72), 0xff80000000000000u64 as i64);
+	}
+
+	#[test]
+	fn stimecmp_raises_supervisor_timer_interrupt() {
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[beq(0, 0, 0)]);
+		cpu.write_csr_raw(CSR_MENVCFG_ADDRESS, MENVCFG_STCE);
+		cpu.write_csr_raw(CSR_STIMECMP_ADDRESS, 10);
+		// mtime advances by one on each tick in deterministic mode
+		for _ in 0..9 {
+			cpu.step_with_devices();
+			assert_eq!(cpu.read_csr_raw(CSR_MIP_ADDRESS) & 0x20, 0);
+		}
+		cpu.step_with_devices();
+		assert_eq!(cpu.mmu.read_mtime(), 10);
+		assert_eq!(cpu.read_csr_raw(CSR_MIP_ADDRESS) & 0x20, 0x20);
+		// Moving stimecmp ahead clears STIP
+		cpu.write_csr_raw(CSR_STIMECMP_ADDRESS, 20);
+		cpu.step_with_devices();
+		assert_eq!(cpu.read_csr_raw(CSR_MIP_ADDRESS) & 0x20, 0);
+	}
+
+	#[test]
+	fn stimecmp_access_requires_stce() {
+		let word = csrrs(3, CSR_STIMECMP_ADDRESS, 0);
+		for (menvcfg, trapped) in [(0, true), (MENVCFG_STCE, false)].iter() {
+			let mut cpu = create_cpu();
+			load_program(&mut cpu, DRAM_BASE, &[word]);
+			cpu.write_csr_raw(CSR_MENVCFG_ADDRESS, *menvcfg);
+			cpu.write_csr_raw(CSR_MCOUNTEREN_ADDRESS, 0x2); // TM
+			cpu.write_csr_raw(CSR_STIMECMP_ADDRESS, 10);
+			update_privilege_mode(&mut cpu, PrivilegeMode::Supervisor);
+			assert_eq!(cpu.step().trapped, *trapped);
+			match trapped {
+				true => assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 2),
+				false => assert_eq!(cpu.read_register(3), 10)
+			};
+		}
+	}
+
+	#[test]
+	fn cbo_zero_clears_one_block() {
+		let block = DRAM_BASE + 0x1040;
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[i_type(4, 1, 2, 0, 0x0f)]);
+		cpu.write_memory(DRAM_BASE + 0x1000, &[0xff; 0x100]).unwrap();
+		cpu.write_register(1, (block + 0x28) as i64);
+		assert!(!cpu.step().trapped);
+		assert_eq!(read_doubleword(&cpu, block - 8), 0xffffffffffffffff);
+		for offset in 0..8 {
+			assert_eq!(read_doubleword(&cpu, block + offset * 8), 0);
+		}
+		assert_eq!(read_doubleword(&cpu, block + 0x40), 0xffffffffffffffff);
+	}
+
+	#[test]
+	fn cbo_zero_faults_and_is_gated_by_cbze() {
+		// Store page fault on an unmapped page reports rs1 address
+		let v_address = 0x40000000;
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[csrrw(0, CSR_SATP_ADDRESS, 10), i_type(4, 1, 2, 0, 0x0f)]);
+		map_page(&mut cpu, v_address, DRAM_BASE + 0x20000, 0x6);
+		enable_data_translation(&mut cpu);
+		cpu.write_register(1, (v_address + 0x1008) as i64);
+		cpu.write_register(10, SATP_SV39 as i64);
+		assert!(!cpu.step().trapped);
+		assert!(cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 15);
+		assert_eq!(cpu.read_csr_raw(CSR_MTVAL_ADDRESS), v_address + 0x1008);
+
+		// Below machine mode, menvcfg.CBZE enables cbo.zero
+		for (menvcfg, trapped) in [(0, true), (MENVCFG_CBZE, false)].iter() {
+			let mut cpu = create_cpu();
+			load_program(&mut cpu, DRAM_BASE, &[i_type(4, 1, 2, 0, 0x0f)]);
+			cpu.write_register(1, (DRAM_BASE + 0x1000) as i64);
+			cpu.write_csr_raw(CSR_MENVCFG_ADDRESS, *menvcfg);
+			update_privilege_mode(&mut cpu, PrivilegeMode::Supervisor);
+			assert_eq!(cpu.step().trapped, *trapped);
+			assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), match trapped {
+				true => 2,
+				false => 0
+			});
+		}
+	}
+
+	#[test]
+	fn fence_tso_decodes_and_executes() {
+		let word = 0x8330000f;
+		assert_eq!(decode(word, &Xlen::Bit64, true, true).map(|instruction| get_instruction_name(&instruction)), Ok("FENCE.TSO"));
+		// Other fm=8 encodings are a normal fence
+		assert_eq!(decode(0x8ff0000f, &Xlen::Bit64, true, true).map(|instruction| get_instruction_name(&instruction)), Ok("FENCE"));
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[word]);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_pc(), DRAM_BASE + 4);
+	}
+
+	#[test]
+	fn undelegated_supervisor_interrupt_is_taken_in_machine_mode() {
+		// Lower privilege interrupts handled in machine mode are enabled by mstatus.MIE
+		for (mstatus, trapped) in [(0x8, true), (0, false)].iter() {
+			let mut cpu = create_cpu();
+			load_program(&mut cpu, DRAM_BASE, &[addi(0, 0, 0)]);
+			cpu.write_csr_raw(CSR_MTVEC_ADDRESS, DRAM_BASE + 0x1000);
+			cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, *mstatus);
+			cpu.write_csr_raw(CSR_MIE_ADDRESS, 0x20); // STIE
+			cpu.write_csr_raw(CSR_MIP_ADDRESS, 0x20); // STIP
+			cpu.step_with_devices();
+			assert!(cpu.privilege_mode == PrivilegeMode::Machine);
+			match trapped {
+				true => {
+					assert_eq!(cpu.read_pc(), DRAM_BASE + 0x1000);
+					assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 0x8000000000000005);
+					assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), DRAM_BASE + 4);
+				},
+				false => assert_eq!(cpu.read_pc(), DRAM_BASE + 4)
+			};
+		}
+	}
+
+	#[test]
+	fn page_table_walk_is_checked_by_pmp() {
+		let v_address = 0x40000000;
+		let page = DRAM_BASE + 0x20000;
+		// Entry 0 covers the page tables without permissions, and entry 1
+		// allows everything else. The walk is checked as supervisor mode
+		// access even for the load in machine mode with MPRV.
+		for (pmpcfg0, trapped) in [(0x1f18, true), (0x1f00, false)].iter() {
+			let mut cpu = create_cpu();
+			load_program(&mut cpu, DRAM_BASE, &[
+				csrrw(0, CSR_PMPADDR0_ADDRESS, 5),
+				csrrw(0, CSR_PMPADDR0_ADDRESS + 1, 6),
+				csrrw(0, CSR_PMPCFG0_ADDRESS, 7),
+				csrrw(0, CSR_SATP_ADDRESS, 10),
+				ld(2, 1, 0)
+			]);
+			cpu.write_memory(page, &0x1234u64.to_le_bytes()).unwrap();
+			map_page(&mut cpu, v_address, page, 0x6);
+			enable_data_translation(&mut cpu);
+			cpu.write_register(1, v_address as i64);
+			cpu.write_register(5, ((PAGE_TABLE_ADDRESS >> 2) | 0x7ff) as i64);
+			cpu.write_register(6, 0x3fffffffffffff);
+			cpu.write_register(7, *pmpcfg0);
+			cpu.write_register(10, SATP_SV39 as i64);
+			for _ in 0..4 {
+				assert!(!cpu.step().trapped);
+			}
+			assert_eq!(cpu.step().trapped, *trapped);
+			match trapped {
+				true => assert_eq!(cpu.read_csr_raw(CSR_MCAUSE_ADDRESS), 13),
+				false => assert_eq!(cpu.read_register(2), 0x1234)
+			};
+		}
+	}
+
+	#[test]
+	fn mstatus_write_is_masked_to_writable_fields() {
+		let word = csrrw(2, CSR_MSTATUS_ADDRESS, 1);
+		let mut cpu = create_cpu();
+		load_program(&mut cpu, DRAM_BASE, &[word, csrrs(2, CSR_MSTATUS_ADDRESS, 0)]);
+		cpu.write_register(1, -1);
+		for _ in 0..2 {
+			assert!(!cpu.step().trapped);
+		}
+		// SD follows the dirty FS, and UXL follows xlen
+		assert_eq!(cpu.read_register(2) as u64, (1 << 63) | MSTATUS_UXL_64 | MSTATUS_WRITABLE_MASK);
+		assert_eq!(cpu.read_csr_raw(CSR_MSTATUS_ADDRESS), MSTATUS_WRITABLE_MASK);
+		// The reserved MPP encoding 2 keeps the current mode
+		load_program(&mut cpu, DRAM_BASE, &[word]);
+		cpu.write_register(1, 0x1000);
+		assert!(!cpu.step().trapped);
+		assert_eq!(cpu.read_csr_raw(CSR_MSTATUS_ADDRESS), 0x1800);
+
+		let mut cpu = create_cpu();
+		cpu.update_xlen(Xlen::Bit32);
+		load_program(&mut cpu, DRAM_BASE, &[word, csrrs(2, CSR_MSTATUS_ADDRESS, 0)]);
+		cpu.write_register(1, -1);
+		for _ in 0..2 {
+			assert!(!cpu.step().trapped);
+		}
+		assert_eq!(cpu.read_register(2) as u32 as u64, 0x80000000 | MSTATUS_WRITABLE_MASK);
 	}
 }
@@ -0,0 +1,190 @@
+use clint::TimerMode;
+use clock_source::ClockSource;
+use cpu::{Cpu, Xlen};
+use entropy_source::EntropySource;
+use loader::{load_elf, LoadError};
+use mmu::MemoryMap;
+use terminal::Terminal;
+
+#[derive(Debug)]
+pub enum BuildError {
+	NoMemory, // Memory capacity isn't set or is zero
+	NoHarts, // The number of harts is zero
+	Load(LoadError),
+	DtbUnmapped(u64) // No memory is mapped to the whole device tree blob
+}
+
+// Sets up a runnable Cpu in the order the setup methods of Cpu expect,
+// memory map, memory, program, xlen, device tree, disk, pc, and harts.
+//
+// let cpu = CpuBuilder::new(terminal, TimerMode::Deterministic)
+// 	.memory(1024 * 1024 * 128)
+// 	.elf(data)
+// 	.build();
+pub struct CpuBuilder {
+	terminal: Box<dyn Terminal>,
+	timer_mode: TimerMode,
+	clock_source: Option<Box<dyn ClockSource>>,
+	entropy_source: Option<Box<dyn EntropySource>>,
+	memory_map: Option<MemoryMap>,
+	memory_capacity: u64,
+	elf: Option<Vec<u8>>,
+	xlen: Option<Xlen>,
+	dtb: Option<(u64, Vec<u8>)>,
+	disk: Option<Vec<u8>>,
+	entry_pc: Option<u64>,
+	harts: usize
+}
+
+impl CpuBuilder {
+	pub fn new(terminal: Box<dyn Terminal>, timer_mode: TimerMode) -> Self {
+		CpuBuilder {
+			terminal: terminal,
+			timer_mode: timer_mode,
+			clock_source: None,
+			entropy_source: None,
+			memory_map: None,
+			memory_capacity: 0,
+			elf: None,
+			xlen: None,
+			dtb: None,
+			disk: None,
+			entry_pc: None,
+			harts: 1
+		}
+	}
+
+	// Host time mtime follows in real time mode. Host monotonic clock by default.
+	pub fn clock_source(mut self, clock_source: Box<dyn ClockSource>) -> Self {
+		self.clock_source = Some(clock_source);
+		self
+	}
+
+	// Randomness of virtio entropy device. Seeded deterministic generator
+	// in deterministic timer mode and host entropy otherwise by default.
+	pub fn entropy_source(mut self, entropy_source: Box<dyn EntropySource>) -> Self {
+		self.entropy_source = Some(entropy_source);
+		self
+	}
+
+	pub fn memory_map(mut self, memory_map: MemoryMap) -> Self {
+		self.memory_map = Some(memory_map);
+		self
+	}
+
+	// Required. Main memory capacity in bytes.
+	pub fn memory(mut self, capacity: u64) -> Self {
+		self.memory_capacity = capacity;
+		self
+	}
+
+	// ELF program to load. xlen and entry pc follow the ELF file
+	// unless set with xlen() and entry_pc().
+	pub fn elf(mut self, data: Vec<u8>) -> Self {
+		self.elf = Some(data);
+		self
+	}
+
+	pub fn xlen(mut self, xlen: Xlen) -> Self {
+		self.xlen = Some(xlen);
+		self
+	}
+
+	// Device tree blob stored at the physical address, passed in a1
+	pub fn dtb(mut self, address: u64, data: Vec<u8>) -> Self {
+		self.dtb = Some((address, data));
+		self
+	}
+
+	// File system image of virtio block disk
+	pub fn disk(mut self, data: Vec<u8>) -> Self {
+		self.disk = Some(data);
+		self
+	}
+
+	pub fn entry_pc(mut self, address: u64) -> Self {
+		self.entry_pc = Some(address);
+		self
+	}
+
+	// The number of harts, one by default. Zero is rejected by build().
+	pub fn harts(mut self, num: usize) -> Self {
+		self.harts = num;
+		self
+	}
+
+	pub fn build(self) -> Result<Cpu, BuildError> {
+		if self.memory_capacity == 0 {
+			return Err(BuildError::NoMemory);
+		}
+		if self.harts == 0 {
+			return Err(BuildError::NoHarts);
+		}
+		let mut cpu = match self.clock_source {
+			Some(clock_source) => Cpu::new_with_clock_source(self.terminal, self.timer_mode, clock_source),
+			None => Cpu::new(self.terminal, self.timer_mode)
+		};
+		match self.entropy_source {
+			Some(entropy_source) => cpu.update_entropy_source(entropy_source),
+			None => {}
+		};
+		match self.memory_map {
+			Some(memory_map) => cpu.update_memory_map(memory_map),
+			None => {}
+		};
+		cpu.setup_memory(self.memory_capacity);
+		let elf_entry = match self.elf {
+			Some(data) => match load_elf(&mut cpu, &data) {
+				Ok(entry) => Some(entry),
+				Err(e) => return Err(BuildError::Load(e))
+			},
+			None => None
+		};
+		match self.xlen {
+			Some(xlen) => cpu.update_xlen(xlen),
+			None => {}
+		};
+		match self.dtb {
+			Some((address, data)) => match cpu.setup_dtb(address, &data) {
+				Ok(()) => {},
+				Err(()) => return Err(BuildError::DtbUnmapped(address))
+			},
+			None => {}
+		};
+		match self.disk {
+			Some(data) => cpu.setup_filesystem(data),
+			None => {}
+		};
+		// Starts from the DRAM base if neither entry pc nor ELF is given
+		let entry_pc = match (self.entry_pc, elf_entry) {
+			(Some(address), _) => address,
+			(None, Some(address)) => address,
+			(None, None) => cpu.get_memory_map().dram_base
+		};
+		cpu.update_pc(entry_pc);
+		cpu.setup_harts(self.harts);
+		Ok(cpu)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use writer_terminal::WriterTerminal;
+
+	fn create_builder() -> CpuBuilder {
+		CpuBuilder::new(Box::new(WriterTerminal::new(std::io::sink())), TimerMode::Deterministic)
+			.memory(0x100000)
+	}
+
+	#[test]
+	fn zero_harts_is_rejected() {
+		match create_builder().harts(0).build() {
+			Err(BuildError::NoHarts) => {},
+			_ => panic!("Zero harts is accepted")
+		};
+		let mut cpu = create_builder().harts(2).build().unwrap();
+		cpu.switch_hart(1);
+		assert_eq!(cpu.get_hart_id(), 1);
+	}
+}
@@ -0,0 +1,57 @@
+use cpu::Instruction;
+
+const DECODE_CACHE_BITS: u32 = 12;
+const DECODE_CACHE_ENTRIES: usize = 1 << DECODE_CACHE_BITS;
+
+#[derive(Clone, Copy)]
+struct DecodeCacheEntry {
+	key: u32,
+	instruction: Instruction,
+	word: u32 // uncompressed instruction word which operands are read from
+}
+
+// Direct mapped cache of decoded instructions, keyed by the fetched
+// instruction bits, 16 bits for compressed instructions. Keys never
+// collide between them because only 32-bit instructions end with 0b11.
+// Decoding depends only on the bits, not on pc or memory, so self-modifying
+// code, FENCE.I, and SFENCE.VMA don't need to invalidate entries. Entries
+// must be cleared only when the decoding rule itself changes, xlen or
+// extensions decoded.
+pub struct DecodeCache {
+	entries: Vec<Option<DecodeCacheEntry>>
+}
+
+impl DecodeCache {
+	pub fn new() -> Self {
+		DecodeCache {
+			entries: vec![None; DECODE_CACHE_ENTRIES]
+		}
+	}
+
+	pub fn get(&self, key: u32) -> Option<(Instruction, u32)> {
+		match &self.entries[get_index(key)] {
+			Some(entry) if entry.key == key => Some((entry.instruction, entry.word)),
+			_ => None
+		}
+	}
+
+	pub fn insert(&mut self, key: u32, instruction: Instruction, word: u32) {
+		self.entries[get_index(key)] = Some(DecodeCacheEntry {
+			key: key,
+			instruction: instruction,
+			word: word
+		});
+	}
+
+	pub fn clear(&mut self) {
+		for entry in self.entries.iter_mut() {
+			*entry = None;
+		}
+	}
+}
+
+// Fibonacci hashing. Low bits of instruction words are mostly opcode
+// so they alone spread keys poorly.
+fn get_index(key: u32) -> usize {
+	(key.wrapping_mul(0x9e3779b9) >> (32 - DECODE_CACHE_BITS)) as usize
+}
@@ -0,0 +1,239 @@
+use cpu::{decode, get_atomic_ordering, get_fence_ordering, get_instruction_fields, get_instruction_format,
+	get_instruction_name, uncompress, AtomicOrdering, Instruction, InstructionFormat, Xlen};
+
+const X_REGISTER_NAMES: [&str; 32] = [
+	"zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+	"s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+	"a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+	"s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"
+];
+
+const F_REGISTER_NAMES: [&str; 32] = [
+	"ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7",
+	"fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+	"fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+	"fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11"
+];
+
+// Disassembles an instruction word into a string like "addi a0, a1, 4".
+// Compressed instruction is accepted in the lower 16 bits of word.
+// pc is used to print branch and jump targets as absolute addresses.
+pub fn disassemble(word: u32, xlen: &Xlen, pc: u64) -> String {
+	// Same as Cpu, first try to decode as non-compressed instruction
+	// and then as compressed instruction
+	let (word, instruction) = match decode(word, xlen, true, true) {
+		Ok(instruction) => (word, instruction),
+		Err(()) => {
+			let uncompressed_word = uncompress(word & 0xffff, xlen);
+			match decode(uncompressed_word, xlen, true, true) {
+				Ok(instruction) => (uncompressed_word, instruction),
+				Err(()) => return format!(".word 0x{:08x}", word)
+			}
+		}
+	};
+	let name = get_instruction_name(&instruction).to_lowercase().replace("_", ".");
+	let format = get_instruction_format(&instruction);
+	let fields = get_instruction_fields(word, &format);
+	let rd = fields.rd as usize;
+	let rs1 = fields.rs1 as usize;
+	let rs2 = fields.rs2 as usize;
+	let rs3 = fields.rs3 as usize;
+	let imm = fields.imm;
+	let x = X_REGISTER_NAMES;
+	let f = F_REGISTER_NAMES;
+	match format {
+		InstructionFormat::B => {
+			let target = get_target_address(pc.wrapping_add(imm as u64), xlen);
+			format!("{} {}, {}, 0x{:x}", name, x[rs1], x[rs2], target)
+		},
+		InstructionFormat::C => {
+			let csr = imm;
+			match instruction {
+				Instruction::CSRRCI |
+				Instruction::CSRRSI |
+				Instruction::CSRRWI => format!("{} {}, 0x{:03x}, {}", name, x[rd], csr, rs1),
+				_ => format!("{} {}, 0x{:03x}, {}", name, x[rd], csr, x[rs1])
+			}
+		},
+		InstructionFormat::I => {
+			match instruction {
+				Instruction::FLD |
+				Instruction::FLW => format!("{} {}, {}({})", name, f[rd], imm, x[rs1]),
+				Instruction::JALR |
+				Instruction::LB |
+				Instruction::LBU |
+				Instruction::LD |
+				Instruction::LH |
+				Instruction::LHU |
+				Instruction::LW |
+				Instruction::LWU => format!("{} {}, {}({})", name, x[rd], imm, x[rs1]),
+				Instruction::RORI |
+				Instruction::RORIW |
+				Instruction::SLLI |
+				Instruction::SLLIUW |
+				Instruction::SLLIW |
+				Instruction::SRAI |
+				Instruction::SRAIW |
+				Instruction::SRLI |
+				Instruction::SRLIW => format!("{} {}, {}, {}", name, x[rd], x[rs1], imm & 0x3f),
+				_ => format!("{} {}, {}, {}", name, x[rd], x[rs1], imm)
+			}
+		},
+		InstructionFormat::J => {
+			let target = get_target_address(pc.wrapping_add(imm as u64), xlen);
+			format!("{} {}, 0x{:x}", name, x[rd], target)
+		},
+		InstructionFormat::O => match instruction {
+			Instruction::FENCE => {
+				let ordering = get_fence_ordering(word);
+				format!("{} {}, {}", name, get_fence_set_name(ordering.predecessor),
+					get_fence_set_name(ordering.successor))
+			},
+			_ => name
+		},
+		InstructionFormat::R => match instruction {
+			Instruction::EBREAK |
+			Instruction::ECALL |
+			Instruction::MRET |
+			Instruction::SRET |
+			Instruction::URET |
+			Instruction::WFI => name,
+			Instruction::LRD |
+			Instruction::LRW => format!("{}{} {}, ({})", name, get_ordering_suffix(word), x[rd], x[rs1]),
+			Instruction::AMOADDD |
+			Instruction::AMOADDW |
+			Instruction::AMOANDD |
+			Instruction::AMOANDW |
+			Instruction::AMOMAXD |
+			Instruction::AMOMAXUD |
+			Instruction::AMOMAXUW |
+			Instruction::AMOMAXW |
+			Instruction::AMOMIND |
+			Instruction::AMOMINUD |
+			Instruction::AMOMINUW |
+			Instruction::AMOMINW |
+			Instruction::AMOORD |
+			Instruction::AMOORW |
+			Instruction::AMOSWAPD |
+			Instruction::AMOSWAPW |
+			Instruction::AMOXORD |
+			Instruction::AMOXORW |
+			Instruction::SCD |
+			Instruction::SCW => format!("{}{} {}, {}, ({})", name, get_ordering_suffix(word), x[rd], x[rs2], x[rs1]),
+			Instruction::SFENCEVMA => format!("{} {}, {}", name, x[rs1], x[rs2]),
+			Instruction::CBOCLEAN |
+			Instruction::CBOFLUSH |
+			Instruction::CBOINVAL |
+			Instruction::CBOZERO => format!("{} ({})", name, x[rs1]),
+			Instruction::CLZ |
+			Instruction::CLZW |
+			Instruction::CPOP |
+			Instruction::CPOPW |
+			Instruction::CTZ |
+			Instruction::CTZW |
+			Instruction::ORCB |
+			Instruction::REV8 |
+			Instruction::SEXTB |
+			Instruction::SEXTH |
+			Instruction::ZEXTH => format!("{} {}, {}", name, x[rd], x[rs1]),
+			Instruction::FMADDD |
+			Instruction::FMADDS |
+			Instruction::FMSUBD |
+			Instruction::FMSUBS |
+			Instruction::FNMADDD |
+			Instruction::FNMADDS |
+			Instruction::FNMSUBD |
+			Instruction::FNMSUBS => format!("{} {}, {}, {}, {}", name, f[rd], f[rs1], f[rs2], f[rs3]),
+			Instruction::FCVTDS |
+			Instruction::FCVTSD |
+			Instruction::FSQRTD |
+			Instruction::FSQRTS => format!("{} {}, {}", name, f[rd], f[rs1]),
+			Instruction::FCLASSD |
+			Instruction::FCLASSS |
+			Instruction::FCVTLD |
+			Instruction::FCVTLS |
+			Instruction::FCVTLUD |
+			Instruction::FCVTLUS |
+			Instruction::FCVTWD |
+			Instruction::FCVTWS |
+			Instruction::FCVTWUD |
+			Instruction::FCVTWUS |
+			Instruction::FMVXD |
+			Instruction::FMVXW => format!("{} {}, {}", name, x[rd], f[rs1]),
+			Instruction::FCVTDL |
+			Instruction::FCVTDLU |
+			Instruction::FCVTDW |
+			Instruction::FCVTDWU |
+			Instruction::FCVTSL |
+			Instruction::FCVTSLU |
+			Instruction::FCVTSW |
+			Instruction::FCVTSWU |
+			Instruction::FMVDX |
+			Instruction::FMVWX => format!("{} {}, {}", name, f[rd], x[rs1]),
+			Instruction::FEQD |
+			Instruction::FEQS |
+			Instruction::FLED |
+			Instruction::FLES |
+			Instruction::FLTD |
+			Instruction::FLTS => format!("{} {}, {}, {}", name, x[rd], f[rs1], f[rs2]),
+			Instruction::FADDD |
+			Instruction::FADDS |
+			Instruction::FDIVD |
+			Instruction::FDIVS |
+			Instruction::FMAXD |
+			Instruction::FMAXS |
+			Instruction::FMIND |
+			Instruction::FMINS |
+			Instruction::FMULD |
+			Instruction::FMULS |
+			Instruction::FSGNJD |
+			Instruction::FSGNJND |
+			Instruction::FSGNJNS |
+			Instruction::FSGNJS |
+			Instruction::FSGNJXD |
+			Instruction::FSGNJXS |
+			Instruction::FSUBD |
+			Instruction::FSUBS => format!("{} {}, {}, {}", name, f[rd], f[rs1], f[rs2]),
+			_ => format!("{} {}, {}, {}", name, x[rd], x[rs1], x[rs2])
+		},
+		InstructionFormat::S => {
+			match instruction {
+				Instruction::FSD |
+				Instruction::FSW => format!("{} {}, {}({})", name, f[rs2], imm, x[rs1]),
+				_ => format!("{} {}, {}({})", name, x[rs2], imm, x[rs1])
+			}
+		},
+		InstructionFormat::U => format!("{} {}, 0x{:x}", name, x[rd], word >> 12)
+	}
+}
+
+// Suffix of atomic instructions for the aq and rl bits, like "amoadd.w.aqrl"
+fn get_ordering_suffix(word: u32) -> &'static str {
+	match get_atomic_ordering(word) {
+		AtomicOrdering::Relaxed => "",
+		AtomicOrdering::Acquire => ".aq",
+		AtomicOrdering::Release => ".rl",
+		AtomicOrdering::AcquireRelease => ".aqrl"
+	}
+}
+
+// Ordering set of FENCE like "rw"
+fn get_fence_set_name(set: u8) -> String {
+	let mut name = String::new();
+	for (bit, c) in [(8, 'i'), (4, 'o'), (2, 'r'), (1, 'w')].iter() {
+		if set & bit != 0 {
+			name.push(*c);
+		}
+	}
+	match name.is_empty() {
+		true => "0".to_string(),
+		false => name
+	}
+}
+
+fn get_target_address(address: u64, xlen: &Xlen) -> u64 {
+	match xlen {
+		Xlen::Bit32 => address & 0xffffffff,
+		Xlen::Bit64 => address
+	}
+}
@@ -1,7 +1,7 @@
 use std::str;
 use std::io::{stdout, Write};
 
-use terminal::Terminal;
+use riscv_rust::terminal::Terminal;
 
 pub struct DummyTerminal {
 }
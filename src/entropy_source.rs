@@ -0,0 +1,80 @@
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+
+// Host randomness which virtio entropy device fills the guest's buffers
+// with. Given to Cpu so that host can make the guest's entropy reproducible.
+pub trait EntropySource {
+	fn fill_bytes(&mut self, buffer: &mut [u8]);
+}
+
+// Host random number generator, the default in real time mode. Reads
+// /dev/urandom if available. Otherwise, e.g. on wasm32, falls back to
+// hashing a counter with the random keys of RandomState.
+pub struct HostEntropy {
+	file: Option<File>,
+	state: RandomState,
+	counter: u64
+}
+
+impl HostEntropy {
+	pub fn new() -> Self {
+		HostEntropy {
+			file: File::open("/dev/urandom").ok(),
+			state: RandomState::new(),
+			counter: 0
+		}
+	}
+}
+
+impl EntropySource for HostEntropy {
+	fn fill_bytes(&mut self, buffer: &mut [u8]) {
+		match self.file.as_mut() {
+			Some(file) => match file.read_exact(buffer) {
+				Ok(()) => return,
+				Err(_) => {}
+			},
+			None => {}
+		};
+		for chunk in buffer.chunks_mut(8) {
+			let mut hasher = self.state.build_hasher();
+			hasher.write_u64(self.counter);
+			self.counter = self.counter.wrapping_add(1);
+			let bytes = hasher.finish().to_le_bytes();
+			chunk.copy_from_slice(&bytes[..chunk.len()]);
+		}
+	}
+}
+
+// Deterministic generator, SplitMix64, the default in deterministic timer
+// mode with seed 0. The same seed gives the guest the same bytes, for
+// reproducible tests. Not for cryptographic use.
+pub struct SeededEntropy {
+	state: u64
+}
+
+impl SeededEntropy {
+	pub fn new(seed: u64) -> Self {
+		SeededEntropy {
+			state: seed
+		}
+	}
+
+	fn next_u64(&mut self) -> u64 {
+		self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+		let mut z = self.state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		z ^ (z >> 31)
+	}
+}
+
+impl EntropySource for SeededEntropy {
+	fn fill_bytes(&mut self, buffer: &mut [u8]) {
+		for chunk in buffer.chunks_mut(8) {
+			let bytes = self.next_u64().to_le_bytes();
+			chunk.copy_from_slice(&bytes[..chunk.len()]);
+		}
+	}
+}
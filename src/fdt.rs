@@ -0,0 +1,133 @@
+// Builds a flattened device tree blob, version 17 of the Devicetree
+// Specification. Nodes and properties are written in order, so begin_node()
+// and end_node() calls must be nested the same as the tree.
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+pub struct FdtBuilder {
+	structure: Vec<u8>,
+	strings: Vec<u8>,
+	// Property names already in strings and their offsets
+	string_offsets: Vec<(&'static str, u32)>
+}
+
+impl FdtBuilder {
+	pub fn new() -> Self {
+		FdtBuilder {
+			structure: vec![],
+			strings: vec![],
+			string_offsets: vec![]
+		}
+	}
+
+	// The root node's name is empty
+	pub fn begin_node(&mut self, name: &str) {
+		push_u32(&mut self.structure, FDT_BEGIN_NODE);
+		self.structure.extend_from_slice(name.as_bytes());
+		self.structure.push(0);
+		align(&mut self.structure, 4);
+	}
+
+	pub fn end_node(&mut self) {
+		push_u32(&mut self.structure, FDT_END_NODE);
+	}
+
+	pub fn property(&mut self, name: &'static str, value: &[u8]) {
+		let name_offset = self.get_string_offset(name);
+		push_u32(&mut self.structure, FDT_PROP);
+		push_u32(&mut self.structure, value.len() as u32);
+		push_u32(&mut self.structure, name_offset);
+		self.structure.extend_from_slice(value);
+		align(&mut self.structure, 4);
+	}
+
+	// Boolean property like interrupt-controller
+	pub fn property_empty(&mut self, name: &'static str) {
+		self.property(name, &[]);
+	}
+
+	pub fn property_u32(&mut self, name: &'static str, value: u32) {
+		self.property_cells(name, &[value]);
+	}
+
+	// Big-endian 32-bit cells. A 64-bit value is two cells, upper first.
+	pub fn property_cells(&mut self, name: &'static str, cells: &[u32]) {
+		let mut value = vec![];
+		for cell in cells.iter() {
+			push_u32(&mut value, *cell);
+		}
+		self.property(name, &value);
+	}
+
+	pub fn property_string(&mut self, name: &'static str, value: &str) {
+		self.property_strings(name, &[value]);
+	}
+
+	// String list like compatible, each terminated by null
+	pub fn property_strings(&mut self, name: &'static str, values: &[&str]) {
+		let mut value = vec![];
+		for s in values.iter() {
+			value.extend_from_slice(s.as_bytes());
+			value.push(0);
+		}
+		self.property(name, &value);
+	}
+
+	// Returns the blob. boot_cpuid_phys is the ID of the booting hart.
+	pub fn finish(mut self, boot_cpuid_phys: u32) -> Vec<u8> {
+		push_u32(&mut self.structure, FDT_END);
+		// The memory reservation block only has the terminating entry
+		let reservation_offset = FDT_HEADER_SIZE;
+		let structure_offset = reservation_offset + 16;
+		let strings_offset = structure_offset + self.structure.len();
+		let total_size = strings_offset + self.strings.len();
+		let mut blob = vec![];
+		push_u32(&mut blob, FDT_MAGIC);
+		push_u32(&mut blob, total_size as u32);
+		push_u32(&mut blob, structure_offset as u32);
+		push_u32(&mut blob, strings_offset as u32);
+		push_u32(&mut blob, reservation_offset as u32);
+		push_u32(&mut blob, FDT_VERSION);
+		push_u32(&mut blob, FDT_LAST_COMPATIBLE_VERSION);
+		push_u32(&mut blob, boot_cpuid_phys);
+		push_u32(&mut blob, self.strings.len() as u32);
+		push_u32(&mut blob, self.structure.len() as u32);
+		blob.extend_from_slice(&[0; 16]);
+		blob.extend_from_slice(&self.structure);
+		blob.extend_from_slice(&self.strings);
+		blob
+	}
+
+	fn get_string_offset(&mut self, name: &'static str) -> u32 {
+		match self.string_offsets.iter().find(|(s, _)| *s == name) {
+			Some((_, offset)) => return *offset,
+			None => {}
+		};
+		let offset = self.strings.len() as u32;
+		self.strings.extend_from_slice(name.as_bytes());
+		self.strings.push(0);
+		self.string_offsets.push((name, offset));
+		offset
+	}
+}
+
+// reg cells of a region with #address-cells and #size-cells of 2
+pub fn get_reg_cells(address: u64, size: u64) -> [u32; 4] {
+	[(address >> 32) as u32, address as u32, (size >> 32) as u32, size as u32]
+}
+
+fn push_u32(buffer: &mut Vec<u8>, value: u32) {
+	buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn align(buffer: &mut Vec<u8>, alignment: usize) {
+	while buffer.len() % alignment != 0 {
+		buffer.push(0);
+	}
+}
@@ -0,0 +1,491 @@
+use cpu::{Cpu, Xlen};
+use mmu::WatchKind;
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// GDB remote serial protocol stub. A debugger connects over TCP and
+// controls Cpu with the basic packet set. Registers are numbered the same
+// as GDB's RISC-V target, x0-x31 are 0-31, pc is 32, and a CSR is
+// 65 + its address. Registers are of the running hart. Memory is accessed
+// with physical address.
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const PC_REGISTER: usize = 32;
+const FIRST_CSR_REGISTER: usize = 65;
+
+// Maximum packet size told to the debugger in qSupported, in hex
+const PACKET_SIZE: u64 = 0x4000;
+
+// Ticks between checks for interrupt (Ctrl-C) from the debugger while running
+const INTERRUPT_CHECK_INTERVAL: u64 = 0x10000;
+
+const REGISTER_NAMES: [&str; 32] = [
+	"zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+	"fp", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+	"a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+	"s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"
+];
+
+// CSRs described in the target description
+const CSRS: [(&str, u16); 19] = [
+	("sstatus", 0x100),
+	("stvec", 0x105),
+	("sscratch", 0x140),
+	("sepc", 0x141),
+	("scause", 0x142),
+	("stval", 0x143),
+	("satp", 0x180),
+	("mstatus", 0x300),
+	("misa", 0x301),
+	("medeleg", 0x302),
+	("mideleg", 0x303),
+	("mie", 0x304),
+	("mtvec", 0x305),
+	("mscratch", 0x340),
+	("mepc", 0x341),
+	("mcause", 0x342),
+	("mtval", 0x343),
+	("mip", 0x344),
+	("mhartid", 0xf14)
+];
+
+pub struct GdbStub {
+	stream: TcpStream
+}
+
+impl GdbStub {
+	// Blocks until a debugger connects to the address, for example
+	// "127.0.0.1:1234" for "target remote :1234"
+	pub fn accept(address: &str) -> std::io::Result<Self> {
+		let listener = match TcpListener::bind(address) {
+			Ok(listener) => listener,
+			Err(e) => return Err(e)
+		};
+		let stream = match listener.accept() {
+			Ok((stream, _)) => stream,
+			Err(e) => return Err(e)
+		};
+		match stream.set_nodelay(true) {
+			Ok(()) => {},
+			Err(e) => return Err(e)
+		};
+		Ok(GdbStub {
+			stream: stream
+		})
+	}
+
+	// Serves packets until the debugger detaches, kills, or disconnects
+	pub fn run(&mut self, cpu: &mut Cpu) -> std::io::Result<()> {
+		loop {
+			let packet = match self.read_packet() {
+				Ok(Some(packet)) => packet,
+				Ok(None) => return Ok(()),
+				Err(e) => return Err(e)
+			};
+			let (command, arguments) = match packet.len() {
+				0 => continue,
+				_ => packet.split_at(1)
+			};
+			let response = match command {
+				"?" => format!("S{:02x}", SIGTRAP),
+				"g" => {
+					let mut response = String::new();
+					for i in 0..PC_REGISTER + 1 {
+						response.push_str(&encode_register(read_register(cpu, i).unwrap(), cpu.get_xlen()));
+					}
+					response
+				},
+				"G" => {
+					let width = get_register_width(cpu.get_xlen()) * 2;
+					let mut response = "OK".to_string();
+					for i in 0..PC_REGISTER + 1 {
+						match arguments.get(i * width..(i + 1) * width).and_then(decode_register) {
+							Some(value) => write_register(cpu, i, value),
+							None => {
+								response = "E01".to_string();
+								break;
+							}
+						};
+					}
+					response
+				},
+				"p" => match parse_hex(arguments) {
+					Some(index) => match read_register(cpu, index as usize) {
+						Some(value) => encode_register(value, cpu.get_xlen()),
+						None => "E01".to_string()
+					},
+					None => "E01".to_string()
+				},
+				"P" => {
+					let mut fields = arguments.split('=');
+					match (fields.next().and_then(parse_hex), fields.next().and_then(decode_register)) {
+						(Some(index), Some(value)) if write_register(cpu, index as usize, value) => "OK".to_string(),
+						_ => "E01".to_string()
+					}
+				},
+				"m" => match parse_address_length(arguments) {
+					Some((address, length)) => {
+						// A reply can't exceed the packet size, two hex digits per byte.
+						// The debugger reads the rest with more packets.
+						let length = length.min(PACKET_SIZE / 2);
+						let mut response = String::new();
+						let mut buf = vec![0; length as usize];
+						match cpu.read_memory(address, &mut buf) {
+							Ok(()) => for byte in buf {
+								response.push_str(&format!("{:02x}", byte));
+							},
+							// Not in the main memory. Reads bytes until unmapped one.
+							Err(()) => for i in 0..length {
+								match cpu.load_raw(address.wrapping_add(i)) {
+									Ok(byte) => response.push_str(&format!("{:02x}", byte)),
+									Err(()) => break
+								};
+							}
+						};
+						match response.len() {
+							0 => "E01".to_string(),
+							_ => response
+						}
+					},
+					None => "E01".to_string()
+				},
+				"M" => {
+					let mut fields = arguments.split(':');
+					match (fields.next().and_then(parse_address_length), fields.next()) {
+						(Some((address, length)), Some(data)) => {
+							let bytes = (0..length).map(|i| match data.get((i * 2) as usize..(i * 2 + 2) as usize) {
+								Some(hex) => u8::from_str_radix(hex, 16).ok(),
+								None => None
+							}).collect::<Option<Vec<u8>>>();
+							match bytes {
+								Some(bytes) if cpu.write_memory(address, &bytes).is_ok() => "OK".to_string(),
+								_ => "E01".to_string()
+							}
+						},
+						_ => "E01".to_string()
+					}
+				},
+				"s" => {
+					match parse_hex(arguments) {
+						Some(address) => cpu.update_pc(address),
+						None => {}
+					};
+					step(cpu);
+					get_stop_reply(cpu, SIGTRAP)
+				},
+				"c" => {
+					match parse_hex(arguments) {
+						Some(address) => cpu.update_pc(address),
+						None => {}
+					};
+					match self.resume(cpu) {
+						Ok(signal) => get_stop_reply(cpu, signal),
+						Err(e) => return Err(e)
+					}
+				},
+				"Z" | "z" => {
+					// Software breakpoint Z0,address,kind and
+					// write, read, and access watchpoints Z2-4,address,length
+					let mut fields = arguments.split(',');
+					let kind = fields.next();
+					let address = fields.next().and_then(parse_hex);
+					let length = fields.next().and_then(parse_hex);
+					let watch_kind = match kind {
+						Some("2") => Some(WatchKind::Write),
+						Some("3") => Some(WatchKind::Read),
+						Some("4") => Some(WatchKind::Access),
+						_ => None
+					};
+					match (kind, address, length, watch_kind) {
+						(Some("0"), Some(address), _, _) => {
+							match command {
+								"Z" => cpu.add_breakpoint(address),
+								_ => cpu.remove_breakpoint(address)
+							};
+							"OK".to_string()
+						},
+						(_, Some(address), Some(length), Some(watch_kind)) => {
+							match command {
+								"Z" => cpu.add_watchpoint(address, length, watch_kind),
+								_ => {
+									cpu.remove_watchpoint(address, length, watch_kind);
+								}
+							};
+							"OK".to_string()
+						},
+						_ => String::new()
+					}
+				},
+				"q" => {
+					if arguments.starts_with("Supported") {
+						format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+					} else if let Some(range) = arguments.strip_prefix("Xfer:features:read:target.xml:") {
+						match parse_address_length(range) {
+							Some((offset, length)) => read_chunk(&get_target_description(cpu.get_xlen()), offset, length),
+							None => "E01".to_string()
+						}
+					} else if arguments == "Attached" {
+						"1".to_string()
+					} else {
+						String::new()
+					}
+				},
+				"H" => "OK".to_string(),
+				"D" => {
+					return self.write_packet("OK");
+				},
+				"k" => return Ok(()),
+				_ => String::new() // Unsupported
+			};
+			match self.write_packet(&response) {
+				Ok(()) => {},
+				Err(e) => return Err(e)
+			};
+		}
+	}
+
+	// Runs until pc hits a breakpoint, a watchpoint is hit, the debugger
+	// requests interrupt, or the machine halts. Returns the signal to report.
+	fn resume(&mut self, cpu: &mut Cpu) -> std::io::Result<u8> {
+		let mut count = 0 as u64;
+		loop {
+			// Breakpoint at the current pc doesn't stop because
+			// the instruction there is executed first
+			if cpu.tick() || cpu.is_halted() {
+				return Ok(SIGTRAP);
+			}
+			count = count.wrapping_add(1);
+			if (count % INTERRUPT_CHECK_INTERVAL) == 0 {
+				match self.is_interrupt_requested() {
+					Ok(true) => return Ok(SIGINT),
+					Ok(false) => {},
+					Err(e) => return Err(e)
+				};
+			}
+		}
+	}
+
+	fn is_interrupt_requested(&mut self) -> std::io::Result<bool> {
+		match self.stream.set_nonblocking(true) {
+			Ok(()) => {},
+			Err(e) => return Err(e)
+		};
+		let mut buffer = [0; 1];
+		let result = match self.stream.read(&mut buffer) {
+			Ok(0) => Err(Error::new(ErrorKind::UnexpectedEof, "Debugger disconnected")),
+			Ok(_) => Ok(buffer[0] == 0x03),
+			Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+			Err(e) => Err(e)
+		};
+		match self.stream.set_nonblocking(false) {
+			Ok(()) => {},
+			Err(e) => return Err(e)
+		};
+		result
+	}
+
+	fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+		let mut buffer = [0; 1];
+		match self.stream.read(&mut buffer) {
+			Ok(0) => Ok(None),
+			Ok(_) => Ok(Some(buffer[0])),
+			Err(e) => Err(e)
+		}
+	}
+
+	// Reads a packet $data#checksum and acknowledges it.
+	// Returns None if the debugger disconnects.
+	fn read_packet(&mut self) -> std::io::Result<Option<String>> {
+		loop {
+			// Skips acknowledgements and interrupt while stopped
+			match self.read_byte() {
+				Ok(Some(b'$')) => {},
+				Ok(Some(_)) => continue,
+				Ok(None) => return Ok(None),
+				Err(e) => return Err(e)
+			};
+			let mut data = vec![];
+			loop {
+				match self.read_byte() {
+					Ok(Some(b'#')) => break,
+					Ok(Some(byte)) => data.push(byte),
+					Ok(None) => return Ok(None),
+					Err(e) => return Err(e)
+				};
+			}
+			let mut checksum = [0; 2];
+			for byte in checksum.iter_mut() {
+				*byte = match self.read_byte() {
+					Ok(Some(byte)) => byte,
+					Ok(None) => return Ok(None),
+					Err(e) => return Err(e)
+				};
+			}
+			let expected = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+			let valid = expected == Some(get_checksum(&data));
+			match self.stream.write_all(match valid {
+				true => b"+",
+				false => b"-" // Requests retransmission
+			}) {
+				Ok(()) => {},
+				Err(e) => return Err(e)
+			};
+			if valid {
+				return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+			}
+		}
+	}
+
+	fn write_packet(&mut self, data: &str) -> std::io::Result<()> {
+		let packet = format!("${}#{:02x}", data, get_checksum(data.as_bytes()));
+		loop {
+			match self.stream.write_all(packet.as_bytes()) {
+				Ok(()) => {},
+				Err(e) => return Err(e)
+			};
+			// Resends until the debugger acknowledges
+			match self.read_byte() {
+				Ok(Some(b'-')) => continue,
+				Ok(_) => return Ok(()),
+				Err(e) => return Err(e)
+			};
+		}
+	}
+}
+
+// Stop reply packet. Exit with the code once the machine halts. A watchpoint
+// hit is reported with the accessed address and taken.
+fn get_stop_reply(cpu: &mut Cpu, signal: u8) -> String {
+	match (cpu.get_exit_code(), cpu.take_watchpoint_hit()) {
+		(Some(exit_code), _) => format!("W{:02x}", exit_code as u8),
+		(None, Some(hit)) => {
+			let reason = match hit.kind {
+				WatchKind::Write => "watch",
+				WatchKind::Read => "rwatch",
+				WatchKind::Access => "awatch"
+			};
+			format!("T{:02x}{}:{:x};", signal, reason, hit.address)
+		},
+		(None, None) => format!("S{:02x}", signal)
+	}
+}
+
+// Runs an instruction of the running hart. Other harts run
+// an instruction each, too, so that the same hart is running after that.
+fn step(cpu: &mut Cpu) {
+	let hart_id = cpu.get_hart_id();
+	loop {
+		cpu.tick();
+		if cpu.get_hart_id() == hart_id {
+			break;
+		}
+	}
+}
+
+fn read_register(cpu: &Cpu, index: usize) -> Option<u64> {
+	match index {
+		0..=31 => Some(cpu.read_register(index) as u64),
+		PC_REGISTER => Some(cpu.read_pc()),
+		_ if (FIRST_CSR_REGISTER..FIRST_CSR_REGISTER + 4096).contains(&index) => {
+			Some(cpu.read_csr_raw((index - FIRST_CSR_REGISTER) as u16))
+		},
+		_ => None
+	}
+}
+
+// Returns false if no such register
+fn write_register(cpu: &mut Cpu, index: usize, value: u64) -> bool {
+	match index {
+		0..=31 => cpu.write_register(index, value as i64),
+		PC_REGISTER => cpu.update_pc(value),
+		_ if (FIRST_CSR_REGISTER..FIRST_CSR_REGISTER + 4096).contains(&index) => {
+			cpu.write_csr_raw((index - FIRST_CSR_REGISTER) as u16, value);
+		},
+		_ => return false
+	};
+	true
+}
+
+fn get_register_width(xlen: &Xlen) -> usize {
+	match xlen {
+		Xlen::Bit32 => 4,
+		Xlen::Bit64 => 8
+	}
+}
+
+// Registers are transferred as little endian hex string
+fn encode_register(value: u64, xlen: &Xlen) -> String {
+	let mut hex = String::new();
+	for i in 0..get_register_width(xlen) {
+		hex.push_str(&format!("{:02x}", (value >> (i * 8)) as u8));
+	}
+	hex
+}
+
+fn decode_register(hex: &str) -> Option<u64> {
+	let mut value = 0;
+	for i in 0..hex.len() / 2 {
+		let byte = match hex.get(i * 2..i * 2 + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()) {
+			Some(byte) => byte,
+			None => return None
+		};
+		value |= (byte as u64) << (i * 8);
+	}
+	Some(value)
+}
+
+fn parse_hex(hex: &str) -> Option<u64> {
+	u64::from_str_radix(hex, 16).ok()
+}
+
+// Parses "address,length"
+fn parse_address_length(arguments: &str) -> Option<(u64, u64)> {
+	let mut fields = arguments.split(',');
+	match (fields.next().and_then(parse_hex), fields.next().and_then(parse_hex)) {
+		(Some(address), Some(length)) => Some((address, length)),
+		_ => None
+	}
+}
+
+fn get_checksum(data: &[u8]) -> u8 {
+	data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// qXfer response, 'm' if more data follows and 'l' if last
+fn read_chunk(data: &str, offset: u64, length: u64) -> String {
+	let start = (offset as usize).min(data.len());
+	let end = (offset.saturating_add(length) as usize).min(data.len());
+	let prefix = match end == data.len() {
+		true => "l",
+		false => "m"
+	};
+	format!("{}{}", prefix, &data[start..end])
+}
+
+fn get_target_description(xlen: &Xlen) -> String {
+	let (architecture, bitsize) = match xlen {
+		Xlen::Bit32 => ("riscv:rv32", 32),
+		Xlen::Bit64 => ("riscv:rv64", 64)
+	};
+	let mut description = format!(concat!(
+		"<?xml version=\"1.0\"?>",
+		"<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+		"<target version=\"1.0\">",
+		"<architecture>{}</architecture>",
+		"<feature name=\"org.gnu.gdb.riscv.core\">"
+	), architecture);
+	for (i, name) in REGISTER_NAMES.iter().enumerate() {
+		description.push_str(&format!("<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\" type=\"int\"/>", name, bitsize, i));
+	}
+	description.push_str(&format!("<reg name=\"pc\" bitsize=\"{}\" regnum=\"{}\" type=\"code_ptr\"/>", bitsize, PC_REGISTER));
+	description.push_str("</feature><feature name=\"org.gnu.gdb.riscv.csr\">");
+	for (name, address) in CSRS.iter() {
+		description.push_str(&format!("<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\" type=\"int\" group=\"csr\"/>",
+			name, bitsize, FIRST_CSR_REGISTER + *address as usize));
+	}
+	description.push_str("</feature></target>");
+	description
+}
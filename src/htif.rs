@@ -0,0 +1,87 @@
+// Host-Target Interface of Spike, which riscv-tests use to finish and to
+// print through the host. The guest writes a request to tohost in the main
+// memory and waits for the response in fromhost. A request has the device
+// in bits [63:56], the command in [55:48], and the payload in [47:0].
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Htif {
+	tohost_address: Option<u64>,
+	fromhost_address: Option<u64>,
+	// Set when tohost is written. Request is read after the instruction
+	// so that the whole value is written.
+	tohost_written: bool
+}
+
+pub enum HtifRequest {
+	// Device 0 command 0 with payload bit 0 set, exit code in the rest
+	Exit(u64),
+	// Device 0 command 0 otherwise, address of the system call arguments
+	Syscall(u64),
+	// Device 1 command 1, a character to print
+	ConsolePutchar(u8),
+	Unsupported
+}
+
+// Proxied system calls of riscv-tests benchmarks
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_EXIT: u64 = 93;
+
+impl Htif {
+	pub fn new() -> Self {
+		Htif {
+			tohost_address: None,
+			fromhost_address: None,
+			tohost_written: false
+		}
+	}
+
+	pub fn update_tohost_address(&mut self, address: u64) {
+		self.tohost_address = Some(address);
+	}
+
+	pub fn update_fromhost_address(&mut self, address: u64) {
+		self.fromhost_address = Some(address);
+	}
+
+	pub fn get_tohost_address(&self) -> Option<u64> {
+		self.tohost_address
+	}
+
+	pub fn get_fromhost_address(&self) -> Option<u64> {
+		self.fromhost_address
+	}
+
+	// Called for every store to the main memory with the stored range
+	pub fn notify_store(&mut self, address: u64, length: u64) {
+		match self.tohost_address {
+			Some(tohost_address) if address < tohost_address.wrapping_add(8) &&
+				tohost_address < address.wrapping_add(length) => self.tohost_written = true,
+			_ => {}
+		};
+	}
+
+	pub fn take_tohost_written(&mut self) -> bool {
+		let written = self.tohost_written;
+		self.tohost_written = false;
+		written
+	}
+
+	pub fn decode_request(value: u64) -> HtifRequest {
+		let device = value >> 56;
+		let command = (value >> 48) & 0xff;
+		let payload = value & 0xffffffffffff;
+		match (device, command) {
+			(0, 0) => match payload & 1 {
+				1 => HtifRequest::Exit(payload >> 1),
+				_ => HtifRequest::Syscall(payload)
+			},
+			(1, 1) => HtifRequest::ConsolePutchar(payload as u8),
+			_ => HtifRequest::Unsupported
+		}
+	}
+
+	// Response of the request written to fromhost. The device and the
+	// command are echoed back.
+	pub fn get_response(value: u64, payload: u64) -> u64 {
+		(value & 0xffff000000000000) | (payload & 0xffffffffffff)
+	}
+}
@@ -0,0 +1,148 @@
+use cpu::{Cpu, Xlen};
+
+const EM_RISCV: u64 = 0xf3;
+const PT_LOAD: u64 = 1;
+
+// .bss is zero-filled by this size so that a huge p_memsz doesn't make
+// the host allocate it at once
+const ZERO_FILL_CHUNK_SIZE: u64 = 0x1000;
+
+#[derive(Debug)]
+pub enum LoadError {
+	InvalidMagic,
+	UnsupportedClass(u8),
+	UnsupportedEndian(u8),
+	UnsupportedMachine(u64),
+	Truncated, // Header or segment points out of the file
+	Unmapped(u64) // No memory is mapped to the segment address
+}
+
+// Loads PT_LOAD segments of a RISC-V ELF file to physical addresses
+// and sets xlen from ELF class. Returns entry point address.
+// Expecting memory is already set up with Cpu.setup_memory().
+// Header fields are untrusted so offsets and sizes are checked for overflow.
+pub fn load_elf(cpu: &mut Cpu, data: &[u8]) -> Result<u64, LoadError> {
+	if data.len() < 0x10 || data[0..4] != [0x7f, 0x45, 0x4c, 0x46] { // 0x7f 'E' 'L' 'F'
+		return Err(LoadError::InvalidMagic);
+	}
+
+	let e_class = data[4];
+	let e_width = match e_class {
+		1 => 32,
+		2 => 64,
+		_ => return Err(LoadError::UnsupportedClass(e_class))
+	};
+
+	let e_endian = data[5];
+	if e_endian != 1 { // Only little endian
+		return Err(LoadError::UnsupportedEndian(e_endian));
+	}
+
+	let e_machine = match read_data(data, 0x12, 2) {
+		Ok(value) => value,
+		Err(e) => return Err(e)
+	};
+	if e_machine != EM_RISCV {
+		return Err(LoadError::UnsupportedMachine(e_machine));
+	}
+
+	// Offsets of the fields after e_entry depend on the width
+	let word_size = e_width / 8;
+	let e_entry = match read_data(data, 0x18, word_size) {
+		Ok(value) => value,
+		Err(e) => return Err(e)
+	};
+	let e_phoff = match read_data(data, 0x18 + word_size, word_size) {
+		Ok(value) => value,
+		Err(e) => return Err(e)
+	};
+	let e_phentsize = match read_data(data, 0x18 + word_size * 3 + 6, 2) {
+		Ok(value) => value,
+		Err(e) => return Err(e)
+	};
+	let e_phnum = match read_data(data, 0x18 + word_size * 3 + 8, 2) {
+		Ok(value) => value,
+		Err(e) => return Err(e)
+	};
+
+	cpu.update_xlen(match e_width {
+		32 => Xlen::Bit32,
+		_ => Xlen::Bit64
+	});
+
+	for i in 0..e_phnum {
+		let offset = match i.checked_mul(e_phentsize).and_then(|offset| offset.checked_add(e_phoff)) {
+			Some(offset) if offset <= data.len() as u64 => offset as usize,
+			_ => return Err(LoadError::Truncated)
+		};
+		let p_type = match read_data(data, offset, 4) {
+			Ok(value) => value,
+			Err(e) => return Err(e)
+		};
+		if p_type != PT_LOAD {
+			continue;
+		}
+
+		// p_flags is right after p_type in 64-bit and
+		// after p_memsz in 32-bit so skipping it
+		let fields_offset = match e_width {
+			32 => offset + 4,
+			_ => offset + 8
+		};
+		let mut fields = [0; 5]; // p_offset, p_vaddr, p_paddr, p_filesz, p_memsz
+		for (j, field) in fields.iter_mut().enumerate() {
+			*field = match read_data(data, fields_offset + j * word_size, word_size) {
+				Ok(value) => value,
+				Err(e) => return Err(e)
+			};
+		}
+		let p_offset = fields[0];
+		let p_paddr = fields[2];
+		let p_filesz = fields[3];
+		let p_memsz = fields[4];
+
+		let p_end = match p_offset.checked_add(p_filesz) {
+			Some(end) if end <= data.len() as u64 => end,
+			_ => return Err(LoadError::Truncated)
+		};
+		match cpu.write_memory(p_paddr, &data[p_offset as usize..p_end as usize]) {
+			Ok(()) => {},
+			Err(()) => return Err(LoadError::Unmapped(p_paddr))
+		};
+		// Zero-fill .bss. A segment wrapping around the address space can't
+		// be mapped.
+		if p_memsz > p_filesz {
+			match p_paddr.checked_add(p_memsz) {
+				Some(_) => {},
+				None => return Err(LoadError::Unmapped(p_paddr))
+			};
+			let zeros = [0; ZERO_FILL_CHUNK_SIZE as usize];
+			let mut address = p_paddr + p_filesz;
+			let mut remaining = p_memsz - p_filesz;
+			while remaining > 0 {
+				let size = remaining.min(ZERO_FILL_CHUNK_SIZE);
+				match cpu.write_memory(address, &zeros[..size as usize]) {
+					Ok(()) => {},
+					Err(()) => return Err(LoadError::Unmapped(address))
+				};
+				address += size;
+				remaining -= size;
+			}
+		}
+	}
+
+	Ok(e_entry)
+}
+
+// Reads little endian data
+fn read_data(data: &[u8], offset: usize, size: usize) -> Result<u64, LoadError> {
+	match offset.checked_add(size) {
+		Some(end) if end <= data.len() => {},
+		_ => return Err(LoadError::Truncated)
+	};
+	let mut value = 0;
+	for i in 0..size {
+		value |= (data[offset + i] as u64) << (8 * i);
+	}
+	Ok(value)
+}
@@ -1,24 +1,22 @@
 extern crate getopts;
+extern crate riscv_rust;
 
-mod application;
-mod cpu;
-mod mmu;
-mod plic;
-mod clint;
-mod uart;
-mod virtio_block_disk;
-mod terminal;
+// The emulator is the library crate. Only the terminals are of the
+// standalone program.
 mod dummy_terminal;
 mod popup_terminal;
 
-use cpu::Xlen;
-use terminal::Terminal;
+use riscv_rust::{TimerMode, DEFAULT_TIMEBASE_FREQUENCY};
+use riscv_rust::entropy_source::SeededEntropy;
+use riscv_rust::cpu::{AccessedDirtyPolicy, MisalignedAccessPolicy, UnimplementedPolicy, Xlen};
+use riscv_rust::terminal::Terminal;
 use popup_terminal::PopupTerminal;
 use dummy_terminal::DummyTerminal;
-use application::Application;
+use riscv_rust::application::Application;
+use riscv_rust::stats::ExecStats;
 
 use std::env;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::Read;
 
 use getopts::Options;
@@ -33,6 +31,19 @@ fn print_usage(program: &str, opts: Options) {
 	print!("{}", opts.usage(&usage));
 }
 
+fn print_stats(stats: &ExecStats) {
+	println!("Instructions: {}", stats.get_instructions());
+	for (name, count) in stats.get_instruction_histogram() {
+		println!("  {:<12}{}", name, count);
+	}
+	println!("Traps:");
+	for (name, count) in stats.get_trap_histogram() {
+		println!("  {:<30}{}", name, count);
+	}
+	println!("TLB hits: {}, misses: {}, page table walks: {}",
+		stats.get_tlb_hits(), stats.get_tlb_misses(), stats.get_page_table_walks());
+}
+
 fn get_terminal(terminal_type: TerminalType) -> Box<dyn Terminal> {
 	match terminal_type {
 		TerminalType::PopupTerminal => Box::new(PopupTerminal::new()),
@@ -47,7 +58,20 @@ fn main () -> std::io::Result<()> {
 	let mut opts = Options::new();
 	opts.optopt("x", "xlen", "Set bit mode. Default is auto detect from elf file", "32|64");
 	opts.optopt("f", "fs", "File system image file", "xv6/fs.img");
+	opts.optflag("w", "writeback", "Write file system changes back to the image file");
+	opts.optflag("v", "virtio_modern", "Present the disk with modern (version 2) virtio MMIO interface instead of legacy one, e.g. for Linux");
+	opts.optopt("e", "entropy_seed", "Seed of the deterministic generator virtio entropy device gives the guest. Default is host entropy, or seed 0 in deterministic mode", "SEED");
 	opts.optflag("n", "no_terminal", "No popup terminal");
+	opts.optopt("u", "unimplemented", "Behavior on unimplemented instructions. Default is trap", "trap|nop|panic");
+	opts.optopt("m", "misaligned", "Behavior on misaligned loads and stores. Default is emulate", "emulate|trap");
+	opts.optopt("a", "accessed_dirty", "Behavior on page table entries with A or D bit clear. Default is update", "update|fault");
+	opts.optopt("c", "harts", "Number of harts. Default is 1", "1|2|...");
+	opts.optopt("b", "timebase", "Timebase frequency of mtime in Hz. Default is 10000000", "HZ");
+	opts.optopt("g", "gdb", "Wait for GDB connection on the port and run under its control", "PORT");
+	opts.optflag("d", "deterministic", "Reproducible run. Increment mtime by one every instruction instead of following host clock, and take input only from input file");
+	opts.optopt("i", "input", "File fed to UART as input before terminal input", "FILE");
+	opts.optflag("s", "sbi", "Start in supervisor mode and emulate SBI firmware calls");
+	opts.optflag("t", "stats", "Print execution statistics when the program halts");
 	opts.optflag("h", "help", "Show this help menu");
 
 	let matches = match opts.parse(&args[1..]) {
@@ -71,13 +95,21 @@ fn main () -> std::io::Result<()> {
 		return Ok(());
 	}
 
+	// The image file is kept open to write back if writeback is enabled
+	let mut fs_file = None;
 	let fs_contents = match matches.opt_str("f") {
-		Some(path) => {
-			let mut file = File::open(path)?;
-			let mut contents = vec![];
-			file.read_to_end(&mut contents)?;
-			contents
-		}
+		Some(path) => match matches.opt_present("w") {
+			true => {
+				fs_file = Some(OpenOptions::new().read(true).write(true).open(path)?);
+				vec![]
+			},
+			false => {
+				let mut file = File::open(path)?;
+				let mut contents = vec![];
+				file.read_to_end(&mut contents)?;
+				contents
+			}
+		},
 		None => vec![]
 	};
 
@@ -94,8 +126,33 @@ fn main () -> std::io::Result<()> {
 		false => TerminalType::PopupTerminal
 	};
 
-	let mut application = Application::new(get_terminal(terminal_type));
+	let timer_mode = match matches.opt_present("d") {
+		true => TimerMode::Deterministic,
+		false => match matches.opt_str("b") {
+			Some(frequency) => match frequency.parse::<u64>() {
+				Ok(frequency) if frequency > 0 => TimerMode::RealTime(frequency),
+				_ => {
+					print_usage(&program, opts);
+					// @TODO: throw error?
+					return Ok(());
+				}
+			},
+			None => TimerMode::RealTime(DEFAULT_TIMEBASE_FREQUENCY)
+		}
+	};
+
+	let mut application = Application::new(get_terminal(terminal_type), timer_mode);
 	application.setup_from_elf(elf_contents);
+
+	match matches.opt_str("i") {
+		Some(path) => {
+			let mut file = File::open(path)?;
+			let mut contents = vec![];
+			file.read_to_end(&mut contents)?;
+			application.preload_input(&contents);
+		},
+		None => {}
+	};
 	
 	match matches.opt_str("x") {
 		Some(x) => match x.as_str() {
@@ -116,7 +173,95 @@ fn main () -> std::io::Result<()> {
 		None => {}
 	};
 
-	application.setup_filesystem(fs_contents);
-	application.run();
+	match matches.opt_str("u") {
+		Some(policy) => match policy.as_str() {
+			"trap" => application.set_unimplemented_policy(UnimplementedPolicy::Trap),
+			"nop" => application.set_unimplemented_policy(UnimplementedPolicy::NopWarn),
+			"panic" => application.set_unimplemented_policy(UnimplementedPolicy::Panic),
+			_ => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		None => {}
+	};
+
+	match matches.opt_str("m") {
+		Some(policy) => match policy.as_str() {
+			"emulate" => application.set_misaligned_access_policy(MisalignedAccessPolicy::Emulate),
+			"trap" => application.set_misaligned_access_policy(MisalignedAccessPolicy::Trap),
+			_ => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		None => {}
+	};
+
+	match matches.opt_str("a") {
+		Some(policy) => match policy.as_str() {
+			"update" => application.set_accessed_dirty_policy(AccessedDirtyPolicy::Update),
+			"fault" => application.set_accessed_dirty_policy(AccessedDirtyPolicy::Fault),
+			_ => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		None => {}
+	};
+
+	if matches.opt_present("s") {
+		application.setup_sbi();
+	}
+
+	match matches.opt_str("c") {
+		Some(num) => match num.parse::<usize>() {
+			Ok(num) if num > 0 => application.setup_harts(num),
+			_ => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		None => {}
+	};
+
+	application.update_stats_enabled(matches.opt_present("t"));
+
+	match matches.opt_str("e") {
+		Some(seed) => match seed.parse::<u64>() {
+			Ok(seed) => application.update_entropy_source(Box::new(SeededEntropy::new(seed))),
+			Err(_) => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		None => {}
+	};
+
+	application.update_disk_legacy(!matches.opt_present("v"));
+	match fs_file {
+		Some(file) => application.setup_filesystem_with_file(file)?,
+		None => application.setup_filesystem(fs_contents)
+	};
+	match matches.opt_str("g") {
+		Some(port) => {
+			println!("Waiting for GDB connection on port {}.", port);
+			application.run_with_gdb(&format!("127.0.0.1:{}", port))?;
+		},
+		None => application.run()
+	};
+	if matches.opt_present("t") {
+		print_stats(application.get_stats());
+	}
+	// Exits with the code the program halted the machine with
+	match application.get_exit_code() {
+		Some(exit_code) if exit_code != 0 => std::process::exit(exit_code as i32),
+		_ => {}
+	};
 	Ok(())
 }
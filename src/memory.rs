@@ -0,0 +1,86 @@
+// Main memory. Pages are allocated on the first write so that a large
+// memory costs host memory only for what the guest touches. Never
+// written pages read as zero.
+const PAGE_SIZE: u64 = 4096;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Memory {
+	capacity: u64,
+	// Indexed by page number. Only the page table is allocated up front.
+	pages: Vec<Option<Box<[u8]>>>
+}
+
+impl Memory {
+	pub fn new() -> Self {
+		Memory {
+			capacity: 0,
+			pages: vec![]
+		}
+	}
+
+	pub fn init(&mut self, capacity: u64) {
+		let num_pages = capacity.div_ceil(PAGE_SIZE);
+		self.capacity = capacity;
+		self.pages = (0..num_pages).map(|_| None).collect();
+	}
+
+	pub fn get_capacity(&self) -> u64 {
+		self.capacity
+	}
+
+	// Address is an offset from the memory base and needs to be
+	// smaller than the capacity
+
+	pub fn read_byte(&self, address: u64) -> u8 {
+		match &self.pages[(address / PAGE_SIZE) as usize] {
+			Some(page) => page[(address % PAGE_SIZE) as usize],
+			None => 0
+		}
+	}
+
+	pub fn write_byte(&mut self, address: u64, value: u8) {
+		let page = self.pages[(address / PAGE_SIZE) as usize]
+			.get_or_insert_with(|| vec![0; PAGE_SIZE as usize].into_boxed_slice());
+		page[(address % PAGE_SIZE) as usize] = value;
+	}
+
+	// Copies to buf page by page. Address + buf length needs to be
+	// equal to or smaller than the capacity.
+	pub fn read_bytes(&self, address: u64, buf: &mut [u8]) {
+		let mut done = 0;
+		while done < buf.len() {
+			let current = address + done as u64;
+			let offset = (current % PAGE_SIZE) as usize;
+			let size = std::cmp::min(PAGE_SIZE as usize - offset, buf.len() - done);
+			match &self.pages[(current / PAGE_SIZE) as usize] {
+				Some(page) => buf[done..done + size].copy_from_slice(&page[offset..offset + size]),
+				None => buf[done..done + size].fill(0)
+			};
+			done += size;
+		}
+	}
+
+	// Reads a little endian value of width bytes, up to eight
+	pub fn read_le_bytes(&self, address: u64, width: u64) -> u64 {
+		let mut buf = [0; 8];
+		self.read_bytes(address, &mut buf[..width as usize]);
+		u64::from_le_bytes(buf)
+	}
+
+	pub fn write_le_bytes(&mut self, address: u64, value: u64, width: u64) {
+		self.write_bytes(address, &value.to_le_bytes()[..width as usize]);
+	}
+
+	pub fn write_bytes(&mut self, address: u64, data: &[u8]) {
+		let mut done = 0;
+		while done < data.len() {
+			let current = address + done as u64;
+			let offset = (current % PAGE_SIZE) as usize;
+			let size = std::cmp::min(PAGE_SIZE as usize - offset, data.len() - done);
+			let page = self.pages[(current / PAGE_SIZE) as usize]
+				.get_or_insert_with(|| vec![0; PAGE_SIZE as usize].into_boxed_slice());
+			page[offset..offset + size].copy_from_slice(&data[done..done + size]);
+			done += size;
+		}
+	}
+}
@@ -0,0 +1,9 @@
+// Memory mapped device attached by host via Cpu.register_device()
+pub trait MmioDevice {
+	// Returns (base, size) of the physical address range
+	fn range(&self) -> (u64, u64);
+	// address is an offset from base
+	fn load(&mut self, address: u64) -> u8;
+	fn store(&mut self, address: u64, value: u8);
+	fn tick(&mut self);
+}
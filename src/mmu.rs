@@ -1,53 +1,280 @@
-use cpu::{PrivilegeMode, Trap, TrapType, Xlen};
-use virtio_block_disk::VirtioBlockDisk;
-use plic::{InterruptType, Plic};
-use clint::Clint;
+use cpu::{get_instruction_length, AccessedDirtyPolicy, FenceOrdering, MisalignedAccessPolicy, PrivilegeMode, Trap,
+	TrapType, Xlen};
+use virtio_block_disk::{VirtioBlockDisk, SECTOR_SIZE, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+	VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_UNSUPP};
+use virtio_mmio::{VirtioMmio, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use virtio_rng::VirtioRng;
+use htif::{Htif, HtifRequest, SYS_EXIT, SYS_WRITE};
+use memory::Memory;
+use plic::Plic;
+use pmp::Pmp;
+use clint::{Clint, TimerMode};
+use clock_source::ClockSource;
+use entropy_source::{EntropySource, HostEntropy, SeededEntropy};
 use uart::Uart;
 use terminal::Terminal;
+use mmio_device::MmioDevice;
+use stats::ExecStats;
 
-const DRAM_BASE: usize = 0x80000000;
+use std::fs::File;
 
+const TLB_SETS: usize = 64;
+const TLB_WAYS: usize = 4;
+
+// PLIC interrupt sources of built-in devices, the same as QEMU virt machine
+pub const VIRTIO_IRQ: u32 = 1;
+pub const VIRTIO_RNG_IRQ: u32 = 2;
+pub const UART_IRQ: u32 = 10;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Mmu {
 	clock: u64,
 	xlen: Xlen,
 	ppn: u64,
 	addressing_mode: AddressingMode,
 	privilege_mode: PrivilegeMode,
-	interrupt: InterruptType,
-	memory: Vec<u8>,
+	// Privilege mode loads and stores are translated and checked in.
+	// Differs from privilege_mode while mstatus.MPRV is set in M-mode.
+	data_privilege_mode: PrivilegeMode,
+	// mstatus.SUM and MXR
+	sum: bool,
+	mxr: bool,
+	pmp: Pmp,
+	// The running hart. ppn, addressing_mode, privilege modes, SUM, MXR,
+	// PMP, and TLB
+	// belong to it and the other harts' are parked in MmuContext
+	hart_id: usize,
+	// physical address reserved by LR, per hart
+	reservations: Vec<Option<u64>>,
+	misaligned_access_policy: MisalignedAccessPolicy,
+	accessed_dirty_policy: AccessedDirtyPolicy,
+	memory: Memory,
 	disk: VirtioBlockDisk,
+	rng: VirtioRng,
 	plic: Plic,
 	clint: Clint,
-	uart: Uart
+	uart: Uart,
+	// TLB is just a cache so it starts empty after restoring state
+	#[cfg_attr(feature = "serde", serde(skip, default = "create_tlb"))]
+	tlb: Vec<Option<TlbEntry>>,
+	#[cfg_attr(feature = "serde", serde(skip, default = "create_tlb_next_ways"))]
+	tlb_next_ways: [usize; TLB_SETS], // Round robin replacement
+	memory_map: MemoryMap,
+	// Set once the guest halts the machine, with the exit code
+	exit_code: Option<u64>,
+	htif: Htif,
+	// Value being written to the test finisher, byte by byte
+	finisher_value: u32,
+	// Devices registered by host. Their state is not a part of the snapshot.
+	#[cfg_attr(feature = "serde", serde(skip))]
+	devices: Vec<Box<dyn MmioDevice>>,
+	// Execution statistics are for host analysis, not a part of the snapshot
+	#[cfg_attr(feature = "serde", serde(skip, default = "ExecStats::new"))]
+	stats: ExecStats,
+	// Watchpoints are set up by host, not a part of machine state
+	#[cfg_attr(feature = "serde", serde(skip))]
+	watchpoints: Vec<Watchpoint>,
+	#[cfg_attr(feature = "serde", serde(skip))]
+	watchpoint_access: Option<(u64, WatchKind)> // address and kind of the hit watchpoint
+}
+
+// Address translation state of a hart which is not running.
+// Cpu swaps it with Mmu's when switching harts.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MmuContext {
+	ppn: u64,
+	addressing_mode: AddressingMode,
+	privilege_mode: PrivilegeMode,
+	data_privilege_mode: PrivilegeMode,
+	sum: bool,
+	mxr: bool,
+	pmp: Pmp,
+	#[cfg_attr(feature = "serde", serde(skip, default = "create_tlb"))]
+	tlb: Vec<Option<TlbEntry>>,
+	#[cfg_attr(feature = "serde", serde(skip, default = "create_tlb_next_ways"))]
+	tlb_next_ways: [usize; TLB_SETS]
+}
+
+// Physical address range from base to base + size - 1
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MemoryRegion {
+	pub base: u64,
+	pub size: u64
+}
+
+// Where memory and devices are placed in physical address space.
+// Memory size is given by Mmu.init_memory()
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MemoryMap {
+	pub dram_base: u64,
+	pub clint: MemoryRegion,
+	pub plic: MemoryRegion,
+	pub uart: MemoryRegion,
+	pub virtio: MemoryRegion,
+	pub virtio_rng: MemoryRegion,
+	// SiFive test device. The guest writes 0x5555 to pass, or 0x3333
+	// with the exit code in the upper 16 bits to fail, to halt the machine.
+	pub finisher: MemoryRegion
+}
+
+// Entries are separated per access type so that the first write to
+// a page cached by read or execute still walks the page table and
+// sets dirty bit in the page table entry
+struct TlbEntry {
+	vpn: u64,
+	privilege_mode: PrivilegeMode,
+	access_type: MemoryAccessType,
+	// Permission checks depend on mstatus.SUM and MXR, too
+	sum: bool,
+	mxr: bool,
+	ppn: u64
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum AddressingMode {
 	None,
 	SV32,
 	SV39,
-	SV48 // @TODO: Implement
+	SV48
 }
 
-enum MemoryAccessType {
+#[derive(Clone, Copy, PartialEq)]
+pub enum MemoryAccessType {
 	Execute,
 	Read,
 	Write
 }
 
+// Data accesses a watchpoint stops at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+	Read,
+	Write,
+	Access // Read or write
+}
+
+// Virtio devices whose queues Mmu processes
+enum VirtioDevice {
+	BlockDisk,
+	Rng
+}
+
+// Virtual address range watched for loads and stores by instructions
+struct Watchpoint {
+	address: u64,
+	length: u64,
+	kind: WatchKind
+}
+
+impl MemoryRegion {
+	fn contains(&self, address: u64) -> bool {
+		address >= self.base && address - self.base < self.size
+	}
+}
+
+impl MemoryMap {
+	// The same as QEMU virt machine, which xv6 expects
+	pub fn new() -> Self {
+		MemoryMap {
+			dram_base: 0x80000000,
+			clint: MemoryRegion {
+				base: 0x02000000,
+				size: 0x10000
+			},
+			plic: MemoryRegion {
+				base: 0x0c000000,
+				size: 0x4000000
+			},
+			uart: MemoryRegion {
+				base: 0x10000000,
+				size: 0x100
+			},
+			virtio: MemoryRegion {
+				base: 0x10001000,
+				size: 0x1000
+			},
+			virtio_rng: MemoryRegion {
+				base: 0x10002000,
+				size: 0x1000
+			},
+			finisher: MemoryRegion {
+				base: 0x100000,
+				size: 0x1000
+			}
+		}
+	}
+}
+
+impl MmuContext {
+	pub fn new() -> Self {
+		MmuContext {
+			ppn: 0,
+			addressing_mode: AddressingMode::None,
+			privilege_mode: PrivilegeMode::Machine,
+			data_privilege_mode: PrivilegeMode::Machine,
+			sum: false,
+			mxr: false,
+			pmp: Pmp::new(),
+			tlb: create_tlb(),
+			tlb_next_ways: create_tlb_next_ways()
+		}
+	}
+}
+
+fn create_tlb() -> Vec<Option<TlbEntry>> {
+	(0..TLB_SETS * TLB_WAYS).map(|_| None).collect()
+}
+
+fn create_tlb_next_ways() -> [usize; TLB_SETS] {
+	[0; TLB_SETS]
+}
+
 impl Mmu {
-	pub fn new(xlen: Xlen, terminal: Box<dyn Terminal>) -> Self {
+	// Deterministic timer mode makes the whole machine deterministic,
+	// UART input and entropy device included. clock_source is the host
+	// time of real time mode.
+	pub fn new(xlen: Xlen, terminal: Box<dyn Terminal>, timer_mode: TimerMode,
+		clock_source: Box<dyn ClockSource>) -> Self {
+		let deterministic = match timer_mode {
+			TimerMode::Deterministic => true,
+			TimerMode::RealTime(_) => false
+		};
+		let entropy_source: Box<dyn EntropySource> = match deterministic {
+			true => Box::new(SeededEntropy::new(0)),
+			false => Box::new(HostEntropy::new())
+		};
 		Mmu {
 			clock: 0,
 			xlen: xlen,
 			ppn: 0,
 			addressing_mode: AddressingMode::None,
 			privilege_mode: PrivilegeMode::Machine,
-			interrupt: InterruptType::None,
-			memory: vec![],
+			data_privilege_mode: PrivilegeMode::Machine,
+			sum: false,
+			mxr: false,
+			pmp: Pmp::new(),
+			hart_id: 0,
+			reservations: vec![None],
+			misaligned_access_policy: MisalignedAccessPolicy::Emulate,
+			accessed_dirty_policy: AccessedDirtyPolicy::Update,
+			memory: Memory::new(),
 			disk: VirtioBlockDisk::new(),
+			rng: VirtioRng::new(entropy_source),
 			plic: Plic::new(),
-			clint: Clint::new(),
-			uart: Uart::new(terminal)
+			clint: Clint::new(timer_mode, clock_source),
+			uart: Uart::new(terminal, deterministic),
+			tlb: create_tlb(),
+			tlb_next_ways: create_tlb_next_ways(),
+			memory_map: MemoryMap::new(),
+			exit_code: None,
+			htif: Htif::new(),
+			finisher_value: 0,
+			devices: vec![],
+			stats: ExecStats::new(),
+			watchpoints: vec![],
+			watchpoint_access: None
 		}
 	}
 
@@ -55,63 +282,423 @@ impl Mmu {
 		self.xlen = xlen;
 	}
 
+	pub fn update_misaligned_access_policy(&mut self, policy: MisalignedAccessPolicy) {
+		self.misaligned_access_policy = policy;
+	}
+
+	pub fn update_accessed_dirty_policy(&mut self, policy: AccessedDirtyPolicy) {
+		self.accessed_dirty_policy = policy;
+	}
+
+	pub fn update_memory_map(&mut self, memory_map: MemoryMap) {
+		self.memory_map = memory_map;
+	}
+
+	pub fn get_memory_map(&self) -> &MemoryMap {
+		&self.memory_map
+	}
+
+	pub fn register_device(&mut self, device: Box<dyn MmioDevice>) {
+		self.devices.push(device);
+	}
+
+	pub fn init_harts(&mut self, num: usize) {
+		self.reservations.resize(num, None);
+		self.clint.init_harts(num);
+		self.plic.init_harts(num);
+	}
+
+	pub fn update_hart_id(&mut self, hart_id: usize) {
+		self.hart_id = hart_id;
+	}
+
+	pub fn swap_context(&mut self, context: &mut MmuContext) {
+		std::mem::swap(&mut self.ppn, &mut context.ppn);
+		std::mem::swap(&mut self.addressing_mode, &mut context.addressing_mode);
+		std::mem::swap(&mut self.privilege_mode, &mut context.privilege_mode);
+		std::mem::swap(&mut self.data_privilege_mode, &mut context.data_privilege_mode);
+		std::mem::swap(&mut self.sum, &mut context.sum);
+		std::mem::swap(&mut self.mxr, &mut context.mxr);
+		std::mem::swap(&mut self.pmp, &mut context.pmp);
+		std::mem::swap(&mut self.tlb, &mut context.tlb);
+		std::mem::swap(&mut self.tlb_next_ways, &mut context.tlb_next_ways);
+	}
+
 	pub fn init_memory(&mut self, capacity: u64) {
-		for _i in 0..capacity {
-			self.memory.push(0);
-		}
+		self.memory.init(capacity);
+	}
+
+	pub fn get_memory_size(&self) -> u64 {
+		self.memory.get_capacity()
 	}
 	
 	pub fn init_disk(&mut self, data: Vec<u8>) {
 		self.disk.init(data);
 	}
 
+	pub fn update_disk_legacy(&mut self, legacy: bool) {
+		self.disk.update_legacy(legacy);
+	}
+
+	// Randomness virtio entropy device fills the guest's buffers with
+	pub fn update_entropy_source(&mut self, entropy_source: Box<dyn EntropySource>) {
+		self.rng.update_entropy_source(entropy_source);
+	}
+
+	// Disk backed by the host file. Changes are written back to it.
+	pub fn init_disk_with_file(&mut self, file: File) -> std::io::Result<()> {
+		self.disk.init_with_file(file)
+	}
+
+	pub fn flush_disk(&mut self) -> std::io::Result<()> {
+		self.disk.flush()
+	}
+
+	#[cfg(feature = "serde")]
+	pub fn swap_terminal(&mut self, other: &mut Mmu) {
+		self.uart.swap_terminal(&mut other.uart);
+	}
+
+	#[cfg(feature = "serde")]
+	pub fn swap_clock_source(&mut self, other: &mut Mmu) {
+		self.clint.swap_clock_source(&mut other.clint);
+	}
+
+	#[cfg(feature = "serde")]
+	pub fn swap_entropy_source(&mut self, other: &mut Mmu) {
+		self.rng.swap_entropy_source(&mut other.rng);
+	}
+
+	#[cfg(feature = "serde")]
+	pub fn swap_devices(&mut self, other: &mut Mmu) {
+		std::mem::swap(&mut self.devices, &mut other.devices);
+	}
+
+	#[cfg(feature = "serde")]
+	pub fn swap_watchpoints(&mut self, other: &mut Mmu) {
+		std::mem::swap(&mut self.watchpoints, &mut other.watchpoints);
+	}
+
 	pub fn tick(&mut self) {
-		self.disk.tick();
+		self.disk.get_mut_transport().tick();
+		if self.disk.get_transport().is_access_due() {
+			self.handle_virtio_access(VirtioDevice::BlockDisk);
+		}
+		self.rng.get_mut_transport().tick();
+		if self.rng.get_transport().is_access_due() {
+			self.handle_virtio_access(VirtioDevice::Rng);
+		}
 		self.plic.tick();
 		self.clint.tick();
 		self.uart.tick();
+		for device in self.devices.iter_mut() {
+			device.tick();
+		}
+		if self.htif.take_tohost_written() {
+			self.handle_htif_request();
+		}
 		self.clock = self.clock.wrapping_add(1);
 	}
 
-	pub fn detect_interrupt(&mut self) -> &InterruptType {
-		// @TODO: Implement properly
-		match self.interrupt {
-			InterruptType::None => {
-				let mut interrupt = InterruptType::None;
-				if self.is_disk_interrupting() {
-					interrupt = InterruptType::Virtio;
-				} else if self.is_uart_interrupting() {
-					interrupt = InterruptType::KeyInput;
-				} else if self.is_clint_interrupting() {
-					interrupt = InterruptType::Timer;
-				}
-				match interrupt {
-					InterruptType::None => {},
-					_ => {
-						self.update_plic(&interrupt);
+	pub fn update_tohost_address(&mut self, address: u64) {
+		self.htif.update_tohost_address(address);
+	}
+
+	pub fn update_fromhost_address(&mut self, address: u64) {
+		self.htif.update_fromhost_address(address);
+	}
+
+	// Serves the request in tohost. tohost is cleared and the response
+	// is written to fromhost, if its address is known, as Spike does.
+	fn handle_htif_request(&mut self) {
+		let tohost_address = match self.htif.get_tohost_address() {
+			Some(address) => address,
+			None => return
+		};
+		let value = match self.load_doubleword_raw(tohost_address) {
+			Ok(value) if value != 0 => value,
+			_ => return
+		};
+		let payload = match Htif::decode_request(value) {
+			HtifRequest::Exit(exit_code) => {
+				self.halt(exit_code);
+				return;
+			},
+			HtifRequest::Syscall(address) => {
+				self.handle_htif_syscall(address);
+				1
+			},
+			HtifRequest::ConsolePutchar(value) => {
+				self.uart.put_output(value);
+				0
+			},
+			HtifRequest::Unsupported => 0
+		};
+		match self.store_doubleword_raw(tohost_address, 0) {
+			_ => {} // Ignoring error so far
+		};
+		match self.htif.get_fromhost_address() {
+			Some(address) => match self.store_doubleword_raw(address, Htif::get_response(value, payload)) {
+				_ => {} // Ignoring error so far
+			},
+			None => {}
+		};
+	}
+
+	// Arguments are in 64-bit words from the address, system call number
+	// first. The return value is written back to the first word.
+	fn handle_htif_syscall(&mut self, address: u64) {
+		let mut arguments = [0; 4];
+		for (i, argument) in arguments.iter_mut().enumerate() {
+			*argument = self.load_doubleword_raw(address.wrapping_add(i as u64 * 8)).unwrap_or(0);
+		}
+		let result = match arguments[0] {
+			SYS_WRITE => match arguments[1] {
+				1 | 2 => { // stdout and stderr
+					let mut data = vec![0; std::cmp::min(arguments[3], 0x10000) as usize];
+					match self.read_memory(arguments[2], &mut data) {
+						Ok(()) => {
+							for value in data.iter() {
+								self.uart.put_output(*value);
+							}
+							data.len() as i64
+						},
+						Err(()) => -14 // EFAULT
 					}
-				};
-				self.interrupt = interrupt;
+				},
+				_ => -9 // EBADF
+			},
+			SYS_EXIT => {
+				self.halt(arguments[1]);
+				0
 			},
-			_ => {}
+			_ => -38 // ENOSYS
 		};
-		&self.interrupt
+		match self.store_doubleword_raw(address, result as u64) {
+			_ => {} // Ignoring error so far
+		};
+	}
+
+	// For Cpu.reset(). Hart contexts are reset by Cpu.
+	pub fn reset(&mut self) {
+		for reservation in self.reservations.iter_mut() {
+			*reservation = None;
+		}
+		self.exit_code = None;
+		self.finisher_value = 0;
+	}
+
+	// Halts the machine. Only the first exit code is kept.
+	pub fn halt(&mut self, exit_code: u64) {
+		if self.exit_code.is_none() {
+			self.exit_code = Some(exit_code);
+		}
+	}
+
+	pub fn is_halted(&self) -> bool {
+		self.exit_code.is_some()
+	}
+
+	pub fn get_exit_code(&self) -> Option<u64> {
+		self.exit_code
+	}
+
+	pub fn get_stats(&self) -> &ExecStats {
+		&self.stats
+	}
+
+	pub fn get_mut_stats(&mut self) -> &mut ExecStats {
+		&mut self.stats
 	}
 
-	pub fn reset_interrupt(&mut self) {
-		self.interrupt = InterruptType::None;
+	pub fn add_watchpoint(&mut self, address: u64, length: u64, kind: WatchKind) {
+		self.watchpoints.push(Watchpoint {
+			address: address,
+			length: length,
+			kind: kind
+		});
+	}
+
+	// Returns false if no watchpoint of the range and kind exists
+	pub fn remove_watchpoint(&mut self, address: u64, length: u64, kind: WatchKind) -> bool {
+		match self.watchpoints.iter().position(|w| w.address == address && w.length == length && w.kind == kind) {
+			Some(index) => {
+				self.watchpoints.remove(index);
+				true
+			},
+			None => false
+		}
+	}
+
+	// Address in the range of the watchpoint the last load or store hit, and
+	// the watchpoint's kind. Cleared by taking.
+	pub fn take_watchpoint_access(&mut self) -> Option<(u64, WatchKind)> {
+		self.watchpoint_access.take()
+	}
+
+	// Called after a load or store succeeds so that watchpoints don't
+	// change what the access does
+	fn check_watchpoints(&mut self, v_address: u64, width: u64, access_kind: WatchKind) {
+		if self.watchpoints.is_empty() {
+			return;
+		}
+		for watchpoint in self.watchpoints.iter() {
+			let kind_matches = watchpoint.kind == WatchKind::Access || watchpoint.kind == access_kind;
+			let end = watchpoint.address.wrapping_add(watchpoint.length);
+			if kind_matches && v_address < end && watchpoint.address < v_address.wrapping_add(width) {
+				self.watchpoint_access = Some((v_address.max(watchpoint.address), watchpoint.kind));
+				return;
+			}
+		}
+	}
+
+	fn store_finisher(&mut self, offset: u64, value: u8) {
+		if offset >= 4 {
+			return;
+		}
+		let shift = offset * 8;
+		self.finisher_value = (self.finisher_value & !(0xff << shift)) | ((value as u32) << shift);
+		// Acts when the most significant byte of a word write arrives
+		if offset == 3 {
+			match self.finisher_value & 0xffff {
+				0x5555 => self.halt(0), // Pass
+				0x3333 => self.halt((self.finisher_value >> 16) as u64), // Fail
+				_ => {} // Reset (0x7777) isn't supported
+			};
+		}
+	}
+
+	pub fn detect_interrupt(&mut self) {
+		let sources = [
+			(VIRTIO_IRQ, self.disk.get_transport().is_interrupting()),
+			(VIRTIO_RNG_IRQ, self.rng.get_transport().is_interrupting()),
+			(UART_IRQ, self.uart.is_interrupting())
+		];
+		self.plic.detect_interrupt(&sources);
+	}
+
+	// External interrupt lines of sources other than built-in devices,
+	// for host to model its own devices. See Plic::raise_irq().
+	pub fn raise_irq(&mut self, irq: u32) {
+		self.plic.raise_irq(irq);
+		self.detect_interrupt();
+	}
+
+	pub fn lower_irq(&mut self, irq: u32) {
+		self.plic.lower_irq(irq);
+	}
+
+	// Whether any device raises an interrupt to any hart, for host to
+	// decide if it can sleep while harts wait for interrupt
+	pub fn has_pending_interrupt(&self) -> bool {
+		self.clint.is_any_hart_interrupting() ||
+			self.plic.has_pending() ||
+			self.uart.is_interrupting() ||
+			self.disk.get_transport().is_interrupting() ||
+			self.rng.get_transport().is_interrupting()
+	}
+
+	// mtime when CLINT timer of any hart fires next. None if no timer is set.
+	pub fn get_next_timer_deadline(&self) -> Option<u64> {
+		self.clint.get_next_deadline()
+	}
+
+	// Whether PLIC raises an external interrupt to the privilege mode
+	// of the running hart
+	pub fn is_plic_interrupting(&self, privilege_mode: &PrivilegeMode) -> bool {
+		let context = self.hart_id * 2 + match privilege_mode {
+			PrivilegeMode::Machine => 0,
+			_ => 1
+		};
+		self.plic.is_interrupting(context)
 	}
 
 	pub fn update_addressing_mode(&mut self, new_addressing_mode: AddressingMode) {
 		self.addressing_mode = new_addressing_mode;
+		self.flush_tlb();
 	}
 
 	pub fn update_privilege_mode(&mut self, mode: PrivilegeMode) {
 		self.privilege_mode = mode;
 	}
 
+	pub fn update_data_privilege_mode(&mut self, mode: PrivilegeMode) {
+		self.data_privilege_mode = mode;
+	}
+
+	// mstatus.SUM permits supervisor mode loads and stores to user pages,
+	// and mstatus.MXR permits loads from executable pages
+	pub fn update_sum_and_mxr(&mut self, sum: bool, mxr: bool) {
+		self.sum = sum;
+		self.mxr = mxr;
+	}
+
+	// Instruction fetch always uses the current privilege mode
+	fn get_access_privilege_mode(&self, access_type: MemoryAccessType) -> &PrivilegeMode {
+		match access_type {
+			MemoryAccessType::Execute => &self.privilege_mode,
+			_ => &self.data_privilege_mode
+		}
+	}
+
+	pub fn get_pmp_config(&self, index: usize) -> u8 {
+		self.pmp.get_config(index)
+	}
+
+	pub fn update_pmp_config(&mut self, index: usize, value: u8) {
+		self.pmp.update_config(index, value);
+	}
+
+	pub fn get_pmp_address(&self, index: usize) -> u64 {
+		self.pmp.get_address(index)
+	}
+
+	pub fn update_pmp_address(&mut self, index: usize, value: u64) {
+		self.pmp.update_address(index, value);
+	}
+
+	// Whether PMP permits the access of width bytes from the physical
+	// address in the privilege mode of the access
+	fn check_pmp(&self, p_address: u64, width: u64, access_type: MemoryAccessType) -> bool {
+		let is_machine_mode = match self.get_access_privilege_mode(access_type) {
+			PrivilegeMode::Machine => true,
+			_ => false
+		};
+		self.pmp.check(p_address, width, access_type, is_machine_mode)
+	}
+
 	pub fn update_ppn(&mut self, ppn: u64) {
 		self.ppn = ppn;
+		self.flush_tlb();
+	}
+
+	pub fn flush_tlb(&mut self) {
+		for entry in self.tlb.iter_mut() {
+			*entry = None;
+		}
+	}
+
+	// FENCE and FENCE.TSO. Harts run one instruction at a time in turn and
+	// every memory and device access completes before the next one starts,
+	// so accesses are already ordered whatever the ordering sets are and
+	// this is a no-op. Hook for a future memory model which buffers stores.
+	pub fn fence(&mut self, _ordering: &FenceOrdering) {
+	}
+
+	// Drops only the entries cached by instruction fetch, for FENCE.I
+	pub fn flush_instruction_tlb(&mut self) {
+		for entry in self.tlb.iter_mut() {
+			let is_execute = match entry {
+				Some(tlb_entry) => match tlb_entry.access_type {
+					MemoryAccessType::Execute => true,
+					_ => false
+				},
+				None => false
+			};
+			if is_execute {
+				*entry = None;
+			}
+		}
 	}
 
 	fn get_effective_address(&self, address: u64) -> u64 {
@@ -130,7 +717,19 @@ impl Mmu {
 				value: v_address
 			})
 		};
-		Ok(self.load_raw(p_address))
+		if !self.check_pmp(p_address, 1, MemoryAccessType::Execute) {
+			return Err(Trap {
+				trap_type: TrapType::InstructionAccessFault,
+				value: v_address
+			});
+		}
+		match self.load_raw(p_address) {
+			Ok(data) => Ok(data),
+			Err(()) => Err(Trap {
+				trap_type: TrapType::InstructionAccessFault,
+				value: v_address
+			})
+		}
 	}
 
 	fn fetch_bytes(&mut self, v_address: u64, width: u64) -> Result<u64, Trap> {
@@ -145,9 +744,19 @@ impl Mmu {
 						value: v_address
 					})
 				};
-				for i in 0..width {
-					data |= (self.load_raw(p_address.wrapping_add(i) as u64) as u64) << (i * 8);
+				if !self.check_pmp(p_address, width, MemoryAccessType::Execute) {
+					return Err(Trap {
+						trap_type: TrapType::InstructionAccessFault,
+						value: v_address
+					});
 				}
+				data = match self.load_bytes_raw(p_address, width) {
+					Ok(data) => data,
+					Err(()) => return Err(Trap {
+						trap_type: TrapType::InstructionAccessFault,
+						value: v_address
+					})
+				};
 			},
 			false => {
 				for i in 0..width {
@@ -163,9 +772,21 @@ impl Mmu {
 		Ok(data)
 	}
 
-	pub fn fetch_word(&mut self, v_address: u64) -> Result<u32, Trap> {
-		match self.fetch_bytes(v_address, 4) {
-			Ok(data) => Ok(data as u32),
+	// Fetches a 32-bit or compressed instruction. The upper halfword is
+	// fetched only if the lower one is of a 32-bit instruction, ending with
+	// 0b11, so that a compressed instruction at the end of a page or memory
+	// doesn't fault on the bytes after it. Returns the lower 16 bits alone
+	// for a compressed instruction.
+	pub fn fetch_instruction(&mut self, v_address: u64) -> Result<u32, Trap> {
+		let lower = match self.fetch_bytes(v_address, 2) {
+			Ok(data) => data as u32,
+			Err(e) => return Err(e)
+		};
+		if get_instruction_length(lower as u16) == 2 {
+			return Ok(lower);
+		}
+		match self.fetch_bytes(v_address.wrapping_add(2), 2) {
+			Ok(data) => Ok(((data as u32) << 16) | lower),
 			Err(e) => Err(e)
 		}
 	}
@@ -179,11 +800,33 @@ impl Mmu {
 				value: v_address
 			})
 		};
-		Ok(self.load_raw(p_address))
+		if !self.check_pmp(p_address, 1, MemoryAccessType::Read) {
+			return Err(Trap {
+				trap_type: TrapType::LoadAccessFault,
+				value: v_address
+			});
+		}
+		match self.load_raw(p_address) {
+			Ok(data) => {
+				self.check_watchpoints(v_address, 1, WatchKind::Read);
+				Ok(data)
+			},
+			Err(()) => Err(Trap {
+				trap_type: TrapType::LoadAccessFault,
+				value: v_address
+			})
+		}
 	}
 
 	fn load_bytes(&mut self, v_address: u64, width: u64) -> Result<u64, Trap> {
 		let mut data = 0 as u64;
+		match self.check_alignment(v_address, width) {
+			true => {},
+			false => return Err(Trap {
+				trap_type: TrapType::LoadAddressMisaligned,
+				value: v_address
+			})
+		};
 		match (v_address & 0xfff) <= (0x1000 - width) {
 			true => {
 				let effective_address = self.get_effective_address(v_address);
@@ -194,9 +837,20 @@ impl Mmu {
 						value: v_address
 					})
 				};
-				for i in 0..width {
-					data |= (self.load_raw(p_address.wrapping_add(i) as u64) as u64) << (i * 8);
+				if !self.check_pmp(p_address, width, MemoryAccessType::Read) {
+					return Err(Trap {
+						trap_type: TrapType::LoadAccessFault,
+						value: v_address
+					});
 				}
+				data = match self.load_bytes_raw(p_address, width) {
+					Ok(data) => data,
+					Err(()) => return Err(Trap {
+						trap_type: TrapType::LoadAccessFault,
+						value: v_address
+					})
+				};
+				self.check_watchpoints(v_address, width, WatchKind::Read);
 			},
 			false => {
 				for i in 0..width {
@@ -242,11 +896,32 @@ impl Mmu {
 				value: v_address
 			})
 		};
-		self.store_raw(p_address, value);
-		Ok(())
+		if !self.check_pmp(p_address, 1, MemoryAccessType::Write) {
+			return Err(Trap {
+				trap_type: TrapType::StoreAccessFault,
+				value: v_address
+			});
+		}
+		match self.store_raw(p_address, value) {
+			Ok(()) => {
+				self.check_watchpoints(v_address, 1, WatchKind::Write);
+				Ok(())
+			},
+			Err(()) => Err(Trap {
+				trap_type: TrapType::StoreAccessFault,
+				value: v_address
+			})
+		}
 	}
 
 	fn store_bytes(&mut self, v_address: u64, value: u64, width: u64) -> Result<(), Trap> {
+		match self.check_alignment(v_address, width) {
+			true => {},
+			false => return Err(Trap {
+				trap_type: TrapType::StoreAddressMisaligned,
+				value: v_address
+			})
+		};
 		match (v_address & 0xfff) <= (0x1000 - width) {
 			true => {
 				let effective_address = self.get_effective_address(v_address);
@@ -257,9 +932,20 @@ impl Mmu {
 						value: v_address
 					})
 				};
-				for i in 0..width {
-					self.store_raw(p_address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8);
+				if !self.check_pmp(p_address, width, MemoryAccessType::Write) {
+					return Err(Trap {
+						trap_type: TrapType::StoreAccessFault,
+						value: v_address
+					});
 				}
+				match self.store_bytes_raw(p_address, value, width) {
+					Ok(()) => {},
+					Err(()) => return Err(Trap {
+						trap_type: TrapType::StoreAccessFault,
+						value: v_address
+					})
+				};
+				self.check_watchpoints(v_address, width, WatchKind::Write);
 			},
 			false => {
 				for i in 0..width {
@@ -285,148 +971,383 @@ impl Mmu {
 		self.store_bytes(v_address, value as u64, 8)
 	}
 
-	pub fn load_raw(&mut self, address: u64) -> u8 {
+	// Returns false if the access must trap as misaligned. Misaligned
+	// access within a page is done by the raw accessors as it is, and
+	// crossing a page is split into byte accesses.
+	fn check_alignment(&self, v_address: u64, width: u64) -> bool {
+		match self.misaligned_access_policy {
+			MisalignedAccessPolicy::Emulate => true,
+			MisalignedAccessPolicy::Trap => (v_address % width) == 0
+		}
+	}
+
+	pub fn reserve(&mut self, v_address: u64) -> Result<(), Trap> {
+		let effective_address = self.get_effective_address(v_address);
+		let p_address = match self.translate_address(effective_address, MemoryAccessType::Read) {
+			Ok(address) => address,
+			Err(()) => return Err(Trap {
+				trap_type: TrapType::LoadPageFault,
+				value: v_address
+			})
+		};
+		self.reservations[self.hart_id] = Some(p_address);
+		Ok(())
+	}
+
+	// Returns whether SC can store to the address.
+	// The reservation is cleared either way.
+	pub fn check_reservation(&mut self, v_address: u64) -> Result<bool, Trap> {
+		let effective_address = self.get_effective_address(v_address);
+		let p_address = match self.translate_address(effective_address, MemoryAccessType::Write) {
+			Ok(address) => address,
+			Err(()) => return Err(Trap {
+				trap_type: TrapType::StorePageFault,
+				value: v_address
+			})
+		};
+		let reserved = match self.reservations[self.hart_id] {
+			Some(address) => address == p_address,
+			None => false
+		};
+		self.reservations[self.hart_id] = None;
+		Ok(reserved)
+	}
+
+	pub fn clear_reservation(&mut self) {
+		self.reservations[self.hart_id] = None;
+	}
+
+	// Raw access methods return Err if no device or memory
+	// is mapped to the physical address
+
+	pub fn load_raw(&mut self, address: u64) -> Result<u8, ()> {
 		let effective_address = self.get_effective_address(address);
-		// @TODO: Check valid memory map
-		match address {
-			0x0200bff8..=0x0200bfff => self.clint.load(effective_address) as u8,
-			0x0c201004..=0x0c201007 => self.plic.load(effective_address) as u8,
-			0x10000000..=0x10000005 => self.uart.load(effective_address),
-			0x10001000..=0x10001FFF => self.disk.load(effective_address),
-			_ => {
-				if effective_address < DRAM_BASE as u64 {
-					panic!("No memory map support yet to load AD:{:X}", effective_address);
-				}
-				self.memory[effective_address as usize - DRAM_BASE]
+		// Host registered devices have priority over memory and built-in devices
+		for device in self.devices.iter_mut() {
+			let (base, size) = device.range();
+			if effective_address >= base && effective_address - base < size {
+				return Ok(device.load(effective_address - base));
 			}
 		}
+		// Checking memory first because it's accessed the most
+		match self.get_memory_index(effective_address) {
+			Some(index) => return Ok(self.memory.read_byte(index)),
+			None => {}
+		};
+		if self.memory_map.clint.contains(effective_address) {
+			Ok(self.clint.load(effective_address - self.memory_map.clint.base))
+		} else if self.memory_map.plic.contains(effective_address) {
+			let value = self.plic.load(effective_address - self.memory_map.plic.base);
+			// Virtio devices interrupt once per notification, see VirtioMmio.
+			// UART keeps interrupting until the guest reads received data.
+			match self.plic.take_claimed_irq() {
+				Some(VIRTIO_IRQ) => {
+					self.disk.get_mut_transport().reset_interrupting();
+				},
+				Some(VIRTIO_RNG_IRQ) => {
+					self.rng.get_mut_transport().reset_interrupting();
+				},
+				_ => {}
+			};
+			Ok(value)
+		} else if self.memory_map.uart.contains(effective_address) {
+			Ok(self.uart.load(effective_address - self.memory_map.uart.base))
+		} else if self.memory_map.virtio.contains(effective_address) {
+			Ok(self.disk.load(effective_address - self.memory_map.virtio.base))
+		} else if self.memory_map.virtio_rng.contains(effective_address) {
+			Ok(self.rng.load(effective_address - self.memory_map.virtio_rng.base))
+		} else if self.memory_map.finisher.contains(effective_address) {
+			Ok(0)
+		} else {
+			Err(())
+		}
 	}
 
-	pub fn load_halfword_raw(&mut self, address: u64) -> u16 {
-		let mut data = 0 as u16;
-		for i in 0..2 {
-			data |= (self.load_raw(address.wrapping_add(i)) as u16) << (i * 8)
+	// Accesses the main memory at once, or devices byte by byte
+	fn load_bytes_raw(&mut self, address: u64, width: u64) -> Result<u64, ()> {
+		match self.get_memory_range_index(address, width) {
+			Some(index) => return Ok(self.memory.read_le_bytes(index, width)),
+			None => {}
+		};
+		let mut data = 0 as u64;
+		for i in 0..width {
+			match self.load_raw(address.wrapping_add(i)) {
+				Ok(byte) => {
+					data |= (byte as u64) << (i * 8)
+				},
+				Err(()) => return Err(())
+			};
 		}
-		data
+		Ok(data)
 	}
 
-	pub fn load_word_raw(&mut self, address: u64) -> u32 {
-		let mut data = 0 as u32;
-		for i in 0..4 {
-			data |= (self.load_raw(address.wrapping_add(i)) as u32) << (i * 8)
+	pub fn load_halfword_raw(&mut self, address: u64) -> Result<u16, ()> {
+		match self.load_bytes_raw(address, 2) {
+			Ok(data) => Ok(data as u16),
+			Err(()) => Err(())
 		}
-		data
 	}
 
-	pub fn load_doubleword_raw(&mut self, address: u64) -> u64 {
-		let mut data = 0 as u64;
-		for i in 0..8 {
-			data |= (self.load_raw(address.wrapping_add(i)) as u64) << (i * 8)
+	pub fn load_word_raw(&mut self, address: u64) -> Result<u32, ()> {
+		match self.load_bytes_raw(address, 4) {
+			Ok(data) => Ok(data as u32),
+			Err(()) => Err(())
 		}
-		data
 	}
 
-	pub fn store_raw(&mut self, address: u64, value: u8) {
+	pub fn load_doubleword_raw(&mut self, address: u64) -> Result<u64, ()> {
+		self.load_bytes_raw(address, 8)
+	}
+
+	pub fn store_raw(&mut self, address: u64, value: u8) -> Result<(), ()> {
 		let effective_address = self.get_effective_address(address);
-		// @TODO: Check memory map
-		match address {
-			0x0c000004..=0x0c000007 => {}, // @TODO: Where to?
-			0x0c000028..=0x0c00002b => {}, // @TODO: Where to?
-			0x0c002080..=0x0c002083 => { // PLIC_SENABLE(hart) (PLIC + 0x2080 + (hart)*0x100)
-				self.plic.store(effective_address, value);
+		self.invalidate_reservations(effective_address, 1);
+		for device in self.devices.iter_mut() {
+			let (base, size) = device.range();
+			if effective_address >= base && effective_address - base < size {
+				device.store(effective_address - base, value);
+				return Ok(());
+			}
+		}
+		match self.get_memory_index(effective_address) {
+			Some(index) => {
+				self.memory.write_byte(index, value);
+				self.htif.notify_store(effective_address, 1);
+				return Ok(());
 			},
-			0x0c201000..=0x0c201007 => {}, // @TODO: Where to?
-			0x02004000..=0x02004007 => {
-				self.clint.store(effective_address, value);
+			None => {}
+		};
+		if self.memory_map.clint.contains(effective_address) {
+			self.clint.store(effective_address - self.memory_map.clint.base, value);
+		} else if self.memory_map.plic.contains(effective_address) {
+			self.plic.store(effective_address - self.memory_map.plic.base, value);
+		} else if self.memory_map.uart.contains(effective_address) {
+			self.uart.store(effective_address - self.memory_map.uart.base, value);
+		} else if self.memory_map.virtio.contains(effective_address) {
+			self.disk.store(effective_address - self.memory_map.virtio.base, value);
+		} else if self.memory_map.virtio_rng.contains(effective_address) {
+			self.rng.store(effective_address - self.memory_map.virtio_rng.base, value);
+		} else if self.memory_map.finisher.contains(effective_address) {
+			self.store_finisher(effective_address - self.memory_map.finisher.base, value);
+		} else {
+			return Err(());
+		}
+		Ok(())
+	}
+
+	fn store_bytes_raw(&mut self, address: u64, value: u64, width: u64) -> Result<(), ()> {
+		match self.get_memory_range_index(address, width) {
+			Some(index) => {
+				let effective_address = self.get_effective_address(address);
+				self.invalidate_reservations(effective_address, width);
+				self.memory.write_le_bytes(index, value, width);
+				self.htif.notify_store(effective_address, width);
+				return Ok(());
 			},
-			0x10000000..=0x10000005 => {
-				self.uart.store(effective_address, value);
+			None => {}
+		};
+		for i in 0..width {
+			match self.store_raw(address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8) {
+				Ok(()) => {},
+				Err(()) => return Err(())
+			};
+		}
+		Ok(())
+	}
+
+	pub fn store_halfword_raw(&mut self, address: u64, value: u16) -> Result<(), ()> {
+		self.store_bytes_raw(address, value as u64, 2)
+	}
+
+	pub fn store_word_raw(&mut self, address: u64, value: u32) -> Result<(), ()> {
+		self.store_bytes_raw(address, value as u64, 4)
+	}
+
+	pub fn store_doubleword_raw(&mut self, address: u64, value: u64) -> Result<(), ()> {
+		self.store_bytes_raw(address, value, 8)
+	}
+
+	// Bulk raw access methods for the host. The range is copied at once
+	// if it is in the main memory and no host registered device overlaps.
+
+	// Reading devices can have side effects so only the main memory
+	// can be read. Returns Err if the range isn't in the main memory.
+	pub fn read_memory(&self, address: u64, buf: &mut [u8]) -> Result<(), ()> {
+		match self.get_memory_range_index(address, buf.len() as u64) {
+			Some(index) => {
+				self.memory.read_bytes(index, buf);
+				Ok(())
 			},
-			0x10001000..=0x10001FFF => { // @TODO: Check a valid range
-				self.disk.store(effective_address, value);
+			None => Err(())
+		}
+	}
+
+	// Falls back to byte stores otherwise. Returns Err if any byte
+	// in the range is unmapped.
+	pub fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<(), ()> {
+		match self.get_memory_range_index(address, data.len() as u64) {
+			Some(index) => {
+				let effective_address = self.get_effective_address(address);
+				self.invalidate_reservations(effective_address, data.len() as u64);
+				self.memory.write_bytes(index, data);
+				Ok(())
 			},
-			_ => {
-				if effective_address < DRAM_BASE as u64 {
-					panic!("No memory map support yet to store AD:{:X}", effective_address);
+			None => {
+				for (i, value) in data.iter().enumerate() {
+					match self.store_raw(address.wrapping_add(i as u64), *value) {
+						Ok(()) => {},
+						Err(()) => return Err(())
+					};
 				}
-				self.memory[effective_address as usize - DRAM_BASE] = value;
+				Ok(())
 			}
-		};
+		}
 	}
 
-	pub fn store_halfword_raw(&mut self, address: u64, value: u16) {
-		for i in 0..2 {
-			self.store_raw(address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8);
+	// Any store to the reserved doubleword invalidates the reservation,
+	// including the ones made by other harts
+	fn invalidate_reservations(&mut self, effective_address: u64, length: u64) {
+		let start = effective_address & !0x7;
+		let end = effective_address.wrapping_add(length);
+		for reservation in self.reservations.iter_mut() {
+			match *reservation {
+				Some(reserved) if (reserved & !0x7) >= start && (reserved & !0x7) < end => *reservation = None,
+				_ => {}
+			};
 		}
 	}
 
-	pub fn store_word_raw(&mut self, address: u64, value: u32) {
-		for i in 0..4 {
-			self.store_raw(address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8);
+	fn get_memory_index(&self, effective_address: u64) -> Option<u64> {
+		let dram_base = self.memory_map.dram_base;
+		match effective_address >= dram_base && effective_address - dram_base < self.memory.get_capacity() {
+			true => Some(effective_address - dram_base),
+			false => None
 		}
 	}
 
-	pub fn store_doubleword_raw(&mut self, address: u64, value: u64) {
-		for i in 0..8 {
-			self.store_raw(address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8);
+	// Returns the memory index of the range start if the whole range
+	// is in the main memory and isn't overlapped by any host registered device
+	fn get_memory_range_index(&self, address: u64, length: u64) -> Option<u64> {
+		let effective_address = self.get_effective_address(address);
+		let index = match self.get_memory_index(effective_address) {
+			Some(index) => index,
+			None => return None
+		};
+		if length > self.memory.get_capacity() - index {
+			return None;
+		}
+		let end = effective_address + length;
+		for device in self.devices.iter() {
+			let (base, size) = device.range();
+			if base < end && effective_address < base.wrapping_add(size) {
+				return None;
+			}
 		}
+		Some(index)
 	}
 
 	fn translate_address(&mut self, address: u64, access_type: MemoryAccessType) -> Result<u64, ()> {
+		let privilege_mode = self.get_access_privilege_mode(access_type).clone();
 		match self.addressing_mode {
 			AddressingMode::None => Ok(address),
-			AddressingMode::SV32 => match self.privilege_mode {
+			AddressingMode::SV32 => match privilege_mode {
 				PrivilegeMode::User | PrivilegeMode::Supervisor => {
 					let vpns = [(address >> 12) & 0x3ff, (address >> 22) & 0x3ff];
-					self.traverse_page(address, 2 - 1, self.ppn, &vpns, access_type)
+					self.translate_address_with_tlb(address, 2 - 1, &vpns, access_type, privilege_mode)
 				},
 				_ => Ok(address)
 			},
-			AddressingMode::SV39 => match self.privilege_mode {
+			AddressingMode::SV39 => match privilege_mode {
 				PrivilegeMode::User | PrivilegeMode::Supervisor => {
 					let vpns = [(address >> 12) & 0x1ff, (address >> 21) & 0x1ff, (address >> 30) & 0x1ff];
-					self.traverse_page(address, 3 - 1, self.ppn, &vpns, access_type)
+					self.translate_address_with_tlb(address, 3 - 1, &vpns, access_type, privilege_mode)
 				},
 				_ => Ok(address)
 			},
-			AddressingMode::SV48 => {
-				panic!("AddressingMode SV48 is not supported yet.");
+			AddressingMode::SV48 => match privilege_mode {
+				PrivilegeMode::User | PrivilegeMode::Supervisor => {
+					let vpns = [(address >> 12) & 0x1ff, (address >> 21) & 0x1ff, (address >> 30) & 0x1ff, (address >> 39) & 0x1ff];
+					self.translate_address_with_tlb(address, 4 - 1, &vpns, access_type, privilege_mode)
+				},
+				_ => Ok(address)
 			}
 		}
 	}
 
+	fn translate_address_with_tlb(&mut self, v_address: u64, level: u8, vpns: &[u64],
+		access_type: MemoryAccessType, privilege_mode: PrivilegeMode) -> Result<u64, ()> {
+		let vpn = v_address >> 12;
+		let set = (vpn as usize) & (TLB_SETS - 1);
+		for way in 0..TLB_WAYS {
+			match &self.tlb[set * TLB_WAYS + way] {
+				Some(entry) if entry.vpn == vpn && entry.access_type == access_type &&
+					entry.privilege_mode == privilege_mode &&
+					entry.sum == self.sum && entry.mxr == self.mxr => {
+					self.stats.record_tlb_hit();
+					return Ok((entry.ppn << 12) | (v_address & 0xfff));
+				},
+				_ => {}
+			};
+		}
+		self.stats.record_tlb_miss();
+		self.stats.record_page_table_walk();
+		let p_address = match self.traverse_page(v_address, level, self.ppn, vpns, access_type, &privilege_mode) {
+			Ok(address) => address,
+			Err(()) => return Err(())
+		};
+		let way = self.tlb_next_ways[set];
+		self.tlb_next_ways[set] = (way + 1) % TLB_WAYS;
+		self.tlb[set * TLB_WAYS + way] = Some(TlbEntry {
+			vpn: vpn,
+			privilege_mode: privilege_mode,
+			access_type: access_type,
+			sum: self.sum,
+			mxr: self.mxr,
+			ppn: p_address >> 12
+		});
+		Ok(p_address)
+	}
+
 	fn traverse_page(&mut self, v_address: u64, level: u8, parent_ppn: u64,
-		vpns: &[u64], access_type: MemoryAccessType) -> Result<u64, ()> {
+		vpns: &[u64], access_type: MemoryAccessType, privilege_mode: &PrivilegeMode) -> Result<u64, ()> {
 		let pagesize = 4096;
 		let ptesize = match self.addressing_mode {
 			AddressingMode::SV32 => 4,
 			_ => 8
 		};
 		let pte_address = parent_ppn * pagesize + vpns[level as usize] * ptesize;
+		// Page table accesses are checked by PMP as supervisor mode accesses
+		// whatever the privilege mode of the original access is.
+		// @TODO: Unmapped or PMP denied page table entry address should raise
+		// access fault, not page fault
+		if !self.pmp.check(pte_address, ptesize, MemoryAccessType::Read, false) {
+			return Err(());
+		}
 		let pte = match self.addressing_mode {
-			AddressingMode::SV32 => self.load_word_raw(pte_address) as u64,
-			_ => self.load_doubleword_raw(pte_address)
-		};
-		let ppn = match self.addressing_mode {
-			AddressingMode::SV32 => (pte >> 10) & 0x3fffff,
-			_ => (pte >> 10) & 0xfffffffffff
+			AddressingMode::SV32 => match self.load_word_raw(pte_address) {
+				Ok(pte) => pte as u64,
+				Err(()) => return Err(())
+			},
+			_ => match self.load_doubleword_raw(pte_address) {
+				Ok(pte) => pte,
+				Err(()) => return Err(())
+			}
 		};
-		let ppns = match self.addressing_mode {
-			AddressingMode::SV32 => [(pte >> 10) & 0x3ff, (pte >> 20) & 0xfff, 0 /*dummy*/],
-			AddressingMode::SV39 => [(pte >> 10) & 0x1ff, (pte >> 19) & 0x1ff, (pte >> 28) & 0x3ffffff],
-			_ => panic!() // Shouldn't happen
+		let ppn = match self.get_pte_ppn(pte) {
+			Ok(ppn) => ppn,
+			Err(()) => return Err(())
 		};
 		let _rsw = (pte >> 8) & 0x3;
 		let d = (pte >> 7) & 1;
 		let a = (pte >> 6) & 1;
 		let _g = (pte >> 5) & 1;
-		let _u = (pte >> 4) & 1;
+		let u = (pte >> 4) & 1;
 		let x = (pte >> 3) & 1;
 		let w = (pte >> 2) & 1;
 		let r = (pte >> 1) & 1;
 		let v = pte & 1;
 
-		// println!("VA:{:X} Level:{:X} PTE_AD:{:X} PTE:{:X} PPPN:{:X} PPN:{:X} PPN1:{:X} PPN0:{:X}", v_address, level, pte_address, pte, parent_ppn, ppn, ppns[1], ppns[0]);
+		// println!("VA:{:X} Level:{:X} PTE_AD:{:X} PTE:{:X} PPPN:{:X} PPN:{:X}", v_address, level, pte_address, pte, parent_ppn, ppn);
 
 		if v == 0 || (r == 0 && w == 1) {
 			return Err(());
@@ -435,22 +1356,26 @@ impl Mmu {
 		if r == 0 && x == 0 {
 			return match level {
 				0 => Err(()),
-				_ => self.traverse_page(v_address, level - 1, ppn, vpns, access_type)
+				_ => self.traverse_page(v_address, level - 1, ppn, vpns, access_type, privilege_mode)
 			};
 		}
 
 		// Leaf page found
 
-		if a == 0 || (match access_type { MemoryAccessType::Write => d == 0, _ => false }) {
-			let new_pte = pte | (1 << 6) | (match access_type {
-				MemoryAccessType::Write => 1 << 7,
-				_ => 0
-			});
-			match self.addressing_mode {
-				AddressingMode::SV32 => self.store_word_raw(pte_address, new_pte as u32),
-				_ => self.store_doubleword_raw(pte_address, new_pte)
-			};
-		}
+		// User mode can access only user pages. Supervisor mode can't
+		// execute user pages, and loads and stores them only if SUM is set.
+		match privilege_mode {
+			PrivilegeMode::User => {
+				if u == 0 {
+					return Err(());
+				}
+			},
+			_ => {
+				if u == 1 && (access_type == MemoryAccessType::Execute || !self.sum) {
+					return Err(());
+				}
+			}
+		};
 
 		match access_type {
 			MemoryAccessType::Execute => {
@@ -459,7 +1384,8 @@ impl Mmu {
 				}
 			},
 			MemoryAccessType::Read => {
-				if r == 0 {
+				// MXR makes executable pages readable
+				if r == 0 && !(self.mxr && x == 1) {
 					return Err(());
 				}
 			},
@@ -470,154 +1396,301 @@ impl Mmu {
 			}
 		};
 
-		let offset = v_address & 0xfff; // [11:0]
-		// @TODO: Optimize
-		let p_address = match self.addressing_mode {
-			AddressingMode::SV32 => match level {
-				1 => {
-					if ppns[0] != 0 {
-						return Err(());
-					}
-					(ppns[1] << 22) | (vpns[0] << 12) | offset
-				},
-				0 => (ppn << 12) | offset,
-				_ => panic!() // Shouldn't happen
-			},
-			_ => match level {
-				2 => {
-					if ppns[1] != 0 || ppns[0] != 0 {
-						return Err(());
-					}
-					(ppns[2] << 30) | (vpns[1] << 21) | (vpns[0] << 12) | offset
-				},
-				1 => {
-					if ppns[0] != 0 {
-						return Err(());
-					}
-					(ppns[2] << 30) | (ppns[1] << 21) | (vpns[0] << 12) | offset
-				},
-				0 => (ppn << 12) | offset,
-				_ => panic!() // Shouldn't happen
-			},
+		let p_address = match self.get_leaf_address(ppn, level, v_address) {
+			Ok(address) => address,
+			Err(()) => return Err(())
 		};
+
+		// A and D are updated only for accesses which don't fault
+		if a == 0 || (match access_type { MemoryAccessType::Write => d == 0, _ => false }) {
+			match self.accessed_dirty_policy {
+				AccessedDirtyPolicy::Update => {},
+				AccessedDirtyPolicy::Fault => return Err(())
+			};
+			let new_pte = pte | (1 << 6) | (match access_type {
+				MemoryAccessType::Write => 1 << 7,
+				_ => 0
+			});
+			if !self.pmp.check(pte_address, ptesize, MemoryAccessType::Write, false) {
+				return Err(());
+			}
+			let result = match self.addressing_mode {
+				AddressingMode::SV32 => self.store_word_raw(pte_address, new_pte as u32),
+				_ => self.store_doubleword_raw(pte_address, new_pte)
+			};
+			match result {
+				Ok(()) => {},
+				Err(()) => return Err(())
+			};
+		}
 		// println!("PA:{:X}", p_address);
 		Ok(p_address)
 	}
 
+	// PPN of a page table entry. Err if the reserved bits [63:54] of
+	// Sv39 and Sv48 entries, including N and PBMT which aren't
+	// implemented, are set.
+	fn get_pte_ppn(&self, pte: u64) -> Result<u64, ()> {
+		match self.addressing_mode {
+			AddressingMode::SV32 => Ok((pte >> 10) & 0x3fffff),
+			_ => match pte >> 54 {
+				0 => Ok((pte >> 10) & 0xfffffffffff),
+				_ => Err(())
+			}
+		}
+	}
+
+	// Physical address mapped by a leaf entry at the level. A superpage
+	// maps the virtual address bits below the level as is, so the PPN
+	// fields below the level must be zero. Err if the superpage is
+	// misaligned.
+	fn get_leaf_address(&self, ppn: u64, level: u8, v_address: u64) -> Result<u64, ()> {
+		let vpn_bits = match self.addressing_mode {
+			AddressingMode::SV32 => 10,
+			_ => 9
+		};
+		let page_bits = 12 + vpn_bits * level as u64;
+		let page_mask = (1 << page_bits) - 1;
+		match ((ppn << 12) & page_mask) == 0 {
+			true => Ok((ppn << 12) | (v_address & page_mask)),
+			false => Err(())
+		}
+	}
+
 	//
 
-	// @TODO: This implementation is too specific to xv6.
-	// Follow the virtio block specification more propertly.
-	pub fn handle_disk_access(&mut self) {
-		let avail_address = self.disk.get_avail_address();
-		let base_desc_address = self.disk.get_desc_address() as u64;
-		let base_used_address = self.disk.get_used_address();
-
-		let _flag = self.load_halfword_raw(avail_address);
-		let offset = self.load_halfword_raw(avail_address.wrapping_add(1));
-		let index = self.load_halfword_raw(avail_address.wrapping_add(offset as u64 % 8).wrapping_add(2));
-		let desc_size = 16;
-
-		let desc_address0 = base_desc_address + desc_size * index as u64;
-		let addr0 = self.load_doubleword_raw(desc_address0);
-		let _len0 = self.load_word_raw(desc_address0.wrapping_add(8));
-		let _flags0 = self.load_halfword_raw(desc_address0.wrapping_add(12));
-		let next0 = self.load_halfword_raw(desc_address0.wrapping_add(14));
-
-		let desc_address1 = base_desc_address + desc_size * next0 as u64;
-		let addr1 = self.load_doubleword_raw(desc_address1);
-		let len1 = self.load_word_raw(desc_address1.wrapping_add(8));
-		let flags1 = self.load_halfword_raw(desc_address1.wrapping_add(12));
-		let next1 = self.load_halfword_raw(desc_address1.wrapping_add(14));
-
-		let desc_address2 = base_desc_address + desc_size * next1 as u64;
-		let _addr2 = self.load_doubleword_raw(desc_address2);
-		let _len2 = self.load_word_raw(desc_address2.wrapping_add(8));
-		let _flags2 = self.load_halfword_raw(desc_address2.wrapping_add(12));
-		let _next2 = self.load_halfword_raw(desc_address2.wrapping_add(14));
-
-		/*
-		println!("Avail AD:{:X}", avail_address);
-		println!("Flag:{:X}", flag);
-		println!("Offset:{:X}", offset);
-		println!("Index:{:X}", index);
-		println!("addr0:{:X}", addr0);
-		println!("len0:{:X}", len0);
-		println!("flags0:{:X}", flags0);
-		println!("next0:{:X}", next0);
-		println!("addr1:{:X}", addr1);
-		println!("len1:{:X}", len1);
-		println!("flags1:{:X}", flags1);
-		println!("next1:{:X}", next1);
-		println!("addr2:{:X}", addr2);
-		println!("len2:{:X}", len2);
-		println!("flags2:{:X}", flags2);
-		println!("next2:{:X}", next2);
-		*/
-		
-		let _blk_type = self.load_word_raw(addr0);
-		let _blk_reserved = self.load_word_raw(addr0.wrapping_add(4));
-		let blk_sector = self.load_doubleword_raw(addr0.wrapping_add(8));
-
-		/*
-		println!("Blk type:{:X}", blk_type);
-		println!("Blk reserved:{:X}", blk_reserved);
-		println!("Blk sector:{:X}", blk_sector);
-		*/
-
-		match (flags1 & 2) == 0 {
-			true => { // write to disk
-				// println!("Write to disk");
-				for i in 0..len1 as u64 {
-					let data = self.load_raw(addr1 + i);
-					self.disk.write_to_disk(blk_sector * 512 + i, data);
-					// print!("{:02X} ", data);
+	fn get_virtio_transport(&self, device: &VirtioDevice) -> &VirtioMmio {
+		match device {
+			VirtioDevice::BlockDisk => self.disk.get_transport(),
+			VirtioDevice::Rng => self.rng.get_transport()
+		}
+	}
+
+	fn get_mut_virtio_transport(&mut self, device: &VirtioDevice) -> &mut VirtioMmio {
+		match device {
+			VirtioDevice::BlockDisk => self.disk.get_mut_transport(),
+			VirtioDevice::Rng => self.rng.get_mut_transport()
+		}
+	}
+
+	// Processes the requests the driver has made available to the device
+	// since the last call and puts them to used ring. Reading from unmapped
+	// address returns zero and writing to there is ignored so that the guest
+	// can't crash the host.
+	fn handle_virtio_access(&mut self, device: VirtioDevice) {
+		let queue_size = self.get_virtio_transport(&device).get_queue_size();
+		if queue_size > 0 {
+			let desc_address = self.get_virtio_transport(&device).get_desc_address();
+			let avail_address = self.get_virtio_transport(&device).get_avail_address();
+			let used_address = self.get_virtio_transport(&device).get_used_address();
+			let avail_index = self.load_halfword_raw(avail_address.wrapping_add(2)).unwrap_or(0);
+			while self.get_virtio_transport(&device).get_last_avail_index() != avail_index {
+				let ring_index = self.get_virtio_transport(&device).get_last_avail_index() as u64 % queue_size;
+				let head = self.load_halfword_raw(avail_address.wrapping_add(4 + ring_index * 2)).unwrap_or(0);
+				self.get_mut_virtio_transport(&device).advance_last_avail_index();
+
+				let descs = self.read_descriptor_chain(desc_address, head as u64, queue_size);
+				let length = match device {
+					VirtioDevice::BlockDisk => self.handle_disk_request(&descs),
+					VirtioDevice::Rng => self.handle_rng_request(&descs)
+				};
+
+				let used_ring_index = self.get_virtio_transport(&device).get_used_index() as u64 % queue_size;
+				let element_address = used_address.wrapping_add(4 + used_ring_index * 8);
+				match self.store_word_raw(element_address, head as u32) {
+					_ => {} // Ignoring error so far
+				};
+				match self.store_word_raw(element_address.wrapping_add(4), length) {
+					_ => {} // Ignoring error so far
+				};
+				self.get_mut_virtio_transport(&device).advance_used_index();
+			}
+			// Used index is updated after the elements are written
+			let used_index = self.get_virtio_transport(&device).get_used_index();
+			match self.store_halfword_raw(used_address.wrapping_add(2), used_index) {
+				_ => {} // Ignoring error so far
+			};
+		}
+		match device {
+			// Writes through to the host file if the disk is backed by it
+			VirtioDevice::BlockDisk => match self.disk.flush() {
+				_ => {} // Ignoring error so far
+			},
+			VirtioDevice::Rng => {}
+		};
+		self.get_mut_virtio_transport(&device).complete_access();
+	}
+
+	// Returns (address, length, flags) of the descriptors chained from head.
+	// The chain is followed at most queue size times so that a looped chain
+	// can't hang the host.
+	fn read_descriptor_chain(&mut self, desc_address: u64, head: u64, queue_size: u64) -> Vec<(u64, u64, u16)> {
+		let mut descs = vec![];
+		let mut index = head;
+		for _i in 0..queue_size {
+			let address = desc_address.wrapping_add((index % queue_size) * 16);
+			let addr = self.load_doubleword_raw(address).unwrap_or(0);
+			let len = self.load_word_raw(address.wrapping_add(8)).unwrap_or(0);
+			let flags = self.load_halfword_raw(address.wrapping_add(12)).unwrap_or(0);
+			let next = self.load_halfword_raw(address.wrapping_add(14)).unwrap_or(0);
+			descs.push((addr, len as u64, flags));
+			if (flags & VIRTQ_DESC_F_NEXT) == 0 {
+				break;
+			}
+			index = next as u64;
+		}
+		descs
+	}
+
+	// Handles a disk request of the descriptor chain, which consists of
+	// a header, data, and a status descriptors. Returns the number of bytes
+	// written to the guest memory.
+	fn handle_disk_request(&mut self, descs: &[(u64, u64, u16)]) -> u32 {
+		if descs.len() < 2 {
+			return 0;
+		}
+
+		let header_address = descs[0].0;
+		let blk_type = self.load_word_raw(header_address).unwrap_or(0);
+		let sector = self.load_doubleword_raw(header_address.wrapping_add(8)).unwrap_or(0);
+		let data = descs[1..descs.len() - 1].to_vec();
+		let data_length = data.iter().fold(0, |sum, desc| sum + desc.1);
+
+		let disk_size = self.disk.get_capacity() * SECTOR_SIZE;
+		let in_range = match sector.checked_mul(SECTOR_SIZE) {
+			Some(address) => address <= disk_size && data_length <= disk_size - address,
+			None => false
+		};
+
+		let mut written_length = 0;
+		let status = match blk_type {
+			VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT if !in_range => VIRTIO_BLK_S_IOERR,
+			VIRTIO_BLK_T_IN => {
+				let mut disk_address = sector * SECTOR_SIZE;
+				for &(address, length, _flags) in data.iter() {
+					for i in 0..length {
+						let value = self.disk.read_from_disk(disk_address);
+						match self.store_raw(address.wrapping_add(i), value) {
+							_ => {} // Ignoring error so far
+						};
+						disk_address += 1;
+					}
+				}
+				written_length = data_length;
+				VIRTIO_BLK_S_OK
+			},
+			VIRTIO_BLK_T_OUT => {
+				let mut disk_address = sector * SECTOR_SIZE;
+				for &(address, length, _flags) in data.iter() {
+					for i in 0..length {
+						let value = self.load_raw(address.wrapping_add(i)).unwrap_or(0);
+						self.disk.write_to_disk(disk_address, value);
+						disk_address += 1;
+					}
 				}
-				// println!();
-			},
-			false => { // read from disk
-				// println!("Read from disk");
-				for i in 0..len1 as u64 {
-					let data = self.disk.read_from_disk(blk_sector * 512 + i);
-					self.store_raw(addr1 + i, data);
-					// print!("{:02X} ", data);
+				VIRTIO_BLK_S_OK
+			},
+			VIRTIO_BLK_T_FLUSH => match self.disk.flush() {
+				Ok(()) => VIRTIO_BLK_S_OK,
+				Err(_) => VIRTIO_BLK_S_IOERR
+			},
+			VIRTIO_BLK_T_GET_ID => {
+				// Serial number string, zero padded to 20 bytes
+				let id = b"riscv-rust";
+				if !data.is_empty() {
+					let (address, length, _flags) = data[0];
+					written_length = match length < 20 {
+						true => length,
+						false => 20
+					};
+					for i in 0..written_length {
+						let value = match (i as usize) < id.len() {
+							true => id[i as usize],
+							false => 0
+						};
+						match self.store_raw(address.wrapping_add(i), value) {
+							_ => {} // Ignoring error so far
+						};
+					}
 				}
-				// println!();
-			}
+				VIRTIO_BLK_S_OK
+			},
+			_ => VIRTIO_BLK_S_UNSUPP
 		};
-		
-		let new_id = self.disk.get_new_id() as u16;
-		self.store_halfword_raw(base_used_address.wrapping_add(2), new_id % 8);
+
+		let (status_address, status_length, status_flags) = descs[descs.len() - 1];
+		if status_length > 0 && (status_flags & VIRTQ_DESC_F_WRITE) != 0 {
+			match self.store_raw(status_address, status) {
+				_ => {} // Ignoring error so far
+			};
+			written_length += 1;
+		}
+		written_length as u32
+	}
+
+	// Fills the device writable buffers of the descriptor chain with host
+	// entropy. Returns the number of bytes written to the guest memory.
+	fn handle_rng_request(&mut self, descs: &[(u64, u64, u16)]) -> u32 {
+		let mut written_length = 0;
+		for &(address, length, flags) in descs.iter() {
+			if (flags & VIRTQ_DESC_F_WRITE) == 0 {
+				continue;
+			}
+			// The length is up to the guest so the buffer is filled in chunks
+			let mut offset = 0;
+			while offset < length {
+				let mut buffer = [0; 256];
+				let chunk_length = std::cmp::min(length - offset, buffer.len() as u64);
+				self.rng.fill_bytes(&mut buffer[..chunk_length as usize]);
+				for i in 0..chunk_length {
+					match self.store_raw(address.wrapping_add(offset + i), buffer[i as usize]) {
+						_ => {} // Ignoring error so far
+					};
+				}
+				offset += chunk_length;
+			}
+			written_length += length;
+		}
+		written_length as u32
 	}
 
 	//
 
-	pub fn is_disk_interrupting(&mut self) -> bool {
-		self.disk.is_interrupting()
+	pub fn read_mtime(&self) -> u64 {
+		self.clint.read_mtime()
+	}
+
+	pub fn get_timebase_frequency(&self) -> u64 {
+		self.clint.get_timebase_frequency()
 	}
 
-	pub fn reset_disk_interrupting(&mut self) {
-		self.disk.reset_interrupting();
+	// CLINT interrupts are raised to the running hart
+
+	pub fn is_clint_software_interrupting(&self) -> bool {
+		self.clint.is_software_interrupting(self.hart_id)
 	}
 
 	pub fn is_clint_interrupting(&self) -> bool {
-		self.clint.is_interrupting()
+		self.clint.is_interrupting(self.hart_id)
 	}
 
-	pub fn reset_clint_interrupting(&mut self) {
-		self.clint.reset_interrupting();
+	// For firmware emulated by host. Updates mtimecmp of the running hart
+	// and takes input from UART.
+
+	pub fn update_mtimecmp(&mut self, value: u64) {
+		self.clint.update_mtimecmp(self.hart_id, value);
 	}
 
-	pub fn is_uart_interrupting(&mut self) -> bool {
-		self.uart.is_interrupting()
+	pub fn receive_uart_input(&mut self) -> Option<u8> {
+		self.uart.receive()
 	}
 
-	pub fn reset_uart_interrupting(&mut self) {
-		self.uart.reset_interrupting();
+	pub fn preload_uart_input(&mut self, data: &[u8]) {
+		self.uart.preload_input(data);
 	}
 
-	pub fn update_plic(&mut self, interrupt_type: &InterruptType) {
-		self.plic.update(interrupt_type);
+	pub fn get_uart_divisor(&self) -> u16 {
+		self.uart.get_divisor()
 	}
 
 	// Wasm specific
@@ -632,4 +1705,365 @@ impl Mmu {
 	pub fn put_uart_input(&mut self, data: u8) {
 		self.uart.put_input(data);
 	}
-}
\ No newline at end of file
+
+	pub fn get_uart_output_bytes(&mut self) -> Vec<u8> {
+		self.uart.get_output_bytes()
+	}
+
+	pub fn put_uart_input_bytes(&mut self, data: &[u8]) {
+		self.uart.put_input_bytes(data);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use clock_source::ManualClock;
+	use writer_terminal::WriterTerminal;
+
+	const DRAM_BASE: u64 = 0x80000000;
+	// Page tables, a page per level, from the root
+	const PAGE_TABLE_ADDRESS: u64 = DRAM_BASE + 0x10000;
+	const DATA_ADDRESS: u64 = DRAM_BASE + 0x20000;
+	const V_ADDRESS: u64 = 0x40201000;
+
+	const PTE_V: u64 = 1 << 0;
+	const PTE_R: u64 = 1 << 1;
+	const PTE_W: u64 = 1 << 2;
+	const PTE_X: u64 = 1 << 3;
+	const PTE_U: u64 = 1 << 4;
+	const PTE_A: u64 = 1 << 6;
+	const PTE_D: u64 = 1 << 7;
+
+	// Mmu translating supervisor mode accesses with the page tables
+	fn create_mmu(addressing_mode: AddressingMode) -> Mmu {
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(WriterTerminal::new(std::io::sink())),
+			TimerMode::Deterministic, Box::new(ManualClock::new()));
+		mmu.init_memory(0x100000);
+		mmu.update_addressing_mode(addressing_mode);
+		mmu.update_ppn(PAGE_TABLE_ADDRESS >> 12);
+		mmu.update_privilege_mode(PrivilegeMode::Supervisor);
+		mmu.update_data_privilege_mode(PrivilegeMode::Supervisor);
+		mmu
+	}
+
+	fn get_levels(mmu: &Mmu) -> u64 {
+		match mmu.addressing_mode {
+			AddressingMode::SV48 => 4,
+			_ => 3
+		}
+	}
+
+	// Maps the virtual address with a leaf entry at the level. Every
+	// level has a single table so the mapped addresses in a test share
+	// the upper virtual page numbers. Returns the leaf entry address.
+	fn map(mmu: &mut Mmu, v_address: u64, p_address: u64, flags: u64, leaf_level: u64) -> u64 {
+		let levels = get_levels(mmu);
+		let mut level = levels - 1;
+		loop {
+			let table = PAGE_TABLE_ADDRESS + (levels - 1 - level) * 0x1000;
+			let pte_address = table + ((v_address >> (12 + level * 9)) & 0x1ff) * 8;
+			if level == leaf_level {
+				mmu.store_doubleword_raw(pte_address, ((p_address >> 12) << 10) | flags).unwrap();
+				return pte_address;
+			}
+			mmu.store_doubleword_raw(pte_address, (((table + 0x1000) >> 12) << 10) | PTE_V).unwrap();
+			level -= 1;
+		}
+	}
+
+	fn load_page_fault(mmu: &mut Mmu, v_address: u64) -> Option<u64> {
+		match mmu.load_doubleword(v_address) {
+			Err(Trap { trap_type: TrapType::LoadPageFault, value }) => Some(value),
+			_ => None
+		}
+	}
+
+	fn store_page_fault(mmu: &mut Mmu, v_address: u64, value: u64) -> Option<u64> {
+		match mmu.store_doubleword(v_address, value) {
+			Err(Trap { trap_type: TrapType::StorePageFault, value }) => Some(value),
+			_ => None
+		}
+	}
+
+	fn fetch_page_fault(mmu: &mut Mmu, v_address: u64) -> Option<u64> {
+		match mmu.fetch_instruction(v_address) {
+			Err(Trap { trap_type: TrapType::InstructionPageFault, value }) => Some(value),
+			_ => None
+		}
+	}
+
+	#[test]
+	fn accessed_dirty_update_policy_sets_bits() {
+		let mut mmu = create_mmu(AddressingMode::SV39);
+		let pte_address = map(&mut mmu, V_ADDRESS, DATA_ADDRESS, PTE_V | PTE_R | PTE_W, 0);
+		mmu.store_doubleword_raw(DATA_ADDRESS, 0x1234).unwrap();
+		assert_eq!(mmu.load_doubleword(V_ADDRESS).ok(), Some(0x1234));
+		assert_eq!(mmu.load_doubleword_raw(pte_address).unwrap() & (PTE_A | PTE_D), PTE_A);
+		assert!(mmu.store_doubleword(V_ADDRESS, 0x5678).is_ok());
+		assert_eq!(mmu.load_doubleword_raw(pte_address).unwrap() & (PTE_A | PTE_D), PTE_A | PTE_D);
+		assert_eq!(mmu.load_doubleword_raw(DATA_ADDRESS).unwrap(), 0x5678);
+	}
+
+	#[test]
+	fn accessed_dirty_fault_policy_faults() {
+		let mut mmu = create_mmu(AddressingMode::SV39);
+		mmu.update_accessed_dirty_policy(AccessedDirtyPolicy::Fault);
+		let pte_address = map(&mut mmu, V_ADDRESS, DATA_ADDRESS, PTE_V | PTE_R | PTE_W, 0);
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+		assert_eq!(store_page_fault(&mut mmu, V_ADDRESS + 8, 1), Some(V_ADDRESS + 8));
+		// Software sets A, and then a store to the clean page still faults
+		mmu.store_doubleword_raw(pte_address, ((DATA_ADDRESS >> 12) << 10) | PTE_V | PTE_R | PTE_W | PTE_A).unwrap();
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), None);
+		assert_eq!(store_page_fault(&mut mmu, V_ADDRESS + 8, 1), Some(V_ADDRESS + 8));
+		mmu.store_doubleword_raw(pte_address, ((DATA_ADDRESS >> 12) << 10) | PTE_V | PTE_R | PTE_W | PTE_A | PTE_D).unwrap();
+		assert_eq!(store_page_fault(&mut mmu, V_ADDRESS + 8, 1), None);
+		assert_eq!(mmu.load_doubleword_raw(DATA_ADDRESS + 8).unwrap(), 1);
+		// Entries are never written back
+		assert_eq!(mmu.load_doubleword_raw(pte_address).unwrap() & 0xff, PTE_V | PTE_R | PTE_W | PTE_A | PTE_D);
+	}
+
+	#[test]
+	fn reserved_bits_fault() {
+		for bit in &[54, 60, 61, 62, 63] {
+			let mut mmu = create_mmu(AddressingMode::SV39);
+			map(&mut mmu, V_ADDRESS, DATA_ADDRESS, PTE_V | PTE_R | PTE_W | PTE_A | PTE_D | (1 << bit), 0);
+			assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+			assert_eq!(store_page_fault(&mut mmu, V_ADDRESS, 1), Some(V_ADDRESS));
+		}
+		// Also in a non-leaf entry
+		let mut mmu = create_mmu(AddressingMode::SV39);
+		map(&mut mmu, V_ADDRESS, DATA_ADDRESS, PTE_V | PTE_R | PTE_A, 0);
+		let pte = mmu.load_doubleword_raw(PAGE_TABLE_ADDRESS + 8).unwrap();
+		mmu.store_doubleword_raw(PAGE_TABLE_ADDRESS + 8, pte | (1 << 63)).unwrap();
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+	}
+
+	#[test]
+	fn superpage() {
+		// 2MiB page at level 1 maps the lower 21 bits as they are
+		let mut mmu = create_mmu(AddressingMode::SV39);
+		map(&mut mmu, V_ADDRESS, DRAM_BASE, PTE_V | PTE_R | PTE_A, 1);
+		mmu.store_doubleword_raw(DRAM_BASE + 0x1008, 0x1234).unwrap();
+		assert_eq!(mmu.load_doubleword(V_ADDRESS + 8).ok(), Some(0x1234));
+	}
+
+	#[test]
+	fn misaligned_superpage_faults() {
+		let mut mmu = create_mmu(AddressingMode::SV39);
+		map(&mut mmu, V_ADDRESS, DRAM_BASE + 0x1000, PTE_V | PTE_R | PTE_W | PTE_A | PTE_D, 1);
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+		assert_eq!(store_page_fault(&mut mmu, V_ADDRESS, 1), Some(V_ADDRESS));
+	}
+
+	#[test]
+	fn supervisor_accesses_user_page_only_with_sum() {
+		let mut mmu = create_mmu(AddressingMode::SV39);
+		map(&mut mmu, V_ADDRESS, DATA_ADDRESS, PTE_V | PTE_R | PTE_W | PTE_X | PTE_U | PTE_A | PTE_D, 0);
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+		assert_eq!(store_page_fault(&mut mmu, V_ADDRESS, 1), Some(V_ADDRESS));
+		assert_eq!(fetch_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+		mmu.update_sum_and_mxr(true, false);
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), None);
+		assert_eq!(store_page_fault(&mut mmu, V_ADDRESS, 1), None);
+		// Supervisor mode never executes user pages
+		assert_eq!(fetch_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+		mmu.update_sum_and_mxr(false, false);
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+	}
+
+	#[test]
+	fn user_accesses_only_user_page() {
+		let mut mmu = create_mmu(AddressingMode::SV39);
+		mmu.update_privilege_mode(PrivilegeMode::User);
+		mmu.update_data_privilege_mode(PrivilegeMode::User);
+		map(&mut mmu, V_ADDRESS, DATA_ADDRESS, PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D, 0);
+		map(&mut mmu, V_ADDRESS + 0x1000, DATA_ADDRESS, PTE_V | PTE_R | PTE_W | PTE_X | PTE_U | PTE_A | PTE_D, 0);
+		// SUM doesn't matter to user mode
+		mmu.update_sum_and_mxr(true, false);
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+		assert_eq!(store_page_fault(&mut mmu, V_ADDRESS, 1), Some(V_ADDRESS));
+		assert_eq!(fetch_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS + 0x1000), None);
+		assert_eq!(store_page_fault(&mut mmu, V_ADDRESS + 0x1000, 1), None);
+		assert_eq!(fetch_page_fault(&mut mmu, V_ADDRESS + 0x1000), None);
+	}
+
+	#[test]
+	fn mxr_makes_execute_only_page_readable() {
+		let mut mmu = create_mmu(AddressingMode::SV39);
+		map(&mut mmu, V_ADDRESS, DATA_ADDRESS, PTE_V | PTE_X | PTE_A, 0);
+		assert_eq!(fetch_page_fault(&mut mmu, V_ADDRESS), None);
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+		mmu.update_sum_and_mxr(false, true);
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), None);
+		// Still not writable
+		assert_eq!(store_page_fault(&mut mmu, V_ADDRESS, 1), Some(V_ADDRESS));
+		mmu.update_sum_and_mxr(false, false);
+		assert_eq!(load_page_fault(&mut mmu, V_ADDRESS), Some(V_ADDRESS));
+	}
+
+	#[test]
+	fn sv48_walk() {
+		// Walks four levels, VPN[3] is 0x24
+		let v_address = 0x123456789000;
+		let mut mmu = create_mmu(AddressingMode::SV48);
+		map(&mut mmu, v_address, DATA_ADDRESS, PTE_V | PTE_R | PTE_W | PTE_A | PTE_D, 0);
+		mmu.store_doubleword_raw(DATA_ADDRESS + 0x10, 0x1234).unwrap();
+		assert_eq!(mmu.load_doubleword(v_address + 0x10).ok(), Some(0x1234));
+		assert!(mmu.store_doubleword(v_address + 0x18, 0x5678).is_ok());
+		assert_eq!(mmu.load_doubleword_raw(DATA_ADDRESS + 0x18).unwrap(), 0x5678);
+		assert_eq!(load_page_fault(&mut mmu, v_address + 0x1000), Some(v_address + 0x1000));
+		// Walking the same tables in three levels doesn't reach the page
+		mmu.update_addressing_mode(AddressingMode::SV39);
+		assert_eq!(load_page_fault(&mut mmu, v_address + 0x10), Some(v_address + 0x10));
+	}
+
+	#[test]
+	fn sv48_superpages() {
+		// 1GiB page at level 2
+		let v_address = 0x123440000000;
+		let mut mmu = create_mmu(AddressingMode::SV48);
+		map(&mut mmu, v_address, 0x80000000, PTE_V | PTE_R | PTE_A, 2);
+		mmu.store_doubleword_raw(DRAM_BASE + 0x20010, 0x1234).unwrap();
+		assert_eq!(mmu.load_doubleword(v_address + 0x20010).ok(), Some(0x1234));
+
+		// Misaligned 512GiB page at level 3
+		let mut mmu = create_mmu(AddressingMode::SV48);
+		map(&mut mmu, v_address, DRAM_BASE, PTE_V | PTE_R | PTE_A, 3);
+		assert_eq!(load_page_fault(&mut mmu, v_address), Some(v_address));
+	}
+
+	#[test]
+	fn store_after_cached_load_sets_dirty_bit() {
+		let mut mmu = create_mmu(AddressingMode::SV39);
+		mmu.get_mut_stats().update_enabled(true);
+		let pte_address = map(&mut mmu, V_ADDRESS, DATA_ADDRESS, PTE_V | PTE_R | PTE_W, 0);
+		assert!(mmu.load_doubleword(V_ADDRESS).is_ok());
+		assert!(mmu.load_doubleword(V_ADDRESS).is_ok());
+		assert_eq!(mmu.get_stats().get_tlb_hits(), 1);
+		// The translation for loads isn't used for the first store
+		assert!(mmu.store_doubleword(V_ADDRESS, 1).is_ok());
+		assert_eq!(mmu.get_stats().get_tlb_misses(), 2);
+		assert_eq!(mmu.load_doubleword_raw(pte_address).unwrap() & (PTE_A | PTE_D), PTE_A | PTE_D);
+	}
+
+	// Legacy virtqueue of size 8 in a page at QUEUE_ADDRESS. The available
+	// ring follows the descriptor table and used ring is on the next page.
+	const QUEUE_ADDRESS: u64 = DRAM_BASE + 0x40000;
+	const REQUEST_ADDRESS: u64 = DRAM_BASE + 0x50000;
+
+	fn store_virtio_register(mmu: &mut Mmu, offset: u64, value: u32) {
+		let address = mmu.memory_map.virtio.base + offset;
+		mmu.store_word_raw(address, value).unwrap();
+	}
+
+	fn store_descriptor(mmu: &mut Mmu, index: u64, address: u64, length: u32, flags: u16, next: u16) {
+		let desc_address = QUEUE_ADDRESS + index * 16;
+		mmu.store_doubleword_raw(desc_address, address).unwrap();
+		mmu.store_word_raw(desc_address + 8, length).unwrap();
+		mmu.store_halfword_raw(desc_address + 12, flags).unwrap();
+		mmu.store_halfword_raw(desc_address + 14, next).unwrap();
+	}
+
+	// Descriptors 0 to 2 chain the header reading sector 1, data buffer,
+	// and status byte initialized to 0xff
+	fn store_read_request(mmu: &mut Mmu) {
+		mmu.store_word_raw(REQUEST_ADDRESS, VIRTIO_BLK_T_IN).unwrap();
+		mmu.store_doubleword_raw(REQUEST_ADDRESS + 8, 1).unwrap();
+		mmu.store_raw(REQUEST_ADDRESS + 0x300, 0xff).unwrap();
+		store_descriptor(mmu, 0, REQUEST_ADDRESS, 16, VIRTQ_DESC_F_NEXT, 1);
+		store_descriptor(mmu, 1, REQUEST_ADDRESS + 0x100, SECTOR_SIZE as u32,
+			VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 2);
+		store_descriptor(mmu, 2, REQUEST_ADDRESS + 0x300, 1, VIRTQ_DESC_F_WRITE, 0);
+	}
+
+	#[test]
+	fn disk_read_request_status() {
+		let mut mmu = create_mmu(AddressingMode::None);
+		let mut contents = vec![0; SECTOR_SIZE as usize * 2];
+		for i in 0..SECTOR_SIZE as usize {
+			contents[SECTOR_SIZE as usize + i] = (i % 251) as u8;
+		}
+		mmu.init_disk(contents);
+		// Notification at clock 0 isn't distinguished from no notification
+		mmu.tick();
+		store_virtio_register(&mut mmu, 0x028, 4096); // GuestPageSize
+		store_virtio_register(&mut mmu, 0x038, 8); // QueueNum
+		store_virtio_register(&mut mmu, 0x040, (QUEUE_ADDRESS >> 12) as u32); // QueuePFN
+
+		store_read_request(&mut mmu);
+		let avail_address = QUEUE_ADDRESS + 8 * 16;
+		mmu.store_halfword_raw(avail_address + 4, 0).unwrap();
+		mmu.store_halfword_raw(avail_address + 2, 1).unwrap();
+		store_virtio_register(&mut mmu, 0x050, 0); // QueueNotify
+		for _ in 0..1000 {
+			mmu.tick();
+		}
+
+		assert_eq!(mmu.load_raw(REQUEST_ADDRESS + 0x300).unwrap(), 0);
+		for i in 0..SECTOR_SIZE {
+			assert_eq!(mmu.load_raw(REQUEST_ADDRESS + 0x100 + i).unwrap(), (i % 251) as u8);
+		}
+		let used_address = QUEUE_ADDRESS + 0x1000;
+		assert_eq!(mmu.load_halfword_raw(used_address + 2).unwrap(), 1);
+		assert_eq!(mmu.load_word_raw(used_address + 4).unwrap(), 0);
+		assert_eq!(mmu.load_word_raw(used_address + 8).unwrap(), SECTOR_SIZE as u32 + 1);
+		assert_eq!(mmu.load_word_raw(mmu.memory_map.virtio.base + 0x060).unwrap(), 1);
+	}
+
+	#[test]
+	fn modern_queue_size_is_honored_in_used_ring() {
+		let mut mmu = create_mmu(AddressingMode::None);
+		mmu.init_disk(vec![0; SECTOR_SIZE as usize * 2]);
+		mmu.update_disk_legacy(false);
+		mmu.tick();
+		let avail_address = QUEUE_ADDRESS + 0x400;
+		let used_address = QUEUE_ADDRESS + 0x800;
+		store_virtio_register(&mut mmu, 0x038, 16); // QueueNum
+		store_virtio_register(&mut mmu, 0x080, QUEUE_ADDRESS as u32); // QueueDescLow
+		store_virtio_register(&mut mmu, 0x090, avail_address as u32); // QueueDriverLow
+		store_virtio_register(&mut mmu, 0x0a0, used_address as u32); // QueueDeviceLow
+		store_virtio_register(&mut mmu, 0x044, 1); // QueueReady
+		store_read_request(&mut mmu);
+		// Ten requests would wrap around the rings of size 8
+		for i in 0..10 {
+			mmu.store_halfword_raw(avail_address + 4 + i * 2, 0).unwrap();
+		}
+		mmu.store_halfword_raw(avail_address + 2, 10).unwrap();
+		store_virtio_register(&mut mmu, 0x050, 0); // QueueNotify
+		for _ in 0..1000 {
+			mmu.tick();
+		}
+
+		assert_eq!(mmu.load_halfword_raw(used_address + 2).unwrap(), 10);
+		for i in 0..16 {
+			assert_eq!(mmu.load_word_raw(used_address + 8 + i * 8).unwrap(), match i < 10 {
+				true => SECTOR_SIZE as u32 + 1,
+				false => 0
+			});
+		}
+	}
+
+	#[test]
+	fn invalid_queue_size_is_not_processed() {
+		for &queue_num in [0, 12, 256].iter() {
+			let mut mmu = create_mmu(AddressingMode::None);
+			mmu.init_disk(vec![0; SECTOR_SIZE as usize * 2]);
+			mmu.tick();
+			store_virtio_register(&mut mmu, 0x028, 4096); // GuestPageSize
+			store_virtio_register(&mut mmu, 0x038, queue_num); // QueueNum
+			store_virtio_register(&mut mmu, 0x040, (QUEUE_ADDRESS >> 12) as u32); // QueuePFN
+			store_read_request(&mut mmu);
+			let avail_address = QUEUE_ADDRESS + queue_num as u64 * 16;
+			mmu.store_halfword_raw(avail_address + 4, 0).unwrap();
+			mmu.store_halfword_raw(avail_address + 2, 1).unwrap();
+			store_virtio_register(&mut mmu, 0x050, 0); // QueueNotify
+			for _ in 0..1000 {
+				mmu.tick();
+			}
+			assert_eq!(mmu.load_raw(REQUEST_ADDRESS + 0x300).unwrap(), 0xff);
+			assert_eq!(mmu.disk.get_transport().get_used_index(), 0);
+		}
+	}
+}
@@ -1,57 +1,348 @@
-pub enum InterruptType {
-	None,
-	KeyInput,
-	Timer,
-	Virtio
-}
+// Platform-Level Interrupt Controller. Any of interrupt sources
+// 1..NUM_SOURCES can be attached. Source 0 is reserved by the spec.
+// Each hart has two contexts, machine mode (2 * hart) and supervisor
+// mode (2 * hart + 1), the same as QEMU virt machine.
+pub const NUM_SOURCES: usize = 1024;
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Plic {
 	clock: u64,
-	irq: u32,
-	enabled: bool
+	priorities: Vec<u32>,
+	// Bitsets of sources, 32 sources per word
+	pending: Vec<u32>,
+	// Whether any bit of pending is set, to skip scanning them every tick
+	has_pending: bool,
+	// Bitsets of sources raised by host. They are level-triggered and
+	// become pending again after completion until lowered.
+	raised: Vec<u32>,
+	// Claimed and not completed yet. The gateway doesn't forward
+	// the source's interrupt again until completion.
+	in_service: Vec<u32>,
+	// Per context
+	enables: Vec<Vec<u32>>,
+	thresholds: Vec<u32>,
+	// Registers are accessed byte by byte so the claimed source is latched
+	// when the lowest byte of the claim register is read, and completion
+	// takes effect when the highest byte is written
+	claims: Vec<u32>,
+	completions: Vec<u32>,
+	// Claimed but not handed to the device yet. See take_claimed_irq().
+	claimed_irq: Option<u32>
 }
 
 impl Plic {
 	pub fn new() -> Self {
-		Plic {
+		let mut plic = Plic {
 			clock: 0,
-			irq: 0,
-			enabled: false
-		}
+			priorities: vec![0; NUM_SOURCES],
+			pending: vec![0; NUM_SOURCES / 32],
+			has_pending: false,
+			raised: vec![0; NUM_SOURCES / 32],
+			in_service: vec![0; NUM_SOURCES / 32],
+			enables: vec![],
+			thresholds: vec![],
+			claims: vec![],
+			completions: vec![],
+			claimed_irq: None
+		};
+		plic.init_harts(1);
+		plic
+	}
+
+	pub fn init_harts(&mut self, num: usize) {
+		self.enables.resize(num * 2, vec![0; NUM_SOURCES / 32]);
+		self.thresholds.resize(num * 2, 0);
+		self.claims.resize(num * 2, 0);
+		self.completions.resize(num * 2, 0);
 	}
 
 	pub fn tick(&mut self) {
 		self.clock = self.clock.wrapping_add(1);
 	}
 
-	pub fn update(&mut self, interrupt_type: &InterruptType) {
-		match interrupt_type {
-			InterruptType::Virtio => {
-				self.irq = 1;
+	// Takes (irq, is_interrupting) pairs of the attached devices.
+	// Pending bit is kept until the source is claimed.
+	pub fn detect_interrupt(&mut self, sources: &[(u32, bool)]) {
+		for &(irq, interrupting) in sources.iter() {
+			if interrupting && irq != 0 && (irq as usize) < NUM_SOURCES {
+				let (index, bit) = ((irq / 32) as usize, 1 << (irq % 32));
+				if (self.in_service[index] & bit) == 0 {
+					self.pending[index] |= bit;
+					self.has_pending = true;
+				}
+			}
+		}
+		for (i, raised) in self.raised.iter().enumerate() {
+			let bits = raised & !self.in_service[i];
+			if bits != 0 {
+				self.pending[i] |= bits;
+				self.has_pending = true;
+			}
+		}
+	}
+
+	// Raises the interrupt line of the source on behalf of host. Sources
+	// out of range and the reserved source 0 are ignored.
+	pub fn raise_irq(&mut self, irq: u32) {
+		if irq != 0 && (irq as usize) < NUM_SOURCES {
+			self.raised[(irq / 32) as usize] |= 1 << (irq % 32);
+		}
+	}
+
+	// Lowers the line raised by raise_irq(). The pending bit is kept until
+	// the source is claimed, the same as built-in devices.
+	pub fn lower_irq(&mut self, irq: u32) {
+		if (irq as usize) < NUM_SOURCES {
+			self.raised[(irq / 32) as usize] &= !(1 << (irq % 32));
+		}
+	}
+
+	// Whether any source is pending, whether or not it's enabled
+	pub fn has_pending(&self) -> bool {
+		self.has_pending
+	}
+
+	pub fn is_interrupting(&self, context: usize) -> bool {
+		self.has_pending && self.get_claimable_irq(context) != 0
+	}
+
+	// Returns the pending source enabled for the context with the highest
+	// priority above threshold. The lowest id wins among the same priority.
+	// Returns 0 if no such source.
+	fn get_claimable_irq(&self, context: usize) -> u32 {
+		let enables = &self.enables[context];
+		let mut irq = 0;
+		let mut priority = self.thresholds[context];
+		for (i, pending) in self.pending.iter().enumerate() {
+			let bits = pending & enables[i];
+			if bits == 0 {
+				continue;
 			}
-			InterruptType::KeyInput => {
-				self.irq = 10;
+			for j in 0..32 {
+				let source = i * 32 + j;
+				if (bits & (1 << j)) != 0 && self.priorities[source] > priority {
+					irq = source as u32;
+					priority = self.priorities[source];
+				}
 			}
-			InterruptType::None |
-			InterruptType::Timer => {
-				self.irq = 0;
+		}
+		irq
+	}
+
+	fn claim(&mut self, context: usize) -> u32 {
+		let irq = self.get_claimable_irq(context);
+		if irq != 0 {
+			let (index, bit) = ((irq / 32) as usize, 1 << (irq % 32));
+			self.pending[index] &= !bit;
+			self.has_pending = self.pending.iter().any(|bits| *bits != 0);
+			self.in_service[index] |= bit;
+			self.claimed_irq = Some(irq);
+		}
+		irq
+	}
+
+	fn complete(&mut self, context: usize, irq: u32) {
+		// Completion of a source not enabled for the context is ignored
+		if irq != 0 && (irq as usize) < NUM_SOURCES {
+			let (index, bit) = ((irq / 32) as usize, 1 << (irq % 32));
+			if (self.enables[context][index] & bit) != 0 {
+				self.in_service[index] &= !bit;
 			}
 		}
 	}
 
-	pub fn store(&mut self, address: u64, _value: u8) {
-		match address {
-			0x0c002080 => { // PLIC_SENABLE(hart) (PLIC + 0x2080 + (hart)*0x100)
-				self.enabled = true;
+	// Returns the source claimed since the last call, for the devices
+	// to stop interrupting
+	pub fn take_claimed_irq(&mut self) -> Option<u32> {
+		self.claimed_irq.take()
+	}
+
+	pub fn load(&mut self, offset: u64) -> u8 {
+		let shift = (offset % 4) * 8;
+		let value = match offset {
+			0x000000..=0x000fff => self.priorities[(offset / 4) as usize],
+			0x001000..=0x00107f => self.pending[((offset - 0x1000) / 4) as usize],
+			0x002000..=0x1fffff => {
+				let context = ((offset - 0x2000) / 0x80) as usize;
+				match context < self.enables.len() {
+					true => self.enables[context][((offset % 0x80) / 4) as usize],
+					false => 0
+				}
+			},
+			0x200000..=0x3fffffff => {
+				let context = ((offset - 0x200000) / 0x1000) as usize;
+				match context < self.thresholds.len() {
+					true => match offset % 0x1000 {
+						0x0..=0x3 => self.thresholds[context],
+						0x4..=0x7 => {
+							if shift == 0 {
+								self.claims[context] = self.claim(context);
+							}
+							self.claims[context]
+						},
+						_ => 0
+					},
+					false => 0
+				}
+			},
+			_ => 0
+		};
+		(value >> shift) as u8
+	}
+
+	pub fn store(&mut self, offset: u64, value: u8) {
+		match offset {
+			// Priority of source 0 is reserved
+			0x000004..=0x000fff => {
+				let index = (offset / 4) as usize;
+				self.priorities[index] = write_byte(self.priorities[index], offset, value);
+			},
+			0x002000..=0x1fffff => {
+				let context = ((offset - 0x2000) / 0x80) as usize;
+				if context < self.enables.len() {
+					let index = ((offset % 0x80) / 4) as usize;
+					self.enables[context][index] = write_byte(self.enables[context][index], offset, value) & match index {
+						0 => !1, // Source 0 is reserved
+						_ => !0
+					};
+				}
+			},
+			0x200000..=0x3fffffff => {
+				let context = ((offset - 0x200000) / 0x1000) as usize;
+				if context < self.thresholds.len() {
+					match offset % 0x1000 {
+						0x0..=0x3 => {
+							self.thresholds[context] = write_byte(self.thresholds[context], offset, value);
+						},
+						0x4..=0x7 => {
+							self.completions[context] = write_byte(self.completions[context], offset, value);
+							if (offset % 4) == 3 {
+								let irq = self.completions[context];
+								self.complete(context, irq);
+							}
+						},
+						_ => {}
+					};
+				}
 			},
 			_ => {}
 		};
 	}
+}
 
-	pub fn load(&self, address: u64) -> u32 {
-		match address {
-			0x0c201004 => self.irq, // PLIC_SCLAIM(hart) (PLIC + 0x201004 + (hart)*0x2000)
-			_ => 0
+fn write_byte(register: u32, offset: u64, value: u8) -> u32 {
+	let shift = (offset % 4) * 8;
+	(register & !(0xff << shift)) | ((value as u32) << shift)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Supervisor mode context of hart 0
+	const CONTEXT: u64 = 1;
+
+	fn store_word(plic: &mut Plic, offset: u64, value: u32) {
+		for i in 0..4 {
+			plic.store(offset + i, (value >> (i * 8)) as u8);
+		}
+	}
+
+	fn load_word(plic: &mut Plic, offset: u64) -> u32 {
+		let mut value = 0;
+		for i in 0..4 {
+			value |= (plic.load(offset + i) as u32) << (i * 8);
 		}
+		value
+	}
+
+	fn update_priority(plic: &mut Plic, irq: u32, priority: u32) {
+		store_word(plic, irq as u64 * 4, priority);
+	}
+
+	fn enable(plic: &mut Plic, irq: u32) {
+		let offset = 0x2000 + CONTEXT * 0x80 + (irq as u64 / 32) * 4;
+		let enables = load_word(plic, offset);
+		store_word(plic, offset, enables | (1 << (irq % 32)));
+	}
+
+	fn claim(plic: &mut Plic) -> u32 {
+		load_word(plic, 0x200004 + CONTEXT * 0x1000)
+	}
+
+	fn complete(plic: &mut Plic, irq: u32) {
+		store_word(plic, 0x200004 + CONTEXT * 0x1000, irq);
+	}
+
+	#[test]
+	fn highest_priority_wins() {
+		let mut plic = Plic::new();
+		for &(irq, priority) in [(3, 2), (7, 5), (40, 5), (100, 1)].iter() {
+			update_priority(&mut plic, irq, priority);
+			enable(&mut plic, irq);
+		}
+		plic.detect_interrupt(&[(3, true), (7, true), (40, true), (100, true)]);
+		assert!(plic.is_interrupting(CONTEXT as usize));
+		// The lowest id wins among the same priority
+		assert_eq!(claim(&mut plic), 7);
+		assert_eq!(plic.take_claimed_irq(), Some(7));
+		assert_eq!(claim(&mut plic), 40);
+		assert_eq!(claim(&mut plic), 3);
+		assert_eq!(claim(&mut plic), 100);
+		assert_eq!(claim(&mut plic), 0);
+		assert!(!plic.has_pending());
+	}
+
+	#[test]
+	fn disabled_and_threshold_sources_are_not_claimed() {
+		let mut plic = Plic::new();
+		update_priority(&mut plic, 1, 3);
+		update_priority(&mut plic, 2, 7);
+		update_priority(&mut plic, 10, 4);
+		enable(&mut plic, 1);
+		enable(&mut plic, 10);
+		store_word(&mut plic, 0x200000 + CONTEXT * 0x1000, 3); // Threshold
+		plic.detect_interrupt(&[(1, true), (2, true), (10, true)]);
+		// Source 2 isn't enabled and source 1 isn't above the threshold
+		assert_eq!(claim(&mut plic), 10);
+		assert!(!plic.is_interrupting(CONTEXT as usize));
+		assert_eq!(claim(&mut plic), 0);
+		// Machine mode context doesn't enable any source
+		assert!(!plic.is_interrupting(0));
+		store_word(&mut plic, 0x200000 + CONTEXT * 0x1000, 2);
+		assert_eq!(claim(&mut plic), 1);
+	}
+
+	#[test]
+	fn claimed_source_is_held_until_completion() {
+		let mut plic = Plic::new();
+		update_priority(&mut plic, 5, 1);
+		enable(&mut plic, 5);
+		plic.raise_irq(5);
+		plic.detect_interrupt(&[]);
+		assert_eq!(claim(&mut plic), 5);
+		// The line is still raised but the source is in service
+		plic.detect_interrupt(&[(5, true)]);
+		assert!(!plic.is_interrupting(CONTEXT as usize));
+		complete(&mut plic, 5);
+		plic.detect_interrupt(&[]);
+		assert_eq!(claim(&mut plic), 5);
+		complete(&mut plic, 5);
+		plic.lower_irq(5);
+		plic.detect_interrupt(&[]);
+		assert_eq!(claim(&mut plic), 0);
+	}
+
+	#[test]
+	fn reserved_and_out_of_range_sources_are_ignored() {
+		let mut plic = Plic::new();
+		update_priority(&mut plic, 0, 1);
+		enable(&mut plic, 0);
+		plic.raise_irq(0);
+		plic.raise_irq(NUM_SOURCES as u32);
+		plic.detect_interrupt(&[(0, true), (NUM_SOURCES as u32, true)]);
+		assert!(!plic.has_pending());
+		assert_eq!(load_word(&mut plic, 0), 0);
+		assert_eq!(load_word(&mut plic, 0x2000 + CONTEXT * 0x80), 0);
 	}
 }
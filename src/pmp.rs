@@ -0,0 +1,197 @@
+use mmu::MemoryAccessType;
+
+// Physical Memory Protection with 16 entries and 4-byte granularity.
+// Each entry has an 8-bit configuration, packed into pmpcfgN CSRs by Cpu,
+// and an address register holding physical address bits [55:2].
+pub const NUM_ENTRIES: usize = 16;
+
+// Configuration bits
+const PMP_R: u8 = 0x01;
+const PMP_W: u8 = 0x02;
+const PMP_X: u8 = 0x04;
+const PMP_A: u8 = 0x18;
+const PMP_L: u8 = 0x80;
+
+// Address matching modes in A field
+const PMP_A_OFF: u8 = 0x00;
+const PMP_A_TOR: u8 = 0x08;
+const PMP_A_NA4: u8 = 0x10;
+const PMP_A_NAPOT: u8 = 0x18;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Pmp {
+	configs: Vec<u8>,
+	addresses: Vec<u64>,
+	// Whether any entry is enabled. No check is done until then, the same
+	// as QEMU, so that software not setting up PMP can run in S/U-mode.
+	enabled: bool,
+	// Whether any enabled entry is locked. M-mode accesses can be
+	// rejected only if so.
+	locked: bool
+}
+
+impl Pmp {
+	pub fn new() -> Self {
+		Pmp {
+			configs: vec![0; NUM_ENTRIES],
+			addresses: vec![0; NUM_ENTRIES],
+			enabled: false,
+			locked: false
+		}
+	}
+
+	pub fn get_config(&self, index: usize) -> u8 {
+		self.configs[index]
+	}
+
+	pub fn update_config(&mut self, index: usize, value: u8) {
+		// Locked entry can't be modified until reset
+		if (self.configs[index] & PMP_L) != 0 {
+			return;
+		}
+		// Bits 5 and 6 are reserved. R=0 and W=1 combination is reserved, too.
+		let mut config = value & !0x60;
+		if (config & (PMP_R | PMP_W)) == PMP_W {
+			config &= !PMP_W;
+		}
+		self.configs[index] = config;
+		self.enabled = self.configs.iter().any(|config| (config & PMP_A) != PMP_A_OFF);
+		self.locked = self.configs.iter().any(|config| (config & PMP_A) != PMP_A_OFF && (config & PMP_L) != 0);
+	}
+
+	pub fn get_address(&self, index: usize) -> u64 {
+		self.addresses[index]
+	}
+
+	pub fn update_address(&mut self, index: usize, value: u64) {
+		// Address of a locked entry, and the bottom of locked TOR range
+		// of the next entry, can't be modified
+		if (self.configs[index] & PMP_L) != 0 {
+			return;
+		}
+		if index + 1 < NUM_ENTRIES &&
+			(self.configs[index + 1] & (PMP_L | PMP_A)) == (PMP_L | PMP_A_TOR) {
+			return;
+		}
+		self.addresses[index] = value & 0x3fffffffffffff;
+	}
+
+	// Returns whether the access of width bytes from the physical
+	// address is permitted in the privilege mode
+	pub fn check(&self, address: u64, width: u64, access_type: MemoryAccessType, is_machine_mode: bool) -> bool {
+		if !self.enabled || (is_machine_mode && !self.locked) {
+			return true;
+		}
+		let end = address.wrapping_add(width);
+		// The lowest-numbered entry matching any byte of the access decides
+		for i in 0..NUM_ENTRIES {
+			let config = self.configs[i];
+			let (range_start, range_end) = match config & PMP_A {
+				PMP_A_TOR => {
+					let bottom = match i {
+						0 => 0,
+						_ => self.addresses[i - 1] << 2
+					};
+					(bottom, self.addresses[i] << 2)
+				},
+				PMP_A_NA4 => (self.addresses[i] << 2, (self.addresses[i] << 2) + 4),
+				PMP_A_NAPOT => {
+					// Trailing ones of the address register encode the size
+					let ones = self.addresses[i].trailing_ones() as u64;
+					let size = 8u64 << ones;
+					let base = (self.addresses[i] << 2) & !(size - 1);
+					(base, base.wrapping_add(size))
+				},
+				_ => continue // PMP_A_OFF
+			};
+			if address >= range_end || end <= range_start {
+				continue;
+			}
+			// Partially matching access fails
+			if address < range_start || end > range_end {
+				return false;
+			}
+			if is_machine_mode && (config & PMP_L) == 0 {
+				return true;
+			}
+			let permission = match access_type {
+				MemoryAccessType::Execute => PMP_X,
+				MemoryAccessType::Read => PMP_R,
+				MemoryAccessType::Write => PMP_W
+			};
+			return (config & permission) != 0;
+		}
+		// No entry matches. M-mode succeeds and S/U-mode fails.
+		is_machine_mode
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn check(pmp: &Pmp, address: u64, width: u64, access_type: MemoryAccessType) -> bool {
+		pmp.check(address, width, access_type, false)
+	}
+
+	#[test]
+	fn address_matching_modes() {
+		let mut pmp = Pmp::new();
+		// TOR ranges [0, 0x1000) and [0x1000, 0x2000)
+		pmp.update_address(0, 0x1000 >> 2);
+		pmp.update_config(0, PMP_A_TOR | PMP_R);
+		pmp.update_address(1, 0x2000 >> 2);
+		pmp.update_config(1, PMP_A_TOR | PMP_R | PMP_W);
+		// NA4 at 0x3000 and NAPOT [0x4000, 0x5000)
+		pmp.update_address(2, 0x3000 >> 2);
+		pmp.update_config(2, PMP_A_NA4 | PMP_X);
+		pmp.update_address(3, (0x4000 >> 2) | 0x1ff);
+		pmp.update_config(3, PMP_A_NAPOT | PMP_R | PMP_W | PMP_X);
+
+		assert!(check(&pmp, 0xff8, 8, MemoryAccessType::Read));
+		assert!(!check(&pmp, 0xff8, 8, MemoryAccessType::Write));
+		assert!(check(&pmp, 0x1000, 8, MemoryAccessType::Write));
+		assert!(!check(&pmp, 0x2000, 4, MemoryAccessType::Read));
+		assert!(check(&pmp, 0x3000, 4, MemoryAccessType::Execute));
+		assert!(!check(&pmp, 0x3000, 4, MemoryAccessType::Read));
+		assert!(!check(&pmp, 0x3004, 4, MemoryAccessType::Execute));
+		assert!(check(&pmp, 0x4000, 8, MemoryAccessType::Write));
+		assert!(check(&pmp, 0x4ff8, 8, MemoryAccessType::Read));
+		// No entry matches in supervisor mode
+		assert!(!check(&pmp, 0x5000, 8, MemoryAccessType::Read));
+		// Unlocked entries don't constrain machine mode
+		assert!(pmp.check(0x3000, 4, MemoryAccessType::Write, true));
+		assert!(pmp.check(0x5000, 8, MemoryAccessType::Write, true));
+	}
+
+	#[test]
+	fn partially_matching_access_fails() {
+		let mut pmp = Pmp::new();
+		pmp.update_address(0, (0x4000 >> 2) | 0x1ff);
+		pmp.update_config(0, PMP_A_NAPOT | PMP_R | PMP_W);
+		pmp.update_address(1, 0x10000 >> 2);
+		pmp.update_config(1, PMP_A_TOR | PMP_R | PMP_W);
+		// Crossing the top of the NAPOT range fails even though the next entry permits it
+		assert!(!check(&pmp, 0x4ffc, 8, MemoryAccessType::Read));
+		assert!(!check(&pmp, 0x3ffc, 8, MemoryAccessType::Read));
+		assert!(check(&pmp, 0x5000, 8, MemoryAccessType::Read));
+	}
+
+	#[test]
+	fn locked_entry_constrains_machine_mode() {
+		let mut pmp = Pmp::new();
+		pmp.update_address(0, 0x3000 >> 2);
+		pmp.update_config(0, PMP_A_NA4 | PMP_R | PMP_L);
+		assert!(pmp.check(0x3000, 4, MemoryAccessType::Read, true));
+		assert!(!pmp.check(0x3000, 4, MemoryAccessType::Write, true));
+		// Other addresses are still accessible in machine mode only
+		assert!(pmp.check(0x4000, 4, MemoryAccessType::Write, true));
+		assert!(!pmp.check(0x4000, 4, MemoryAccessType::Write, false));
+		// Locked entry can't be modified
+		pmp.update_config(0, PMP_A_NA4 | PMP_R | PMP_W);
+		pmp.update_address(0, 0x4000 >> 2);
+		assert_eq!(pmp.get_config(0), PMP_A_NA4 | PMP_R | PMP_L);
+		assert_eq!(pmp.get_address(0), 0x3000 >> 2);
+		assert!(!pmp.check(0x3000, 4, MemoryAccessType::Write, true));
+	}
+}
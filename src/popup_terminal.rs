@@ -1,6 +1,6 @@
 extern crate pancurses;
 
-use terminal::Terminal;
+use riscv_rust::terminal::Terminal;
 use std::str;
 use self::pancurses::*;
 
@@ -38,6 +38,17 @@ impl Terminal for PopupTerminal {
 		}
 	}
 
+	fn get_input_bytes(&mut self) -> Vec<u8> {
+		let mut bytes = vec![];
+		loop {
+			match self.window.getch() {
+				Some(Input::Character(c)) => bytes.push(c as u8),
+				_ => break
+			};
+		}
+		bytes
+	}
+
 	// Wasm specific methods. No use.
 	
 	fn put_input(&mut self, _value: u8) {
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+// Aggregate statistics of execution for performance analysis. Nothing is
+// counted until enabled so that disabled statistics cost only a flag check.
+pub struct ExecStats {
+	enabled: bool,
+	instructions: u64, // the number of retired instructions
+	instruction_counts: HashMap<&'static str, u64>, // keyed by instruction name
+	trap_counts: HashMap<&'static str, u64>, // keyed by trap type name
+	tlb_hits: u64,
+	tlb_misses: u64,
+	page_table_walks: u64
+}
+
+impl ExecStats {
+	pub fn new() -> Self {
+		ExecStats {
+			enabled: false,
+			instructions: 0,
+			instruction_counts: HashMap::new(),
+			trap_counts: HashMap::new(),
+			tlb_hits: 0,
+			tlb_misses: 0,
+			page_table_walks: 0
+		}
+	}
+
+	pub fn update_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	// Clears the counts, keeping enabled or disabled
+	pub fn reset(&mut self) {
+		self.instructions = 0;
+		self.instruction_counts.clear();
+		self.trap_counts.clear();
+		self.tlb_hits = 0;
+		self.tlb_misses = 0;
+		self.page_table_walks = 0;
+	}
+
+	pub fn record_instruction(&mut self, name: &'static str) {
+		if !self.enabled {
+			return;
+		}
+		self.instructions += 1;
+		*self.instruction_counts.entry(name).or_insert(0) += 1;
+	}
+
+	pub fn record_trap(&mut self, name: &'static str) {
+		if !self.enabled {
+			return;
+		}
+		*self.trap_counts.entry(name).or_insert(0) += 1;
+	}
+
+	pub fn record_tlb_hit(&mut self) {
+		if self.enabled {
+			self.tlb_hits += 1;
+		}
+	}
+
+	pub fn record_tlb_miss(&mut self) {
+		if self.enabled {
+			self.tlb_misses += 1;
+		}
+	}
+
+	pub fn record_page_table_walk(&mut self) {
+		if self.enabled {
+			self.page_table_walks += 1;
+		}
+	}
+
+	pub fn get_instructions(&self) -> u64 {
+		self.instructions
+	}
+
+	pub fn get_instruction_count(&self, name: &str) -> u64 {
+		match self.instruction_counts.get(name) {
+			Some(count) => *count,
+			None => 0
+		}
+	}
+
+	pub fn get_trap_count(&self, name: &str) -> u64 {
+		match self.trap_counts.get(name) {
+			Some(count) => *count,
+			None => 0
+		}
+	}
+
+	// Instruction names and counts, the most executed first
+	pub fn get_instruction_histogram(&self) -> Vec<(&'static str, u64)> {
+		sort_counts(&self.instruction_counts)
+	}
+
+	// Trap type names and counts, the most taken first
+	pub fn get_trap_histogram(&self) -> Vec<(&'static str, u64)> {
+		sort_counts(&self.trap_counts)
+	}
+
+	pub fn get_tlb_hits(&self) -> u64 {
+		self.tlb_hits
+	}
+
+	pub fn get_tlb_misses(&self) -> u64 {
+		self.tlb_misses
+	}
+
+	pub fn get_page_table_walks(&self) -> u64 {
+		self.page_table_walks
+	}
+}
+
+fn sort_counts(counts: &HashMap<&'static str, u64>) -> Vec<(&'static str, u64)> {
+	let mut histogram: Vec<(&'static str, u64)> = counts.iter()
+		.map(|(name, count)| (*name, *count))
+		.collect();
+	// Ties are ordered by name to keep the output stable
+	histogram.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+	histogram
+}
@@ -1,7 +1,31 @@
 pub trait Terminal {
 	fn put_byte(&mut self, value: u8);
 	fn get_input(&mut self) -> u8;
+	// Returns all the buffered input without blocking. The default
+	// implementation returns at most a byte from get_input().
+	fn get_input_bytes(&mut self) -> Vec<u8> {
+		match self.get_input() {
+			0 => vec![],
+			value => vec![value]
+		}
+	}
 	// Wasm specific
 	fn get_output(&mut self) -> u8;
 	fn put_input(&mut self, data: u8);
+	// Returns all the buffered output. The default implementation
+	// takes bytes from get_output() until it returns 0.
+	fn get_output_bytes(&mut self) -> Vec<u8> {
+		let mut data = vec![];
+		loop {
+			match self.get_output() {
+				0 => return data,
+				value => data.push(value)
+			};
+		}
+	}
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		for value in data.iter() {
+			self.put_input(*value);
+		}
+	}
 }
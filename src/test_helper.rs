@@ -0,0 +1,83 @@
+// Helpers shared by unit tests. They build a Cpu with memory and encode
+// instructions so that tests can run small programs without a toolchain.
+
+use clint::TimerMode;
+use cpu::Cpu;
+use writer_terminal::WriterTerminal;
+
+pub const DRAM_BASE: u64 = 0x80000000;
+
+// Cpu in machine mode with 1MiB memory whose pc is at DRAM_BASE
+pub fn create_cpu() -> Cpu {
+	let mut cpu = Cpu::new(Box::new(WriterTerminal::new(std::io::sink())), TimerMode::Deterministic);
+	cpu.setup_memory(0x100000);
+	cpu.update_pc(DRAM_BASE);
+	cpu
+}
+
+// Writes instructions from the address and moves pc to it
+pub fn load_program(cpu: &mut Cpu, address: u64, words: &[u32]) {
+	for (i, word) in words.iter().enumerate() {
+		cpu.write_memory(address + i as u64 * 4, &word.to_le_bytes()).unwrap();
+	}
+	cpu.update_pc(address);
+}
+
+pub fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+	(funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+pub fn i_type(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+	(((imm as u32) & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+pub fn s_type(imm: i32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+	let imm = imm as u32;
+	(((imm >> 5) & 0x7f) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | opcode
+}
+
+pub fn sd(rs2: u32, rs1: u32, imm: i32) -> u32 {
+	s_type(imm, rs2, rs1, 3, 0x23)
+}
+
+pub fn b_type(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+	let imm = imm as u32;
+	(((imm >> 12) & 1) << 31) | (((imm >> 5) & 0x3f) << 25) | (rs2 << 20) | (rs1 << 15) |
+		(funct3 << 12) | (((imm >> 1) & 0xf) << 8) | (((imm >> 11) & 1) << 7) | 0x63
+}
+
+pub fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+	i_type(imm, rs1, 0, rd, 0x13)
+}
+
+pub fn ld(rd: u32, rs1: u32, imm: i32) -> u32 {
+	i_type(imm, rs1, 3, rd, 0x03)
+}
+
+pub fn bne(rs1: u32, rs2: u32, imm: i32) -> u32 {
+	b_type(imm, rs2, rs1, 1)
+}
+
+pub fn csrrw(rd: u32, csr: u16, rs1: u32) -> u32 {
+	i_type(csr as i32, rs1, 1, rd, 0x73)
+}
+
+pub const SFENCE_VMA: u32 = 0x12000073;
+
+pub fn csrrs(rd: u32, csr: u16, rs1: u32) -> u32 {
+	i_type(csr as i32, rs1, 2, rd, 0x73)
+}
+
+pub const ECALL: u32 = 0x00000073;
+pub const SRET: u32 = 0x10200073;
+pub const MRET: u32 = 0x30200073;
+
+pub fn beq(rs1: u32, rs2: u32, imm: i32) -> u32 {
+	b_type(imm, rs2, rs1, 0)
+}
+
+pub fn lui(rd: u32, imm: u32) -> u32 {
+	(imm << 12) | (rd << 7) | 0x37
+}
+
+pub const WFI: u32 = 0x10500073;
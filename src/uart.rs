@@ -1,66 +1,336 @@
+use std::collections::VecDeque;
 use terminal::Terminal;
 
+// 16550 compatible. Without FIFO mode (FCR bit 0), receive and transmit
+// FIFOs hold a byte each, working as RBR and THR of 8250.
+const FIFO_SIZE: usize = 16;
+
+// Terminal is polled every INPUT_INTERVAL ticks while no input is
+// waiting, and a character of the input is received every CHARACTER_TIME
+// ticks. Polling less often keeps host overhead low and receiving faster
+// lets pasted text arrive quickly.
+const INPUT_INTERVAL: u64 = 0x10000;
+const CHARACTER_TIME: u64 = 0x1000;
+
+// Character timeout interrupt is raised if no character is received
+// or read for four character times while receive FIFO has data
+const RECEIVE_TIMEOUT: u64 = CHARACTER_TIME * 4;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Uart {
 	clock: u64,
-	receive_register: u8,
-	line_status_register: u8,
-	interrupting: bool,
+	// Input from the terminal waiting to be received. It's kept here
+	// while receive FIFO is full so that characters aren't dropped.
+	input_buffer: VecDeque<u8>,
+	receive_fifo: VecDeque<u8>,
+	transmit_fifo: VecDeque<u8>,
+	interrupt_enable_register: u8,
+	// Word length, stop bits, parity, break, and DLAB (bit 7).
+	// Stored to read back but they don't affect transfer.
+	line_control_register: u8,
+	// DLL and DLM. Accessed at offset 0 and 1 while DLAB is set.
+	divisor_latch: u16,
+	fifo_enabled: bool,
+	receive_trigger_level: usize,
+	// Clock when a character was last received or read
+	receive_clock: u64,
+	// DTR, RTS, OUT1, OUT2, and loopback (bit 4)
+	modem_control_register: u8,
+	// Delta bits of MSR, set when the modem status changes and
+	// cleared by reading MSR
+	modem_status_delta: u8,
+	// THR empty interrupt is latched when transmit FIFO becomes empty or
+	// the interrupt is enabled in IER while it's empty, and is cleared by
+	// writing THR or reading IIR which reports it. Receive interrupts only
+	// take priority over it in IIR and don't clear it.
+	thre_interrupting: bool,
+	// Input is taken only from input_buffer preloaded by host, not
+	// polled from the terminal, so that runs are reproducible
+	deterministic: bool,
+	// Terminal can't be serialized. Restored Uart has DetachedTerminal
+	// until the terminal is moved in with swap_terminal()
+	#[cfg_attr(feature = "serde", serde(skip, default = "create_detached_terminal"))]
 	terminal: Box<dyn Terminal>
 }
 
+#[cfg(feature = "serde")]
+struct DetachedTerminal {
+}
+
+#[cfg(feature = "serde")]
+impl Terminal for DetachedTerminal {
+	fn put_byte(&mut self, _value: u8) {
+	}
+
+	fn get_input(&mut self) -> u8 {
+		0
+	}
+
+	fn get_output(&mut self) -> u8 {
+		0
+	}
+
+	fn put_input(&mut self, _value: u8) {
+	}
+}
+
+#[cfg(feature = "serde")]
+fn create_detached_terminal() -> Box<dyn Terminal> {
+	Box::new(DetachedTerminal {})
+}
+
 impl Uart {
-	pub fn new(terminal: Box<dyn Terminal>) -> Self {
+	pub fn new(terminal: Box<dyn Terminal>, deterministic: bool) -> Self {
 		Uart {
 			clock: 0,
-			receive_register: 0,
-			line_status_register: 0x20,
-			interrupting: false,
+			input_buffer: VecDeque::new(),
+			receive_fifo: VecDeque::new(),
+			transmit_fifo: VecDeque::new(),
+			interrupt_enable_register: 0,
+			line_control_register: 0x3, // 8 data bits, 1 stop bit, no parity
+			divisor_latch: 0,
+			fifo_enabled: false,
+			receive_trigger_level: 1,
+			receive_clock: 0,
+			modem_control_register: 0,
+			modem_status_delta: 0,
+			thre_interrupting: false,
+			deterministic: deterministic,
 			terminal: terminal
 		}
 	}
 
 	pub fn tick(&mut self) {
 		self.clock = self.clock.wrapping_add(1);
-		if (self.clock % 0x10000) == 0 && !self.interrupting {
-			let value = self.terminal.get_input();
-			if value != 0 {
-				self.interrupting = true;
-				self.receive_register = value;
-				self.line_status_register = 1;
-			}
+		// Transmits a byte per tick. In loopback mode, it's received
+		// instead, and lost if receive FIFO is full.
+		match self.transmit_fifo.pop_front() {
+			Some(value) => {
+				match self.is_loopback() {
+					true => {
+						if self.receive_fifo.len() < self.get_fifo_size() {
+							self.receive_fifo.push_back(value);
+							self.receive_clock = self.clock;
+						}
+					},
+					false => self.terminal.put_byte(value)
+				};
+				if self.transmit_fifo.is_empty() {
+					self.thre_interrupting = true;
+				}
+			},
+			None => {}
+		};
+		// Input from the terminal is disconnected in loopback mode
+		if self.is_loopback() {
+			return;
+		}
+		if !self.deterministic && (self.clock % INPUT_INTERVAL) == 0 && self.input_buffer.is_empty() {
+			self.input_buffer.extend(self.terminal.get_input_bytes());
+		}
+		if (self.clock % CHARACTER_TIME) == 0 && self.receive_fifo.len() < self.get_fifo_size() {
+			match self.input_buffer.pop_front() {
+				Some(value) => {
+					self.receive_fifo.push_back(value);
+					self.receive_clock = self.clock;
+				},
+				None => {}
+			};
 		}
 	}
 
-	pub fn is_interrupting(&self) -> bool {
-		self.interrupting
+	// Baud rate is the input clock / (16 * divisor).
+	// Transfer timing doesn't follow it so far.
+	pub fn get_divisor(&self) -> u16 {
+		self.divisor_latch
 	}
 
-	pub fn reset_interrupting(&mut self) {
-		self.interrupting = false;
+	fn is_divisor_latch_accessed(&self) -> bool {
+		(self.line_control_register & 0x80) != 0
+	}
+
+	fn is_loopback(&self) -> bool {
+		(self.modem_control_register & 0x10) != 0
+	}
+
+	// CTS, DSR, RI, and DCD in bits [7:4] of MSR. In loopback mode, they
+	// reflect RTS, DTR, OUT1, and OUT2 of MCR. Otherwise the terminal is
+	// always ready and carrier is detected.
+	fn get_modem_status(&self) -> u8 {
+		match self.is_loopback() {
+			true => {
+				let mcr = self.modem_control_register;
+				((mcr & 0x2) << 3) | ((mcr & 0x1) << 5) | ((mcr & 0xc) << 4)
+			},
+			false => 0xb0
+		}
+	}
+
+	fn update_modem_control_register(&mut self, value: u8) {
+		let old_status = self.get_modem_status();
+		self.modem_control_register = value & 0x1f;
+		let new_status = self.get_modem_status();
+		// DCTS, DDSR, and DDCD on any change, and TERI when RI turns off
+		let changed = (old_status ^ new_status) >> 4;
+		let trailing_edge = (old_status & !new_status) >> 4;
+		self.modem_status_delta |= (changed & 0xb) | (trailing_edge & 0x4);
+	}
+
+	fn get_fifo_size(&self) -> usize {
+		match self.fifo_enabled {
+			true => FIFO_SIZE,
+			false => 1
+		}
+	}
+
+	// Returns IIR interrupt id in the order of priority.
+	// 0x1 means no interrupt is pending.
+	fn get_interrupt_id(&self) -> u8 {
+		if (self.interrupt_enable_register & 0x1) != 0 && !self.receive_fifo.is_empty() {
+			// Received data available
+			if !self.fifo_enabled || self.receive_fifo.len() >= self.receive_trigger_level {
+				return 0x4;
+			}
+			// Character timeout
+			if self.clock.wrapping_sub(self.receive_clock) >= RECEIVE_TIMEOUT {
+				return 0xc;
+			}
+		}
+		if (self.interrupt_enable_register & 0x2) != 0 && self.thre_interrupting {
+			return 0x2;
+		}
+		// Modem status
+		if (self.interrupt_enable_register & 0x8) != 0 && self.modem_status_delta != 0 {
+			return 0x0;
+		}
+		0x1
+	}
+
+	// Level-sensitive. Keeps interrupting until the guest reads
+	// received data or MSR, writes THR, or disables the interrupt in IER.
+	pub fn is_interrupting(&self) -> bool {
+		self.get_interrupt_id() != 0x1
 	}
 
-	pub fn load(&mut self, address: u64) -> u8 {
-		match address {
-			0x10000000 => {
-				let value = self.receive_register;
-				self.receive_register = 0x0;
-				self.line_status_register = 0x20;
-				value
+	pub fn load(&mut self, offset: u64) -> u8 {
+		match offset {
+			0x0 => match self.is_divisor_latch_accessed() {
+				true => self.divisor_latch as u8, // DLL
+				false => { // RBR
+					self.receive_clock = self.clock;
+					self.receive_fifo.pop_front().unwrap_or(0)
+				}
+			},
+			0x1 => match self.is_divisor_latch_accessed() {
+				true => (self.divisor_latch >> 8) as u8, // DLM
+				false => self.interrupt_enable_register // IER
+			},
+			0x2 => { // IIR
+				let id = self.get_interrupt_id();
+				if id == 0x2 {
+					self.thre_interrupting = false;
+				}
+				match self.fifo_enabled {
+					true => id | 0xc0,
+					false => id
+				}
+			},
+			0x3 => self.line_control_register, // LCR
+			0x4 => self.modem_control_register, // MCR
+			0x5 => { // LSR
+				let data_ready = match self.receive_fifo.is_empty() {
+					true => 0,
+					false => 0x1
+				};
+				// Transmitter is empty when transmit FIFO is because
+				// it has no shift register
+				let transmitter_empty = match self.transmit_fifo.is_empty() {
+					true => 0x60, // TEMT and THRE
+					false => 0
+				};
+				data_ready | transmitter_empty
+			},
+			0x6 => { // MSR
+				let delta = self.modem_status_delta;
+				self.modem_status_delta = 0;
+				self.get_modem_status() | delta
 			},
-			0x10000005 => self.line_status_register, // UART0 LSR
 			_ => 0
 		}
 	}
 
-	pub fn store(&mut self, address: u64, value: u8) {
-		match address {
-			0x10000000 => { // UART0 THR
-				self.terminal.put_byte(value);
+	pub fn store(&mut self, offset: u64, value: u8) {
+		match offset {
+			0x0 if self.is_divisor_latch_accessed() => { // DLL
+				self.divisor_latch = (self.divisor_latch & !0xff) | (value as u16);
+			},
+			0x1 if self.is_divisor_latch_accessed() => { // DLM
+				self.divisor_latch = (self.divisor_latch & !0xff00) | ((value as u16) << 8);
+			},
+			0x0 => { // THR
+				// Written data is lost if transmit FIFO is full
+				if self.transmit_fifo.len() < self.get_fifo_size() {
+					self.transmit_fifo.push_back(value);
+				}
+				self.thre_interrupting = false;
+			},
+			0x1 => { // IER
+				// Enabling THR empty interrupt raises it if THR is already empty
+				if (self.interrupt_enable_register & 0x2) == 0 && (value & 0x2) != 0 &&
+					self.transmit_fifo.is_empty() {
+					self.thre_interrupting = true;
+				}
+				self.interrupt_enable_register = value & 0xf;
+			},
+			0x2 => { // FCR
+				let fifo_enabled = (value & 0x1) != 0;
+				// Enabling or disabling FIFO mode clears the FIFOs
+				if fifo_enabled != self.fifo_enabled || (value & 0x2) != 0 {
+					self.receive_fifo.clear();
+				}
+				if fifo_enabled != self.fifo_enabled || (value & 0x4) != 0 {
+					self.transmit_fifo.clear();
+				}
+				self.fifo_enabled = fifo_enabled;
+				self.receive_trigger_level = match value >> 6 {
+					0 => 1,
+					1 => 4,
+					2 => 8,
+					_ => 14
+				};
+			},
+			0x3 => { // LCR
+				self.line_control_register = value;
+			},
+			0x4 => { // MCR
+				self.update_modem_control_register(value);
 			},
 			_ => {}
 		};
 	}
 
+	#[cfg(feature = "serde")]
+	pub fn swap_terminal(&mut self, other: &mut Uart) {
+		std::mem::swap(&mut self.terminal, &mut other.terminal);
+	}
+
+	// Input received before any from the terminal
+	pub fn preload_input(&mut self, data: &[u8]) {
+		self.input_buffer.extend(data);
+	}
+
+	// Takes a received byte bypassing the registers, for console
+	// of firmware emulated by host
+	pub fn receive(&mut self) -> Option<u8> {
+		match self.receive_fifo.pop_front() {
+			Some(value) => {
+				self.receive_clock = self.clock;
+				Some(value)
+			},
+			None => None
+		}
+	}
+
 	// Wasm specific
 
 	pub fn get_output(&mut self) -> u8 {
@@ -74,4 +344,130 @@ impl Uart {
 	pub fn put_input(&mut self, data: u8) {
 		self.terminal.put_input(data);
 	}
+
+	pub fn get_output_bytes(&mut self) -> Vec<u8> {
+		self.terminal.get_output_bytes()
+	}
+
+	pub fn put_input_bytes(&mut self, data: &[u8]) {
+		self.terminal.put_input_bytes(data);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use writer_terminal::WriterTerminal;
+
+	fn create_uart() -> Uart {
+		Uart::new(Box::new(WriterTerminal::new(std::io::sink())), true)
+	}
+
+	#[test]
+	fn thr_empty_interrupt_toggles_while_draining() {
+		let mut uart = create_uart();
+		// Enabling the interrupt while THR is empty raises it
+		uart.store(0x1, 0x2);
+		assert!(uart.is_interrupting());
+		uart.store(0x0, b'a');
+		assert!(!uart.is_interrupting());
+		uart.tick();
+		assert!(uart.is_interrupting());
+		// Reading IIR which reports it clears it
+		assert_eq!(uart.load(0x2), 0x2);
+		assert!(!uart.is_interrupting());
+		assert_eq!(uart.load(0x2), 0x1);
+
+		// With FIFO, raised when the last byte is transmitted
+		uart.store(0x2, 0x1);
+		for value in b"abc" {
+			uart.store(0x0, *value);
+		}
+		assert_eq!(uart.load(0x5) & 0x60, 0);
+		for _ in 0..2 {
+			uart.tick();
+			assert!(!uart.is_interrupting());
+		}
+		uart.tick();
+		assert!(uart.is_interrupting());
+		assert_eq!(uart.load(0x5) & 0x60, 0x60);
+		// Writing THR clears it
+		uart.store(0x0, b'd');
+		assert!(!uart.is_interrupting());
+		uart.tick();
+		assert!(uart.is_interrupting());
+	}
+
+	#[test]
+	fn thr_empty_interrupt_isnt_raised_while_disabled() {
+		let mut uart = create_uart();
+		uart.store(0x0, b'a');
+		uart.tick();
+		assert!(!uart.is_interrupting());
+		assert_eq!(uart.load(0x2), 0x1);
+	}
+
+	// Receives the input byte after a character time
+	fn receive_input(uart: &mut Uart, value: u8) {
+		uart.preload_input(&[value]);
+		for _ in 0..CHARACTER_TIME {
+			uart.tick();
+		}
+		assert_eq!(uart.load(0x5) & 0x1, 0x1);
+	}
+
+	#[test]
+	fn receive_only_interrupt() {
+		let mut uart = create_uart();
+		uart.store(0x1, 0x1);
+		// Transmitter is empty but its interrupt isn't enabled
+		assert!(!uart.is_interrupting());
+		uart.store(0x0, b'a');
+		uart.tick();
+		assert!(!uart.is_interrupting());
+		receive_input(&mut uart, b'b');
+		assert!(uart.is_interrupting());
+		assert_eq!(uart.load(0x2), 0x4);
+		assert_eq!(uart.load(0x0), b'b');
+		assert!(!uart.is_interrupting());
+		assert_eq!(uart.load(0x2), 0x1);
+	}
+
+	#[test]
+	fn transmit_only_interrupt() {
+		let mut uart = create_uart();
+		uart.store(0x0, b'a');
+		uart.store(0x1, 0x2);
+		// Received data doesn't interrupt
+		receive_input(&mut uart, b'b');
+		assert!(uart.is_interrupting());
+		assert_eq!(uart.load(0x2), 0x2);
+		assert!(!uart.is_interrupting());
+		assert_eq!(uart.load(0x0), b'b');
+	}
+
+	#[test]
+	fn received_data_interrupt_takes_priority() {
+		let mut uart = create_uart();
+		uart.store(0x1, 0x3);
+		receive_input(&mut uart, b'a');
+		// Reading IIR reporting received data keeps THR empty interrupt
+		assert_eq!(uart.load(0x2), 0x4);
+		assert_eq!(uart.load(0x2), 0x4);
+		assert_eq!(uart.load(0x0), b'a');
+		assert_eq!(uart.load(0x2), 0x2);
+		assert_eq!(uart.load(0x2), 0x1);
+		// Modem status is the lowest. Entering and leaving loopback mode changes it.
+		uart.store(0x1, 0xb);
+		uart.store(0x4, 0x10);
+		uart.store(0x4, 0x0);
+		uart.store(0x0, b'b');
+		receive_input(&mut uart, b'c');
+		assert_eq!(uart.load(0x2), 0x4);
+		assert_eq!(uart.load(0x0), b'c');
+		assert_eq!(uart.load(0x2), 0x2);
+		assert_eq!(uart.load(0x2), 0x0);
+		uart.load(0x6);
+		assert_eq!(uart.load(0x2), 0x1);
+	}
 }
@@ -1,44 +1,62 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use virtio_mmio::VirtioMmio;
+
+// Virtio block device. Its MMIO interface is VirtioMmio and requests
+// are processed by Mmu.handle_disk_request().
+
+const VIRTIO_BLK_DEVICE_ID: u32 = 2;
+
+// Feature bits
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+// Request types
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// Request status
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VirtioBlockDisk {
-	id: u8,
-	clock: u64,
-	driver_features: u32,
-	guest_page_size: u32,
-	queue_select: u32,
-	queue_num: u32,
-	queue_pfn: u32,
-	queue_notify: u32,
-	status: u32,
-	notify_clock: u64,
-	interrupting: bool,
-	contents: Vec<u8>
+	transport: VirtioMmio,
+	contents: Vec<u8>,
+	// Host file which modified sectors are written back to. None for
+	// in-memory disk. File can't be serialized so restored disk is in-memory.
+	#[cfg_attr(feature = "serde", serde(skip))]
+	file: Option<File>,
+	dirty_sectors: BTreeSet<u64>
 }
 
 impl VirtioBlockDisk {
 	pub fn new() -> Self {
 		VirtioBlockDisk {
-			id: 0,
-			clock: 0,
-			driver_features: 0,
-			guest_page_size: 0,
-			queue_select: 0,
-			queue_num: 0,
-			queue_pfn: 0,
-			queue_notify: 0,
-			status: 0,
-			notify_clock: 0,
-			interrupting: false,
-			contents: vec![]
+			transport: VirtioMmio::new(VIRTIO_BLK_DEVICE_ID, VIRTIO_BLK_F_FLUSH),
+			contents: vec![],
+			file: None,
+			dirty_sectors: BTreeSet::new()
 		}
 	}
 
-	pub fn is_interrupting(&mut self) -> bool {
-		self.interrupting
+	// xv6 expects legacy interface. Linux's virtio-blk driver prefers modern one.
+	pub fn update_legacy(&mut self, legacy: bool) {
+		self.transport.update_legacy(legacy);
 	}
 
-	// @TODO: Rename
-	pub fn reset_interrupting(&mut self) {
-		self.interrupting = false;
-		self.notify_clock = 0;
+	pub fn get_transport(&self) -> &VirtioMmio {
+		&self.transport
+	}
+
+	pub fn get_mut_transport(&mut self) -> &mut VirtioMmio {
+		&mut self.transport
 	}
 
 	pub fn init(&mut self, contents: Vec<u8>) {
@@ -47,151 +65,69 @@ impl VirtioBlockDisk {
 		}
 	}
 
-	pub fn tick(&mut self) {
-		if self.notify_clock > 0 && self.clock > self.notify_clock + 500 {
-			self.interrupting = true;
-		}
-		self.clock = self.clock.wrapping_add(1);
-	}
-
-	pub fn load(&self, address: u64) -> u8 {
-		match address {
-			0x10001000 => 0x76, // vertio disk magic value: 0x74726976
-			0x10001001 => 0x69,
-			0x10001002 => 0x72,
-			0x10001003 => 0x74,
-			0x10001004 => 1, // vertio version: 1
-			0x10001008 => 2, // vertio device id: 2
-			0x1000100c => 0x51, // vertio vendor id: 0x554d4551
-			0x1000100d => 0x45,
-			0x1000100e => 0x4d,
-			0x1000100f => 0x55,
-			0x10001034 => 8, // vertio  queue num max: At least 8
-			_ => 0
-		}
-	}
-	
-	pub fn store(&mut self, address: u64, value: u8) {
-		match address {
-			0x10001020 => {
-				self.driver_features = (self.driver_features & !0xff) | (value as u32);
-			},
-			0x10001021 => {
-				self.driver_features = (self.driver_features & !0xff00) | ((value as u32) << 8);
-			},
-			0x10001022 => {
-				self.driver_features = (self.driver_features & !0xff0000) | ((value as u32) << 16);			
-			},
-			0x10001023 => {
-				self.driver_features = (self.driver_features & !0xff000000) | ((value as u32) << 24);
-			},
-			0x10001028 => {
-				self.guest_page_size = (self.guest_page_size & !0xff) | (value as u32);
-			},
-			0x10001029 => {
-				self.guest_page_size = (self.guest_page_size & !0xff00) | ((value as u32) << 8);
-			},
-			0x1000102a => {
-				self.guest_page_size = (self.guest_page_size & !0xff0000) | ((value as u32) << 16);			
-			},
-			0x1000102b => {
-				self.guest_page_size = (self.guest_page_size & !0xff000000) | ((value as u32) << 24);
-			},
-			0x10001030 => {
-				self.queue_select = (self.queue_select & !0xff) | (value as u32);
-			},
-			0x10001031 => {
-				self.queue_select = (self.queue_select & !0xff00) | ((value as u32) << 8);
-			},
-			0x10001032 => {
-				self.queue_select = (self.queue_select & !0xff0000) | ((value as u32) << 16);			
-			},
-			0x10001033 => {
-				self.queue_select = (self.queue_select & !0xff000000) | ((value as u32) << 24);
-			},
-			0x10001038 => {
-				self.queue_num = (self.queue_num & !0xff) | (value as u32);
-			},
-			0x10001039 => {
-				self.queue_num = (self.queue_num & !0xff00) | ((value as u32) << 8);
-			},
-			0x1000103a => {
-				self.queue_num = (self.queue_num & !0xff0000) | ((value as u32) << 16);			
-			},
-			0x1000103b => {
-				self.queue_num = (self.queue_num & !0xff000000) | ((value as u32) << 24);
-			},
-			0x10001040 => {
-				self.queue_pfn = (self.queue_pfn & !0xff) | (value as u32);
-			},
-			0x10001041 => {
-				self.queue_pfn = (self.queue_pfn & !0xff00) | ((value as u32) << 8);
-			},
-			0x10001042 => {
-				self.queue_pfn = (self.queue_pfn & !0xff0000) | ((value as u32) << 16);			
-			},
-			0x10001043 => {
-				self.queue_pfn = (self.queue_pfn & !0xff000000) | ((value as u32) << 24);
-			},
-			0x10001050 => {
-				self.queue_notify = (self.queue_notify & !0xff) | (value as u32);
-			},
-			0x10001051 => {
-				self.queue_notify = (self.queue_notify & !0xff00) | ((value as u32) << 8);
-			},
-			0x10001052 => {
-				self.queue_notify = (self.queue_notify & !0xff0000) | ((value as u32) << 16);			
-			},
-			0x10001053 => {
-				self.queue_notify = (self.queue_notify & !0xff000000) | ((value as u32) << 24);
-				self.notify_clock = self.clock;
-			},
-			0x10001070 => {
-				self.status = (self.status & !0xff) | (value as u32);
-			},
-			0x10001071 => {
-				self.status = (self.status & !0xff00) | ((value as u32) << 8);
-			},
-			0x10001072 => {
-				self.status = (self.status & !0xff0000) | ((value as u32) << 16);			
-			},
-			0x10001073 => {
-				self.status = (self.status & !0xff000000) | ((value as u32) << 24);
-			},
-			_ => {}
+	// Reads the contents from the file and writes changes back to it
+	// with flush(). The file needs to be opened readable and writable.
+	pub fn init_with_file(&mut self, mut file: File) -> std::io::Result<()> {
+		let mut contents = vec![];
+		match file.read_to_end(&mut contents) {
+			Ok(_) => {},
+			Err(e) => return Err(e)
 		};
+		self.init(contents);
+		self.file = Some(file);
+		Ok(())
 	}
 
-	pub fn get_page_address(&self) -> u64 {
-		self.queue_pfn as u64 * self.guest_page_size as u64
+	// Writes modified sectors back to the file. No-op for in-memory disk.
+	pub fn flush(&mut self) -> std::io::Result<()> {
+		let file = match self.file.as_mut() {
+			Some(file) => file,
+			None => return Ok(())
+		};
+		for sector in self.dirty_sectors.iter() {
+			let start = sector * SECTOR_SIZE;
+			let end = match start + SECTOR_SIZE > self.contents.len() as u64 {
+				true => self.contents.len() as u64,
+				false => start + SECTOR_SIZE
+			};
+			match file.seek(SeekFrom::Start(start)) {
+				Ok(_) => {},
+				Err(e) => return Err(e)
+			};
+			match file.write_all(&self.contents[start as usize..end as usize]) {
+				Ok(()) => {},
+				Err(e) => return Err(e)
+			};
+		}
+		self.dirty_sectors.clear();
+		file.flush()
 	}
 
-	// desc = pages -- num * VRingDesc
-	// avail = pages + 0x40 -- 2 * uint16, then num * uint16
-	// used = pages + 4096 -- 2 * uint16, then num * vRingUsedElem
-	
-	pub fn get_desc_address(&self) -> u64 {
-		self.get_page_address()
+	pub fn load(&self, offset: u64) -> u8 {
+		// Configuration space has capacity in 512-byte sectors as 64-bit value
+		match offset {
+			0x100..=0x107 => (self.get_capacity() >> ((offset - 0x100) * 8)) as u8,
+			_ => self.transport.load(offset)
+		}
 	}
 
-	pub fn get_avail_address(&self) -> u64 {
-		self.get_page_address() + 0x40
+	pub fn store(&mut self, offset: u64, value: u8) {
+		self.transport.store(offset, value);
 	}
 
-	pub fn get_used_address(&self) -> u64 {
-		self.get_page_address() + 4096
+	// Capacity in 512-byte sectors
+	pub fn get_capacity(&self) -> u64 {
+		self.contents.len() as u64 / SECTOR_SIZE
 	}
 
 	pub fn read_from_disk(&mut self, address: u64) -> u8 {
 		self.contents[address as usize]
 	}
-	
-	pub fn write_to_disk(&mut self, address: u64, value: u8) {
-		self.contents[address as usize] = value
-	}
 
-	pub fn get_new_id(&mut self) -> u8 {
-		self.id += 1;
-		self.id
+	pub fn write_to_disk(&mut self, address: u64, value: u8) {
+		self.contents[address as usize] = value;
+		if self.file.is_some() {
+			self.dirty_sectors.insert(address / SECTOR_SIZE);
+		}
 	}
 }
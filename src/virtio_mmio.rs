@@ -0,0 +1,402 @@
+// MMIO interface of virtio devices, legacy (version 1) by default or
+// modern (version 2) which the driver negotiates VIRTIO_F_VERSION_1 on.
+// A device has a single virtqueue. This manages the registers and the queue
+// state, and the device implements its configuration space from 0x100.
+// Descriptors are processed by Mmu because they live in the guest memory.
+
+// Queue size the device accepts at most
+const QUEUE_NUM_MAX: u32 = 128;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Device status bits
+const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+
+// Ticks from queue notification until the requests are processed
+const ACCESS_DELAY: u64 = 500;
+
+// Descriptor flags
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VirtioMmio {
+	clock: u64,
+	device_id: u32,
+	// Device specific features. VIRTIO_F_VERSION_1 is added by modern interface.
+	features: u64,
+	// Legacy interface rather than modern. Set up by host, kept on reset.
+	legacy: bool,
+	device_features_select: u32,
+	driver_features: u64,
+	driver_features_select: u32,
+	guest_page_size: u32,
+	queue_select: u32,
+	queue_num: u32,
+	// Legacy interface places the queue in guest pages
+	queue_align: u32,
+	queue_pfn: u32,
+	// Modern interface has the address of each area and ready bit
+	queue_ready: u32,
+	queue_desc: u64,
+	queue_driver: u64,
+	queue_device: u64,
+	queue_notify: u32,
+	interrupt_status: u32,
+	status: u32,
+	// Next entry of available ring to process
+	last_avail_index: u16,
+	// Index of used ring the device writes next
+	used_index: u16,
+	notify_clock: u64,
+	// Interrupt line to PLIC. Raised when requests are processed and
+	// lowered when the source is claimed, regardless of InterruptACK
+	// which xv6 doesn't write. Same as QEMU which delivers an interrupt
+	// per notification.
+	interrupting: bool
+}
+
+impl VirtioMmio {
+	pub fn new(device_id: u32, features: u64) -> Self {
+		VirtioMmio {
+			clock: 0,
+			device_id: device_id,
+			features: features,
+			legacy: true,
+			device_features_select: 0,
+			driver_features: 0,
+			driver_features_select: 0,
+			guest_page_size: 0,
+			queue_select: 0,
+			queue_num: 0,
+			queue_align: 4096,
+			queue_pfn: 0,
+			queue_ready: 0,
+			queue_desc: 0,
+			queue_driver: 0,
+			queue_device: 0,
+			queue_notify: 0,
+			interrupt_status: 0,
+			status: 0,
+			last_avail_index: 0,
+			used_index: 0,
+			notify_clock: 0,
+			interrupting: false
+		}
+	}
+
+	pub fn update_legacy(&mut self, legacy: bool) {
+		self.legacy = legacy;
+	}
+
+	pub fn is_interrupting(&self) -> bool {
+		self.interrupting
+	}
+
+	pub fn reset_interrupting(&mut self) {
+		self.interrupting = false;
+	}
+
+	pub fn tick(&mut self) {
+		self.clock = self.clock.wrapping_add(1);
+	}
+
+	// Whether notified requests are ready to be processed
+	pub fn is_access_due(&self) -> bool {
+		self.notify_clock > 0 && self.clock > self.notify_clock + ACCESS_DELAY
+	}
+
+	// Called after the requests are processed and used ring is updated
+	pub fn complete_access(&mut self) {
+		self.notify_clock = 0;
+		self.interrupt_status |= 0x1; // Used buffer notification
+		self.interrupting = true;
+	}
+
+	// Resets the device when the driver writes zero to status
+	fn reset(&mut self) {
+		self.device_features_select = 0;
+		self.driver_features = 0;
+		self.driver_features_select = 0;
+		self.queue_select = 0;
+		self.queue_num = 0;
+		self.queue_align = 4096;
+		self.queue_pfn = 0;
+		self.queue_ready = 0;
+		self.queue_desc = 0;
+		self.queue_driver = 0;
+		self.queue_device = 0;
+		self.queue_notify = 0;
+		self.interrupt_status = 0;
+		self.status = 0;
+		self.last_avail_index = 0;
+		self.used_index = 0;
+		self.notify_clock = 0;
+		self.interrupting = false;
+	}
+
+	// Registers are 32-bit width. Configuration space from 0x100 is
+	// read as zero, the device overrides it.
+	pub fn load(&self, offset: u64) -> u8 {
+		let value = match offset & !0x3 {
+			0x000 => 0x74726976, // Magic value "virt"
+			0x004 => match self.legacy { // Version
+				true => 1,
+				false => 2
+			},
+			0x008 => self.device_id,
+			0x00c => 0x554d4551, // Vendor ID: "QEMU"
+			0x010 => match self.device_features_select { // 32 bits of device features selected
+				0 => self.get_device_features() as u32,
+				1 => (self.get_device_features() >> 32) as u32,
+				_ => 0
+			},
+			0x034 => match self.queue_select {
+				0 => QUEUE_NUM_MAX,
+				_ => 0 // Only a queue
+			},
+			0x040 if self.legacy => self.queue_pfn,
+			0x044 if !self.legacy => self.queue_ready,
+			0x060 => self.interrupt_status,
+			0x070 => self.status,
+			_ => 0
+		};
+		(value >> ((offset % 4) * 8)) as u8
+	}
+
+	pub fn store(&mut self, offset: u64, value: u8) {
+		match offset & !0x3 {
+			0x014 => {
+				self.device_features_select = write_byte(self.device_features_select, offset, value);
+			},
+			// 32 bits of driver features selected
+			0x020 if self.driver_features_select < 2 => {
+				let shift = self.driver_features_select * 32;
+				let features = write_byte((self.driver_features >> shift) as u32, offset, value);
+				self.driver_features = (self.driver_features & !(0xffffffff << shift)) | ((features as u64) << shift);
+			},
+			0x024 => {
+				self.driver_features_select = write_byte(self.driver_features_select, offset, value);
+			},
+			0x028 => {
+				self.guest_page_size = write_byte(self.guest_page_size, offset, value);
+			},
+			0x030 => {
+				self.queue_select = write_byte(self.queue_select, offset, value);
+			},
+			0x038 => {
+				self.queue_num = write_byte(self.queue_num, offset, value);
+			},
+			0x03c => {
+				self.queue_align = write_byte(self.queue_align, offset, value);
+			},
+			0x040 => {
+				self.queue_pfn = write_byte(self.queue_pfn, offset, value);
+			},
+			0x044 => {
+				self.queue_ready = write_byte(self.queue_ready, offset, value);
+			},
+			0x050 => {
+				self.queue_notify = write_byte(self.queue_notify, offset, value);
+				if (offset % 4) == 3 && self.notify_clock == 0 {
+					self.notify_clock = self.clock;
+				}
+			},
+			0x064 => { // Interrupt ACK
+				self.interrupt_status &= !((value as u32) << ((offset % 4) * 8));
+			},
+			0x070 => {
+				self.status = write_byte(self.status, offset, value);
+				if (offset % 4) == 3 {
+					if self.status == 0 {
+						self.reset();
+					} else if !self.legacy && !self.is_features_acceptable() {
+						// The driver reads status back to know the features are rejected
+						self.status &= !VIRTIO_STATUS_FEATURES_OK;
+					}
+				}
+			},
+			// 64-bit addresses of the modern queue areas, the lower 32 bits first
+			0x080 | 0x084 => {
+				self.queue_desc = write_byte_u64(self.queue_desc, offset, value);
+			},
+			0x090 | 0x094 => {
+				self.queue_driver = write_byte_u64(self.queue_driver, offset, value);
+			},
+			0x0a0 | 0x0a4 => {
+				self.queue_device = write_byte_u64(self.queue_device, offset, value);
+			},
+			_ => {}
+		};
+	}
+
+	fn get_device_features(&self) -> u64 {
+		match self.legacy {
+			true => self.features,
+			false => self.features | VIRTIO_F_VERSION_1
+		}
+	}
+
+	// Modern interface requires the driver to accept VIRTIO_F_VERSION_1 and
+	// no features the device doesn't offer. Legacy one doesn't have FEATURES_OK.
+	fn is_features_acceptable(&self) -> bool {
+		(self.driver_features & !self.get_device_features()) == 0 &&
+			(self.driver_features & VIRTIO_F_VERSION_1) != 0
+	}
+
+	// Negotiated queue size. Zero if the queue isn't set up or the driver
+	// wrote an invalid size, more than QueueNumMax or not a power of 2 which
+	// split virtqueues require. Requests on such a queue aren't processed.
+	pub fn get_queue_size(&self) -> u64 {
+		if !self.legacy && self.queue_ready == 0 {
+			return 0;
+		}
+		match self.queue_num > QUEUE_NUM_MAX || !self.queue_num.is_power_of_two() {
+			true => 0,
+			false => self.queue_num as u64
+		}
+	}
+
+	pub fn get_page_address(&self) -> u64 {
+		self.queue_pfn as u64 * self.guest_page_size as u64
+	}
+
+	// Legacy virtqueue layout
+	// desc = pages -- num * VRingDesc
+	// avail = desc + num * 16 -- 2 * uint16, then num * uint16, then uint16
+	// used = avail + 6 + num * 2 aligned to QueueAlign -- 2 * uint16, then num * vRingUsedElem
+
+	// Modern interface gives the addresses of descriptor table, available
+	// ring (driver area), and used ring (device area) directly
+
+	pub fn get_desc_address(&self) -> u64 {
+		match self.legacy {
+			true => self.get_page_address(),
+			false => self.queue_desc
+		}
+	}
+
+	pub fn get_avail_address(&self) -> u64 {
+		match self.legacy {
+			true => self.get_desc_address() + self.get_queue_size() * 16,
+			false => self.queue_driver
+		}
+	}
+
+	pub fn get_used_address(&self) -> u64 {
+		if !self.legacy {
+			return self.queue_device;
+		}
+		// QueueAlign is a power of 2
+		let address = self.get_avail_address() + 6 + self.get_queue_size() * 2;
+		let mask = match self.queue_align {
+			0 => 0,
+			align => align as u64 - 1
+		};
+		(address + mask) & !mask
+	}
+
+	// Index of available ring entry to process next
+	pub fn get_last_avail_index(&self) -> u16 {
+		self.last_avail_index
+	}
+
+	pub fn advance_last_avail_index(&mut self) {
+		self.last_avail_index = self.last_avail_index.wrapping_add(1);
+	}
+
+	// Index of used ring entry to write next
+	pub fn get_used_index(&self) -> u16 {
+		self.used_index
+	}
+
+	pub fn advance_used_index(&mut self) {
+		self.used_index = self.used_index.wrapping_add(1);
+	}
+}
+
+fn write_byte(register: u32, offset: u64, value: u8) -> u32 {
+	let shift = (offset % 4) * 8;
+	(register & !(0xff << shift)) | ((value as u32) << shift)
+}
+
+// For 64-bit register made of two 32-bit ones at 8-byte aligned offset
+fn write_byte_u64(register: u64, offset: u64, value: u8) -> u64 {
+	let shift = (offset % 8) * 8;
+	(register & !(0xff << shift)) | ((value as u64) << shift)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FEATURES: u64 = 1 << 5;
+	const STATUS_ACKNOWLEDGE_DRIVER: u32 = 3;
+
+	fn store_word(virtio: &mut VirtioMmio, offset: u64, value: u32) {
+		for i in 0..4 {
+			virtio.store(offset + i, (value >> (i * 8)) as u8);
+		}
+	}
+
+	fn load_word(virtio: &VirtioMmio, offset: u64) -> u32 {
+		let mut value = 0;
+		for i in 0..4 {
+			value |= (virtio.load(offset + i) as u32) << (i * 8);
+		}
+		value
+	}
+
+	// Writes the driver features and FEATURES_OK, and returns status read back
+	fn negotiate(virtio: &mut VirtioMmio, driver_features: u64) -> u32 {
+		store_word(virtio, 0x070, STATUS_ACKNOWLEDGE_DRIVER);
+		for i in 0..2 {
+			store_word(virtio, 0x024, i); // DriverFeaturesSel
+			store_word(virtio, 0x020, (driver_features >> (i * 32)) as u32);
+		}
+		store_word(virtio, 0x070, STATUS_ACKNOWLEDGE_DRIVER | VIRTIO_STATUS_FEATURES_OK);
+		load_word(virtio, 0x070)
+	}
+
+	fn create_modern_virtio() -> VirtioMmio {
+		let mut virtio = VirtioMmio::new(2, FEATURES);
+		virtio.update_legacy(false);
+		virtio
+	}
+
+	#[test]
+	fn feature_negotiation() {
+		let mut virtio = create_modern_virtio();
+		assert_eq!(load_word(&virtio, 0x004), 2);
+		assert_eq!(load_word(&virtio, 0x010), FEATURES as u32);
+		store_word(&mut virtio, 0x014, 1); // DeviceFeaturesSel
+		assert_eq!(load_word(&virtio, 0x010), (VIRTIO_F_VERSION_1 >> 32) as u32);
+
+		// Missing VIRTIO_F_VERSION_1 clears FEATURES_OK
+		assert_eq!(negotiate(&mut virtio, FEATURES), STATUS_ACKNOWLEDGE_DRIVER);
+		// So does a feature the device doesn't offer
+		store_word(&mut virtio, 0x070, 0);
+		assert_eq!(negotiate(&mut virtio, VIRTIO_F_VERSION_1 | 1), STATUS_ACKNOWLEDGE_DRIVER);
+		store_word(&mut virtio, 0x070, 0);
+		assert_eq!(negotiate(&mut virtio, VIRTIO_F_VERSION_1 | FEATURES),
+			STATUS_ACKNOWLEDGE_DRIVER | VIRTIO_STATUS_FEATURES_OK);
+		assert_eq!(negotiate(&mut VirtioMmio::new(2, FEATURES), 0),
+			STATUS_ACKNOWLEDGE_DRIVER | VIRTIO_STATUS_FEATURES_OK);
+	}
+
+	#[test]
+	fn invalid_queue_size() {
+		let mut virtio = VirtioMmio::new(2, 0);
+		assert_eq!(load_word(&virtio, 0x034), QUEUE_NUM_MAX);
+		for &(queue_num, size) in [(0, 0), (1, 1), (8, 8), (12, 0), (128, 128), (129, 0), (256, 0)].iter() {
+			store_word(&mut virtio, 0x038, queue_num);
+			assert_eq!(virtio.get_queue_size(), size);
+		}
+		// Modern queue isn't used until it's ready
+		let mut virtio = create_modern_virtio();
+		store_word(&mut virtio, 0x038, 8);
+		assert_eq!(virtio.get_queue_size(), 0);
+		store_word(&mut virtio, 0x044, 1);
+		assert_eq!(virtio.get_queue_size(), 8);
+	}
+}
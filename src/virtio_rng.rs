@@ -0,0 +1,62 @@
+use entropy_source::EntropySource;
+#[cfg(feature = "serde")]
+use entropy_source::HostEntropy;
+use virtio_mmio::VirtioMmio;
+
+// Virtio entropy device. Its MMIO interface is VirtioMmio and the guest's
+// buffers are filled by Mmu.handle_rng_request(). The device has no
+// features and no configuration space.
+
+const VIRTIO_RNG_DEVICE_ID: u32 = 4;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VirtioRng {
+	transport: VirtioMmio,
+	// Host randomness isn't a part of the snapshot. Restored device
+	// reads host entropy until the source is moved in with swap_entropy_source().
+	#[cfg_attr(feature = "serde", serde(skip, default = "create_host_entropy"))]
+	entropy_source: Box<dyn EntropySource>
+}
+
+#[cfg(feature = "serde")]
+fn create_host_entropy() -> Box<dyn EntropySource> {
+	Box::new(HostEntropy::new())
+}
+
+impl VirtioRng {
+	pub fn new(entropy_source: Box<dyn EntropySource>) -> Self {
+		VirtioRng {
+			transport: VirtioMmio::new(VIRTIO_RNG_DEVICE_ID, 0),
+			entropy_source: entropy_source
+		}
+	}
+
+	pub fn update_entropy_source(&mut self, entropy_source: Box<dyn EntropySource>) {
+		self.entropy_source = entropy_source;
+	}
+
+	#[cfg(feature = "serde")]
+	pub fn swap_entropy_source(&mut self, other: &mut VirtioRng) {
+		std::mem::swap(&mut self.entropy_source, &mut other.entropy_source);
+	}
+
+	pub fn get_transport(&self) -> &VirtioMmio {
+		&self.transport
+	}
+
+	pub fn get_mut_transport(&mut self) -> &mut VirtioMmio {
+		&mut self.transport
+	}
+
+	pub fn load(&self, offset: u64) -> u8 {
+		self.transport.load(offset)
+	}
+
+	pub fn store(&mut self, offset: u64, value: u8) {
+		self.transport.store(offset, value);
+	}
+
+	pub fn fill_bytes(&mut self, buffer: &mut [u8]) {
+		self.entropy_source.fill_bytes(buffer);
+	}
+}
@@ -1,19 +1,50 @@
 extern crate wasm_bindgen;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate bincode;
 use wasm_bindgen::prelude::*;
 
-mod application;
-mod cpu;
+// application, clock_source, cpu, cpu_builder, disasm, entropy_source,
+// gdbstub, loader, mmio_device, terminal, and writer_terminal are public
+// so that the emulator can be embedded in other Rust programs
+pub mod application;
+pub mod cpu;
+pub mod cpu_builder;
+mod decode_cache;
+pub mod disasm;
+mod fdt;
+pub mod gdbstub;
+mod htif;
+pub mod loader;
+pub mod mmio_device;
+mod memory;
 mod mmu;
 mod plic;
+mod pmp;
+pub mod stats;
 mod clint;
+pub mod clock_source;
+pub mod entropy_source;
 mod uart;
 mod virtio_block_disk;
-mod terminal;
+mod virtio_mmio;
+mod virtio_rng;
+pub mod terminal;
+pub mod writer_terminal;
 mod wasm_terminal;
+#[cfg(test)]
+mod test_helper;
 
 use wasm_terminal::WasmTerminal;
 use application::Application;
 
+// mmu and clint are private but memory map and timer
+// are configurable from outside
+pub use mmu::{MemoryMap, MemoryRegion, WatchKind};
+pub use clint::{TimerMode, DEFAULT_TIMEBASE_FREQUENCY};
+
 #[wasm_bindgen]
 pub struct WasmRiscv {
 	application: Application
@@ -23,7 +54,8 @@ pub struct WasmRiscv {
 impl WasmRiscv {
 	pub fn new() -> Self {
 		WasmRiscv {
-			application: Application::new(Box::new(WasmTerminal::new()))
+			// wasm32 doesn't have monotonic clock
+			application: Application::new(Box::new(WasmTerminal::new()), TimerMode::Deterministic)
 		}
 	}
 	
@@ -49,4 +81,13 @@ impl WasmRiscv {
 	pub fn put_input(&mut self, data: u8) {
 		self.application.put_input(data);
 	}
+
+	// Takes all the output at once, returned to JavaScript as Uint8Array
+	pub fn get_output_bytes(&mut self) -> Vec<u8> {
+		self.application.get_output_bytes()
+	}
+
+	pub fn put_input_bytes(&mut self, data: &[u8]) {
+		self.application.put_input_bytes(data);
+	}
 }
\ No newline at end of file
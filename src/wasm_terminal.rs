@@ -1,39 +1,48 @@
+use std::collections::VecDeque;
 use terminal::Terminal;
 
+// Buffers are ring buffers so that bytes are taken from the front
+// without moving the rest
 pub struct WasmTerminal {
-	input_data: Vec<u8>,
-	output_data: Vec<u8>
+	input_data: VecDeque<u8>,
+	output_data: VecDeque<u8>
 }
 
 impl WasmTerminal {
 	pub fn new() -> Self {
 		WasmTerminal {
-			input_data: vec![],
-			output_data: vec![]
+			input_data: VecDeque::new(),
+			output_data: VecDeque::new()
 		}
 	}
 }
 
 impl Terminal for WasmTerminal {
 	fn put_byte(&mut self, value: u8) {
-		self.output_data.push(value);
+		self.output_data.push_back(value);
 	}
 	
 	fn get_input(&mut self) -> u8 {
-		match self.input_data.len() > 0 {
-			true => self.input_data.remove(0),
-			false => 0
-		}
+		self.input_data.pop_front().unwrap_or(0)
+	}
+
+	fn get_input_bytes(&mut self) -> Vec<u8> {
+		self.input_data.drain(..).collect()
 	}
 	
 	fn put_input(&mut self, value: u8) {
-		self.input_data.push(value);
+		self.input_data.push_back(value);
 	}
 	
 	fn get_output(&mut self) -> u8 {
-		match self.output_data.len() > 0 {
-			true => self.output_data.remove(0),
-			false => 0
-		}
+		self.output_data.pop_front().unwrap_or(0)
+	}
+
+	fn get_output_bytes(&mut self) -> Vec<u8> {
+		self.output_data.drain(..).collect()
+	}
+
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		self.input_data.extend(data);
 	}
 }
\ No newline at end of file
@@ -0,0 +1,42 @@
+use std::io::Write;
+
+use terminal::Terminal;
+
+// Output only terminal writing guest output to any Write,
+// for example a file or a Vec<u8> to capture boot log.
+// It has no input.
+pub struct WriterTerminal<W: Write> {
+	writer: W
+}
+
+impl<W: Write> WriterTerminal<W> {
+	pub fn new(writer: W) -> Self {
+		WriterTerminal {
+			writer: writer
+		}
+	}
+}
+
+impl<W: Write> Terminal for WriterTerminal<W> {
+	fn put_byte(&mut self, value: u8) {
+		match self.writer.write_all(&[value]) {
+			_ => {} // Ignoring error so far
+		};
+		match self.writer.flush() {
+			_ => {} // Ignoring error so far
+		};
+	}
+
+	fn get_input(&mut self) -> u8 {
+		0
+	}
+
+	// Wasm specific methods. No use.
+
+	fn put_input(&mut self, _value: u8) {
+	}
+
+	fn get_output(&mut self) -> u8 {
+		0
+	}
+}